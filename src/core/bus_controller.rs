@@ -0,0 +1,434 @@
+use super::execution_path::BusAccessRequst;
+use crate::display::into_table;
+use crate::util::clone_box;
+use crate::util::json::Json;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Display};
+
+/// A bus access waiting for arbitration
+#[derive(Debug, Clone)]
+pub struct PendingAccess {
+    request: BusAccessRequst,
+    /// Issue order of the instruction which sends the request
+    age: u64,
+    /// Cycle that the request entered the queue
+    arrival: usize,
+}
+
+impl PendingAccess {
+    pub fn path_name(&self) -> String {
+        self.request.path_name()
+    }
+    pub fn age(&self) -> u64 {
+        self.age
+    }
+    pub fn is_load(&self) -> bool {
+        self.request.is_load()
+    }
+}
+
+clone_box! {
+    /// Clone a policy behind a box, implemented for every cloneable ArbitrationPolicy
+    CloneArbitrationPolicy for ArbitrationPolicy
+}
+
+/// Policy to decide which pending access is granted the bus
+pub trait ArbitrationPolicy: Debug + CloneArbitrationPolicy {
+    fn name(&self) -> String;
+    /// Select an access from the queue.
+    /// On found, index of the access in the queue returned.
+    /// Otherwise, None returned.
+    fn select(&mut self, queue: &[PendingAccess]) -> Option<usize>;
+    /// Return state of the policy to save in a snapshot, null if it's stateless
+    fn save(&self) -> Json {
+        Json::Null
+    }
+    /// Restore state returned by [ArbitrationPolicy::save]
+    fn restore(&mut self, _state: &Json) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Grant accesses in the order they are requested
+#[derive(Debug, Clone)]
+pub struct Fifo;
+
+impl ArbitrationPolicy for Fifo {
+    fn name(&self) -> String {
+        String::from("FIFO")
+    }
+    fn select(&mut self, queue: &[PendingAccess]) -> Option<usize> {
+        if queue.is_empty() {
+            None
+        } else {
+            Some(0)
+        }
+    }
+}
+
+/// Grant the access of the earliest issued instruction
+#[derive(Debug, Clone)]
+pub struct OldestFirst;
+
+impl ArbitrationPolicy for OldestFirst {
+    fn name(&self) -> String {
+        String::from("Oldest first")
+    }
+    fn select(&mut self, queue: &[PendingAccess]) -> Option<usize> {
+        queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, access)| access.age())
+            .map(|(idx, _)| idx)
+    }
+}
+
+/// Grant loads before stores, accesses of the same type are granted in FIFO order
+#[derive(Debug, Clone)]
+pub struct LoadFirst;
+
+impl ArbitrationPolicy for LoadFirst {
+    fn name(&self) -> String {
+        String::from("Load first")
+    }
+    fn select(&mut self, queue: &[PendingAccess]) -> Option<usize> {
+        queue
+            .iter()
+            .position(|access| access.is_load())
+            .or_else(|| Fifo.select(queue))
+    }
+}
+
+/// Grant access paths in turn, accesses of the same path are granted in FIFO order
+#[derive(Debug, Clone, Default)]
+pub struct RoundRobin {
+    /// Name of the path which is granted last time
+    last: Option<String>,
+}
+
+impl ArbitrationPolicy for RoundRobin {
+    fn name(&self) -> String {
+        String::from("Round robin")
+    }
+    fn select(&mut self, queue: &[PendingAccess]) -> Option<usize> {
+        let mut paths: Vec<String> = queue.iter().map(|access| access.path_name()).collect();
+        paths.sort();
+        paths.dedup();
+        // The first path after the last granted one, wrap around if there is no such path.
+        let next = paths
+            .iter()
+            .find(|path| self.last.as_ref().is_none_or(|last| *path > last))
+            .or_else(|| paths.first())?
+            .clone();
+        let idx = queue.iter().position(|access| access.path_name() == next)?;
+        self.last = Some(next);
+        Some(idx)
+    }
+    fn save(&self) -> Json {
+        self.last.as_ref().map_or(Json::Null, Json::str)
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.last = state
+            .opt()
+            .map(|last| last.as_str().map(String::from))
+            .transpose()?;
+        Ok(())
+    }
+}
+
+/// Statistics of accesses sent by an access path
+#[derive(Debug, Default, Clone)]
+pub struct PathStatistics {
+    /// Count of granted accesses
+    pub granted: usize,
+    /// Sum of cycles which granted accesses waited in the queue
+    pub total_wait: usize,
+    /// Longest cycles an access waited in the queue
+    pub max_wait: usize,
+    /// Count of cycles which queue depth are sampled
+    pub sampled: usize,
+    /// Sum of queue depth of the path, sampled every cycle
+    pub total_depth: usize,
+    /// Deepest queue depth of the path
+    pub max_depth: usize,
+}
+
+impl Display for PathStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "granted {}, wait avg {:.2} max {}, depth avg {:.2} max {}",
+            self.granted,
+            self.average_wait(),
+            self.max_wait,
+            self.average_depth(),
+            self.max_depth
+        )
+    }
+}
+
+impl PathStatistics {
+    pub fn average_wait(&self) -> f64 {
+        if self.granted == 0 {
+            0.0
+        } else {
+            self.total_wait as f64 / self.granted as f64
+        }
+    }
+    pub fn average_depth(&self) -> f64 {
+        if self.sampled == 0 {
+            0.0
+        } else {
+            self.total_depth as f64 / self.sampled as f64
+        }
+    }
+}
+
+/// Construct an arbitration policy by its name
+pub fn policy_of(name: &str) -> Result<Box<dyn ArbitrationPolicy>, String> {
+    match name {
+        "fifo" => Ok(Box::new(Fifo)),
+        "oldest" => Ok(Box::new(OldestFirst)),
+        "load-first" => Ok(Box::new(LoadFirst)),
+        "round-robin" => Ok(Box::new(RoundRobin::default())),
+        _ => Err(format!("Unknown arbitration policy {}", name)),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BusController {
+    access_queue: Vec<PendingAccess>,
+    policy: Box<dyn ArbitrationPolicy>,
+    /// Cycles elapsed
+    cycle: usize,
+    /// Statistics of each access path, indexed by name of the path
+    statistics: BTreeMap<String, PathStatistics>,
+}
+
+impl Display for BusController {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let queue: Vec<String> = self
+            .access_queue
+            .iter()
+            .map(|access| format!("{}", access.request))
+            .collect();
+        let title = format!("Bus Access Queue ({})", self.policy.name());
+        write!(f, "{}", into_table(&title, queue))
+    }
+}
+
+impl BusController {
+    pub fn new() -> Self {
+        Self {
+            access_queue: Vec::new(),
+            policy: Box::new(Fifo),
+            cycle: 0,
+            statistics: BTreeMap::new(),
+        }
+    }
+    pub fn policy_name(&self) -> String {
+        self.policy.name()
+    }
+    pub fn set_policy(&mut self, policy: Box<dyn ArbitrationPolicy>) {
+        self.policy = policy;
+    }
+    /// Queue a request which sent by an instruction with given issue order
+    pub fn push(&mut self, request: BusAccessRequst, age: u64) {
+        self.statistics.entry(request.path_name()).or_default();
+        self.access_queue.push(PendingAccess {
+            request,
+            age,
+            arrival: self.cycle,
+        });
+    }
+    /// Grant a request by the arbitration policy
+    pub fn pop(&mut self) -> Option<BusAccessRequst> {
+        let idx = self.policy.select(&self.access_queue)?;
+        let access = self.access_queue.remove(idx);
+        let wait = self.cycle - access.arrival;
+        let stat = self.statistics.entry(access.path_name()).or_default();
+        stat.granted += 1;
+        stat.total_wait += wait;
+        stat.max_wait = stat.max_wait.max(wait);
+        Some(access.request)
+    }
+    /// Sample queue depth of each path and move to next cycle
+    pub fn next_cycle(&mut self) {
+        for (path, stat) in self.statistics.iter_mut() {
+            let depth = self
+                .access_queue
+                .iter()
+                .filter(|access| access.path_name() == *path)
+                .count();
+            stat.sampled += 1;
+            stat.total_depth += depth;
+            stat.max_depth = stat.max_depth.max(depth);
+        }
+        self.cycle += 1;
+    }
+    pub fn statistics(&self) -> &BTreeMap<String, PathStatistics> {
+        &self.statistics
+    }
+    /// Return queue, statistics and state of the policy.
+    /// The policy itself is given by the configuration, only its name is saved to check it.
+    pub fn save(&self) -> Json {
+        let queue = self
+            .access_queue
+            .iter()
+            .map(|access| {
+                Json::object(vec![
+                    ("request", access.request.save()),
+                    ("age", Json::from(access.age)),
+                    ("arrival", Json::from(access.arrival)),
+                ])
+            })
+            .collect();
+        let statistics = self
+            .statistics
+            .iter()
+            .map(|(path, stat)| {
+                let stat = Json::object(vec![
+                    ("granted", Json::from(stat.granted)),
+                    ("total_wait", Json::from(stat.total_wait)),
+                    ("max_wait", Json::from(stat.max_wait)),
+                    ("sampled", Json::from(stat.sampled)),
+                    ("total_depth", Json::from(stat.total_depth)),
+                    ("max_depth", Json::from(stat.max_depth)),
+                ]);
+                (path, stat)
+            })
+            .collect();
+        Json::object(vec![
+            ("policy", Json::str(self.policy.name())),
+            ("policy_state", self.policy.save()),
+            ("queue", Json::Array(queue)),
+            ("cycle", Json::from(self.cycle)),
+            ("statistics", Json::object(statistics)),
+        ])
+    }
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        let policy = state.get("policy")?.as_str()?;
+        if policy != self.policy.name() {
+            let msg = format!(
+                "Arbitration policy of the snapshot is {}, but the machine uses {}",
+                policy,
+                self.policy.name()
+            );
+            return Err(msg);
+        }
+        self.policy.restore(state.get("policy_state")?)?;
+        self.access_queue = state
+            .get("queue")?
+            .as_array()?
+            .iter()
+            .map(|access| {
+                Ok(PendingAccess {
+                    request: BusAccessRequst::load(access.get("request")?)?,
+                    age: access.get("age")?.as_u64()?,
+                    arrival: access.get("arrival")?.as_usize()?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        self.cycle = state.get("cycle")?.as_usize()?;
+        self.statistics = state
+            .get("statistics")?
+            .as_object()?
+            .iter()
+            .map(|(path, stat)| {
+                let stat = PathStatistics {
+                    granted: stat.get("granted")?.as_usize()?,
+                    total_wait: stat.get("total_wait")?.as_usize()?,
+                    max_wait: stat.get("max_wait")?.as_usize()?,
+                    sampled: stat.get("sampled")?.as_usize()?,
+                    total_depth: stat.get("total_depth")?.as_usize()?,
+                    max_depth: stat.get("max_depth")?.as_usize()?,
+                };
+                Ok((path.clone(), stat))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(())
+    }
+    /// Return queued requests in arrival order
+    pub fn trace(&self) -> Json {
+        let queue = self
+            .access_queue
+            .iter()
+            .map(|access| {
+                Json::object(vec![
+                    ("request", Json::str(&access.request)),
+                    ("age", Json::from(access.age)),
+                ])
+            })
+            .collect();
+        Json::Array(queue)
+    }
+}
+
+#[cfg(test)]
+mod arbitration {
+    use super::*;
+
+    fn queue(accesses: &[(&str, u64, bool)]) -> Vec<PendingAccess> {
+        accesses
+            .iter()
+            .enumerate()
+            .map(|(arrival, (path, age, is_load))| {
+                let request = if *is_load {
+                    BusAccessRequst::new_load(path.to_string(), 0, 0, 4)
+                } else {
+                    BusAccessRequst::new_store(path.to_string(), 0, 0, vec![0; 4])
+                };
+                PendingAccess {
+                    request,
+                    age: *age,
+                    arrival,
+                }
+            })
+            .collect()
+    }
+    #[test]
+    fn fifo() {
+        let q = queue(&[("a", 3, false), ("b", 1, true)]);
+        assert_eq!(Fifo.select(&q), Some(0));
+        assert_eq!(Fifo.select(&[]), None);
+    }
+    #[test]
+    fn oldest_first() {
+        let q = queue(&[("a", 3, false), ("b", 1, true), ("a", 2, true)]);
+        assert_eq!(OldestFirst.select(&q), Some(1));
+    }
+    #[test]
+    fn load_first() {
+        let q = queue(&[("a", 1, false), ("a", 2, false), ("b", 3, true)]);
+        assert_eq!(LoadFirst.select(&q), Some(2));
+        let q = queue(&[("a", 1, false), ("a", 2, false)]);
+        assert_eq!(LoadFirst.select(&q), Some(0));
+    }
+    #[test]
+    fn round_robin() {
+        let mut rr = RoundRobin::default();
+        let q = queue(&[("a", 1, true), ("a", 2, true), ("b", 3, true)]);
+        assert_eq!(rr.select(&q), Some(0));
+        assert_eq!(rr.select(&q), Some(2));
+        assert_eq!(rr.select(&q), Some(0));
+    }
+    #[test]
+    fn statistics() {
+        let mut controller = BusController::new();
+        controller.push(BusAccessRequst::new_load(String::from("a"), 0, 0, 4), 0);
+        controller.push(BusAccessRequst::new_load(String::from("a"), 1, 0, 4), 1);
+        controller.next_cycle();
+        controller.next_cycle();
+        controller.pop().unwrap();
+        controller.next_cycle();
+        controller.pop().unwrap();
+
+        let stat = &controller.statistics()["a"];
+        assert_eq!(stat.granted, 2);
+        assert_eq!(stat.max_wait, 3);
+        assert_eq!(stat.total_wait, 5);
+        assert_eq!(stat.max_depth, 2);
+        assert_eq!(stat.total_depth, 5);
+        assert_eq!(stat.sampled, 3);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::display::into_table;
+use crate::util::json::Json;
+use std::fmt::{self, Display};
+
+/// Machine status, bit 3 enables interrupts
+pub const MSTATUS: u32 = 768;
+/// Address of the interrupt handler
+pub const MTVEC: u32 = 773;
+/// Address to return to from the interrupt handler
+pub const MEPC: u32 = 833;
+/// Interrupt lines which caused the last interrupt, or cause of the last exception
+pub const MCAUSE: u32 = 834;
+/// Faulting address of the last exception
+pub const MTVAL: u32 = 835;
+/// Interrupt lines requested by devices, read only
+pub const MIP: u32 = 836;
+/// Count of cycles, read only
+pub const CYCLE: u32 = 3072;
+/// Count of retired instructions, read only
+pub const INSTRET: u32 = 3074;
+
+const MSTATUS_MIE: u32 = 1 << 3;
+
+/// Bit of exception causes, which tells them from interrupt lines.
+/// The other bits are the RISC-V exception code.
+const EXCEPTION: u32 = 1 << 31;
+/// Cause of a page fault raised by a load
+pub const LOAD_PAGE_FAULT: u32 = EXCEPTION | 13;
+/// Cause of a page fault raised by a store or an atomic access
+pub const STORE_PAGE_FAULT: u32 = EXCEPTION | 15;
+
+/// Exception raised by an instruction, taken once older instructions complete
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exception {
+    pub thread: usize,
+    /// Address of the instruction, which the handler returns to
+    pub pc: usize,
+    pub cause: u32,
+    /// Faulting address
+    pub value: u32,
+}
+
+impl Exception {
+    pub fn save(&self) -> Json {
+        Json::object(vec![
+            ("thread", Json::from(self.thread)),
+            ("pc", Json::from(self.pc)),
+            ("cause", Json::from(self.cause)),
+            ("value", Json::from(self.value)),
+        ])
+    }
+    pub fn load(state: &Json) -> Result<Self, String> {
+        Ok(Self {
+            thread: state.get("thread")?.as_usize()?,
+            pc: state.get("pc")?.as_usize()?,
+            cause: state.get("cause")?.as_u32()?,
+            value: state.get("value")?.as_u32()?,
+        })
+    }
+}
+
+/// Control and status registers
+#[derive(Debug, Clone)]
+pub struct CsrFile {
+    /// Interrupts are taken only if enabled. Cleared while handling an interrupt.
+    pub enabled: bool,
+    pub vector: usize,
+    pub epc: usize,
+    pub cause: u32,
+    pub tval: u32,
+    pub pending: u32,
+    pub cycle: u32,
+    pub instret: u32,
+    /// Count of interrupts taken
+    pub taken: usize,
+    /// Count of cycles spent waiting in-flight instructions to complete before taking interrupts
+    pub drain_cycles: usize,
+}
+
+impl Display for CsrFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = vec![
+            format!(
+                "Enabled: {}, Vector: {}, EPC: {}",
+                self.enabled, self.vector, self.epc
+            ),
+            format!(
+                "Pending: {:#x}, Cause: {:#x}, Tval: {:#x}",
+                self.pending, self.cause, self.tval
+            ),
+            format!("Cycle: {}, Instret: {}", self.cycle, self.instret),
+            format!("Taken: {}, Drain cycles: {}", self.taken, self.drain_cycles),
+        ];
+        write!(f, "{}", into_table("Control and Status Registers", rows))
+    }
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            vector: 0,
+            epc: 0,
+            cause: 0,
+            tval: 0,
+            pending: 0,
+            cycle: 0,
+            instret: 0,
+            taken: 0,
+            drain_cycles: 0,
+        }
+    }
+    pub fn read(&self, csr: u32) -> Result<u32, String> {
+        let val = match csr {
+            MSTATUS => {
+                if self.enabled {
+                    MSTATUS_MIE
+                } else {
+                    0
+                }
+            }
+            MTVEC => self.vector as u32,
+            MEPC => self.epc as u32,
+            MCAUSE => self.cause,
+            MTVAL => self.tval,
+            MIP => self.pending,
+            CYCLE => self.cycle,
+            INSTRET => self.instret,
+            _ => return Err(format!("Unknown CSR {}", csr)),
+        };
+        Ok(val)
+    }
+    pub fn write(&mut self, csr: u32, val: u32) -> Result<(), String> {
+        match csr {
+            MSTATUS => self.enabled = val & MSTATUS_MIE != 0,
+            MTVEC => self.vector = val as usize,
+            MEPC => self.epc = val as usize,
+            MCAUSE => self.cause = val,
+            MTVAL => self.tval = val,
+            MIP | CYCLE | INSTRET => return Err(format!("CSR {} is read only", csr)),
+            _ => return Err(format!("Unknown CSR {}", csr)),
+        }
+        Ok(())
+    }
+    pub fn save(&self) -> Json {
+        Json::object(vec![
+            ("enabled", Json::from(self.enabled)),
+            ("vector", Json::from(self.vector)),
+            ("epc", Json::from(self.epc)),
+            ("cause", Json::from(self.cause)),
+            ("tval", Json::from(self.tval)),
+            ("pending", Json::from(self.pending)),
+            ("cycle", Json::from(self.cycle)),
+            ("instret", Json::from(self.instret)),
+            ("taken", Json::from(self.taken)),
+            ("drain_cycles", Json::from(self.drain_cycles)),
+        ])
+    }
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.enabled = state.get("enabled")?.as_bool()?;
+        self.vector = state.get("vector")?.as_usize()?;
+        self.epc = state.get("epc")?.as_usize()?;
+        self.cause = state.get("cause")?.as_u32()?;
+        self.tval = state.get("tval")?.as_u32()?;
+        self.pending = state.get("pending")?.as_u32()?;
+        self.cycle = state.get("cycle")?.as_u32()?;
+        self.instret = state.get("instret")?.as_u32()?;
+        self.taken = state.get("taken")?.as_usize()?;
+        self.drain_cycles = state.get("drain_cycles")?.as_usize()?;
+        Ok(())
+    }
+    /// Return wheither an interrupt should be taken or not
+    pub fn interrupting(&self) -> bool {
+        self.enabled && self.pending != 0
+    }
+    /// Take the pending interrupt at `pc`, return the address to jump to
+    pub fn take(&mut self, pc: usize) -> usize {
+        self.epc = pc;
+        self.cause = self.pending;
+        self.enabled = false;
+        self.taken += 1;
+        self.vector
+    }
+    /// Return wheither an exception traps to the handler or not.
+    /// Without a handler, the vector is where the program starts,
+    /// and while a trap is handled, taking another would lose the address to return to.
+    pub fn traps_exceptions(&self) -> bool {
+        self.vector != 0 && self.enabled
+    }
+    /// Take the exception, return the address to jump to
+    pub fn trap(&mut self, exception: &Exception) -> usize {
+        self.epc = exception.pc;
+        self.cause = exception.cause;
+        self.tval = exception.value;
+        self.enabled = false;
+        self.vector
+    }
+    /// Return from the interrupt handler, return the address to jump to
+    pub fn ret(&mut self) -> usize {
+        self.enabled = true;
+        self.epc
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod csr {
+    use super::*;
+    #[test]
+    fn take_and_return() {
+        let mut csr = CsrFile::new();
+        csr.vector = 20;
+        assert!(!csr.interrupting());
+        csr.pending = 1;
+        assert!(csr.interrupting());
+
+        assert_eq!(csr.take(7), 20);
+        assert_eq!(csr.cause, 1);
+        // Nested interrupts are disabled
+        assert!(!csr.interrupting());
+        assert_eq!(csr.ret(), 7);
+        assert!(csr.enabled);
+    }
+    #[test]
+    fn trap_exception() -> Result<(), String> {
+        let mut csr = CsrFile::new();
+        // The program starts at the vector, there is no handler
+        assert!(!csr.traps_exceptions());
+        csr.vector = 20;
+        assert!(csr.traps_exceptions());
+        let fault = Exception {
+            thread: 0,
+            pc: 3,
+            cause: STORE_PAGE_FAULT,
+            value: 0x4000_1000,
+        };
+        assert_eq!(csr.trap(&fault), 20);
+        assert_eq!(csr.read(MCAUSE)?, 0x8000_000f);
+        assert_eq!(csr.read(MTVAL)?, 0x4000_1000);
+        // A fault in the handler is not trapped
+        assert!(!csr.traps_exceptions());
+        assert_eq!(csr.ret(), 3);
+        assert_eq!(Exception::load(&fault.save())?, fault);
+        Ok(())
+    }
+    #[test]
+    fn read_and_write() -> Result<(), String> {
+        let mut csr = CsrFile::new();
+        assert_eq!(csr.read(MSTATUS)?, MSTATUS_MIE);
+        csr.write(MSTATUS, 0)?;
+        assert!(!csr.enabled);
+        csr.write(MEPC, 9)?;
+        assert_eq!(csr.ret(), 9);
+
+        csr.cycle = 5;
+        assert_eq!(csr.read(CYCLE)?, 5);
+        assert!(csr.write(CYCLE, 0).is_err());
+        assert!(csr.write(MIP, 0).is_err());
+        assert!(csr.read(1).is_err());
+        Ok(())
+    }
+}
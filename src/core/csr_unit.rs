@@ -0,0 +1,124 @@
+use std::fmt::Display;
+
+use super::decoder::{InstFormat, TokenType};
+use super::execution_path::{ArgState, ExecPath, ExecResult, RStag};
+use super::result_bus::ResultBus;
+use crate::display::into_table;
+use crate::graph::{cluster, Graph};
+use crate::util::json::Json;
+
+const FUNC: &str = "csr";
+const NAME: &str = "csr1";
+/// Read a CSR into a register
+pub const READ: &str = "csrr";
+/// Write a register into a CSR
+pub const WRITE: &str = "csrw";
+/// Return from interrupt, handled by the processor while issuing
+pub const RETURN: &str = "mret";
+
+/// Path of CSR instructions.
+/// CSRs are accessed by the processor while issuing, since the instructions are serialized.
+/// The path only writes back the value read.
+#[derive(Debug, Clone)]
+pub struct Unit {
+    result: Option<u32>,
+    /// Thread of the instruction whose value is held
+    thread: usize,
+}
+
+impl Unit {
+    pub fn new() -> Self {
+        Self {
+            result: None,
+            thread: 0,
+        }
+    }
+}
+
+impl ExecPath for Unit {
+    fn name(&self) -> String {
+        NAME.to_string()
+    }
+    fn function(&self) -> String {
+        FUNC.to_string()
+    }
+    fn list_insts(&self) -> Vec<InstFormat> {
+        vec![
+            InstFormat::create(READ)
+                .add_syntax(TokenType::Writeback)
+                .add_syntax(TokenType::Immediate)
+                .done(),
+            InstFormat::create(WRITE)
+                .add_syntax(TokenType::Register)
+                .add_syntax(TokenType::Immediate)
+                .done(),
+            InstFormat::create(RETURN).done(),
+        ]
+    }
+    fn forward(&mut self, _tag: RStag, _val: u32) {}
+    /// The first argument holds the value to write back
+    fn try_issue(
+        &mut self,
+        _inst: String,
+        vals: &[ArgState],
+        _seq: u64,
+        thread: usize,
+    ) -> Result<RStag, ()> {
+        match (self.result, vals.first()) {
+            (None, Some(ArgState::Ready(val))) => {
+                self.result = Some(*val);
+                self.thread = thread;
+                Ok(RStag::new(NAME, 0).with_thread(thread))
+            }
+            _ => Err(()),
+        }
+    }
+    fn next_cycle(&mut self, bus: &mut ResultBus) -> Result<(), String> {
+        if let Some(val) = self.result {
+            let tag = RStag::new(NAME, 0).with_thread(self.thread);
+            if bus.set(tag, ExecResult::Arith(val)) {
+                self.result = None;
+            }
+        }
+        Ok(())
+    }
+    fn pending(&self) -> usize {
+        self.result.is_some() as usize
+    }
+    fn is_idle(&self) -> bool {
+        self.result.is_none()
+    }
+    fn trace(&self) -> Json {
+        let result = self.result.map_or(Json::Null, Json::from);
+        Json::object(vec![("result", result)])
+    }
+    fn save(&self) -> Json {
+        let result = self.result.map_or(Json::Null, Json::from);
+        Json::object(vec![
+            ("result", result),
+            ("thread", Json::from(self.thread)),
+        ])
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.result = state.get("result")?.opt().map(Json::as_u32).transpose()?;
+        self.thread = state.get("thread")?.as_usize()?;
+        Ok(())
+    }
+}
+
+impl Graph for Unit {
+    fn get_graph(&self) -> String {
+        let nodes = [
+            ("in", String::from("CSR file")),
+            ("out", String::from("Read value")),
+        ];
+        cluster(NAME, &nodes, &[("in", "out")])
+    }
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows = self.result.iter().map(|r| format!("{}", r)).collect();
+        write!(f, "{}", into_table(NAME, rows))
+    }
+}
@@ -2,7 +2,7 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Decoder is used to decode instruction and find
 /// appropriate name of reservation station
 pub struct Decoder {
@@ -15,6 +15,7 @@ pub struct Decoder {
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod decoder {
     use super::*;
     #[test]
@@ -71,6 +72,7 @@ mod decoder {
         assert_eq!(ArgType::Imm(100), got.args[2]);
     }
     #[test]
+    #[allow(clippy::useless_vec)]
     fn invalid_instruction() {
         use TokenType::*;
         let mut d = Decoder::new();
@@ -215,6 +217,23 @@ impl Decoder {
     pub fn last_instruction(&self) -> &str {
         &self.instruction
     }
+    /// Restore the last decoded instruction from a snapshot
+    pub fn set_last_instruction(&mut self, inst: &str) {
+        self.instruction = inst.to_string();
+    }
+}
+
+/// Split an instruction into its name and arguments, without checking its syntax
+pub fn scan(inst: &str) -> Result<(String, Vec<ArgType>), String> {
+    let tokens = text_slicer(inst);
+    let (name, arguments) = tokens
+        .split_first()
+        .ok_or_else(|| format!("No token has been found in instruction {}", inst))?;
+    let args = arguments
+        .iter()
+        .map(|arg| arg_scan(arg))
+        .collect::<Result<_, _>>()?;
+    Ok((name.to_string(), args))
 }
 
 /// Argument scanner. Scan argument string and turn into [ArgType] (Token type).
@@ -257,8 +276,8 @@ fn text_slicer(txt: &str) -> Vec<&str> {
     let mut begin = 0;
     let mut v = Vec::new();
     let delimiters = [' ', ',', '(', ')', ':', '\n'];
-    for (idx, c) in txt.chars().into_iter().enumerate() {
-        if delimiters.iter().any(|&d| d == c) {
+    for (idx, c) in txt.char_indices() {
+        if delimiters.contains(&c) {
             if begin != idx {
                 v.push(&txt[begin..idx]);
             }
@@ -272,6 +291,7 @@ fn text_slicer(txt: &str) -> Vec<&str> {
 
 #[derive(Clone, Debug)]
 /// Use to record list of name of reservation stations.
+/// Clones share the list, which is changed only when paths are registered.
 struct StationList {
     station: Rc<RefCell<Vec<String>>>,
 }
@@ -383,6 +403,12 @@ pub struct InstFormat {
 }
 
 impl InstFormat {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn syntax(&self) -> &[TokenType] {
+        &self.syntax
+    }
     pub fn create(name: &str) -> InstFormatCreater {
         InstFormatCreater {
             body: InstFormat {
@@ -1,10 +1,18 @@
-use crate::util::raw_to_u32_big_endian;
+use crate::util::{clone_box, Endian};
 
 use super::decoder::InstFormat;
+use super::pipeline::Phase;
 use super::result_bus::ResultBus;
+use super::statistics::{PathUsage, StallCause};
+use crate::functional_units::select_policy::SelectPolicy;
+use crate::graph::{DependencyNode, Graph};
+use crate::util::json::Json;
 use std::clone::Clone;
 use std::cmp::PartialEq;
+use std::collections::HashMap;
 use std::fmt::{self, Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
 
 /// State of argument of reservation stations
 /// There are two states
@@ -44,23 +52,54 @@ impl ArgState {
             }
         }
     }
+    /// A ready argument is saved as its value, a waiting one as its tag
+    pub fn save(&self) -> Json {
+        match self {
+            ArgState::Waiting(tag) => tag.save(),
+            ArgState::Ready(val) => Json::from(*val),
+        }
+    }
+    pub fn load(state: &Json) -> Result<Self, String> {
+        match state {
+            Json::Str(_) => RStag::load(state).map(ArgState::Waiting),
+            _ => state.as_u32().map(ArgState::Ready),
+        }
+    }
 }
 
-/// Tag of Reservation station and slot.
+/// Tag of Reservation station and slot, and hardware thread of the instruction in the slot.
+/// Results are broadcast to stations of every thread, and an argument only takes the result
+/// of the tag it waits for, so results never cross threads.
 #[derive(Debug)]
 pub struct RStag {
     name: String,
     slot: usize,
+    thread: usize,
 }
 
+/// Thread 0 is omitted, e.g. "arith0(1)", otherwise it follows the slot, e.g. "arith0(1)@t1"
 impl Display for RStag {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}({})", self.station(), self.slot())
+        write!(f, "{}({})", self.station(), self.slot())?;
+        if self.thread != 0 {
+            write!(f, "@t{}", self.thread)?;
+        }
+        Ok(())
     }
 }
 impl PartialEq for RStag {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name && self.slot == other.slot
+        self.name == other.name && self.slot == other.slot && self.thread == other.thread
+    }
+}
+
+impl Eq for RStag {}
+
+impl Hash for RStag {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.slot.hash(state);
+        self.thread.hash(state);
     }
 }
 
@@ -69,17 +108,25 @@ impl Clone for RStag {
         Self {
             name: self.name.clone(),
             slot: self.slot,
+            thread: self.thread,
         }
     }
 }
 
 impl RStag {
+    /// Tag of the slot holding an instruction of thread 0
     pub fn new(name: &str, slot: usize) -> Self {
         Self {
             name: name.to_string(),
             slot,
+            thread: 0,
         }
     }
+    /// Set hardware thread of the instruction in the slot
+    pub fn with_thread(mut self, thread: usize) -> Self {
+        self.thread = thread;
+        self
+    }
     /// Return the name of execute path of the station.
     pub fn station(&self) -> String {
         self.name.clone()
@@ -87,13 +134,36 @@ impl RStag {
     pub fn slot(&self) -> usize {
         self.slot
     }
+    /// Return hardware thread of the instruction in the slot
+    pub fn thread(&self) -> usize {
+        self.thread
+    }
+    /// Saved as it's displayed, e.g. "arith0(1)@t1"
+    pub fn save(&self) -> Json {
+        Json::str(self)
+    }
+    pub fn load(state: &Json) -> Result<Self, String> {
+        let tag = state.as_str()?;
+        let (slot, thread) = match tag.rsplit_once("@t") {
+            Some((slot, thread)) => (slot, thread.parse().ok()),
+            None => (tag, Some(0)),
+        };
+        slot.strip_suffix(')')
+            .and_then(|slot| slot.rsplit_once('('))
+            .and_then(|(name, slot)| {
+                Some(RStag::new(name, slot.parse().ok()?).with_thread(thread?))
+            })
+            .ok_or_else(|| format!("Expect a tag, found {}", tag))
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ExecResult {
     Arith(u32),
-    MemLoad(Vec<u8>),
+    MemLoad(u32),
     MemStore,
+    /// Result of store conditional, true if the store succeeded
+    MemConditional(bool),
     Err(String),
 }
 
@@ -101,15 +171,45 @@ impl ExecResult {
     pub fn val(&self) -> u32 {
         match self {
             ExecResult::Arith(val) => *val,
-            ExecResult::MemLoad(val) => raw_to_u32_big_endian(val),
+            ExecResult::MemLoad(val) => *val,
             ExecResult::MemStore => 0,
+            ExecResult::MemConditional(success) => !success as u32,
             // TODO: Improve error handling
             ExecResult::Err(msg) => panic!("Result Bus Error: {}", msg),
         }
     }
+    pub fn save(&self) -> Json {
+        let (kind, val) = match self {
+            ExecResult::Arith(val) => ("arith", Json::from(*val)),
+            ExecResult::MemLoad(val) => ("load", Json::from(*val)),
+            ExecResult::MemStore => ("store", Json::Null),
+            ExecResult::MemConditional(success) => ("conditional", Json::from(*success)),
+            ExecResult::Err(msg) => ("err", Json::str(msg)),
+        };
+        Json::object(vec![("kind", Json::str(kind)), ("value", val)])
+    }
+    pub fn load(state: &Json) -> Result<Self, String> {
+        let val = state.get("value")?;
+        let result = match state.get("kind")?.as_str()? {
+            "arith" => ExecResult::Arith(val.as_u32()?),
+            "load" => ExecResult::MemLoad(val.as_u32()?),
+            "store" => ExecResult::MemStore,
+            "conditional" => ExecResult::MemConditional(val.as_bool()?),
+            "err" => ExecResult::Err(val.as_str()?.to_string()),
+            kind => return Err(format!("Unknown result {}", kind)),
+        };
+        Ok(result)
+    }
 }
 
-pub trait ExecPath: Debug + Display {
+clone_box! {
+    /// Clone an execution path behind a box, implemented for every cloneable ExecPath
+    CloneExecPath for ExecPath
+}
+
+/// Graph of a path has node `<name>.in` which receives issued instructions,
+/// and node `<name>.out` which drives the result bus.
+pub trait ExecPath: Debug + Display + Graph + CloneExecPath {
     fn name(&self) -> String;
     /// Return name of class of fucntional unit.
     fn function(&self) -> String;
@@ -121,35 +221,122 @@ pub trait ExecPath: Debug + Display {
     /// On success, [Ok] with tag of issued reservation station returned.
     /// Otherwise, [Err] returned.
     /// If the issued instruction has no regiter to writeback, the contant of RStag is undefined.
-    fn try_issue(&mut self, inst: String, vals: &[ArgState]) -> Result<RStag, ()>;
+    /// `seq` is the sequence number of the instruction, older instructions have smaller ones.
+    /// `thread` is the hardware thread issuing it, which the returned tag carries.
+    fn try_issue(
+        &mut self,
+        inst: String,
+        vals: &[ArgState],
+        seq: u64,
+        thread: usize,
+    ) -> Result<RStag, ()>;
     fn next_cycle(&mut self, bus: &mut ResultBus) -> Result<(), String>;
     /// Return pending instruction count
     fn pending(&self) -> usize;
     /// Return wheither the execution path is idle or not
     fn is_idle(&self) -> bool;
+    /// Return state of the path in current cycle for statistics
+    fn usage(&self) -> PathUsage {
+        PathUsage::default()
+    }
+    /// Return state of reservation stations for tracing
+    fn trace(&self) -> Json {
+        Json::Null
+    }
+    /// Return phase of instructions in reservation stations
+    fn phases(&self) -> Vec<(RStag, Phase)> {
+        Vec::new()
+    }
+    /// Return in-flight instructions with their dependencies
+    fn dependencies(&self) -> Vec<DependencyNode> {
+        Vec::new()
+    }
+    /// Replace the policy which selects ready instructions of reservation stations
+    fn set_select_policy(&mut self, _policy: Box<dyn SelectPolicy>) {}
+    /// Give length of the longest chain of in-flight instructions waiting for each tag,
+    /// for [SelectPolicy::uses_critical_path]
+    fn set_critical_paths(&mut self, _lengths: &HashMap<RStag, usize>) {}
+    /// Return the reason that the last [ExecPath::try_issue] failed
+    fn issue_stall(&self) -> StallCause {
+        StallCause::StationFull
+    }
+    /// Return state of the path to save in a snapshot, null if it's stateless
+    fn save(&self) -> Json {
+        Json::Null
+    }
+    /// Restore state returned by [ExecPath::save]
+    fn restore(&mut self, _state: &Json) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Operation of atomic read-modify-write access
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmoOp {
+    Add,
+    Swap,
+    /// Signed maximum
+    Max,
+}
+
+impl AmoOp {
+    /// Return the value to write back, given the value in memory and the operand
+    pub fn apply(&self, old: u32, operand: u32) -> u32 {
+        match self {
+            AmoOp::Add => old.wrapping_add(operand),
+            AmoOp::Swap => operand,
+            AmoOp::Max => (old as i32).max(operand as i32) as u32,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            AmoOp::Add => "add",
+            AmoOp::Swap => "swap",
+            AmoOp::Max => "max",
+        }
+    }
+    fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "add" => Ok(AmoOp::Add),
+            "swap" => Ok(AmoOp::Swap),
+            "max" => Ok(AmoOp::Max),
+            _ => Err(format!("Unknown atomic operation {}", name)),
+        }
+    }
 }
 
 /// Bus access command
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum BusAccess {
     /// Read(base address, length)
     Load(u32, usize),
     /// Write(base address, data string)
     Store(u32, Vec<u8>),
+    /// Read and reserve the address(base address, length)
+    LoadReserved(u32, usize),
+    /// Write if the address is still reserved(base address, data string)
+    StoreConditional(u32, Vec<u8>),
+    /// Atomically read, modify and write back(operation, base address, operand)
+    Amo(AmoOp, u32, Vec<u8>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum BusAccessResult {
+    /// Data read, atomic accesses return data before modified
     Load(Vec<u8>),
     Store,
+    /// Whether the store conditional succeeded or not
+    Conditional(bool),
 }
 
 /// Handler of a Bus access
 /// Each bus access request containted a handler. The handler will lead the corresponding response to correct execution path
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct BusAccessHandler {
     path: String,
     slot: usize,
+    /// Hardware thread of the instruction, which holds the reservation of load reserved
+    thread: usize,
 }
 
 impl Display for BusAccessHandler {
@@ -164,7 +351,7 @@ impl BusAccessHandler {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BusAccessRequst {
     access: BusAccess,
     handler: BusAccessHandler,
@@ -174,19 +361,166 @@ impl BusAccessRequst {
     pub fn new_load(path: String, slot: usize, address: u32, len: usize) -> Self {
         Self {
             access: BusAccess::Load(address, len),
-            handler: BusAccessHandler { path, slot },
+            handler: BusAccessHandler {
+                path,
+                slot,
+                thread: 0,
+            },
         }
     }
     pub fn new_store(path: String, slot: usize, address: u32, value: Vec<u8>) -> Self {
         Self {
             access: BusAccess::Store(address, value),
-            handler: BusAccessHandler { path, slot },
+            handler: BusAccessHandler {
+                path,
+                slot,
+                thread: 0,
+            },
         }
     }
+    pub fn new_load_reserved(path: String, slot: usize, address: u32, len: usize) -> Self {
+        Self {
+            access: BusAccess::LoadReserved(address, len),
+            handler: BusAccessHandler {
+                path,
+                slot,
+                thread: 0,
+            },
+        }
+    }
+    pub fn new_store_conditional(path: String, slot: usize, address: u32, value: Vec<u8>) -> Self {
+        Self {
+            access: BusAccess::StoreConditional(address, value),
+            handler: BusAccessHandler {
+                path,
+                slot,
+                thread: 0,
+            },
+        }
+    }
+    pub fn new_amo(path: String, slot: usize, op: AmoOp, address: u32, operand: Vec<u8>) -> Self {
+        Self {
+            access: BusAccess::Amo(op, address, operand),
+            handler: BusAccessHandler {
+                path,
+                slot,
+                thread: 0,
+            },
+        }
+    }
+    /// Set hardware thread of the instruction which sends the request
+    pub fn with_thread(mut self, thread: usize) -> Self {
+        self.handler.thread = thread;
+        self
+    }
+    /// Return hardware thread of the instruction which sends the request
+    pub fn thread(&self) -> usize {
+        self.handler.thread
+    }
     /// Get access command from the request
     pub fn request(&self) -> &BusAccess {
         &self.access
     }
+    /// Return name of the execution path which sends the request
+    pub fn path_name(&self) -> String {
+        self.handler.path_name()
+    }
+    /// Return tag of the instruction which sends the request
+    pub fn tag(&self) -> RStag {
+        RStag::new(&self.handler.path, self.handler.slot).with_thread(self.handler.thread)
+    }
+    /// Return wheither the access only reads memory or not
+    pub fn is_load(&self) -> bool {
+        matches!(
+            self.access,
+            BusAccess::Load(_, _) | BusAccess::LoadReserved(_, _)
+        )
+    }
+    /// Return wheither the access writes memory or not
+    pub fn writes(&self) -> bool {
+        !self.is_load()
+    }
+    /// Return wheither the access reads memory or not
+    pub fn reads(&self) -> bool {
+        !matches!(
+            self.access,
+            BusAccess::Store(_, _) | BusAccess::StoreConditional(_, _)
+        )
+    }
+    /// Return base address of the access
+    pub fn address(&self) -> u32 {
+        match self.access {
+            BusAccess::Load(address, _) => address,
+            BusAccess::Store(address, _) => address,
+            BusAccess::LoadReserved(address, _) => address,
+            BusAccess::StoreConditional(address, _) => address,
+            BusAccess::Amo(_, address, _) => address,
+        }
+    }
+    /// Return length of the access in bytes
+    pub fn access_len(&self) -> usize {
+        match &self.access {
+            BusAccess::Load(_, len) | BusAccess::LoadReserved(_, len) => *len,
+            BusAccess::Store(_, data)
+            | BusAccess::StoreConditional(_, data)
+            | BusAccess::Amo(_, _, data) => data.len(),
+        }
+    }
+    /// Redirect the access to given base address
+    pub fn relocate(mut self, address: u32) -> Self {
+        match &mut self.access {
+            BusAccess::Load(base, _) => *base = address,
+            BusAccess::Store(base, _) => *base = address,
+            BusAccess::LoadReserved(base, _) => *base = address,
+            BusAccess::StoreConditional(base, _) => *base = address,
+            BusAccess::Amo(_, base, _) => *base = address,
+        }
+        self
+    }
+    pub fn save(&self) -> Json {
+        let mut fields = vec![
+            ("path", Json::str(&self.handler.path)),
+            ("slot", Json::from(self.handler.slot)),
+            ("thread", Json::from(self.handler.thread)),
+            ("address", Json::from(self.address())),
+        ];
+        let (kind, mut operands) = match &self.access {
+            BusAccess::Load(_, len) => ("load", vec![("len", Json::from(*len))]),
+            BusAccess::Store(_, data) => ("store", vec![("data", Json::bytes(data))]),
+            BusAccess::LoadReserved(_, len) => ("load_reserved", vec![("len", Json::from(*len))]),
+            BusAccess::StoreConditional(_, data) => {
+                ("store_conditional", vec![("data", Json::bytes(data))])
+            }
+            BusAccess::Amo(op, _, data) => (
+                "amo",
+                vec![("op", Json::str(op.name())), ("data", Json::bytes(data))],
+            ),
+        };
+        fields.push(("kind", Json::str(kind)));
+        fields.append(&mut operands);
+        Json::object(fields)
+    }
+    pub fn load(state: &Json) -> Result<Self, String> {
+        let path = state.get("path")?.as_str()?.to_string();
+        let slot = state.get("slot")?.as_usize()?;
+        let address = state.get("address")?.as_u32()?;
+        let len = || state.get("len")?.as_usize();
+        let data = || state.get("data")?.as_bytes();
+        let request = match state.get("kind")?.as_str()? {
+            "load" => BusAccessRequst::new_load(path, slot, address, len()?),
+            "store" => BusAccessRequst::new_store(path, slot, address, data()?),
+            "load_reserved" => BusAccessRequst::new_load_reserved(path, slot, address, len()?),
+            "store_conditional" => {
+                BusAccessRequst::new_store_conditional(path, slot, address, data()?)
+            }
+            "amo" => {
+                let op = AmoOp::from_name(state.get("op")?.as_str()?)?;
+                BusAccessRequst::new_amo(path, slot, op, address, data()?)
+            }
+            kind => return Err(format!("Unknown bus access {}", kind)),
+        };
+        Ok(request.with_thread(state.get("thread")?.as_usize()?))
+    }
     /// Submit a result and consume the BusAccess Request then construct corresponding BusAccessResponse
     pub fn into_respose(self, result: Result<BusAccessResult, String>) -> BusAccessResponse {
         BusAccessResponse {
@@ -202,7 +536,7 @@ impl Display for BusAccessRequst {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BusAccessResponse {
     result: Result<BusAccessResult, String>,
     handler: BusAccessHandler,
@@ -220,7 +554,32 @@ impl BusAccessResponse {
     }
 }
 
-pub trait AccessPath: ExecPath {
+clone_box! {
+    /// Clone a memory access path behind a box, implemented for every cloneable AccessPath
+    CloneAccessPath for AccessPath
+}
+
+/// Memory access issued to an access path and not completed yet.
+/// Accesses of a path are ordered against those of the other paths by them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InFlightAccess {
+    pub tag: RStag,
+    /// Sequence number given on issue
+    pub seq: u64,
+    /// Name of the instruction
+    pub command: String,
+    /// Accessed addresses, None until the address is evaluated
+    pub range: Option<Range<u32>>,
+}
+
+pub trait AccessPath: ExecPath + CloneAccessPath {
     fn request(&mut self) -> Option<BusAccessRequst>;
     fn response(&mut self, slot: usize, result: Result<BusAccessResult, String>);
+    /// Set byte order used to convert between registers and memory
+    fn set_endian(&mut self, endian: Endian);
+    /// Return accesses issued to the path and not completed yet
+    fn in_flight(&self) -> Vec<InFlightAccess>;
+    /// Give accesses in flight in the other paths, which accesses of the path evaluated
+    /// afterward wait for if they are older
+    fn set_other_accesses(&mut self, accesses: Vec<InFlightAccess>);
 }
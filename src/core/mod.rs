@@ -1,6 +1,13 @@
+pub mod bus_controller;
+pub mod csr;
+mod csr_unit;
 pub mod decoder;
 pub mod execution_path;
 mod nop_unit;
+mod physical_register;
+pub mod pipeline;
 pub mod processor;
 mod register;
 pub mod result_bus;
+pub mod smt;
+pub mod statistics;
@@ -3,10 +3,11 @@ use std::fmt::Display;
 use super::decoder::InstFormat;
 use super::execution_path::{ArgState, ExecPath, RStag};
 use super::result_bus::ResultBus;
+use crate::graph::Graph;
 
 const FUNC: &str = "nop";
 const NAME: &str = "nop1";
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Unit {}
 
 impl Unit {
@@ -32,8 +33,14 @@ impl ExecPath for Unit {
     /// Issue a instruction to the execution path.
     /// On success, [Ok] with tag of issued reservation station returned.
     /// Otherwise, [Err] returned.
-    fn try_issue(&mut self, _inst: String, _vals: &[ArgState]) -> Result<RStag, ()> {
-        Ok(RStag::new(NAME, 0))
+    fn try_issue(
+        &mut self,
+        _inst: String,
+        _vals: &[ArgState],
+        _seq: u64,
+        thread: usize,
+    ) -> Result<RStag, ()> {
+        Ok(RStag::new(NAME, 0).with_thread(thread))
     }
     fn next_cycle(&mut self, _bus: &mut ResultBus) -> Result<(), String> {
         Ok(())
@@ -46,6 +53,13 @@ impl ExecPath for Unit {
     }
 }
 
+/// Nop never reaches any unit, so it's not drawn
+impl Graph for Unit {
+    fn get_graph(&self) -> String {
+        String::new()
+    }
+}
+
 impl Display for Unit {
     fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Ok(())
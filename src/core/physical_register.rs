@@ -0,0 +1,287 @@
+use super::execution_path::RStag;
+use crate::display::into_table;
+use crate::util::json::Json;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+
+/// Name of tags of physical registers, e.g. "P(3)"
+const TAG_NAME: &str = "P";
+
+/// Destination register of an in-flight instruction
+#[derive(Debug, Clone, PartialEq)]
+struct Destination {
+    register: usize,
+    /// Register the architectural register was mapped to before
+    previous: usize,
+    architectural: usize,
+}
+
+/// Physical registers shared by threads, renamed in the style of MIPS R10000.
+/// Each thread has a register alias table mapping its architectural registers to physical ones.
+/// A destination is renamed to a register taken from the free list, and the register it was
+/// mapped to is released when the instruction commits, once that one is written as well.
+#[derive(Debug, Clone)]
+pub struct PhysicalRegisterFile {
+    /// Value of each register, None until its producer commits
+    values: Vec<Option<u32>>,
+    /// Register alias table of each thread
+    tables: Vec<Vec<usize>>,
+    free: VecDeque<usize>,
+    /// Destination of each in-flight instruction, by its reservation station tag
+    destinations: HashMap<RStag, Destination>,
+    /// Registers to release once written, their architectural registers are renamed again
+    releasing: Vec<usize>,
+}
+
+impl Display for PhysicalRegisterFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let releasing: Vec<String> = self
+            .releasing
+            .iter()
+            .map(|register| PhysicalRegisterFile::tag(*register).to_string())
+            .collect();
+        let rows = vec![
+            format!("Free: {} of {}", self.free.len(), self.size()),
+            format!("Releasing: {}", releasing.join(", ")),
+        ];
+        write!(f, "{}", into_table("Physical Registers", rows))
+    }
+}
+
+impl PhysicalRegisterFile {
+    pub fn new(size: usize) -> Self {
+        Self {
+            values: vec![None; size],
+            tables: Vec::new(),
+            free: (0..size).collect(),
+            destinations: HashMap::new(),
+            releasing: Vec::new(),
+        }
+    }
+    pub fn size(&self) -> usize {
+        self.values.len()
+    }
+    /// Tag which instructions waiting for the register wait for
+    pub fn tag(register: usize) -> RStag {
+        RStag::new(TAG_NAME, register)
+    }
+    /// Map `registers` architectural registers of a new thread to free registers holding zero.
+    /// Error if no register would be left free to rename to.
+    pub fn add_thread(&mut self, registers: usize) -> Result<(), String> {
+        if self.free.len() <= registers {
+            let msg = format!(
+                "{} physical registers are too few for {} threads of {} registers",
+                self.size(),
+                self.tables.len() + 1,
+                registers
+            );
+            return Err(msg);
+        }
+        let table = self.free.drain(..registers).collect::<Vec<_>>();
+        for register in table.iter() {
+            self.values[*register] = Some(0);
+        }
+        self.tables.push(table);
+        Ok(())
+    }
+    pub fn has_free(&self) -> bool {
+        !self.free.is_empty()
+    }
+    /// Rename the architectural register of the thread to a free register, as the destination
+    /// of the instruction of `tag`. Return tag of the register carrying the thread,
+    /// None if no register is free.
+    pub fn rename(&mut self, thread: usize, register: usize, tag: RStag) -> Option<RStag> {
+        let physical = self.free.pop_front()?;
+        self.values[physical] = None;
+        let previous = std::mem::replace(&mut self.tables[thread][register], physical);
+        let destination = Destination {
+            register: physical,
+            previous,
+            architectural: register,
+        };
+        self.destinations.insert(tag, destination);
+        Some(PhysicalRegisterFile::tag(physical).with_thread(thread))
+    }
+    /// Write the result of the instruction of `tag` to its destination, and release registers
+    /// nothing will read. Return tag of the destination, None if it has none.
+    pub fn commit(&mut self, tag: &RStag, val: u32) -> Option<RStag> {
+        let destination = self.destinations.remove(tag)?;
+        self.values[destination.register] = Some(val);
+        self.releasing.push(destination.previous);
+        // A register is written by its producer only, which may commit after the one renaming again
+        let (values, free) = (&self.values, &mut self.free);
+        self.releasing.retain(|register| {
+            let written = values[*register].is_some();
+            if written {
+                free.push_back(*register);
+            }
+            !written
+        });
+        Some(PhysicalRegisterFile::tag(destination.register).with_thread(tag.thread()))
+    }
+    /// Undo renaming the destination of the instruction of `tag`, which never commits.
+    /// The register is put back to the head of the free list, so renaming is as if it never happened.
+    pub fn cancel(&mut self, tag: &RStag) {
+        if let Some(destination) = self.destinations.remove(tag) {
+            self.tables[tag.thread()][destination.architectural] = destination.previous;
+            self.free.push_front(destination.register);
+        }
+    }
+    /// Return reservation station tag of the instruction writing the register of `tag`
+    pub fn producer(&self, tag: &RStag) -> Option<&RStag> {
+        self.destinations
+            .iter()
+            .find(|(producer, destination)| {
+                let register = PhysicalRegisterFile::tag(destination.register);
+                register.with_thread(producer.thread()) == *tag
+            })
+            .map(|(producer, _)| producer)
+    }
+    pub fn save(&self) -> Json {
+        let values = self
+            .values
+            .iter()
+            .map(|val| val.map_or(Json::Null, Json::from))
+            .collect();
+        let registers = |registers: &mut dyn Iterator<Item = &usize>| {
+            Json::Array(registers.map(|r| Json::from(*r)).collect())
+        };
+        let tables = self
+            .tables
+            .iter()
+            .map(|table| registers(&mut table.iter()))
+            .collect();
+        let mut destinations: Vec<_> = self.destinations.iter().collect();
+        destinations.sort_by_key(|(tag, _)| tag.to_string());
+        let destinations = destinations
+            .into_iter()
+            .map(|(tag, d)| {
+                Json::Array(vec![
+                    tag.save(),
+                    Json::from(d.register),
+                    Json::from(d.previous),
+                    Json::from(d.architectural),
+                ])
+            })
+            .collect();
+        Json::object(vec![
+            ("values", Json::Array(values)),
+            ("tables", Json::Array(tables)),
+            ("free", registers(&mut self.free.iter())),
+            ("destinations", Json::Array(destinations)),
+            ("releasing", registers(&mut self.releasing.iter())),
+        ])
+    }
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        let values = state.get("values")?.as_array()?;
+        if values.len() != self.size() {
+            let msg = format!(
+                "Snapshot has {} physical registers, but the machine has {}",
+                values.len(),
+                self.size()
+            );
+            return Err(msg);
+        }
+        let size = self.size();
+        let register = |register: &Json| -> Result<usize, String> {
+            let register = register.as_usize()?;
+            if register >= size {
+                return Err(format!("No physical register {}", register));
+            }
+            Ok(register)
+        };
+        let registers = |registers: &Json| -> Result<Vec<usize>, String> {
+            registers.as_array()?.iter().map(register).collect()
+        };
+        self.values = values
+            .iter()
+            .map(|val| val.opt().map(Json::as_u32).transpose())
+            .collect::<Result<_, _>>()?;
+        self.tables = state
+            .get("tables")?
+            .as_array()?
+            .iter()
+            .map(registers)
+            .collect::<Result<_, _>>()?;
+        self.free = registers(state.get("free")?)?.into();
+        self.destinations = state
+            .get("destinations")?
+            .as_array()?
+            .iter()
+            .map(|entry| match entry.as_array()? {
+                [tag, physical, previous, architectural] => {
+                    let destination = Destination {
+                        register: register(physical)?,
+                        previous: register(previous)?,
+                        architectural: architectural.as_usize()?,
+                    };
+                    Ok((RStag::load(tag)?, destination))
+                }
+                _ => Err(format!(
+                    "Expect [tag, register, previous, architectural], found {}",
+                    entry
+                )),
+            })
+            .collect::<Result<_, String>>()?;
+        self.releasing = registers(state.get("releasing")?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod physical_register {
+    use super::*;
+
+    #[test]
+    fn rename_and_release() -> Result<(), String> {
+        let mut prf = PhysicalRegisterFile::new(6);
+        prf.add_thread(4)?;
+        assert!(prf.add_thread(2).is_err());
+        let (first, second) = (RStag::new("arith0", 0), RStag::new("arith1", 0));
+        // R1 is renamed twice, the younger instruction commits first
+        assert_eq!(
+            prf.rename(0, 1, first.clone()),
+            Some(PhysicalRegisterFile::tag(4))
+        );
+        assert_eq!(
+            prf.rename(0, 1, second.clone()),
+            Some(PhysicalRegisterFile::tag(5))
+        );
+        assert!(!prf.has_free());
+        assert_eq!(prf.rename(0, 2, RStag::new("arith0", 1)), None);
+        assert_eq!(prf.producer(&PhysicalRegisterFile::tag(4)), Some(&first));
+
+        assert_eq!(prf.commit(&second, 7), Some(PhysicalRegisterFile::tag(5)));
+        // P(4) is still to be written by the older instruction
+        assert!(!prf.has_free());
+        assert_eq!(prf.commit(&first, 3), Some(PhysicalRegisterFile::tag(4)));
+        assert_eq!(prf.free, [4, 1]);
+        assert_eq!(prf.commit(&first, 3), None);
+
+        // Tags of registers carry the thread renaming them
+        prf.add_thread(1)?;
+        let third = RStag::new("arith0", 2).with_thread(1);
+        let renamed = prf.rename(1, 0, third.clone());
+        assert_eq!(renamed, Some(PhysicalRegisterFile::tag(1).with_thread(1)));
+        assert_eq!(prf.producer(&PhysicalRegisterFile::tag(1)), None);
+        assert_eq!(
+            prf.producer(&PhysicalRegisterFile::tag(1).with_thread(1)),
+            Some(&third)
+        );
+        assert_eq!(prf.commit(&third, 1), renamed);
+
+        // Cancelled renaming leaves the free list and the table as they were
+        let (free, tables) = (prf.free.clone(), prf.tables.clone());
+        let fourth = RStag::new("arith0", 3);
+        assert!(prf.rename(0, 2, fourth.clone()).is_some());
+        prf.cancel(&fourth);
+        assert_eq!((prf.free.clone(), prf.tables.clone()), (free, tables));
+
+        let mut restored = PhysicalRegisterFile::new(6);
+        restored.restore(&prf.save())?;
+        assert_eq!(restored.save(), prf.save());
+        assert!(PhysicalRegisterFile::new(8).restore(&prf.save()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+use super::execution_path::RStag;
+use std::collections::HashMap;
+
+/// Phase of an instruction in a reservation station
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Waiting for operands
+    Waiting,
+    /// Operands are ready, waiting for the execution unit
+    Ready,
+    Executing,
+    /// Executed, the result is waiting for the result bus
+    Done,
+}
+
+/// Lifecycle event of an instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// First fetched, the instruction is fetched again every cycle its issue stalls
+    Fetch,
+    /// First decoded, the instruction is decoded again every cycle its issue stalls
+    Decode,
+    /// Issued into a reservation station slot
+    Issue,
+    /// Operands ready
+    Ready,
+    /// Execution started
+    Execute,
+    /// Execution finished, the result waits for the result bus
+    ExecuteEnd,
+    /// Result on the result bus
+    Writeback,
+    Commit,
+}
+
+impl Stage {
+    /// Name of the stage in Kanata log
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Fetch => "F",
+            Stage::Decode => "Dc",
+            Stage::Issue => "Is",
+            Stage::Ready => "Rd",
+            Stage::Execute => "X",
+            Stage::ExecuteEnd => "Xe",
+            Stage::Writeback => "Wb",
+            Stage::Commit => "Cm",
+        }
+    }
+    /// Letter of the stage in Gantt table
+    fn letter(&self) -> char {
+        match self {
+            Stage::Fetch => 'F',
+            Stage::Decode => 'D',
+            Stage::Issue => 'I',
+            Stage::Ready => 'R',
+            Stage::Execute => 'X',
+            Stage::ExecuteEnd => 'E',
+            Stage::Writeback => 'W',
+            Stage::Commit => 'C',
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    thread: usize,
+    text: String,
+    /// Slot the instruction issued to, None if it has no slot
+    tag: Option<RStag>,
+    /// (stage, cycle) in order of stages
+    events: Vec<(Stage, u64)>,
+}
+
+impl Record {
+    fn has(&self, stage: Stage) -> bool {
+        self.events.iter().any(|(s, _)| *s == stage)
+    }
+    fn push(&mut self, stage: Stage, cycle: u64) {
+        if !self.has(stage) {
+            self.events.push((stage, cycle));
+        }
+    }
+    fn end(&self) -> u64 {
+        self.events.last().map_or(0, |(_, cycle)| *cycle)
+    }
+}
+
+/// Record lifecycle of every instruction, for pipeline diagrams
+#[derive(Debug, Clone, Default)]
+pub struct PipelineLog {
+    records: Vec<Record>,
+    /// Index of records of in-flight instructions
+    in_flight: HashMap<RStag, usize>,
+    /// (PC, cycle) of the instruction each thread first fetched and has not issued yet
+    fetched: HashMap<usize, (usize, u64)>,
+    /// Cycle which each thread first decoded the instruction it fetched
+    decoded: HashMap<usize, u64>,
+}
+
+impl PipelineLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Instruction at `pc` fetched by the thread in the cycle.
+    /// Fetching the same instruction again while it stalls keeps the first cycle.
+    pub fn fetch(&mut self, cycle: u64, thread: usize, pc: usize) {
+        match self.fetched.get(&thread) {
+            Some((fetched, _)) if *fetched == pc => (),
+            _ => {
+                self.fetched.insert(thread, (pc, cycle));
+                self.decoded.remove(&thread);
+            }
+        }
+    }
+    /// Instruction fetched by the thread decoded in the cycle.
+    /// Decoding the same instruction again while it stalls keeps the first cycle.
+    pub fn decode(&mut self, cycle: u64, thread: usize) {
+        self.decoded.entry(thread).or_insert(cycle);
+    }
+    /// Instruction fetched by the thread issued in the cycle.
+    /// Instructions without slot are committed on issue.
+    pub fn issue(&mut self, cycle: u64, thread: usize, text: &str, tag: Option<RStag>) {
+        let fetched = self.fetched.remove(&thread).map_or(cycle, |(_, at)| at);
+        let decoded = self.decoded.remove(&thread).unwrap_or(cycle);
+        let events = vec![
+            (Stage::Fetch, fetched),
+            (Stage::Decode, decoded),
+            (Stage::Issue, cycle),
+        ];
+        let mut record = Record {
+            thread,
+            text: text.trim().to_string(),
+            tag: tag.clone(),
+            events,
+        };
+        match tag {
+            Some(tag) => {
+                self.in_flight.insert(tag, self.records.len());
+            }
+            None => record.push(Stage::Commit, cycle),
+        }
+        self.records.push(record);
+    }
+    /// Update phases of in-flight instructions in the cycle
+    pub fn observe(&mut self, cycle: u64, phases: &[(RStag, Phase)]) {
+        for (tag, phase) in phases {
+            let Some(idx) = self.in_flight.get(tag) else {
+                continue;
+            };
+            let record = &mut self.records[*idx];
+            if *phase != Phase::Waiting {
+                record.push(Stage::Ready, cycle);
+            }
+            if *phase == Phase::Executing || *phase == Phase::Done {
+                record.push(Stage::Execute, cycle);
+            }
+            if *phase == Phase::Done {
+                record.push(Stage::ExecuteEnd, cycle);
+            }
+        }
+    }
+    /// Result of the instruction on the result bus in the cycle.
+    /// Execution ends in the same cycle if it isn't seen done before.
+    pub fn writeback(&mut self, cycle: u64, tag: &RStag) {
+        if let Some(idx) = self.in_flight.get(tag) {
+            let record = &mut self.records[*idx];
+            record.push(Stage::Ready, cycle);
+            record.push(Stage::Execute, cycle);
+            record.push(Stage::ExecuteEnd, cycle);
+            record.push(Stage::Writeback, cycle);
+        }
+    }
+    pub fn commit(&mut self, cycle: u64, tag: &RStag) {
+        if let Some(idx) = self.in_flight.remove(tag) {
+            self.records[idx].push(Stage::Commit, cycle);
+        }
+    }
+    /// Return (instruction, issue cycle, complete cycle) of each instruction in order of issue.
+    /// An instruction completes when its result is written back, or on commit if it has no result.
+    pub fn timing(&self) -> Vec<(&str, u64, Option<u64>)> {
+        self.records
+            .iter()
+            .map(|record| {
+                let cycle_of = |stage| {
+                    let event = record.events.iter().find(|(s, _)| *s == stage);
+                    event.map(|(_, cycle)| *cycle)
+                };
+                let issue = cycle_of(Stage::Issue).unwrap_or_default();
+                let complete = cycle_of(Stage::Writeback).or_else(|| cycle_of(Stage::Commit));
+                (record.text.as_str(), issue, complete)
+            })
+            .collect()
+    }
+    /// Export in Kanata log format, which is read by the Konata pipeline viewer
+    pub fn to_kanata(&self) -> String {
+        // (cycle, instruction id, command) of all commands
+        let mut commands: Vec<(u64, usize, String)> = Vec::new();
+        // Retire ids are numbered in commit order
+        let mut commits: Vec<(u64, usize)> = self
+            .records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.has(Stage::Commit))
+            .map(|(id, r)| (r.end(), id))
+            .collect();
+        commits.sort();
+        let retire_id: HashMap<usize, usize> = commits
+            .iter()
+            .enumerate()
+            .map(|(retire, (_, id))| (*id, retire))
+            .collect();
+        for (id, record) in self.records.iter().enumerate() {
+            let start = record.events[0].1;
+            commands.push((start, id, format!("I\t{}\t{}\t{}", id, id, record.thread)));
+            let label = match record.tag.as_ref() {
+                Some(tag) => format!("{} ({})", record.text, tag),
+                None => record.text.clone(),
+            };
+            commands.push((start, id, format!("L\t{}\t0\t{}", id, label)));
+            for (idx, (stage, cycle)) in record.events.iter().enumerate() {
+                if idx > 0 {
+                    let (previous, _) = record.events[idx - 1];
+                    commands.push((*cycle, id, format!("E\t{}\t0\t{}", id, previous.name())));
+                }
+                if *stage == Stage::Commit {
+                    let retire = format!("R\t{}\t{}\t0", id, retire_id[&id]);
+                    commands.push((*cycle, id, retire));
+                } else {
+                    commands.push((*cycle, id, format!("S\t{}\t0\t{}", id, stage.name())));
+                }
+            }
+        }
+        // Stable sort keeps commands of an instruction in order
+        commands.sort_by_key(|(cycle, _, _)| *cycle);
+
+        let mut log = String::from("Kanata\t0004\n");
+        let mut current = None;
+        for (cycle, _, command) in commands {
+            match current {
+                None => log.push_str(&format!("C=\t{}\n", cycle)),
+                Some(last) if cycle > last => log.push_str(&format!("C\t{}\n", cycle - last)),
+                _ => (),
+            }
+            current = Some(cycle);
+            log.push_str(&command);
+            log.push('\n');
+        }
+        log
+    }
+    /// Export as a table of instruction rows and cycle columns, under a header of cycle numbers.
+    /// Each cell is the letter of the latest stage the instruction reached.
+    pub fn to_gantt(&self) -> String {
+        let end = self.records.iter().map(|r| r.end()).max().unwrap_or(0);
+        let width = self.records.iter().map(|r| r.text.len()).max().unwrap_or(0);
+        let mut table = String::new();
+        // Cycle numbers are written vertically, one column per cycle
+        let digits = end.to_string().len() as u32;
+        for place in (0..digits).rev().map(|d| 10u64.pow(d)) {
+            let cycles: String = (0..=end)
+                .map(|c| match c {
+                    c if c < place && place > 1 => ' ',
+                    c => char::from(b'0' + (c / place % 10) as u8),
+                })
+                .collect();
+            table.push_str(&format!("{:>4} {:width$} |{}\n", "", "", cycles));
+        }
+        for (id, record) in self.records.iter().enumerate() {
+            let cells: String = (0..=end)
+                .map(|cycle| {
+                    let stage = record.events.iter().rev().find(|(_, c)| *c <= cycle);
+                    match stage {
+                        Some((stage, _)) if cycle <= record.end() => stage.letter(),
+                        _ => '.',
+                    }
+                })
+                .collect();
+            table.push_str(&format!("{:>4} {:width$} |{}\n", id, record.text, cells));
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod pipeline {
+    use super::*;
+
+    fn log() -> PipelineLog {
+        let mut log = PipelineLog::new();
+        let tag = RStag::new("arith0", 0);
+        log.fetch(0, 0, 0);
+        log.decode(0, 0);
+        log.issue(0, 0, "addi R1, R0, #1", Some(tag.clone()));
+        // Issue of the nop stalls for a cycle
+        log.fetch(1, 0, 1);
+        log.decode(1, 0);
+        log.fetch(2, 0, 1);
+        log.decode(2, 0);
+        log.issue(2, 0, "nop", None);
+        log.observe(1, &[(tag.clone(), Phase::Executing)]);
+        // The result waits a cycle for the result bus
+        log.observe(2, &[(tag.clone(), Phase::Done)]);
+        log.writeback(3, &tag);
+        log.commit(4, &tag);
+        log
+    }
+    #[test]
+    fn lifecycle() {
+        let log = log();
+        let stages: Vec<(Stage, u64)> = log.records[0].events.clone();
+        assert_eq!(
+            stages,
+            [
+                (Stage::Fetch, 0),
+                (Stage::Decode, 0),
+                (Stage::Issue, 0),
+                (Stage::Ready, 1),
+                (Stage::Execute, 1),
+                (Stage::ExecuteEnd, 2),
+                (Stage::Writeback, 3),
+                (Stage::Commit, 4),
+            ]
+        );
+        // Decoding again while the issue stalls keeps the first cycle
+        assert_eq!(
+            log.records[1].events[..3],
+            [(Stage::Fetch, 1), (Stage::Decode, 1), (Stage::Issue, 2)]
+        );
+        assert!(log.in_flight.is_empty());
+        assert!(log.fetched.is_empty());
+        assert!(log.decoded.is_empty());
+    }
+    #[test]
+    fn execute_ends_on_writeback() {
+        let mut log = PipelineLog::new();
+        let tag = RStag::new("arith0", 0);
+        log.issue(0, 0, "addi R1, R0, #1", Some(tag.clone()));
+        log.observe(1, &[(tag.clone(), Phase::Executing)]);
+        // Never seen done, the result is written back as soon as execution ends
+        log.writeback(2, &tag);
+        assert_eq!(
+            log.records[0].events[4..],
+            [
+                (Stage::Execute, 1),
+                (Stage::ExecuteEnd, 2),
+                (Stage::Writeback, 2)
+            ]
+        );
+    }
+    #[test]
+    fn kanata() {
+        let kanata = log().to_kanata();
+        let lines: Vec<&str> = kanata.lines().collect();
+        assert_eq!(lines[0], "Kanata\t0004");
+        assert_eq!(lines[1], "C=\t0");
+        assert_eq!(lines[2], "I\t0\t0\t0");
+        assert_eq!(lines[3], "L\t0\t0\taddi R1, R0, #1 (arith0(0))");
+        assert!(kanata.contains("C\t1\n"));
+        assert!(kanata.contains("I\t1\t1\t0\nL\t1\t0\tnop\n"));
+        assert!(kanata.contains("E\t1\t0\tF\nS\t1\t0\tDc\n"));
+        assert!(kanata.contains("E\t0\t0\tX\nS\t0\t0\tXe\n"));
+        assert!(kanata.ends_with("E\t0\t0\tWb\nR\t0\t1\t0\n"));
+    }
+    #[test]
+    fn timing() {
+        let log = log();
+        assert_eq!(
+            log.timing(),
+            [("addi R1, R0, #1", 0, Some(3)), ("nop", 2, Some(2))]
+        );
+    }
+    #[test]
+    fn gantt() {
+        let gantt = log().to_gantt();
+        let lines: Vec<&str> = gantt.lines().collect();
+        assert_eq!(lines[0], "                     |01234");
+        assert_eq!(lines[1], "   0 addi R1, R0, #1 |IXEWC");
+        assert_eq!(lines[2], "   1 nop             |.DC..");
+    }
+    #[test]
+    fn gantt_header() {
+        let mut log = PipelineLog::new();
+        log.issue(12, 0, "nop", None);
+        let gantt = log.to_gantt();
+        let lines: Vec<&str> = gantt.lines().collect();
+        assert_eq!(lines[0], "         |          111");
+        assert_eq!(lines[1], "         |0123456789012");
+        assert_eq!(lines[2], "   0 nop |............C");
+    }
+}
@@ -1,97 +1,217 @@
+use super::bus_controller::{ArbitrationPolicy, BusController, PathStatistics};
+use super::csr::{CsrFile, Exception};
+use super::csr_unit;
 use super::decoder::{ArgType, DecodedInst, Decoder};
 use super::execution_path::{
-    AccessPath, ArgState, BusAccessRequst, BusAccessResponse, ExecPath, RStag,
+    AccessPath, ArgState, BusAccessRequst, BusAccessResponse, ExecPath, InFlightAccess, RStag,
 };
 use super::nop_unit;
-use super::register::RegisterFile;
+use super::physical_register::PhysicalRegisterFile;
+use super::pipeline::PipelineLog;
 use super::result_bus::ResultBus;
+use super::smt::{Candidate, FetchPolicy, RoundRobin, Thread, Translating};
+use super::statistics::{StallCause, Statistics};
 use crate::display::into_table;
-use std::collections::{HashMap, LinkedList};
-use std::fmt::{self, Display};
+use crate::functional_units::select_policy::{FirstSlot, SelectPolicy};
+use crate::graph::{self, dot_id, DependencyNode, Graph};
+use crate::util::json::Json;
+use crate::util::Endian;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
 enum IssueResult {
     Issued(RStag),
-    Stall,
+    Stall(StallCause),
 }
 
-#[derive(Debug)]
-struct BusController {
-    access_queue: LinkedList<BusAccessRequst>,
-}
-
-impl Display for BusController {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let queue: Vec<String> = self
-            .access_queue
-            .iter()
-            .map(|req| format!("{}", req))
-            .collect();
-        write!(f, "{}", into_table("Bus Access Queue", queue))
-    }
-}
-
-impl BusController {
-    fn new() -> Self {
-        Self {
-            access_queue: LinkedList::new(),
-        }
-    }
-    fn push(&mut self, request: BusAccessRequst) {
-        self.access_queue.push_back(request);
-    }
-}
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Processor {
-    pc: usize,
+    /// Hardware threads, which share reservation stations, execution paths and the result bus
+    threads: Vec<Thread>,
+    fetch_policy: Box<dyn FetchPolicy>,
+    /// Policy selecting ready instructions, given to every path
+    select_policy: Box<dyn SelectPolicy>,
     decoder: Decoder,
-    arithmetic_paths: HashMap<String, Box<dyn ExecPath>>,
-    access_paths: HashMap<String, Box<dyn AccessPath>>,
+    /// Paths are ordered by name, so they compete for the result bus in the same order across machines
+    arithmetic_paths: BTreeMap<String, Box<dyn ExecPath>>,
+    access_paths: BTreeMap<String, Box<dyn AccessPath>>,
     bus_controller: BusController,
-    register_file: RegisterFile,
     result_bus: ResultBus,
+    /// Count of issued instructions
+    issued: u64,
+    /// Issue order and address of in-flight instructions.
+    /// Results are committed only to the register file of the thread their tag carries.
+    issue_order: HashMap<RStag, (u64, usize)>,
+    /// Count of cycles which any thread had instruction to issue
+    issue_cycles: u64,
+    /// Interrupts are taken by thread 0
+    csr: CsrFile,
+    /// Accesses are translated by a MMU, which may raise page faults.
+    /// A thread issues nothing after an access until it's translated, so page faults are precise.
+    translated_accesses: bool,
+    /// Exception raised, taken once in-flight instructions complete
+    exception: Option<Exception>,
+    statistics: Statistics,
+    /// Lifecycle of instructions, recorded only if enabled
+    pipeline: Option<PipelineLog>,
+    /// Physical registers destinations are renamed to, None if registers are renamed to
+    /// reservation stations only
+    physical: Option<PhysicalRegisterFile>,
+    /// Registers (thread, register number) written by the commit of current cycle
+    written: Vec<(usize, usize)>,
+    /// Instructions (thread, address) committed in current cycle
+    committed: Vec<(usize, usize)>,
 }
 
 impl fmt::Display for Processor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut registers = vec![format!("PC: {}", self.pc)];
-        self.register_file
-            .into_iter()
-            .enumerate()
-            .for_each(|(idx, reg)| {
-                registers.push(format!("R{idx}: {reg}"));
-            });
+        for pane in self.panes() {
+            writeln!(f, "{}", pane)?;
+        }
+        Ok(())
+    }
+}
+
+impl Processor {
+    /// Return tables of registers, execution paths and buses, in a stable order
+    pub fn panes(&self) -> Vec<String> {
+        let mut panes = Vec::new();
         let last_instruction = self.decoder.last_instruction().to_string();
-        writeln!(f, "{}", into_table("Instruction", vec![last_instruction]))?;
-        writeln!(f, "{}", into_table("Registers", registers))?;
-        for (_, p) in self.arithmetic_paths.iter() {
-            writeln!(f, "{}", p)?;
+        panes.push(into_table("Instruction", vec![last_instruction]));
+        for (id, thread) in self.threads.iter().enumerate() {
+            let mut registers = vec![format!("PC: {}", thread.pc)];
+            thread
+                .register_file
+                .into_iter()
+                .enumerate()
+                .for_each(|(idx, reg)| {
+                    registers.push(format!("R{idx}: {reg}"));
+                });
+            let title = if self.threads.len() == 1 {
+                String::from("Registers")
+            } else {
+                format!("Thread {} Registers", id)
+            };
+            panes.push(into_table(&title, registers));
+        }
+        if self.threads.len() > 1 {
+            let issue = vec![format!(
+                "{}, {}",
+                self.fetch_policy.name(),
+                self.issue_statistics()
+            )];
+            panes.push(into_table("Issue", issue));
+        }
+        let mut paths: Vec<(&String, String)> = self
+            .arithmetic_paths
+            .iter()
+            .map(|(name, p)| (name, p.to_string()))
+            .chain(
+                self.access_paths
+                    .iter()
+                    .map(|(name, p)| (name, p.to_string())),
+            )
+            .collect();
+        paths.sort_by_key(|(name, _)| *name);
+        panes.extend(paths.into_iter().map(|(_, pane)| pane));
+        if let Some(physical) = self.physical.as_ref() {
+            panes.push(physical.to_string());
         }
-        for (_, p) in self.access_paths.iter() {
-            writeln!(f, "{}", p)?;
+        panes.push(self.csr.to_string());
+        panes.push(self.bus_controller.to_string());
+        panes.push(self.result_bus.to_string());
+        panes
+    }
+}
+
+/// Datapath topology: issue, execution paths, the result bus and the memory bus
+impl Graph for Processor {
+    fn get_graph(&self) -> String {
+        let mut dot = String::from("digraph processor {\n    rankdir=LR;\n");
+        dot.push_str("    \"issue\" [shape=box, label=\"Fetch / Decode / Issue\"];\n");
+        dot.push_str("    \"registers\" [shape=box3d, label=\"Register file\"];\n");
+        dot.push_str("    \"result_bus\" [shape=box, label=\"Result bus\"];\n");
+        let policy = format!("Bus controller ({})", self.bus_controller.policy_name());
+        dot.push_str(&format!(
+            "    \"bus_controller\" [shape=box, label={}];\n",
+            dot_id(&policy)
+        ));
+        dot.push_str("    \"memory\" [shape=cylinder, label=\"Memory\"];\n");
+        dot.push_str("    \"registers\" -> \"issue\";\n");
+        dot.push_str("    \"result_bus\" -> \"registers\";\n");
+
+        let mut paths: Vec<(&String, &dyn ExecPath)> = self
+            .arithmetic_paths
+            .iter()
+            .map(|(name, p)| (name, &**p))
+            .chain(
+                self.access_paths
+                    .iter()
+                    .map(|(name, p)| (name, &**p as &dyn ExecPath)),
+            )
+            .collect();
+        paths.sort_by_key(|(name, _)| *name);
+        for (name, path) in paths {
+            let graph = path.get_graph();
+            if graph.is_empty() {
+                continue;
+            }
+            dot.push_str(&graph);
+            let entry = dot_id(&format!("{}.in", name));
+            let exit = dot_id(&format!("{}.out", name));
+            dot.push_str(&format!("    \"issue\" -> {};\n", entry));
+            dot.push_str(&format!("    {} -> \"result_bus\";\n", exit));
+            // Results are forwarded to waiting reservation stations
+            dot.push_str(&format!(
+                "    \"result_bus\" -> {} [style=dashed];\n",
+                entry
+            ));
+            if self.access_paths.contains_key(name) {
+                dot.push_str(&format!("    {} -> \"bus_controller\";\n", exit));
+                dot.push_str(&format!("    \"memory\" -> {};\n", exit));
+            }
         }
-        writeln!(f, "{}", self.bus_controller)?;
-        writeln!(f, "{}", self.result_bus)
+        dot.push_str("    \"bus_controller\" -> \"memory\";\n");
+        dot.push_str("}\n");
+        dot
     }
 }
 
 impl Processor {
     pub fn new() -> Self {
         let mut ret = Self {
-            pc: 0,
+            threads: vec![Thread::new()],
+            fetch_policy: Box::new(RoundRobin::default()),
+            select_policy: Box::new(FirstSlot),
             decoder: Decoder::new(),
-            arithmetic_paths: HashMap::new(),
-            access_paths: HashMap::new(),
+            arithmetic_paths: BTreeMap::new(),
+            access_paths: BTreeMap::new(),
             bus_controller: BusController::new(),
-            register_file: RegisterFile::new(),
             result_bus: ResultBus::new(),
+            issued: 0,
+            issue_order: HashMap::new(),
+            issue_cycles: 0,
+            csr: CsrFile::new(),
+            translated_accesses: false,
+            exception: None,
+            statistics: Statistics::new(),
+            pipeline: None,
+            physical: None,
+            written: Vec::new(),
+            committed: Vec::new(),
         };
         let nop_unit = Box::new(nop_unit::Unit::new());
         ret.add_path(nop_unit)
             .expect("Unable to add nop instruction path");
+        let csr_unit = Box::new(csr_unit::Unit::new());
+        ret.add_path(csr_unit)
+            .expect("Unable to add CSR instruction path");
         ret
     }
     /// Add an execution path to the processor.
-    pub fn add_path(&mut self, func: Box<dyn ExecPath>) -> Result<(), String> {
+    pub fn add_path(&mut self, mut func: Box<dyn ExecPath>) -> Result<(), String> {
+        func.set_select_policy(self.select_policy.clone());
         let insts = func.list_insts();
         let name = func.name();
 
@@ -102,7 +222,8 @@ impl Processor {
             self.decoder.register(insts, name)
         }
     }
-    pub fn add_mem_path(&mut self, func: Box<dyn AccessPath>) -> Result<(), String> {
+    pub fn add_mem_path(&mut self, mut func: Box<dyn AccessPath>) -> Result<(), String> {
+        func.set_select_policy(self.select_policy.clone());
         let insts = func.list_insts();
         let name = func.name();
 
@@ -113,35 +234,221 @@ impl Processor {
             self.decoder.register(insts, name)
         }
     }
+    /// Add a hardware thread, which starts fetching from address 0.
+    /// Return id of the thread.
+    pub fn add_thread(&mut self) -> usize {
+        if let Some(physical) = self.physical.as_mut() {
+            physical
+                .add_thread(self.threads[0].register_file.size())
+                .expect("Too few physical registers for the thread");
+        }
+        self.threads.push(Thread::new());
+        self.threads.len() - 1
+    }
+    /// Rename destinations to `count` physical registers shared by threads, which are released
+    /// on commit, instead of reservation stations.
+    /// Error if they are too few for registers of all threads.
+    pub fn use_physical_registers(&mut self, count: usize) -> Result<(), String> {
+        let mut physical = PhysicalRegisterFile::new(count);
+        for thread in self.threads.iter() {
+            physical.add_thread(thread.register_file.size())?;
+        }
+        self.physical = Some(physical);
+        Ok(())
+    }
+    /// Replace the policy deciding which thread fetches
+    pub fn set_fetch_policy(&mut self, policy: Box<dyn FetchPolicy>) {
+        self.fetch_policy = policy;
+    }
+    /// Replace the policy selecting ready instructions of reservation stations in every path
+    pub fn set_select_policy(&mut self, policy: Box<dyn SelectPolicy>) {
+        for path in self.arithmetic_paths.values_mut() {
+            path.set_select_policy(policy.clone());
+        }
+        for path in self.access_paths.values_mut() {
+            path.set_select_policy(policy.clone());
+        }
+        self.select_policy = policy;
+    }
+    /// Set address of the interrupt handler
+    pub fn set_interrupt_vector(&mut self, vector: usize) {
+        self.csr.vector = vector;
+    }
+    /// Translate addresses of accesses, which may raise page faults
+    pub fn translate_accesses(&mut self) {
+        self.translated_accesses = true;
+    }
+    /// The access of `tag` is translated, so its thread issues again
+    pub fn translated(&mut self, tag: &RStag) {
+        if let Some(thread) = self.threads.get_mut(tag.thread()) {
+            if thread.translating.as_ref().is_some_and(|t| t.tag == *tag) {
+                thread.translating = None;
+            }
+        }
+    }
+    /// Return wheither a page fault traps to the handler, see [CsrFile::traps_exceptions]
+    pub fn traps_exceptions(&self) -> bool {
+        self.csr.traps_exceptions() && self.exception.is_none()
+    }
+    /// The access of `tag` raised a page fault at `address`.
+    /// The access is dropped and its destination register restored,
+    /// then the fault is taken at the access once older instructions complete.
+    pub fn page_fault(&mut self, tag: &RStag, cause: u32, address: u32) -> Result<(), String> {
+        let thread = tag.thread();
+        let translating = self
+            .threads
+            .get_mut(thread)
+            .and_then(|t| t.translating.take())
+            .filter(|translating| translating.tag == *tag)
+            .ok_or_else(|| format!("Page fault of {}, which is not being translated", tag))?;
+        let (_, pc) = self
+            .issue_order
+            .remove(tag)
+            .ok_or_else(|| format!("Page fault of {}, which is not in flight", tag))?;
+        // The thread issued nothing after the access, so no instruction waits for its result.
+        // Forwarding frees its slot, and releases accesses of other threads ordered after it.
+        self.forward(tag, 0);
+        if let Some(physical) = self.physical.as_mut() {
+            physical.cancel(tag);
+        }
+        let t = &mut self.threads[thread];
+        t.in_flight -= 1;
+        if let Some((idx, state)) = translating.destination {
+            t.register_file.set(idx, state);
+        }
+        self.exception = Some(Exception {
+            thread,
+            pc,
+            cause,
+            value: address,
+        });
+        Ok(())
+    }
+    /// Update interrupt lines requested by devices
+    pub fn set_interrupt_pending(&mut self, lines: u32) {
+        self.csr.pending = lines;
+    }
+    /// Return (count of interrupts taken, cycles spent draining in-flight instructions)
+    pub fn interrupt_statistics(&self) -> (usize, usize) {
+        (self.csr.taken, self.csr.drain_cycles)
+    }
     /// Return fetching address.
     pub fn fetch_address(&self) -> usize {
-        self.pc
+        self.fetch_address_of(0)
+    }
+    /// Return fetching address of given thread.
+    pub fn fetch_address_of(&self, thread: usize) -> usize {
+        self.threads[thread].pc
+    }
+    /// Return (count of issued instructions, cycles which any thread had instruction to issue)
+    pub fn issue_counts(&self) -> (u64, u64) {
+        (self.issued, self.issue_cycles)
+    }
+    /// Return count of issued instructions of each thread
+    pub fn thread_issued(&self) -> Vec<u64> {
+        self.threads.iter().map(|thread| thread.issued).collect()
+    }
+    pub fn issue_statistics(&self) -> String {
+        let threads: Vec<String> = self
+            .thread_issued()
+            .iter()
+            .enumerate()
+            .map(|(id, issued)| format!("T{}: {}", id, issued))
+            .collect();
+        let (issued, cycles) = self.issue_counts();
+        let utilization = if cycles == 0 {
+            0.0
+        } else {
+            issued as f64 / cycles as f64
+        };
+        format!(
+            "issued {} in {} cycles ({:.2}), {}",
+            issued,
+            cycles,
+            utilization,
+            threads.join(", ")
+        )
     }
     /// Commit result and forward to reservation stations.
     /// If result bus is holding data to commit, then return `True`.
     /// Otherwise, return `False`.
     fn commit(&mut self) -> bool {
         let result = self.result_bus.take();
-        let forward = |(tag, val): (RStag, u32)| -> Option<(RStag, u32)> {
-            for (_, station) in self.arithmetic_paths.iter_mut() {
-                station.forward(tag.clone(), val);
-            }
-            for (_, station) in self.access_paths.iter_mut() {
-                station.forward(tag.clone(), val);
-            }
-            Some((tag, val))
-        };
         result
             .map(|(tag, result)| (tag, result.val()))
-            .and_then(forward)
             .map(|(tag, val)| {
-                self.register_file.write(tag, val);
+                let issued = self.issue_order.remove(&tag);
+                self.forward(&tag, val);
+                // Consumers of a physical register wait for its tag rather than the station's
+                let renamed = self
+                    .physical
+                    .as_mut()
+                    .and_then(|physical| physical.commit(&tag, val));
+                if let Some(renamed) = renamed.as_ref() {
+                    self.forward(renamed, val);
+                }
+                if let Some(pipeline) = self.pipeline.as_mut() {
+                    pipeline.commit(self.statistics.cycles, &tag);
+                }
+                if let Some((_, address)) = issued {
+                    let id = tag.thread();
+                    self.retire();
+                    self.committed.push((id, address));
+                    let thread = &mut self.threads[id];
+                    thread.in_flight -= 1;
+                    let tag = renamed.unwrap_or(tag);
+                    // State restored if the access being translated faults, which it renamed over
+                    if let Some(Translating {
+                        destination: Some((_, previous)),
+                        ..
+                    }) = thread.translating.as_mut()
+                    {
+                        previous.forwarding(&tag, val);
+                    }
+                    let written = thread.register_file.write(tag, val);
+                    self.written
+                        .extend(written.into_iter().map(|idx| (id, idx)));
+                }
             })
             .is_some()
     }
+    /// Give each access path the accesses in flight in the others, so accesses are ordered across paths
+    fn share_accesses(&mut self) {
+        if self.access_paths.len() < 2 {
+            return;
+        }
+        let in_flight: Vec<(String, Vec<InFlightAccess>)> = self
+            .access_paths
+            .iter()
+            .map(|(name, path)| (name.clone(), path.in_flight()))
+            .collect();
+        for (name, path) in self.access_paths.iter_mut() {
+            let others = in_flight
+                .iter()
+                .filter(|(other, _)| other != name)
+                .flat_map(|(_, accesses)| accesses.iter().cloned())
+                .collect();
+            path.set_other_accesses(others);
+        }
+    }
+    /// Forward result to reservation stations of all paths
+    fn forward(&mut self, tag: &RStag, val: u32) {
+        for (_, station) in self.arithmetic_paths.iter_mut() {
+            station.forward(tag.clone(), val);
+        }
+        for (_, station) in self.access_paths.iter_mut() {
+            station.forward(tag.clone(), val);
+        }
+    }
     /// If issuable reservation found, the instruction issued and [IssueResult::Issued].
-    /// Otherwise [IssueResult::Stall] returned.
-    fn try_issue(&mut self, inst: &DecodedInst, renamed_args: &[ArgState]) -> IssueResult {
+    /// Otherwise [IssueResult::Stall] with the cause returned.
+    fn try_issue(
+        &mut self,
+        thread: usize,
+        inst: &DecodedInst,
+        renamed_args: &[ArgState],
+    ) -> IssueResult {
+        let seq = self.issued;
         let name_of_stations = inst.stations();
         // Order stations by pending instruction count.
         // Therefore, instructions can be execute more parallelly.
@@ -161,33 +468,50 @@ impl Processor {
             })
             .collect::<Vec<(&String, usize)>>();
         stations.sort_by_key(|(_, p)| *p);
+        let mut cause = None;
 
         for (name, _) in stations.iter() {
             let station = self.arithmetic_paths.get_mut(*name);
             if let Some(station) = station {
-                let slot_tag = station.try_issue(inst.name(), renamed_args);
+                let slot_tag = station.try_issue(inst.name(), renamed_args, seq, thread);
                 if let Ok(tag) = slot_tag {
                     return IssueResult::Issued(tag);
                 }
+                cause.get_or_insert(station.issue_stall());
             }
             let station = self.access_paths.get_mut(*name);
             if let Some(station) = station {
-                let slot_tag = station.try_issue(inst.name(), renamed_args);
+                let slot_tag = station.try_issue(inst.name(), renamed_args, seq, thread);
                 if let Ok(tag) = slot_tag {
                     return IssueResult::Issued(tag);
                 }
+                cause.get_or_insert(station.issue_stall());
             }
         }
-        // Issuable reservation not found
-        IssueResult::Stall
+        // Issuable reservation not found, report why the first station refused
+        IssueResult::Stall(cause.unwrap_or(StallCause::StationFull))
     }
-    /// If instruction writeback, Rename destination register to tag of reservation station slot which holds the instruction.
+    /// If instruction writeback, Rename destination register to tag of reservation station slot which holds the instruction,
+    /// or to a free physical register if they are used.
     /// Otherwise, do nothing.
-    fn register_renaming(&mut self, tag: RStag, inst: DecodedInst) -> Result<(), String> {
+    fn register_renaming(
+        &mut self,
+        thread: usize,
+        tag: RStag,
+        inst: DecodedInst,
+    ) -> Result<(), String> {
         let mut ret = Ok(());
         if let Some(dest) = inst.writeback() {
             match dest {
-                ArgType::Reg(idx) => self.register_file.rename(idx, tag),
+                ArgType::Reg(idx) => {
+                    let tag = match self.physical.as_mut() {
+                        Some(physical) => physical
+                            .rename(thread, idx, tag)
+                            .expect("Issue stalls until a physical register is free"),
+                        None => tag,
+                    };
+                    self.threads[thread].register_file.rename(idx, tag)
+                }
                 _ => {
                     let msg = format!("{:?} is not a valid write back destination", dest);
                     ret = Err(msg);
@@ -198,41 +522,576 @@ impl Processor {
     }
     /// Return Err(`Error Message`) if error occur.
     pub fn next_cycle(&mut self, row_inst: &str) -> Result<(), String> {
-        let mut next_pc = self.pc;
+        self.next_smt_cycle(|_, _| Some(row_inst.to_string()))
+    }
+    /// Execute a cycle, in which a thread issues an instruction.
+    /// `fetch` returns the instruction at given (thread, address), or None if the thread has nothing to fetch.
+    /// Threads are tried in the order of the fetch policy until one of them issues.
+    pub fn next_smt_cycle<F>(&mut self, mut fetch: F) -> Result<(), String>
+    where
+        F: FnMut(usize, usize) -> Option<String>,
+    {
+        self.csr.cycle = self.csr.cycle.wrapping_add(1);
+        self.written.clear();
+        self.committed.clear();
         self.commit();
 
+        if self.exception.is_some() {
+            self.take_exception();
+        } else if self.csr.interrupting() {
+            self.interrupt();
+        } else {
+            self.fetch_and_issue(&mut fetch)?;
+        }
+        if self.select_policy.uses_critical_path() {
+            let lengths = self.critical_paths();
+            for path in self.arithmetic_paths.values_mut() {
+                path.set_critical_paths(&lengths);
+            }
+            for path in self.access_paths.values_mut() {
+                path.set_critical_paths(&lengths);
+            }
+        }
+
+        for (_, unit) in self.arithmetic_paths.iter_mut() {
+            unit.next_cycle(&mut self.result_bus)?;
+        }
+
+        self.share_accesses();
+        for (_, unit) in self.access_paths.iter_mut() {
+            unit.next_cycle(&mut self.result_bus)?;
+            if let Some(r) = unit.request() {
+                let age = self
+                    .issue_order
+                    .get(&r.tag())
+                    .map_or(0, |(order, _)| *order);
+                self.bus_controller.push(r, age);
+            }
+        }
+        self.bus_controller.next_cycle();
+        self.observe_pipeline();
+        self.collect_statistics();
+        Ok(())
+    }
+    /// Return length of the longest chain of in-flight instructions waiting for each of them,
+    /// through operands and memory ordering
+    fn critical_paths(&self) -> HashMap<RStag, usize> {
+        let nodes = self.dependencies();
+        let mut consumers: HashMap<&RStag, Vec<&RStag>> = HashMap::new();
+        for node in nodes.iter() {
+            for producer in node.operands.iter().chain(node.ordering.iter()) {
+                consumers.entry(producer).or_default().push(&node.tag);
+            }
+        }
+        // Consumers are younger than their producers, so they are visited first
+        let mut nodes: Vec<_> = nodes.iter().collect();
+        nodes.sort_by_key(|node| Reverse(self.issue_order.get(&node.tag).map(|(order, _)| *order)));
+        let mut lengths = HashMap::new();
+        for node in nodes {
+            let length = consumers.get(&node.tag).map_or(0, |consumers| {
+                consumers
+                    .iter()
+                    .map(|tag| lengths.get(*tag).copied().unwrap_or_default() + 1)
+                    .max()
+                    .unwrap_or_default()
+            });
+            lengths.insert(node.tag.clone(), length);
+        }
+        lengths
+    }
+    /// Record usage of all paths, and close the cycle
+    fn collect_statistics(&mut self) {
+        for (name, path) in self.arithmetic_paths.iter() {
+            self.statistics.record_usage(name, &path.usage());
+        }
+        for (name, path) in self.access_paths.iter() {
+            self.statistics.record_usage(name, &path.usage());
+        }
+        self.statistics.next_cycle();
+    }
+    /// Return state of register files, reservation stations, the bus queue and the result bus
+    pub fn trace(&self) -> Json {
+        let threads = self
+            .threads
+            .iter()
+            .map(|t| {
+                Json::object(vec![
+                    ("pc", Json::from(t.pc)),
+                    ("registers", t.register_file.trace()),
+                ])
+            })
+            .collect();
+        let mut paths: Vec<(String, Json)> = self
+            .arithmetic_paths
+            .iter()
+            .map(|(name, p)| (name.clone(), p.trace()))
+            .chain(
+                self.access_paths
+                    .iter()
+                    .map(|(name, p)| (name.clone(), p.trace())),
+            )
+            .filter(|(_, trace)| *trace != Json::Null)
+            .collect();
+        paths.sort_by(|a, b| a.0.cmp(&b.0));
+        Json::object(vec![
+            ("threads", Json::Array(threads)),
+            ("paths", Json::Object(paths)),
+            ("bus_queue", self.bus_controller.trace()),
+            ("result_bus", self.result_bus.trace()),
+        ])
+    }
+    /// Return DOT of in-flight instructions and their dependencies.
+    /// Solid edges are operands, dashed edges are memory ordering,
+    /// and dotted edges are registers renamed to results of instructions.
+    pub fn dependency_graph(&self) -> String {
+        let mut nodes = self.dependencies();
+        nodes.sort_by_key(|node| node.tag.to_string());
+        let mut renamed = Vec::new();
+        for (id, thread) in self.threads.iter().enumerate() {
+            for (idx, tag) in thread.register_file.renamed() {
+                let register = if self.threads.len() == 1 {
+                    format!("R{}", idx)
+                } else {
+                    format!("T{}.R{}", id, idx)
+                };
+                renamed.push((register, self.producer(tag)));
+            }
+        }
+        graph::dependency_graph(&nodes, &renamed)
+    }
+    /// Return in-flight instructions of all paths.
+    /// Operands waiting for physical registers are given as the instructions producing them.
+    fn dependencies(&self) -> Vec<DependencyNode> {
+        let mut nodes: Vec<_> = self
+            .arithmetic_paths
+            .values()
+            .flat_map(|p| p.dependencies())
+            .chain(self.access_paths.values().flat_map(|p| p.dependencies()))
+            .collect();
+        if self.physical.is_some() {
+            for node in nodes.iter_mut() {
+                for operand in node.operands.iter_mut() {
+                    *operand = self.producer(operand.clone());
+                }
+            }
+        }
+        nodes
+    }
+    /// Return tag of the instruction producing the tag, which is itself unless it's a physical register
+    fn producer(&self, tag: RStag) -> RStag {
+        self.physical
+            .as_ref()
+            .and_then(|physical| physical.producer(&tag))
+            .cloned()
+            .unwrap_or(tag)
+    }
+    /// Return state of the processor to save in a snapshot.
+    /// Paths and policies are given by the configuration, their names are saved to check it.
+    /// The pipeline log is not saved.
+    pub fn save(&self) -> Json {
+        let threads = self
+            .threads
+            .iter()
+            .map(|t| {
+                Json::object(vec![
+                    ("pc", Json::from(t.pc)),
+                    ("registers", t.register_file.save()),
+                    ("issued", Json::from(t.issued)),
+                    ("in_flight", Json::from(t.in_flight)),
+                    (
+                        "translating",
+                        t.translating.as_ref().map_or(Json::Null, Translating::save),
+                    ),
+                ])
+            })
+            .collect();
+        let paths = self
+            .arithmetic_paths
+            .iter()
+            .map(|(name, p)| (name, p.save()))
+            .chain(self.access_paths.iter().map(|(name, p)| (name, p.save())))
+            .collect();
+        let mut issue_order: Vec<(&RStag, &(u64, usize))> = self.issue_order.iter().collect();
+        issue_order.sort_by_key(|(_, (order, _))| *order);
+        let issue_order = issue_order
+            .into_iter()
+            .map(|(tag, (order, address))| {
+                Json::Array(vec![tag.save(), Json::from(*order), Json::from(*address)])
+            })
+            .collect();
+        let pairs = |pairs: &[(usize, usize)]| {
+            let pairs = pairs
+                .iter()
+                .map(|(a, b)| Json::Array(vec![Json::from(*a), Json::from(*b)]))
+                .collect();
+            Json::Array(pairs)
+        };
+        Json::object(vec![
+            ("threads", Json::Array(threads)),
+            ("fetch_policy", Json::str(self.fetch_policy.name())),
+            ("fetch_policy_state", self.fetch_policy.save()),
+            ("select_policy", Json::str(self.select_policy.name())),
+            ("instruction", Json::str(self.decoder.last_instruction())),
+            ("paths", Json::object(paths)),
+            ("bus_controller", self.bus_controller.save()),
+            ("result_bus", self.result_bus.save()),
+            ("issued", Json::from(self.issued)),
+            ("issue_order", Json::Array(issue_order)),
+            ("issue_cycles", Json::from(self.issue_cycles)),
+            ("csr", self.csr.save()),
+            (
+                "exception",
+                self.exception.as_ref().map_or(Json::Null, Exception::save),
+            ),
+            (
+                "physical_registers",
+                self.physical
+                    .as_ref()
+                    .map_or(Json::Null, PhysicalRegisterFile::save),
+            ),
+            ("statistics", self.statistics.save()),
+            ("written", pairs(&self.written)),
+            ("committed", pairs(&self.committed)),
+        ])
+    }
+    /// Restore state returned by [Processor::save].
+    /// Error if the snapshot is taken from a processor with other paths, policies or count of threads.
+    /// On error, the processor is left as it was.
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        let mut restored = self.clone();
+        restored.restore_state(state)?;
+        *self = restored;
+        Ok(())
+    }
+    /// Restore state field by field, which may be left partially restored on error
+    fn restore_state(&mut self, state: &Json) -> Result<(), String> {
+        let threads = state.get("threads")?.as_array()?;
+        if threads.len() != self.threads.len() {
+            let msg = format!(
+                "Snapshot has {} threads, but the machine has {}",
+                threads.len(),
+                self.threads.len()
+            );
+            return Err(msg);
+        }
+        for (t, saved) in self.threads.iter_mut().zip(threads) {
+            t.pc = saved.get("pc")?.as_usize()?;
+            t.register_file.restore(saved.get("registers")?)?;
+            t.issued = saved.get("issued")?.as_u64()?;
+            t.in_flight = saved.get("in_flight")?.as_usize()?;
+            t.translating = saved
+                .get("translating")?
+                .opt()
+                .map(Translating::load)
+                .transpose()?;
+        }
+        let policy = state.get("fetch_policy")?.as_str()?;
+        if policy != self.fetch_policy.name() {
+            let msg = format!(
+                "Fetch policy of the snapshot is {}, but the machine uses {}",
+                policy,
+                self.fetch_policy.name()
+            );
+            return Err(msg);
+        }
+        self.fetch_policy
+            .restore(state.get("fetch_policy_state")?)?;
+        let policy = state.get("select_policy")?.as_str()?;
+        if policy != self.select_policy.name() {
+            let msg = format!(
+                "Select policy of the snapshot is {}, but the machine uses {}",
+                policy,
+                self.select_policy.name()
+            );
+            return Err(msg);
+        }
+        self.decoder
+            .set_last_instruction(state.get("instruction")?.as_str()?);
+
+        let paths = state.get("paths")?;
+        let count = self.arithmetic_paths.len() + self.access_paths.len();
+        if paths.as_object()?.len() != count {
+            let msg = format!(
+                "Snapshot has {} paths, but the machine has {}",
+                paths.as_object()?.len(),
+                count
+            );
+            return Err(msg);
+        }
+        for (name, path) in self.arithmetic_paths.iter_mut() {
+            path.restore(paths.get(name)?)
+                .map_err(|msg| format!("{}: {}", name, msg))?;
+        }
+        for (name, path) in self.access_paths.iter_mut() {
+            path.restore(paths.get(name)?)
+                .map_err(|msg| format!("{}: {}", name, msg))?;
+        }
+        self.bus_controller.restore(state.get("bus_controller")?)?;
+        self.result_bus.restore(state.get("result_bus")?)?;
+        self.issued = state.get("issued")?.as_u64()?;
+        self.issue_order = state
+            .get("issue_order")?
+            .as_array()?
+            .iter()
+            .map(|entry| match entry.as_array()? {
+                [tag, order, address] => {
+                    Ok((RStag::load(tag)?, (order.as_u64()?, address.as_usize()?)))
+                }
+                _ => Err(format!("Expect [tag, order, address], found {}", entry)),
+            })
+            .collect::<Result<_, String>>()?;
+        self.issue_cycles = state.get("issue_cycles")?.as_u64()?;
+        self.csr.restore(state.get("csr")?)?;
+        self.exception = state
+            .get("exception")?
+            .opt()
+            .map(Exception::load)
+            .transpose()?;
+        match (
+            self.physical.as_mut(),
+            state.get("physical_registers")?.opt(),
+        ) {
+            (Some(physical), Some(saved)) => physical.restore(saved)?,
+            (None, None) => (),
+            (physical, _) => {
+                let msg = format!(
+                    "Snapshot {} physical registers, but the machine {}",
+                    if physical.is_some() { "has no" } else { "has" },
+                    if physical.is_some() {
+                        "does"
+                    } else {
+                        "doesn't"
+                    }
+                );
+                return Err(msg);
+            }
+        }
+        self.statistics.restore(state.get("statistics")?)?;
+        let pairs = |key: &str| -> Result<Vec<(usize, usize)>, String> {
+            state
+                .get(key)?
+                .as_array()?
+                .iter()
+                .map(|pair| match pair.as_array()? {
+                    [a, b] => Ok((a.as_usize()?, b.as_usize()?)),
+                    _ => Err(format!("Expect a pair, found {}", pair)),
+                })
+                .collect()
+        };
+        self.written = pairs("written")?;
+        self.committed = pairs("committed")?;
+        Ok(())
+    }
+    /// Return registers (thread, register number) written in the last cycle
+    pub fn written(&self) -> &[(usize, usize)] {
+        &self.written
+    }
+    /// Return instructions (thread, address) committed in the last cycle
+    pub fn committed(&self) -> &[(usize, usize)] {
+        &self.committed
+    }
+    /// Start recording lifecycle of instructions
+    pub fn record_pipeline(&mut self) {
+        self.pipeline.get_or_insert_with(PipelineLog::new);
+    }
+    /// Return recorded lifecycle of instructions, None if recording is not enabled
+    pub fn pipeline(&self) -> Option<&PipelineLog> {
+        self.pipeline.as_ref()
+    }
+    /// Record phases of in-flight instructions, and the result put on the result bus
+    fn observe_pipeline(&mut self) {
+        let Some(pipeline) = self.pipeline.as_mut() else {
+            return;
+        };
+        let cycle = self.statistics.cycles;
+        for (_, path) in self.arithmetic_paths.iter() {
+            pipeline.observe(cycle, &path.phases());
+        }
+        for (_, path) in self.access_paths.iter() {
+            pipeline.observe(cycle, &path.phases());
+        }
+        if let Some(tag) = self.result_bus.tag() {
+            pipeline.writeback(cycle, tag);
+        }
+    }
+    /// Return statistics collected so far
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+    /// An instruction completed
+    fn retire(&mut self) {
+        self.csr.instret = self.csr.instret.wrapping_add(1);
+        self.statistics.committed += 1;
+    }
+    /// Take the pending interrupt.
+    /// Instructions are issued in order, so all in-flight instructions are before the interrupted one.
+    /// Issue stalls until they complete, then the interrupt is taken at a precise boundary.
+    /// Interrupts are not routed per thread, only thread 0 is redirected to the vector.
+    fn interrupt(&mut self) {
+        if self.is_idle() {
+            let thread = &mut self.threads[0];
+            thread.pc = self.csr.take(thread.pc);
+        } else {
+            self.csr.drain_cycles += 1;
+        }
+    }
+    /// Take the exception raised, once in-flight instructions complete as for interrupts.
+    /// The faulting thread is redirected to the vector.
+    fn take_exception(&mut self) {
+        if !self.is_idle() {
+            return;
+        }
+        if let Some(exception) = self.exception.take() {
+            self.threads[exception.thread].pc = self.csr.trap(&exception);
+        }
+    }
+    /// Fetch an instruction of each thread, then issue one of them by the fetch policy
+    fn fetch_and_issue<F>(&mut self, fetch: &mut F) -> Result<(), String>
+    where
+        F: FnMut(usize, usize) -> Option<String>,
+    {
+        let mut fetched = HashMap::new();
+        let mut candidates = Vec::new();
+        for (id, thread) in self.threads.iter().enumerate() {
+            if let Some(inst) = fetch(id, thread.pc) {
+                if let Some(pipeline) = self.pipeline.as_mut() {
+                    pipeline.fetch(self.statistics.cycles, id, thread.pc);
+                }
+                fetched.insert(id, inst);
+                candidates.push(Candidate {
+                    thread: id,
+                    in_flight: thread.in_flight,
+                });
+            }
+        }
+        if !candidates.is_empty() {
+            self.issue_cycles += 1;
+        }
+        let mut stall = None;
+        for thread in self.fetch_policy.order(&candidates) {
+            stall = self.issue(thread, &fetched[&thread])?;
+            if stall.is_none() {
+                break;
+            }
+        }
+        if let Some(cause) = stall {
+            self.statistics.record_stall(cause);
+        }
+        Ok(())
+    }
+    /// Try to issue the instruction fetched by the thread.
+    /// Return None if the instruction issued, otherwise the cause of the stall.
+    fn issue(&mut self, thread: usize, row_inst: &str) -> Result<Option<StallCause>, String> {
         let inst = self.decoder.decode(row_inst)?;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            pipeline.decode(self.statistics.cycles, thread);
+        }
+        if self.threads[thread].translating.is_some() {
+            return Ok(Some(StallCause::Serializing));
+        }
+        // Return from interrupt redirects fetching only, no execution path involved.
+        // It waits for the handler to complete, so acknowledgement of the interrupt is visible.
+        if inst.name() == csr_unit::RETURN {
+            if !self.is_idle() {
+                return Ok(Some(StallCause::Serializing));
+            }
+            let t = &mut self.threads[thread];
+            t.pc = self.csr.ret();
+            t.issued += 1;
+            if let Some(pipeline) = self.pipeline.as_mut() {
+                pipeline.issue(self.statistics.cycles, thread, row_inst, None);
+            }
+            self.retire();
+            self.issued += 1;
+            self.statistics.issued += 1;
+            return Ok(None);
+        }
+        let is_csr = [csr_unit::READ, csr_unit::WRITE].contains(&inst.name().as_str());
+        // CSR instructions are serialized, so counters read are exact and writes take effect in order
+        if is_csr && !self.is_idle() {
+            return Ok(Some(StallCause::Serializing));
+        }
         let args = inst.arguments();
         let mut renamed_args = Vec::with_capacity(args.len());
 
         // Mapping arguments from types to data
+        let register_file = &self.threads[thread].register_file;
         for arg in args.iter() {
             let val = match *arg {
-                ArgType::Reg(idx) => self.register_file.read(idx),
+                ArgType::Reg(idx) => register_file.read(idx),
                 ArgType::Imm(imm) => ArgState::Ready(imm),
             };
             renamed_args.push(val);
         }
-
-        let result = self.try_issue(&inst, &renamed_args);
-        if let IssueResult::Issued(tag) = result {
-            next_pc += 1;
-            self.register_renaming(tag, inst)?;
+        if is_csr {
+            renamed_args = self.access_csr(&inst, &renamed_args)?;
         }
-
-        for (_, unit) in self.arithmetic_paths.iter_mut() {
-            unit.next_cycle(&mut self.result_bus)?;
+        let no_free = self.physical.as_ref().is_some_and(|p| !p.has_free());
+        if no_free && inst.writeback().is_some() {
+            return Ok(Some(StallCause::FreeListEmpty));
         }
 
-        for (_, unit) in self.access_paths.iter_mut() {
-            unit.next_cycle(&mut self.result_bus)?;
-            if let Some(r) = unit.request() {
-                self.bus_controller.push(r);
+        let tag = match self.try_issue(thread, &inst, &renamed_args) {
+            IssueResult::Issued(tag) => tag,
+            IssueResult::Stall(cause) => return Ok(Some(cause)),
+        };
+        // Fence accesses no memory, so it's never translated
+        let accesses = self.access_paths.contains_key(&tag.station()) && inst.name() != "fence";
+        if self.translated_accesses && accesses {
+            let register_file = &self.threads[thread].register_file;
+            let destination = match inst.writeback() {
+                Some(ArgType::Reg(idx)) => Some((idx, register_file.read(idx))),
+                _ => None,
+            };
+            self.threads[thread].translating = Some(Translating {
+                tag: tag.clone(),
+                destination,
+            });
+        }
+        let t = &mut self.threads[thread];
+        t.pc += 1;
+        t.issued += 1;
+        // Nop completes on issue and never reaches the result bus.
+        // It's a bubble rather than work, so statistics and pipeline diagrams skip it.
+        if inst.name() == "nop" {
+            self.csr.instret = self.csr.instret.wrapping_add(1);
+        } else {
+            if let Some(pipeline) = self.pipeline.as_mut() {
+                pipeline.issue(self.statistics.cycles, thread, row_inst, Some(tag.clone()));
             }
+            t.in_flight += 1;
+            self.statistics.issued += 1;
+            self.issue_order
+                .insert(tag.clone(), (self.issued, t.pc - 1));
         }
-
-        self.pc = next_pc;
-        Ok(())
+        self.issued += 1;
+        self.register_renaming(thread, tag, inst)?;
+        Ok(None)
+    }
+    /// Access the CSR named by the last argument.
+    /// Return arguments of the CSR path, whose first one is the value to write back.
+    fn access_csr(
+        &mut self,
+        inst: &DecodedInst,
+        args: &[ArgState],
+    ) -> Result<Vec<ArgState>, String> {
+        let csr = match args.last() {
+            Some(ArgState::Ready(csr)) => *csr,
+            _ => return Err(format!("{} requires a CSR number", inst.name())),
+        };
+        if inst.name() == csr_unit::WRITE {
+            // Processor is idle, so the source register must be ready
+            match args.first() {
+                Some(ArgState::Ready(val)) => self.csr.write(csr, *val)?,
+                _ => return Err(format!("Source of {} is not ready", inst.name())),
+            }
+        }
+        let val = self.csr.read(csr)?;
+        Ok(vec![ArgState::Ready(val)])
+    }
+    /// Return control and status registers
+    pub fn csr(&self) -> &CsrFile {
+        &self.csr
     }
     /// Return the state of the processor.
     /// If there is instruction executing, return false.
@@ -250,13 +1109,24 @@ impl Processor {
             .count();
         let no_instruction_executing = executing_arith + executing_mem == 0;
         let no_writeback = self.result_bus.is_free();
-        println!("{no_instruction_executing} {no_writeback}");
         no_instruction_executing && no_writeback
     }
     pub fn bus_access(&mut self) -> Option<BusAccessRequst> {
-        let controller = &mut self.bus_controller;
-        let request = controller.access_queue.pop_front()?;
-        Some(request)
+        self.bus_controller.pop()
+    }
+    /// Set byte order used by access paths
+    pub fn set_endian(&mut self, endian: Endian) {
+        for (_, path) in self.access_paths.iter_mut() {
+            path.set_endian(endian);
+        }
+    }
+    /// Replace arbitration policy of the bus controller
+    pub fn set_arbitration_policy(&mut self, policy: Box<dyn ArbitrationPolicy>) {
+        self.bus_controller.set_policy(policy);
+    }
+    /// Return bus access statistics of each access path
+    pub fn bus_statistics(&self) -> &BTreeMap<String, PathStatistics> {
+        self.bus_controller.statistics()
     }
     pub fn resolve_access(&mut self, response: BusAccessResponse) -> Result<(), String> {
         let path = response.path_name();
@@ -272,7 +1142,11 @@ impl Processor {
     #[allow(dead_code)]
     /// This function is used to testing
     pub fn peek_registers(&self) -> Vec<ArgState> {
-        let rf = &self.register_file;
+        self.peek_thread_registers(0)
+    }
+    /// This function is used to testing
+    pub fn peek_thread_registers(&self, thread: usize) -> Vec<ArgState> {
+        let rf = &self.threads[thread].register_file;
         let size = rf.size();
         (0..size).map(|i| rf.read(i)).collect()
     }
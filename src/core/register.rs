@@ -1,13 +1,14 @@
 use super::execution_path::{ArgState, RStag};
+use crate::util::json::Json;
 use std::{default::Default, fmt::Display};
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 /// Renamable register file
 pub struct RegisterFile {
     entries: [Entry; 16],
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Entry {
     val: u32,
     tag: Option<RStag>,
@@ -25,6 +26,7 @@ impl Display for Entry {
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod regfile {
     use super::*;
     #[test]
@@ -55,6 +57,17 @@ mod regfile {
         }
     }
     #[test]
+    fn write_returns_written() {
+        let mut rf = RegisterFile::new();
+        let tag = RStag::new("name", 1);
+        rf.entries[3].tag = Some(tag.clone());
+        rf.entries[7].tag = Some(tag.clone());
+        rf.entries[9].tag = Some(RStag::new("name", 2));
+
+        assert_eq!(rf.write(tag.clone(), 100), [3, 7]);
+        assert!(rf.write(tag, 100).is_empty());
+    }
+    #[test]
     fn write_not_match() {
         let mut rf = RegisterFile::new();
         let tag_set = RStag::new("name", 1);
@@ -85,21 +98,73 @@ impl RegisterFile {
             ArgState::Ready(val)
         }
     }
-    /// Write the given `val` to renamed register `tag`
-    pub fn write(&mut self, tag: RStag, val: u32) {
-        for e in self.entries.as_mut() {
+    /// Write the given `val` to renamed register `tag`.
+    /// Return numbers of registers written.
+    pub fn write(&mut self, tag: RStag, val: u32) -> Vec<usize> {
+        let mut written = Vec::new();
+        for (idx, e) in self.entries.iter_mut().enumerate() {
             if let Some(wait) = e.tag.as_ref() {
                 if *wait == tag {
                     e.val = val;
                     e.tag = None;
+                    written.push(idx);
                 }
             }
         }
+        written
     }
     /// Rename register number `idx` with reservation station tag
     pub fn rename(&mut self, idx: usize, tag: RStag) {
         self.entries[idx].tag = Some(tag);
     }
+    /// Set register number `idx` to a value or a tag, undoing a rename
+    pub fn set(&mut self, idx: usize, state: ArgState) {
+        match state {
+            ArgState::Waiting(tag) => self.entries[idx].tag = Some(tag),
+            ArgState::Ready(val) => self.entries[idx] = Entry { val, tag: None },
+        }
+    }
+    /// Return (register number, tag) of renamed registers
+    pub fn renamed(&self) -> Vec<(usize, RStag)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, e)| e.tag.clone().map(|tag| (idx, tag)))
+            .collect()
+    }
+    /// Return value of each register, or the tag it's renamed to
+    pub fn trace(&self) -> Json {
+        let entries = self
+            .entries
+            .iter()
+            .map(|e| e.tag.as_ref().map_or(Json::from(e.val), Json::str))
+            .collect();
+        Json::Array(entries)
+    }
+    /// Return value and tag of each register, the value of a renamed register is kept
+    pub fn save(&self) -> Json {
+        let entries = self
+            .entries
+            .iter()
+            .map(|e| {
+                let tag = e.tag.as_ref().map_or(Json::Null, RStag::save);
+                Json::object(vec![("val", Json::from(e.val)), ("tag", tag)])
+            })
+            .collect();
+        Json::Array(entries)
+    }
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        let entries = state.as_array()?;
+        if entries.len() != self.size() {
+            let msg = format!("Expect {} registers, found {}", self.size(), entries.len());
+            return Err(msg);
+        }
+        for (e, saved) in self.entries.iter_mut().zip(entries) {
+            e.val = saved.get("val")?.as_u32()?;
+            e.tag = saved.get("tag")?.opt().map(RStag::load).transpose()?;
+        }
+        Ok(())
+    }
     /// Return size of the registerfile, in other words, the register count.
     pub fn size(&self) -> usize {
         self.entries.len()
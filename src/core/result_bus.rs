@@ -1,10 +1,11 @@
 use std::fmt::Display;
 
 use crate::display::into_table;
+use crate::util::json::Json;
 
 use super::execution_path::{ExecResult, RStag};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ResultBus {
     value: Option<(RStag, ExecResult)>,
 }
@@ -36,6 +37,33 @@ impl ResultBus {
     pub fn take(&mut self) -> Option<(RStag, ExecResult)> {
         self.value.take()
     }
+    pub fn trace(&self) -> Json {
+        self.value.as_ref().map_or(Json::Null, |(tag, result)| {
+            Json::object(vec![
+                ("tag", Json::str(tag)),
+                ("result", Json::str(format!("{:?}", result))),
+            ])
+        })
+    }
+    pub fn save(&self) -> Json {
+        self.value.as_ref().map_or(Json::Null, |(tag, result)| {
+            Json::object(vec![("tag", tag.save()), ("result", result.save())])
+        })
+    }
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.value = match state.opt() {
+            Some(value) => Some((
+                RStag::load(value.get("tag")?)?,
+                ExecResult::load(value.get("result")?)?,
+            )),
+            None => None,
+        };
+        Ok(())
+    }
+    /// Return tag of the result on the bus
+    pub fn tag(&self) -> Option<&RStag> {
+        self.value.as_ref().map(|(tag, _)| tag)
+    }
     pub fn is_free(&self) -> bool {
         self.value.is_none()
     }
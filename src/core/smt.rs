@@ -0,0 +1,181 @@
+use super::execution_path::{ArgState, RStag};
+use super::register::RegisterFile;
+use crate::util::clone_box;
+use crate::util::json::Json;
+use std::fmt::Debug;
+
+/// Architectural state of a hardware thread
+#[derive(Debug, Clone)]
+pub struct Thread {
+    pub pc: usize,
+    pub register_file: RegisterFile,
+    /// Count of issued instructions
+    pub issued: u64,
+    /// Count of instructions issued but not committed yet
+    pub in_flight: usize,
+    /// Access waiting for its address to be translated, issue stalls until it is
+    pub translating: Option<Translating>,
+}
+
+impl Thread {
+    pub fn new() -> Self {
+        Self {
+            pc: 0,
+            register_file: RegisterFile::new(),
+            issued: 0,
+            in_flight: 0,
+            translating: None,
+        }
+    }
+}
+
+/// Access issued while addresses are translated, which may still raise a page fault
+#[derive(Debug, Clone, PartialEq)]
+pub struct Translating {
+    pub tag: RStag,
+    /// Destination register and its state before the access renamed it,
+    /// restored if the access faults
+    pub destination: Option<(usize, ArgState)>,
+}
+
+impl Translating {
+    pub fn save(&self) -> Json {
+        let destination = self
+            .destination
+            .as_ref()
+            .map_or(Json::Null, |(idx, state)| {
+                Json::Array(vec![Json::from(*idx), state.save()])
+            });
+        Json::object(vec![("tag", self.tag.save()), ("destination", destination)])
+    }
+    pub fn load(state: &Json) -> Result<Self, String> {
+        let destination = match state.get("destination")?.opt() {
+            Some(destination) => match destination.as_array()? {
+                [idx, state] => Some((idx.as_usize()?, ArgState::load(state)?)),
+                _ => return Err(format!("Expect [register, state], found {}", destination)),
+            },
+            None => None,
+        };
+        Ok(Self {
+            tag: RStag::load(state.get("tag")?)?,
+            destination,
+        })
+    }
+}
+
+/// A thread candidate to fetch from
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub thread: usize,
+    /// Count of instructions issued but not committed yet
+    pub in_flight: usize,
+}
+
+clone_box! {
+    /// Clone a policy behind a box, implemented for every cloneable FetchPolicy
+    CloneFetchPolicy for FetchPolicy
+}
+
+/// Policy to decide which thread fetches in a cycle
+pub trait FetchPolicy: Debug + CloneFetchPolicy {
+    fn name(&self) -> String;
+    /// Order candidates by priority.
+    /// If the thread with the highest priority is unable to issue, the next one tries.
+    /// Return thread ids in the order.
+    fn order(&mut self, candidates: &[Candidate]) -> Vec<usize>;
+    /// Return state of the policy to save in a snapshot, null if it's stateless
+    fn save(&self) -> Json {
+        Json::Null
+    }
+    /// Restore state returned by [FetchPolicy::save]
+    fn restore(&mut self, _state: &Json) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Threads fetch in turn
+#[derive(Debug, Clone, Default)]
+pub struct RoundRobin {
+    /// Thread fetched first in last cycle
+    last: Option<usize>,
+}
+
+impl FetchPolicy for RoundRobin {
+    fn name(&self) -> String {
+        String::from("Round robin")
+    }
+    fn order(&mut self, candidates: &[Candidate]) -> Vec<usize> {
+        let mut threads: Vec<usize> = candidates.iter().map(|c| c.thread).collect();
+        threads.sort();
+        // Threads after the last one go first, then wrap around
+        let split = threads
+            .iter()
+            .position(|t| self.last.is_none_or(|last| *t > last))
+            .unwrap_or(threads.len());
+        threads.rotate_left(split);
+        self.last = threads.first().copied();
+        threads
+    }
+    fn save(&self) -> Json {
+        self.last.map_or(Json::Null, Json::from)
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.last = state.opt().map(Json::as_usize).transpose()?;
+        Ok(())
+    }
+}
+
+/// Threads with fewer in-flight instructions fetch first.
+/// Threads which stall in the pipeline are therefore unable to occupy reservation stations.
+#[derive(Debug, Clone)]
+pub struct ICount;
+
+impl FetchPolicy for ICount {
+    fn name(&self) -> String {
+        String::from("ICOUNT")
+    }
+    fn order(&mut self, candidates: &[Candidate]) -> Vec<usize> {
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by_key(|c| (c.in_flight, c.thread));
+        candidates.iter().map(|c| c.thread).collect()
+    }
+}
+
+/// Construct a fetch policy by its name
+pub fn policy_of(name: &str) -> Result<Box<dyn FetchPolicy>, String> {
+    match name {
+        "round-robin" => Ok(Box::new(RoundRobin::default())),
+        "icount" => Ok(Box::new(ICount)),
+        _ => Err(format!("Unknown fetch policy {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod fetch_policy {
+    use super::*;
+
+    fn candidates(in_flight: &[(usize, usize)]) -> Vec<Candidate> {
+        in_flight
+            .iter()
+            .map(|(thread, in_flight)| Candidate {
+                thread: *thread,
+                in_flight: *in_flight,
+            })
+            .collect()
+    }
+    #[test]
+    fn round_robin() {
+        let mut rr = RoundRobin::default();
+        let c = candidates(&[(0, 0), (1, 0), (2, 0)]);
+        assert_eq!(rr.order(&c), [0, 1, 2]);
+        assert_eq!(rr.order(&c), [1, 2, 0]);
+        // Finished threads are skipped
+        let c = candidates(&[(0, 0), (1, 0)]);
+        assert_eq!(rr.order(&c), [0, 1]);
+    }
+    #[test]
+    fn icount() {
+        let c = candidates(&[(0, 3), (1, 1), (2, 1)]);
+        assert_eq!(ICount.order(&c), [1, 2, 0]);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::display::into_table;
+use crate::util::json::Json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+
+/// Reason that an instruction is unable to make progress in a cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StallCause {
+    /// No empty reservation station slot to issue to
+    StationFull,
+    /// Evaluation queue of a memory access unit is full
+    QueueFull,
+    /// Issue waits for older instructions to complete, e.g. CSR instructions
+    Serializing,
+    /// An execution result is waiting for the result bus
+    ResultBusBusy,
+    /// An access is waiting for the memory
+    MemoryBusy,
+    /// Instructions in reservation stations are waiting for operands
+    WaitingOperands,
+    /// No physical register is free to rename the destination to
+    FreeListEmpty,
+}
+
+impl StallCause {
+    pub const ALL: [StallCause; 7] = [
+        StallCause::StationFull,
+        StallCause::QueueFull,
+        StallCause::Serializing,
+        StallCause::ResultBusBusy,
+        StallCause::MemoryBusy,
+        StallCause::WaitingOperands,
+        StallCause::FreeListEmpty,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            StallCause::StationFull => "station_full",
+            StallCause::QueueFull => "queue_full",
+            StallCause::Serializing => "serializing",
+            StallCause::ResultBusBusy => "result_bus_busy",
+            StallCause::MemoryBusy => "memory_busy",
+            StallCause::WaitingOperands => "waiting_operands",
+            StallCause::FreeListEmpty => "free_list_empty",
+        }
+    }
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        StallCause::ALL
+            .iter()
+            .find(|cause| cause.name() == name)
+            .copied()
+            .ok_or_else(|| format!("Unknown stall cause {}", name))
+    }
+}
+
+/// State of an execution path in a cycle
+#[derive(Debug, Clone, Default)]
+pub struct PathUsage {
+    /// Count of occupied reservation station slots
+    pub occupied: usize,
+    /// Count of reservation station slots
+    pub capacity: usize,
+    /// Wheither the path is working on an instruction or not
+    pub busy: bool,
+    pub stalls: Vec<StallCause>,
+}
+
+/// Accumulated usage of an execution path
+#[derive(Debug, Default, Clone)]
+pub struct UnitStatistics {
+    pub busy_cycles: u64,
+    /// Sum of occupied slots of all cycles
+    pub occupied: u64,
+    pub capacity: usize,
+}
+
+impl UnitStatistics {
+    /// Return fraction of cycles that the path was busy
+    pub fn utilisation(&self, cycles: u64) -> f64 {
+        ratio(self.busy_cycles, cycles)
+    }
+    /// Return average count of occupied slots
+    pub fn occupancy(&self, cycles: u64) -> f64 {
+        ratio(self.occupied, cycles)
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
+}
+
+/// Statistics collected by a processor over a run.
+/// A cycle may stall by several causes, each of them is counted.
+#[derive(Debug, Default, Clone)]
+pub struct Statistics {
+    pub cycles: u64,
+    pub issued: u64,
+    pub committed: u64,
+    stalls: BTreeMap<StallCause, u64>,
+    units: BTreeMap<String, UnitStatistics>,
+    /// Causes observed in current cycle
+    current: BTreeSet<StallCause>,
+}
+
+impl Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rows = vec![format!(
+            "Cycles: {}, Issued: {}, Committed: {}, IPC: {:.2}",
+            self.cycles,
+            self.issued,
+            self.committed,
+            self.ipc()
+        )];
+        for cause in StallCause::ALL {
+            let cycles = self.stall_cycles(cause);
+            rows.push(format!("Stall {}: {} cycles", cause.name(), cycles));
+        }
+        for (name, unit) in self.units.iter() {
+            rows.push(format!(
+                "{}: utilisation {:.2}, occupancy {:.2}/{}",
+                name,
+                unit.utilisation(self.cycles),
+                unit.occupancy(self.cycles),
+                unit.capacity
+            ));
+        }
+        write!(f, "{}", into_table("Statistics", rows))
+    }
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Committed instructions per cycle
+    pub fn ipc(&self) -> f64 {
+        ratio(self.committed, self.cycles)
+    }
+    pub fn stall_cycles(&self, cause: StallCause) -> u64 {
+        self.stalls.get(&cause).copied().unwrap_or_default()
+    }
+    pub fn record_stall(&mut self, cause: StallCause) {
+        self.current.insert(cause);
+    }
+    /// Paths without reservation station are not recorded
+    pub fn record_usage(&mut self, name: &str, usage: &PathUsage) {
+        for cause in usage.stalls.iter() {
+            self.current.insert(*cause);
+        }
+        if usage.capacity == 0 {
+            return;
+        }
+        let unit = self.units.entry(name.to_string()).or_default();
+        unit.capacity = usage.capacity;
+        unit.occupied += usage.occupied as u64;
+        unit.busy_cycles += usage.busy as u64;
+    }
+    /// Close current cycle
+    pub fn next_cycle(&mut self) {
+        self.cycles += 1;
+        for cause in std::mem::take(&mut self.current) {
+            *self.stalls.entry(cause).or_default() += 1;
+        }
+    }
+    pub fn save(&self) -> Json {
+        let stalls = self
+            .stalls
+            .iter()
+            .map(|(cause, cycles)| (cause.name(), Json::from(*cycles)))
+            .collect();
+        let units = self
+            .units
+            .iter()
+            .map(|(name, unit)| {
+                let unit = Json::object(vec![
+                    ("busy_cycles", Json::from(unit.busy_cycles)),
+                    ("occupied", Json::from(unit.occupied)),
+                    ("capacity", Json::from(unit.capacity)),
+                ]);
+                (name, unit)
+            })
+            .collect();
+        let current = self.current.iter().map(|c| Json::str(c.name())).collect();
+        Json::object(vec![
+            ("cycles", Json::from(self.cycles)),
+            ("issued", Json::from(self.issued)),
+            ("committed", Json::from(self.committed)),
+            ("stalls", Json::object(stalls)),
+            ("units", Json::object(units)),
+            ("current", Json::Array(current)),
+        ])
+    }
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.cycles = state.get("cycles")?.as_u64()?;
+        self.issued = state.get("issued")?.as_u64()?;
+        self.committed = state.get("committed")?.as_u64()?;
+        self.stalls = state
+            .get("stalls")?
+            .as_object()?
+            .iter()
+            .map(|(cause, cycles)| Ok((StallCause::from_name(cause)?, cycles.as_u64()?)))
+            .collect::<Result<_, String>>()?;
+        self.units = state
+            .get("units")?
+            .as_object()?
+            .iter()
+            .map(|(name, unit)| {
+                let unit = UnitStatistics {
+                    busy_cycles: unit.get("busy_cycles")?.as_u64()?,
+                    occupied: unit.get("occupied")?.as_u64()?,
+                    capacity: unit.get("capacity")?.as_usize()?,
+                };
+                Ok((name.clone(), unit))
+            })
+            .collect::<Result<_, String>>()?;
+        self.current = state
+            .get("current")?
+            .as_array()?
+            .iter()
+            .map(|cause| StallCause::from_name(cause.as_str()?))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+    /// Return rows of (metric, value)
+    fn metrics(&self) -> Vec<(String, Json)> {
+        // Ratios are rounded to 4 decimal places
+        let ratio = |val: f64| Json::from((val * 1e4).round() / 1e4);
+        let mut metrics = vec![
+            (String::from("cycles"), Json::from(self.cycles)),
+            (String::from("issued"), Json::from(self.issued)),
+            (String::from("committed"), Json::from(self.committed)),
+            (String::from("ipc"), ratio(self.ipc())),
+        ];
+        for cause in StallCause::ALL {
+            let cycles = self.stall_cycles(cause);
+            metrics.push((format!("stall.{}", cause.name()), Json::from(cycles)));
+        }
+        for (name, unit) in self.units.iter() {
+            let utilisation = ratio(unit.utilisation(self.cycles));
+            let occupancy = ratio(unit.occupancy(self.cycles));
+            metrics.push((format!("{}.utilisation", name), utilisation));
+            metrics.push((format!("{}.occupancy", name), occupancy));
+            metrics.push((format!("{}.capacity", name), Json::from(unit.capacity)));
+        }
+        metrics
+    }
+    /// Export as CSV with columns of metric and value
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("metric,value\n");
+        for (metric, value) in self.metrics() {
+            csv.push_str(&format!("{},{}\n", metric, value));
+        }
+        csv
+    }
+    /// Export as a flat JSON object of metric and value
+    pub fn to_json(&self) -> String {
+        format!("{}\n", Json::object(self.metrics()))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod statistics {
+    use super::*;
+
+    #[test]
+    fn collect_and_export() -> Result<(), String> {
+        let mut stat = Statistics::new();
+        let usage = PathUsage {
+            occupied: 2,
+            capacity: 4,
+            busy: true,
+            stalls: vec![StallCause::WaitingOperands],
+        };
+        stat.record_usage("arith0", &usage);
+        stat.record_stall(StallCause::WaitingOperands);
+        stat.issued += 1;
+        stat.next_cycle();
+        stat.record_usage(
+            "arith0",
+            &PathUsage {
+                capacity: 4,
+                ..PathUsage::default()
+            },
+        );
+        stat.committed += 1;
+        stat.next_cycle();
+
+        // Counted once per cycle
+        assert_eq!(stat.stall_cycles(StallCause::WaitingOperands), 1);
+        assert_eq!(stat.stall_cycles(StallCause::MemoryBusy), 0);
+        let unit = &stat.units["arith0"];
+        assert_eq!(unit.utilisation(stat.cycles), 0.5);
+        assert_eq!(unit.occupancy(stat.cycles), 1.0);
+        assert_eq!(stat.ipc(), 0.5);
+
+        let csv = stat.to_csv();
+        assert!(csv.starts_with("metric,value\ncycles,2\n"));
+        assert!(csv.contains("stall.waiting_operands,1\n"));
+        let json = Json::parse(&stat.to_json())?;
+        assert_eq!(json.get("arith0.utilisation")?.as_f64()?, 0.5);
+        assert_eq!(json.get("stall.waiting_operands")?.as_u64()?, 1);
+        Ok(())
+    }
+    #[test]
+    fn export_escaped_names() -> Result<(), String> {
+        let mut stat = Statistics::new();
+        let usage = PathUsage {
+            capacity: 2,
+            ..PathUsage::default()
+        };
+        stat.record_usage("say \"hi\"", &usage);
+        stat.next_cycle();
+        let json = Json::parse(&stat.to_json())?;
+        assert_eq!(json.get("say \"hi\".capacity")?.as_u64()?, 2);
+        Ok(())
+    }
+}
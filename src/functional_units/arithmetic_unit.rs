@@ -1,17 +1,36 @@
 use crate::core::decoder::{InstFormat, TokenType};
 use crate::core::execution_path::{ArgState, ExecPath, ExecResult, RStag};
+use crate::core::pipeline::Phase;
 use crate::core::result_bus::ResultBus;
+use crate::core::statistics::{PathUsage, StallCause};
 
 use crate::display::into_table;
+use crate::graph::{cluster, DependencyNode, Graph};
+use crate::util::json::Json;
 
 use super::reservation_station::*;
+use super::select_policy::SelectPolicy;
+use std::collections::HashMap;
 use std::fmt::{self, Display};
 
-#[derive(Debug)]
+/// Default slots of the reservation station
+pub const STATION_SIZE: usize = 5;
+
+/// Cycles an instruction takes to execute
+fn latency_of(inst: &str) -> usize {
+    match inst {
+        "add" | "addi" => 1,
+        "mul" => 4,
+        _ => 0,
+    }
+}
+
+/// A reservation station feeding one ALU, or shared by several of them
+#[derive(Debug, Clone)]
 pub struct Unit {
     name: String,
     station: ReservationStation,
-    exec: Option<ExecUnit>,
+    alus: Vec<Alu>,
 }
 
 impl ExecPath for Unit {
@@ -33,6 +52,11 @@ impl ExecPath for Unit {
                 .add_syntax(TokenType::Register)
                 .add_syntax(TokenType::Immediate)
                 .done(),
+            InstFormat::create("mul")
+                .add_syntax(TokenType::Writeback)
+                .add_syntax(TokenType::Register)
+                .add_syntax(TokenType::Register)
+                .done(),
         ]
     }
     fn forward(&mut self, tag: RStag, val: u32) {
@@ -43,24 +67,31 @@ impl ExecPath for Unit {
         }
         self.station.forward(&tag, val);
     }
-    fn try_issue(&mut self, inst: String, renamed_args: &[ArgState]) -> Result<RStag, ()> {
-        let inst = ArithInst::new(inst, renamed_args).map_err(|_| ())?;
+    fn try_issue(
+        &mut self,
+        inst: String,
+        renamed_args: &[ArgState],
+        seq: u64,
+        thread: usize,
+    ) -> Result<RStag, ()> {
+        let inst = ArithInst::new(inst, renamed_args, seq, thread).map_err(|_| ())?;
         self.station
             .insert(inst as Box<dyn RenamedInst>)
-            .map(|idx| RStag::new(&self.name, idx))
+            .map(|idx| self.tag(idx))
             .ok_or(())
     }
     fn next_cycle(&mut self, bus: &mut ResultBus) -> Result<(), String> {
-        if let Some(unit) = self.exec.as_mut() {
-            let done = unit.next_cycle(bus);
-            if done {
-                // execution done
-                self.exec = None;
-            }
+        for alu in self.alus.iter_mut() {
+            alu.next_cycle(bus);
         }
-        if self.exec.is_none() {
-            if let Some(id) = self.station.ready() {
-                self.execute(id)?;
+        // Every ALU with room starts a ready instruction, the first ALU chooses first
+        for idx in 0..self.alus.len() {
+            if !self.alus[idx].can_start() {
+                continue;
+            }
+            match self.station.ready() {
+                Some(id) => self.execute(idx, id)?,
+                None => break,
             }
         }
         Ok(())
@@ -68,9 +99,123 @@ impl ExecPath for Unit {
     fn pending(&self) -> usize {
         self.station.pending()
     }
+    fn set_select_policy(&mut self, policy: Box<dyn SelectPolicy>) {
+        self.station.set_policy(policy);
+    }
+    fn set_critical_paths(&mut self, lengths: &HashMap<RStag, usize>) {
+        let lengths = (0..self.station.capacity())
+            .map(|idx| {
+                let tag = self.tag(idx);
+                lengths.get(&tag).copied().unwrap_or_default()
+            })
+            .collect();
+        self.station.set_critical_paths(lengths);
+    }
     fn is_idle(&self) -> bool {
         self.station.occupied() == 0
     }
+    fn trace(&self) -> Json {
+        let exec = self.executing().map(Json::str).collect();
+        Json::object(vec![
+            ("station", self.station.trace()),
+            ("executing", Json::Array(exec)),
+        ])
+    }
+    fn dependencies(&self) -> Vec<DependencyNode> {
+        self.station
+            .instructions()
+            .into_iter()
+            .map(|(idx, inst)| {
+                let tag = self.tag(idx);
+                DependencyNode::new(tag, inst.to_string(), &inst.arguments(), inst.ordering())
+            })
+            .collect()
+    }
+    /// Instructions whose result waits for the result bus are done
+    fn phases(&self) -> Vec<(RStag, Phase)> {
+        let done: Vec<&RStag> = self
+            .executing()
+            .filter(|exec| exec.cycle == 0)
+            .map(|exec| &exec.tag)
+            .collect();
+        self.station
+            .phases()
+            .into_iter()
+            .map(|(idx, phase)| {
+                let tag = self.tag(idx);
+                match phase {
+                    Phase::Executing if done.contains(&&tag) => (tag, Phase::Done),
+                    phase => (tag, phase),
+                }
+            })
+            .collect()
+    }
+    fn save(&self) -> Json {
+        let alus = self.alus.iter().map(Alu::save).collect();
+        Json::object(vec![
+            ("station", self.station.save()),
+            ("alus", Json::Array(alus)),
+        ])
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.station.restore(state.get("station")?, |inst| {
+            ArithInst::load(inst).map(|inst| inst as Box<dyn RenamedInst>)
+        })?;
+        let alus = state.get("alus")?.as_array()?;
+        if alus.len() != self.alus.len() {
+            let msg = format!(
+                "{} has {} ALUs, but the snapshot has {}",
+                self.name,
+                self.alus.len(),
+                alus.len()
+            );
+            return Err(msg);
+        }
+        let name = &self.name;
+        for (alu, state) in self.alus.iter_mut().zip(alus) {
+            alu.restore(state)
+                .map_err(|msg| format!("{}: {}", name, msg))?;
+        }
+        Ok(())
+    }
+    fn usage(&self) -> PathUsage {
+        let mut stalls = Vec::new();
+        let busy = self.executing().next().is_some();
+        if self.executing().any(|exec| exec.blocked) {
+            stalls.push(StallCause::ResultBusBusy);
+        } else if !busy && self.station.pending() > 0 {
+            // Nothing executing while instructions pending, none of them is ready
+            stalls.push(StallCause::WaitingOperands);
+        }
+        PathUsage {
+            occupied: self.station.occupied(),
+            capacity: self.station.capacity(),
+            busy,
+            stalls,
+        }
+    }
+}
+
+impl Graph for Unit {
+    fn get_graph(&self) -> String {
+        let station = format!("Reservation station ({})", self.station.capacity());
+        if let [alu] = self.alus.as_slice() {
+            let nodes = [("in", station), ("out", alu.label())];
+            return cluster(&self.name, &nodes, &[("in", "out")]);
+        }
+        // ALUs of a shared station drive the result bus in turn
+        let ids: Vec<String> = (0..self.alus.len())
+            .map(|idx| format!("alu{}", idx))
+            .collect();
+        let mut nodes = vec![("in", station), ("out", String::from("Results"))];
+        let mut edges = Vec::new();
+        for (id, alu) in ids.iter().zip(self.alus.iter()) {
+            nodes.push((id, alu.label()));
+            edges.push(("in", id.as_str()));
+            edges.push((id.as_str(), "out"));
+        }
+        cluster(&self.name, &nodes, &edges)
+    }
 }
 
 impl Display for Unit {
@@ -83,9 +228,19 @@ impl Display for Unit {
             .map(|slot| format!("{}", slot))
             .collect();
         writeln!(f, "{}", into_table("Reservation station", slots))?;
-        if let Some(exec) = self.exec.as_ref() {
-            let exec = exec.to_string();
-            let table = into_table("Executing", vec![exec]);
+        let exec: Vec<String> = self
+            .alus
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, alu)| {
+                alu.exec.iter().map(move |exec| match self.alus.len() {
+                    1 => exec.to_string(),
+                    _ => format!("ALU {}: {}", idx, exec),
+                })
+            })
+            .collect();
+        if !exec.is_empty() {
+            let table = into_table("Executing", exec);
             writeln!(f, "{table}")?;
         }
         Ok(())
@@ -94,15 +249,47 @@ impl Display for Unit {
 
 impl Unit {
     pub fn new(index: usize) -> Self {
+        Unit::with_station_size(index, STATION_SIZE)
+    }
+    /// Construct a unit whose reservation station holds `size` slots
+    pub fn with_station_size(index: usize, size: usize) -> Self {
         Self {
             name: format!("arith{}", index),
-            station: ReservationStation::new(5),
-            exec: None,
+            station: ReservationStation::new(size),
+            alus: vec![Alu::new(1, 1)],
+        }
+    }
+    /// Construct a unit whose reservation station holds `size` slots, shared by an ALU for each
+    /// (depth, interval) of `pipelines`
+    pub fn shared(size: usize, pipelines: &[(usize, usize)]) -> Self {
+        Self {
+            name: String::from("arith"),
+            station: ReservationStation::new(size),
+            alus: pipelines
+                .iter()
+                .map(|(depth, interval)| Alu::new(*depth, *interval))
+                .collect(),
         }
     }
-    /// Execute instruction in given slot.
+    /// Pipeline the ALUs, so each holds up to `depth` instructions and starts one every `interval` cycles.
+    /// The unpipelined ALU has depth 1 and interval 1.
+    pub fn pipelined(mut self, depth: usize, interval: usize) -> Self {
+        for alu in self.alus.iter_mut() {
+            *alu = Alu::new(depth, interval);
+        }
+        self
+    }
+    /// Return tag of the slot, which carries the thread of its instruction
+    fn tag(&self, idx: usize) -> RStag {
+        RStag::new(&self.name, idx).with_thread(self.station.thread(idx))
+    }
+    /// Return instructions in all ALUs
+    fn executing(&self) -> impl Iterator<Item = &ExecUnit> {
+        self.alus.iter().flat_map(|alu| alu.exec.iter())
+    }
+    /// Execute instruction in given slot on the ALU.
     /// On failed, error message returned.
-    fn execute(&mut self, slot_id: usize) -> Result<(), String> {
+    fn execute(&mut self, alu: usize, slot_id: usize) -> Result<(), String> {
         let slot = self
             .station
             .get_slot(slot_id)
@@ -118,8 +305,8 @@ impl Unit {
             };
             let arg0 = value_of(0)?;
             let arg1 = value_of(1)?;
-            let tag = RStag::new(&self.name(), slot_id);
-            self.exec = Some(ExecUnit::exec(tag, name.to_string(), arg0, arg1));
+            let tag = self.tag(slot_id);
+            self.alus[alu].start(ExecUnit::exec(tag, name.to_string(), arg0, arg1));
             self.station.start_execute(slot_id)?;
             Ok(())
         } else {
@@ -128,11 +315,81 @@ impl Unit {
     }
 }
 
+/// Pipeline executing instructions of a reservation station
+#[derive(Debug, Clone)]
+struct Alu {
+    /// Instructions in the pipeline, executing or waiting for the result bus, the oldest first
+    exec: Vec<ExecUnit>,
+    /// Instructions the pipeline holds at most
+    depth: usize,
+    /// Cycles between starting two instructions
+    interval: usize,
+    /// Cycles until the next instruction may start
+    cooldown: usize,
+}
+
+impl Alu {
+    fn new(depth: usize, interval: usize) -> Self {
+        Self {
+            exec: Vec::new(),
+            depth: depth.max(1),
+            interval: interval.max(1),
+            cooldown: 0,
+        }
+    }
+    fn label(&self) -> String {
+        if self.depth > 1 || self.interval > 1 {
+            format!("ALU (depth {}, interval {})", self.depth, self.interval)
+        } else {
+            String::from("ALU")
+        }
+    }
+    fn next_cycle(&mut self, bus: &mut ResultBus) {
+        // Done instructions queue for the result bus, the others keep going
+        self.exec.retain_mut(|unit| !unit.next_cycle(bus));
+        self.cooldown = self.cooldown.saturating_sub(1);
+    }
+    fn can_start(&self) -> bool {
+        self.exec.len() < self.depth && self.cooldown == 0
+    }
+    fn start(&mut self, exec: ExecUnit) {
+        self.exec.push(exec);
+        self.cooldown = self.interval;
+    }
+    fn save(&self) -> Json {
+        let exec = self.exec.iter().map(ExecUnit::save).collect();
+        Json::object(vec![
+            ("executing", Json::Array(exec)),
+            ("cooldown", Json::from(self.cooldown)),
+        ])
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.exec = state
+            .get("executing")?
+            .as_array()?
+            .iter()
+            .map(ExecUnit::load)
+            .collect::<Result<_, _>>()?;
+        if self.exec.len() > self.depth {
+            let msg = format!(
+                "{} instructions in a pipeline of depth {}",
+                self.exec.len(),
+                self.depth
+            );
+            return Err(msg);
+        }
+        self.cooldown = state.get("cooldown")?.as_usize()?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 struct ArithInst {
     name: String,
     arg0: ArgState,
     arg1: ArgState,
+    seq: u64,
+    thread: usize,
 }
 
 impl Display for ArithInst {
@@ -142,7 +399,12 @@ impl Display for ArithInst {
 }
 
 impl ArithInst {
-    fn new(name: String, renamed_args: &[ArgState]) -> Result<Box<Self>, String> {
+    fn new(
+        name: String,
+        renamed_args: &[ArgState],
+        seq: u64,
+        thread: usize,
+    ) -> Result<Box<Self>, String> {
         if renamed_args.len() != 2 {
             Err(format!("Expect 2 arguments, {} got", renamed_args.len()))
         } else {
@@ -150,9 +412,22 @@ impl ArithInst {
                 name,
                 arg0: renamed_args[0].clone(),
                 arg1: renamed_args[1].clone(),
+                seq,
+                thread,
             }))
         }
     }
+    fn load(state: &Json) -> Result<Box<Self>, String> {
+        let args = state
+            .get("args")?
+            .as_array()?
+            .iter()
+            .map(ArgState::load)
+            .collect::<Result<Vec<_>, _>>()?;
+        let seq = state.get("seq")?.as_u64()?;
+        let thread = state.get("thread")?.as_usize()?;
+        ArithInst::new(state.get("name")?.as_str()?.to_string(), &args, seq, thread)
+    }
 }
 
 impl RenamedInst for ArithInst {
@@ -170,14 +445,34 @@ impl RenamedInst for ArithInst {
         self.arg0.forwarding(tag, val);
         self.arg1.forwarding(tag, val);
     }
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+    fn thread(&self) -> usize {
+        self.thread
+    }
+    fn latency(&self) -> usize {
+        latency_of(&self.name)
+    }
+    fn save(&self) -> Json {
+        let args = vec![self.arg0.save(), self.arg1.save()];
+        Json::object(vec![
+            ("name", Json::str(&self.name)),
+            ("args", Json::Array(args)),
+            ("seq", Json::from(self.seq)),
+            ("thread", Json::from(self.thread)),
+        ])
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct ExecUnit {
     instruction: String,
     cycle: usize,
     tag: RStag,
     result: u32,
+    /// The result is done but the result bus is busy
+    blocked: bool,
 }
 
 impl Display for ExecUnit {
@@ -192,22 +487,44 @@ impl Display for ExecUnit {
 
 impl ExecUnit {
     fn exec(tag: RStag, inst: String, arg0: u32, arg1: u32) -> Self {
-        let (cycle, result) = match inst.as_str() {
-            "add" | "addi" => (1, arg0 + arg1),
-            _ => (0, 0),
+        let cycle = latency_of(&inst);
+        let result = match inst.as_str() {
+            "add" | "addi" => arg0.wrapping_add(arg1),
+            "mul" => arg0.wrapping_mul(arg1),
+            _ => 0,
         };
         Self {
             instruction: inst,
             cycle,
             tag,
             result,
+            blocked: false,
         }
     }
+    fn save(&self) -> Json {
+        Json::object(vec![
+            ("instruction", Json::str(&self.instruction)),
+            ("cycle", Json::from(self.cycle)),
+            ("tag", self.tag.save()),
+            ("result", Json::from(self.result)),
+            ("blocked", Json::from(self.blocked)),
+        ])
+    }
+    fn load(state: &Json) -> Result<Self, String> {
+        Ok(Self {
+            instruction: state.get("instruction")?.as_str()?.to_string(),
+            cycle: state.get("cycle")?.as_usize()?,
+            tag: RStag::load(state.get("tag")?)?,
+            result: state.get("result")?.as_u32()?,
+            blocked: state.get("blocked")?.as_bool()?,
+        })
+    }
     fn next_cycle(&mut self, bus: &mut ResultBus) -> bool {
         if self.cycle == 0 {
             let tag = self.tag.clone();
             let result = ExecResult::Arith(self.result);
-            bus.set(tag, result)
+            self.blocked = !bus.set(tag, result);
+            !self.blocked
         } else {
             self.cycle -= 1;
             false
@@ -18,6 +18,10 @@ pub enum MemFunction {
 pub struct Factory {
     index: HashMap<Function, usize>,
     mem_index: HashMap<MemFunction, usize>,
+    /// Slots of reservation stations of units constructed, defaults of each unit if None
+    station_size: Option<usize>,
+    /// (depth, interval) of pipelines of arithmetic units constructed
+    pipeline: (usize, usize),
 }
 
 impl Factory {
@@ -25,8 +29,19 @@ impl Factory {
         Self {
             index: HashMap::new(),
             mem_index: HashMap::new(),
+            station_size: None,
+            pipeline: (1, 1),
         }
     }
+    /// Reservation stations of units constructed afterward hold `size` slots
+    pub fn set_station_size(&mut self, size: usize) {
+        self.station_size = Some(size);
+    }
+    /// Arithmetic units constructed afterward hold up to `depth` instructions, and start one every
+    /// `interval` cycles
+    pub fn set_pipeline(&mut self, depth: usize, interval: usize) {
+        self.pipeline = (depth, interval);
+    }
     /// Generate a execution path by function type
     pub fn new_unit(&mut self, func: Function) -> Box<dyn ExecPath> {
         use Function::*;
@@ -37,8 +52,31 @@ impl Factory {
             self.index.insert(func, 0);
             0
         };
+        let (depth, interval) = self.pipeline;
+        match func {
+            Arithmetic => {
+                let unit = match self.station_size {
+                    Some(size) => arithmetic_unit::Unit::with_station_size(index, size),
+                    None => arithmetic_unit::Unit::new(index),
+                };
+                Box::new(unit.pipelined(depth, interval))
+            }
+        }
+    }
+    /// Generate an execution path whose reservation station is shared by a unit for each
+    /// (depth, interval) of `pipelines`.
+    /// The station holds as many slots as the units would hold together.
+    pub fn new_shared_unit(
+        &mut self,
+        func: Function,
+        pipelines: &[(usize, usize)],
+    ) -> Box<dyn ExecPath> {
         match func {
-            Arithmetic => Box::new(arithmetic_unit::Unit::new(index)),
+            Function::Arithmetic => {
+                let size = self.station_size.unwrap_or(arithmetic_unit::STATION_SIZE);
+                let size = size * pipelines.len();
+                Box::new(arithmetic_unit::Unit::shared(size, pipelines))
+            }
         }
     }
     pub fn new_mem_unit(&mut self, func: MemFunction) -> Box<dyn AccessPath> {
@@ -51,7 +89,10 @@ impl Factory {
             0
         };
         match func {
-            MemoryAccess => Box::new(memory_access_unit::Unit::new(index)),
+            MemoryAccess => match self.station_size {
+                Some(size) => Box::new(memory_access_unit::Unit::with_station_size(index, size)),
+                None => Box::new(memory_access_unit::Unit::new(index)),
+            },
         }
     }
 }
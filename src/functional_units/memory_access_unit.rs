@@ -1,3 +1,4 @@
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
 use std::ops::Range;
 
@@ -5,36 +6,53 @@ use crate::{
     core::{
         decoder::{InstFormat, TokenType},
         execution_path::{
-            AccessPath, ArgState, BusAccessRequst, BusAccessResult, ExecPath, ExecResult, RStag,
+            AccessPath, AmoOp, ArgState, BusAccessRequst, BusAccessResult, ExecPath, ExecResult,
+            InFlightAccess, RStag,
         },
+        pipeline::Phase,
         result_bus::ResultBus,
+        statistics::{PathUsage, StallCause},
     },
     display::into_table,
     functional_units::reservation_station::SlotState,
-    util::{queue::Queue, u32_to_raw_big_endian},
+    graph::{cluster, DependencyNode, Graph},
+    util::{json::Json, queue::Queue, raw_to_u32, u32_to_raw, Endian},
 };
 
 use super::reservation_station::{RenamedInst, ReservationStation};
+use super::select_policy::SelectPolicy;
 
 const FUNCTION_NAME: &str = "mem_access";
-const LOAD_STATION_SIZE: usize = 4;
-const STORE_STATION_SIZE: usize = 4;
+/// Default slots of each of the load and store stations
+const STATION_SIZE: usize = 4;
 const EVALUATION_LATENCY: usize = 1;
-const PENDING_CAPACITY: usize = LOAD_STATION_SIZE + STORE_STATION_SIZE;
 
 /// Used to indicate type of access request
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 enum AccessType {
     Load,
     Store,
 }
 
+/// Operation performed by a memory access instruction
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Load,
+    Store,
+    LoadReserved,
+    StoreConditional,
+    Amo(AmoOp),
+    /// Order all accesses before it and after it
+    Fence,
+}
+
 /// Check wheither address of two requests are overlaping or not
 fn access_overlap(a: &Range<u32>, b: &Range<u32>) -> bool {
     !(a.end <= b.start || a.start >= b.end)
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod overlap {
     use super::access_overlap;
 
@@ -78,40 +96,91 @@ mod overlap {
     }
 }
 
-fn get_access_len(identifier: char) -> usize {
-    match identifier {
-        'w' => 4,
-        _ => panic!("Unknow access len"),
+impl Operation {
+    /// Parse operation and access length from raw instruction
+    /// This function returns (operation, length) tuple,
+    /// or Err if the instruction isn't a memory access
+    fn parse(inst: &str) -> Result<(Self, usize), String> {
+        let op = match inst {
+            "lw" => Operation::Load,
+            "sw" => Operation::Store,
+            "lr.w" => Operation::LoadReserved,
+            "sc.w" => Operation::StoreConditional,
+            "amoadd.w" => Operation::Amo(AmoOp::Add),
+            "amoswap.w" => Operation::Amo(AmoOp::Swap),
+            "amomax.w" => Operation::Amo(AmoOp::Max),
+            "fence" => return Ok((Operation::Fence, 0)),
+            _ => return Err(format!("Undefined memory access {}", inst)),
+        };
+        Ok((op, 4))
     }
-}
-
-fn get_access_range(inst: &str, base: u32) -> Range<u32> {
-    let (_, len) = AccessType::parse(inst);
-    base..base + len as u32
-}
-
-impl AccessType {
-    /// Parse access type and length from raw instruction
-    /// This function returns (access type, length) tuple
+    /// Parse an instruction accepted by the unit, see [Operation::parse]
     ///
     /// # panics
     ///
     /// The function will panic once it's unable to parse the given instruction
-    fn parse(inst: &str) -> (Self, usize) {
-        let mut chars = inst.chars();
-        let type_identifier = chars.next().expect("Missing access type");
-        let ty = match type_identifier {
-            'l' => AccessType::Load,
-            's' => AccessType::Store,
-            _ => panic!("Undefined access type {}", type_identifier),
-        };
-        let len_identifier = chars.next().expect("Missing access length");
-        let len = get_access_len(len_identifier);
-        (ty, len)
+    fn of(inst: &str) -> (Self, usize) {
+        Self::parse(inst).unwrap_or_else(|msg| panic!("{}", msg))
+    }
+    /// Return type of station which the operation is issued to.
+    /// Only plain and reserved loads go to the load station,
+    /// accesses which modify memory and fences go to the store station.
+    fn access_type(&self) -> AccessType {
+        match self {
+            Operation::Load | Operation::LoadReserved => AccessType::Load,
+            _ => AccessType::Store,
+        }
+    }
+    /// Whether the operation sets or consumes the reservation of the path
+    fn reserves(&self) -> bool {
+        matches!(self, Operation::LoadReserved | Operation::StoreConditional)
+    }
+    /// Check wheither the operation on `target` has to wait for a previous access.
+    /// A range is None if the access may touch any address, see [evaluated_range].
+    fn ordered_after(
+        &self,
+        target: Option<&Range<u32>>,
+        previous_op: Operation,
+        previous: Option<&Range<u32>>,
+    ) -> bool {
+        if *self == Operation::Fence || previous_op == Operation::Fence {
+            return true;
+        }
+        if self.reserves() && previous_op.reserves() {
+            return true;
+        }
+        // Loads never wait for loads
+        if self.access_type() == AccessType::Load && previous_op.access_type() == AccessType::Load {
+            return false;
+        }
+        match (previous, target) {
+            (Some(previous), Some(target)) => access_overlap(previous, target),
+            _ => true,
+        }
+    }
+}
+
+/// Return access range of the instruction from `base`.
+/// If the access runs past the end of the address space, None returned,
+/// and the bus faults once the access reaches it.
+fn get_access_range(inst: &str, base: u32) -> Option<Range<u32>> {
+    let (_, len) = Operation::of(inst);
+    base.checked_add(len as u32).map(|end| base..end)
+}
+
+/// Return access range of an instruction whose address is evaluated.
+/// Otherwise, or if the range can't be represented, None returned as it may access any address.
+fn evaluated_range(inst: &dyn RenamedInst) -> Option<Range<u32>> {
+    let args = inst.arguments();
+    match args.last() {
+        Some(ArgState::Ready(base)) => get_access_range(inst.command(), *base),
+        // Fence accesses no memory
+        None => Some(0..0),
+        Some(ArgState::Waiting(_)) => None,
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Memory address which is going to access
 enum MemAddress {
     /// The address has evaluated to exact number
@@ -163,15 +232,34 @@ impl MemAddress {
             }
         }
     }
+    fn save(&self) -> Json {
+        match self {
+            MemAddress::Evaluated(address) => {
+                Json::object(vec![("evaluated", Json::from(*address))])
+            }
+            MemAddress::Evaluating(base, offset) => {
+                Json::object(vec![("base", base.save()), ("offset", Json::from(*offset))])
+            }
+        }
+    }
+    fn load(state: &Json) -> Result<Self, String> {
+        if let Ok(address) = state.get("evaluated") {
+            return Ok(MemAddress::Evaluated(address.as_u32()?));
+        }
+        let base = ArgState::load(state.get("base")?)?;
+        Ok(MemAddress::Evaluating(base, state.get("offset")?.as_u32()?))
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Arguments of different kinds of memory access instruction
 enum AccessArgs {
     /// Load(Base address)
     Load(MemAddress),
     /// Store(Value, Base address)
     Store(ArgState, MemAddress),
+    /// Fence has no argument
+    Fence,
 }
 
 impl Display for AccessArgs {
@@ -183,16 +271,25 @@ impl Display for AccessArgs {
             AccessArgs::Store(value, address) => {
                 write!(f, "{}; {}", value, address)
             }
+            AccessArgs::Fence => write!(f, "Fence"),
         }
     }
 }
 
 impl AccessArgs {
-    /// Construct AccessArgs by given type and arguments
-    fn new(access_type: AccessType, renamed_args: &[ArgState]) -> Self {
-        match access_type {
-            AccessType::Load => AccessArgs::new_load(renamed_args),
-            AccessType::Store => AccessArgs::new_store(renamed_args),
+    /// Construct AccessArgs by given operation and arguments
+    fn new(op: Operation, renamed_args: &[ArgState]) -> Self {
+        match op {
+            Operation::Load => AccessArgs::new_load(renamed_args),
+            Operation::Store => AccessArgs::new_store(renamed_args),
+            Operation::Fence => AccessArgs::Fence,
+            // Atomic accesses take no offset, treat it as zero
+            Operation::LoadReserved => {
+                AccessArgs::new_load(&[renamed_args, &[ArgState::Ready(0)]].concat())
+            }
+            Operation::StoreConditional | Operation::Amo(_) => {
+                AccessArgs::new_store(&[renamed_args, &[ArgState::Ready(0)]].concat())
+            }
         }
     }
     /// Construct a new `AccessArgs::Load` by given arguments
@@ -237,12 +334,14 @@ impl AccessArgs {
                 src.forwarding(tag, val);
                 dest.forwarding(tag, val);
             }
+            AccessArgs::Fence => (),
         }
     }
     /// Return all `ArgState` including in the object
     /// The order of returned vector is:
     /// For Load: [Base]
     /// For Store: [Value, Base]
+    /// For Fence: []
     fn arguments(&self) -> Vec<ArgState> {
         match self {
             AccessArgs::Load(src) => src.arguments(),
@@ -251,6 +350,7 @@ impl AccessArgs {
                 args.append(&mut dest.arguments());
                 args
             }
+            AccessArgs::Fence => vec![],
         }
     }
     /// Update base address to evaluated value
@@ -258,24 +358,46 @@ impl AccessArgs {
         let address = match self {
             AccessArgs::Load(address) => address,
             AccessArgs::Store(_, address) => address,
+            AccessArgs::Fence => return,
         };
         if let MemAddress::Evaluating(_, _) = address {
             *address = MemAddress::Evaluated(base);
         }
     }
+    /// Fence is saved as null
+    fn save(&self) -> Json {
+        match self {
+            AccessArgs::Load(address) => Json::object(vec![("address", address.save())]),
+            AccessArgs::Store(value, address) => {
+                Json::object(vec![("value", value.save()), ("address", address.save())])
+            }
+            AccessArgs::Fence => Json::Null,
+        }
+    }
+    fn load(state: &Json) -> Result<Self, String> {
+        if state.opt().is_none() {
+            return Ok(AccessArgs::Fence);
+        }
+        let address = MemAddress::load(state.get("address")?)?;
+        match state.get("value") {
+            Ok(value) => Ok(AccessArgs::Store(ArgState::load(value)?, address)),
+            Err(_) => Ok(AccessArgs::Load(address)),
+        }
+    }
     /// (base, offset) returned if base address is ready
     /// Otherwise, None returned
     fn ready_for_evaluation(&self) -> Option<(u32, u32)> {
         let address = match self {
             AccessArgs::Load(address) => address,
             AccessArgs::Store(_, address) => address,
+            AccessArgs::Fence => return Some((0, 0)),
         };
         address.ready_for_evaluation()
     }
 }
 
 /// Instruction of memory access
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct AccessInst {
     /// Name of the instruction
     name: String,
@@ -285,6 +407,10 @@ struct AccessInst {
     /// For instance: there is an instruction which is accessing the same acccess as the current instruction
     /// To make sure this memory access will followed by the maintioned one, add it to the dependency of this instruction
     dependencies: Vec<RStag>,
+    /// Sequence number given on issue
+    seq: u64,
+    /// Hardware thread which issued the access
+    thread: usize,
 }
 
 impl Display for AccessInst {
@@ -299,22 +425,21 @@ impl Display for AccessInst {
 
 impl AccessInst {
     /// Construct a new AccessInst by name and arguments
-    fn new(name: String, renamed_args: &[ArgState]) -> Self {
-        let (access_type, _) = AccessType::parse(&name);
-        let args = AccessArgs::new(access_type, renamed_args);
+    fn new(name: String, renamed_args: &[ArgState], seq: u64, thread: usize) -> Self {
+        let (op, _) = Operation::of(&name);
+        let args = AccessArgs::new(op, renamed_args);
         Self {
             name,
             args,
             dependencies: vec![],
+            seq,
+            thread,
         }
     }
-    // TODO: update comment
-    /// Get access type of the instruction
+    /// Get type of station which the instruction is issued to
     fn access_type(&self) -> AccessType {
-        match self.args {
-            AccessArgs::Load(_) => AccessType::Load,
-            AccessArgs::Store(_, _) => AccessType::Store,
-        }
+        let (op, _) = Operation::of(&self.name);
+        op.access_type()
     }
     /// Check wheither the instruction is free from dependencies or not
     /// If there finds no dependency, return true
@@ -334,6 +459,23 @@ impl AccessInst {
     fn ready_for_evaluation(&self) -> Option<(u32, u32)> {
         self.args.ready_for_evaluation()
     }
+    fn load(state: &Json) -> Result<Self, String> {
+        let name = state.get("name")?.as_str()?;
+        Operation::parse(name)?;
+        let dependencies = state
+            .get("dependencies")?
+            .as_array()?
+            .iter()
+            .map(RStag::load)
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            name: name.to_string(),
+            args: AccessArgs::load(state.get("args")?)?,
+            dependencies,
+            seq: state.get("seq")?.as_u64()?,
+            thread: state.get("thread")?.as_usize()?,
+        })
+    }
 }
 
 impl RenamedInst for AccessInst {
@@ -356,6 +498,15 @@ impl RenamedInst for AccessInst {
             self.dependencies.remove(idx);
         }
     }
+    fn ordering(&self) -> Vec<RStag> {
+        self.dependencies.clone()
+    }
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+    fn thread(&self) -> usize {
+        self.thread
+    }
     fn is_ready(&self) -> bool {
         if !self.dependency_free() {
             return false;
@@ -367,9 +518,20 @@ impl RenamedInst for AccessInst {
             .count();
         waiting == 0
     }
+    fn save(&self) -> Json {
+        let dependencies = self.dependencies.iter().map(RStag::save).collect();
+        Json::object(vec![
+            ("name", Json::str(&self.name)),
+            ("args", self.args.save()),
+            ("dependencies", Json::Array(dependencies)),
+            ("seq", Json::from(self.seq)),
+            ("thread", Json::from(self.thread)),
+        ])
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod access_instruction {
     use super::*;
     #[test]
@@ -377,7 +539,7 @@ mod access_instruction {
         let base = RStag::new("base", 10);
         let args = [ArgState::Waiting(base.clone()), ArgState::Ready(10)];
         let inst_name = String::from("lw");
-        let mut inst = AccessInst::new(inst_name, &args);
+        let mut inst = AccessInst::new(inst_name, &args, 0, 0);
 
         assert_eq!(false, inst.is_ready());
 
@@ -395,7 +557,7 @@ mod access_instruction {
             ArgState::Ready(10),
         ];
         let inst_name = String::from("sw");
-        let mut inst = AccessInst::new(inst_name, &args);
+        let mut inst = AccessInst::new(inst_name, &args, 0, 0);
 
         assert_eq!(false, inst.is_ready());
 
@@ -407,9 +569,27 @@ mod access_instruction {
 
         assert_eq!(true, inst.is_ready());
     }
+    #[test]
+    fn forward_within_thread() -> Result<(), String> {
+        let base = RStag::new("base", 10).with_thread(1);
+        let args = [ArgState::Waiting(base.clone()), ArgState::Ready(10)];
+        let mut inst = AccessInst::new(String::from("lw"), &args, 0, 1);
+
+        // The result of the same slot for another thread is not taken
+        inst.forward(&RStag::new("base", 10), 10);
+        assert_eq!(false, inst.is_ready());
+
+        let restored = AccessInst::load(&inst.save())?;
+        assert_eq!(restored.arguments(), [ArgState::Waiting(base.clone())]);
+        assert_eq!(restored.thread(), 1);
+
+        inst.forward(&base, 10);
+        assert_eq!(true, inst.is_ready());
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct EvaluationUnit {
     // Remaining clock cycle to evaluated
     remain_cycle: usize,
@@ -422,7 +602,7 @@ impl EvaluationUnit {
     fn exec(_inst: String, base: u32, offset: u32) -> Self {
         Self {
             remain_cycle: EVALUATION_LATENCY,
-            result: base + offset,
+            result: base.wrapping_add(offset),
         }
     }
     /// Execute next clock cycle for evaluation unit
@@ -438,7 +618,7 @@ impl EvaluationUnit {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Unit {
     name: String,
     /// (logical slot id, evaluating instruction)
@@ -446,22 +626,32 @@ pub struct Unit {
     evaluating: Option<EvaluationUnit>,
     load_station: ReservationStation,
     store_station: ReservationStation,
-    /// (logical slot id, execution result)
-    result: Option<(usize, ExecResult)>,
+    /// (logical slot id, execution result) waiting for the result bus, the earliest first
+    results: VecDeque<(usize, ExecResult)>,
+    /// Byte order of memory
+    endian: Endian,
+    /// Accesses in flight in the other access paths, given every cycle
+    others: Vec<InFlightAccess>,
 }
 
 impl Unit {
     pub fn new(idx: usize) -> Self {
+        Unit::with_station_size(idx, STATION_SIZE)
+    }
+    /// Construct a unit whose load and store stations hold `size` slots each
+    pub fn with_station_size(idx: usize, size: usize) -> Self {
         Self {
             name: format!("{}{}", FUNCTION_NAME, idx),
-            evaluation_queue: Queue::new(PENDING_CAPACITY),
+            evaluation_queue: Queue::new(size * 2),
             evaluating: None,
-            load_station: ReservationStation::new(LOAD_STATION_SIZE),
-            store_station: ReservationStation::new(STORE_STATION_SIZE),
-            result: None,
+            load_station: ReservationStation::new(size),
+            store_station: ReservationStation::new(size),
+            results: VecDeque::new(),
+            endian: Endian::Big,
+            others: Vec::new(),
         }
     }
-    fn physical_slot_id_to_logical(phy_id: usize, access_type: AccessType) -> usize {
+    fn physical_slot_id_to_logical(&self, phy_id: usize, access_type: AccessType) -> usize {
         /* In register renaming, both load and store stations in a access unit shared a same slot index space.
          * The mapping policy from physical to logical id is:
          * Load => logical id = physical id
@@ -469,12 +659,28 @@ impl Unit {
          */
         match access_type {
             AccessType::Load => phy_id,
-            AccessType::Store => LOAD_STATION_SIZE + phy_id,
+            AccessType::Store => self.load_station.capacity() + phy_id,
         }
     }
-    fn logical_slot_id_to_physical(logical_id: usize) -> (AccessType, usize) {
-        if logical_id >= LOAD_STATION_SIZE {
-            (AccessType::Store, logical_id - LOAD_STATION_SIZE)
+    /// Return tag of the logical slot, which carries the thread of its instruction
+    fn tag(&self, logical_id: usize) -> RStag {
+        let (access_type, phy_id) = self.logical_slot_id_to_physical(logical_id);
+        let station = match access_type {
+            AccessType::Load => &self.load_station,
+            AccessType::Store => &self.store_station,
+        };
+        // The slot is reserved while its instruction is in the evaluation queue
+        let thread = self
+            .evaluation_queue
+            .into_iter()
+            .find(|(id, inst)| *id == phy_id && inst.access_type() == access_type)
+            .map_or_else(|| station.thread(phy_id), |(_, inst)| inst.thread);
+        RStag::new(&self.name, logical_id).with_thread(thread)
+    }
+    fn logical_slot_id_to_physical(&self, logical_id: usize) -> (AccessType, usize) {
+        let load_capacity = self.load_station.capacity();
+        if logical_id >= load_capacity {
+            (AccessType::Store, logical_id - load_capacity)
         } else {
             (AccessType::Load, logical_id)
         }
@@ -486,26 +692,42 @@ impl Unit {
     }
     /// Check and list pending accesses which with access range overlaping with the given range
     /// This function return a vector of RStag of access range overlaping pending instruction
-    fn dependency_check(&self, access_type: AccessType, target: Range<u32>) -> Vec<RStag> {
+    fn dependency_check(&self, op: Operation, target: Option<Range<u32>>, seq: u64) -> Vec<RStag> {
         /*
          * Type of dependencies:
          * - Load after Store
          * - Store after Load
          * - Store after Store
-         * Therefore, for load access we check store station only.
+         * - Any access after Fence
+         * - Fence after any access
+         * - Reserved access after Reserved access, since the thread holds only one reservation
+         * Therefore, for load access we check store station only, except for reserved loads.
          * In the other hand, both stations have to be checked in store request.
+         * Atomic accesses modify memory, so they are checked as stores.
+         * Older accesses of other paths are checked by the same rules.
          */
-        let mut dependencies = self.dependency_check_of_station(AccessType::Store, &target);
-        if let AccessType::Store = access_type {
-            let mut load_dependencies = self.dependency_check_of_station(AccessType::Load, &target);
+        let target = target.as_ref();
+        let mut dependencies = self.dependency_check_of_station(AccessType::Store, target, op);
+        if op.access_type() == AccessType::Store || op.reserves() {
+            let mut load_dependencies =
+                self.dependency_check_of_station(AccessType::Load, target, op);
             dependencies.append(&mut load_dependencies);
         }
+        // Older accesses of other paths may still be evaluating, which may access any address
+        for other in self.others.iter().filter(|other| other.seq < seq) {
+            let (previous_op, _) = Operation::of(&other.command);
+            if op.ordered_after(target, previous_op, other.range.as_ref()) {
+                dependencies.push(other.tag.clone());
+            }
+        }
         dependencies
     }
+    /// Return pending accesses of the station which `op` on `target` has to wait for
     fn dependency_check_of_station(
         &self,
         access_type: AccessType,
-        target: &Range<u32>,
+        target: Option<&Range<u32>>,
+        op: Operation,
     ) -> Vec<RStag> {
         let station = match access_type {
             AccessType::Load => &self.load_station,
@@ -513,15 +735,16 @@ impl Unit {
         };
         let mut dependencies = Vec::new();
         for (phy_id, slot) in station.into_iter().enumerate() {
-            if let SlotState::Pending(inst) = slot {
-                let args = inst.arguments();
-                let base = args.last().expect("Base address not found");
-                if let ArgState::Ready(base) = base {
-                    let previous = get_access_range(inst.command(), *base);
-                    let log_id = Unit::physical_slot_id_to_logical(phy_id, access_type);
-                    if access_overlap(&previous, target) {
-                        dependencies.push(RStag::new(&self.name, log_id));
-                    }
+            /*
+             * Executing accesses are waiting for the bus.
+             * Since bus arbitration may reorder requests, they are dependencies as well.
+             */
+            if let SlotState::Pending(inst) | SlotState::Executing(inst) = slot {
+                let log_id = self.physical_slot_id_to_logical(phy_id, access_type);
+                let (previous_op, _) = Operation::of(inst.command());
+                let previous = evaluated_range(&**inst);
+                if op.ordered_after(target, previous_op, previous.as_ref()) {
+                    dependencies.push(self.tag(log_id));
                 }
             }
         }
@@ -539,9 +762,10 @@ impl Unit {
             .pop()
             .ok_or_else(||String::from("Expect instruction in evaluating queue while issuing instruction to reservation station"))?;
 
-        let (access_type, len) = AccessType::parse(issuing.command());
-        let access_range = evaluated_base..evaluated_base + len as u32;
-        let dependiencies = self.dependency_check(access_type, access_range);
+        let (op, _) = Operation::of(issuing.command());
+        let access_type = op.access_type();
+        let access_range = get_access_range(issuing.command(), evaluated_base);
+        let dependiencies = self.dependency_check(op, access_range, issuing.seq);
 
         issuing.evaluated(evaluated_base, dependiencies);
 
@@ -553,7 +777,32 @@ impl Unit {
         let issuing = Box::new(issuing) as Box<dyn RenamedInst>;
         station
             .insert_into_reserved_slot(issuing, reserved_id)
-            .map(|phy_id| Unit::physical_slot_id_to_logical(phy_id, access_type))
+            .map(|phy_id| self.physical_slot_id_to_logical(phy_id, access_type))
+    }
+}
+
+impl Unit {
+    /// Return wheither the ready slot of the station is a fence or not
+    fn fence_ready(station: &ReservationStation) -> bool {
+        let inst = station.ready().and_then(|id| match station.get_slot(id) {
+            Some(SlotState::Pending(inst)) => Some(inst),
+            _ => None,
+        });
+        inst.is_some_and(|inst| Operation::of(inst.command()).0 == Operation::Fence)
+    }
+    /// A fence accesses no memory.
+    /// Once all accesses before it completed, it completes without the bus.
+    fn complete_fence(&mut self) {
+        if !Unit::fence_ready(&self.store_station) {
+            return;
+        }
+        if let Some(slot_id) = self.store_station.ready() {
+            self.store_station
+                .start_execute(slot_id)
+                .unwrap_or_else(|msg| panic!("{}", msg));
+            let logical_id = self.physical_slot_id_to_logical(slot_id, AccessType::Store);
+            self.results.push_back((logical_id, ExecResult::MemStore));
+        }
     }
 }
 
@@ -576,6 +825,31 @@ impl ExecPath for Unit {
                 .add_syntax(TokenType::Register)
                 .add_syntax(TokenType::Immediate)
                 .done(),
+            InstFormat::create("lr.w")
+                .add_syntax(TokenType::Writeback)
+                .add_syntax(TokenType::Register)
+                .done(),
+            InstFormat::create("sc.w")
+                .add_syntax(TokenType::Writeback)
+                .add_syntax(TokenType::Register)
+                .add_syntax(TokenType::Register)
+                .done(),
+            InstFormat::create("amoadd.w")
+                .add_syntax(TokenType::Writeback)
+                .add_syntax(TokenType::Register)
+                .add_syntax(TokenType::Register)
+                .done(),
+            InstFormat::create("amoswap.w")
+                .add_syntax(TokenType::Writeback)
+                .add_syntax(TokenType::Register)
+                .add_syntax(TokenType::Register)
+                .done(),
+            InstFormat::create("amomax.w")
+                .add_syntax(TokenType::Writeback)
+                .add_syntax(TokenType::Register)
+                .add_syntax(TokenType::Register)
+                .done(),
+            InstFormat::create("fence").done(),
         ]
     }
     fn forward(&mut self, tag: RStag, val: u32) {
@@ -584,7 +858,7 @@ impl ExecPath for Unit {
         // If the forwarding result comes from local, reslove and free the corresponding reservation station slot
         if self.name == inst_src {
             let logical_id = tag.slot();
-            let (acc_type, phy_id) = Unit::logical_slot_id_to_physical(logical_id);
+            let (acc_type, phy_id) = self.logical_slot_id_to_physical(logical_id);
             match acc_type {
                 AccessType::Load => self.load_station.sloved(phy_id),
                 AccessType::Store => self.store_station.sloved(phy_id),
@@ -595,11 +869,17 @@ impl ExecPath for Unit {
         self.load_station.forward(&tag, val);
         self.store_station.forward(&tag, val)
     }
-    fn try_issue(&mut self, inst: String, vals: &[ArgState]) -> Result<RStag, ()> {
+    fn try_issue(
+        &mut self,
+        inst: String,
+        vals: &[ArgState],
+        seq: u64,
+        thread: usize,
+    ) -> Result<RStag, ()> {
         if self.evaluation_queue.is_full() {
             return Err(());
         }
-        let inst = AccessInst::new(inst, vals);
+        let inst = AccessInst::new(inst, vals, seq, thread);
         let access_type = inst.access_type();
         let issue_dest = match access_type {
             AccessType::Load => &mut self.load_station,
@@ -620,8 +900,8 @@ impl ExecPath for Unit {
             .insert((phy_id, inst))
             .expect("Evaluating queue never overflow");
 
-        let logical_slot_id = Unit::physical_slot_id_to_logical(phy_id, access_type);
-        Ok(RStag::new(&self.name, logical_slot_id))
+        let logical_slot_id = self.physical_slot_id_to_logical(phy_id, access_type);
+        Ok(RStag::new(&self.name, logical_slot_id).with_thread(thread))
     }
     fn next_cycle(&mut self, bus: &mut ResultBus) -> Result<(), String> {
         if let Some(evaluating) = &mut self.evaluating {
@@ -637,10 +917,12 @@ impl ExecPath for Unit {
                 self.evaluating = Some(evaluation);
             }
         }
+        if self.results.is_empty() {
+            self.complete_fence();
+        }
         if bus.is_free() {
-            if let Some((logical_id, result)) = self.result.take() {
-                let tag = RStag::new(&self.name, logical_id);
-                bus.set(tag, result);
+            if let Some((logical_id, result)) = self.results.pop_front() {
+                bus.set(self.tag(logical_id), result);
             }
         }
         Ok(())
@@ -648,12 +930,219 @@ impl ExecPath for Unit {
     fn pending(&self) -> usize {
         self.load_station.pending() + self.store_station.pending()
     }
+    fn set_select_policy(&mut self, policy: Box<dyn SelectPolicy>) {
+        self.load_station.set_policy(policy.clone());
+        self.store_station.set_policy(policy);
+    }
+    fn set_critical_paths(&mut self, lengths: &HashMap<RStag, usize>) {
+        let capacity = self.load_station.capacity() + self.store_station.capacity();
+        let (load, store): (Vec<_>, Vec<_>) = (0..capacity)
+            .map(|log_id| {
+                let tag = self.tag(log_id);
+                let length = lengths.get(&tag).copied().unwrap_or_default();
+                (self.logical_slot_id_to_physical(log_id).0, length)
+            })
+            .partition(|(access_type, _)| *access_type == AccessType::Load);
+        self.load_station
+            .set_critical_paths(load.into_iter().map(|(_, length)| length).collect());
+        self.store_station
+            .set_critical_paths(store.into_iter().map(|(_, length)| length).collect());
+    }
     fn is_idle(&self) -> bool {
         let evaluating = !self.evaluation_queue.is_empty();
         let loading = self.load_station.occupied() != 0;
         let storing = self.store_station.occupied() != 0;
         !(evaluating || loading || storing)
     }
+    fn usage(&self) -> PathUsage {
+        let executing = self.load_station.executing() + self.store_station.executing();
+        let mut stalls = Vec::new();
+        if !self.results.is_empty() {
+            stalls.push(StallCause::ResultBusBusy);
+        } else if executing > 0 {
+            stalls.push(StallCause::MemoryBusy);
+        }
+        let evaluation_waiting = self.evaluating.is_none()
+            && self
+                .evaluation_queue
+                .head()
+                .is_some_and(|(_, inst)| inst.ready_for_evaluation().is_none());
+        let station_waiting = self.pending() > 0
+            && self.load_station.ready().is_none()
+            && self.store_station.ready().is_none();
+        if evaluation_waiting || (station_waiting && executing == 0) {
+            stalls.push(StallCause::WaitingOperands);
+        }
+        PathUsage {
+            occupied: self.load_station.occupied() + self.store_station.occupied(),
+            capacity: self.evaluation_queue.capacity(),
+            busy: self.evaluating.is_some() || executing > 0 || !self.results.is_empty(),
+            stalls,
+        }
+    }
+    fn trace(&self) -> Json {
+        let evaluation = self
+            .evaluation_queue
+            .into_iter()
+            .map(|(slot, inst)| {
+                Json::object(vec![("slot", Json::from(*slot)), ("inst", Json::str(inst))])
+            })
+            .collect();
+        Json::object(vec![
+            ("evaluation", Json::Array(evaluation)),
+            ("load", self.load_station.trace()),
+            ("store", self.store_station.trace()),
+        ])
+    }
+    fn dependencies(&self) -> Vec<DependencyNode> {
+        let node = |log_id: usize, inst: &dyn RenamedInst| {
+            let tag = self.tag(log_id);
+            DependencyNode::new(tag, inst.to_string(), &inst.arguments(), inst.ordering())
+        };
+        let evaluation = self.evaluation_queue.into_iter().map(|(phy_id, inst)| {
+            let log_id = self.physical_slot_id_to_logical(*phy_id, inst.access_type());
+            node(log_id, inst)
+        });
+        let load = self
+            .load_station
+            .instructions()
+            .into_iter()
+            .map(|(phy_id, inst)| node(phy_id, inst));
+        let store = self
+            .store_station
+            .instructions()
+            .into_iter()
+            .map(|(phy_id, inst)| {
+                let log_id = self.physical_slot_id_to_logical(phy_id, AccessType::Store);
+                node(log_id, inst)
+            });
+        evaluation.chain(load).chain(store).collect()
+    }
+    /// Accesses in the evaluation queue are ready once their base address is ready,
+    /// and accesses whose result waits for the result bus are done
+    fn phases(&self) -> Vec<(RStag, Phase)> {
+        let evaluation = self.evaluation_queue.into_iter().map(|(phy_id, inst)| {
+            let log_id = self.physical_slot_id_to_logical(*phy_id, inst.access_type());
+            let phase = match inst.ready_for_evaluation() {
+                Some(_) => Phase::Ready,
+                None => Phase::Waiting,
+            };
+            (log_id, phase)
+        });
+        let load = self.load_station.phases().into_iter();
+        let store = self
+            .store_station
+            .phases()
+            .into_iter()
+            .map(|(phy_id, phase)| {
+                let log_id = self.physical_slot_id_to_logical(phy_id, AccessType::Store);
+                (log_id, phase)
+            });
+        evaluation
+            .chain(load)
+            .chain(store)
+            .map(|(log_id, phase)| {
+                let done = self.results.iter().any(|(slot, _)| *slot == log_id);
+                let phase = if done { Phase::Done } else { phase };
+                (self.tag(log_id), phase)
+            })
+            .collect()
+    }
+    fn issue_stall(&self) -> StallCause {
+        if self.evaluation_queue.is_full() {
+            StallCause::QueueFull
+        } else {
+            StallCause::StationFull
+        }
+    }
+    fn save(&self) -> Json {
+        let evaluation = self
+            .evaluation_queue
+            .into_iter()
+            .map(|(slot, inst)| {
+                Json::object(vec![("slot", Json::from(*slot)), ("inst", inst.save())])
+            })
+            .collect();
+        let evaluating = self.evaluating.as_ref().map_or(Json::Null, |unit| {
+            Json::object(vec![
+                ("remain_cycle", Json::from(unit.remain_cycle)),
+                ("result", Json::from(unit.result)),
+            ])
+        });
+        let results = self
+            .results
+            .iter()
+            .map(|(slot, result)| {
+                Json::object(vec![("slot", Json::from(*slot)), ("result", result.save())])
+            })
+            .collect();
+        Json::object(vec![
+            ("evaluation", Json::Array(evaluation)),
+            ("evaluating", evaluating),
+            ("load", self.load_station.save()),
+            ("store", self.store_station.save()),
+            ("results", Json::Array(results)),
+        ])
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        let load = |inst: &Json| -> Result<Box<dyn RenamedInst>, String> {
+            Ok(Box::new(AccessInst::load(inst)?))
+        };
+        let mut evaluation_queue = Queue::new(self.evaluation_queue.capacity());
+        for entry in state.get("evaluation")?.as_array()? {
+            let slot = entry.get("slot")?.as_usize()?;
+            evaluation_queue.insert((slot, AccessInst::load(entry.get("inst")?)?))?;
+        }
+        self.evaluation_queue = evaluation_queue;
+        self.evaluating = match state.get("evaluating")?.opt() {
+            Some(unit) => Some(EvaluationUnit {
+                remain_cycle: unit.get("remain_cycle")?.as_usize()?,
+                result: unit.get("result")?.as_u32()?,
+            }),
+            None => None,
+        };
+        self.load_station.restore(state.get("load")?, load)?;
+        self.store_station.restore(state.get("store")?, load)?;
+        self.results = state
+            .get("results")?
+            .as_array()?
+            .iter()
+            .map(|result| -> Result<_, String> {
+                let slot = result.get("slot")?.as_usize()?;
+                Ok((slot, ExecResult::load(result.get("result")?)?))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+}
+
+impl Graph for Unit {
+    fn get_graph(&self) -> String {
+        let nodes = [
+            (
+                "in",
+                format!("Evaluation queue ({})", self.evaluation_queue.capacity()),
+            ),
+            ("evaluation", String::from("Address evaluation")),
+            (
+                "load",
+                format!("Load station ({})", self.load_station.capacity()),
+            ),
+            (
+                "store",
+                format!("Store station ({})", self.store_station.capacity()),
+            ),
+            ("out", String::from("Access port")),
+        ];
+        let edges = [
+            ("in", "evaluation"),
+            ("evaluation", "load"),
+            ("evaluation", "store"),
+            ("load", "out"),
+            ("store", "out"),
+        ];
+        cluster(&self.name, &nodes, &edges)
+    }
 }
 
 impl Display for Unit {
@@ -676,16 +1165,17 @@ impl Display for Unit {
 impl AccessPath for Unit {
     fn request(&mut self) -> Option<BusAccessRequst> {
         let path = self.name();
+        let endian = self.endian;
 
         let stations = vec![
-            (&mut self.load_station, AccessType::Load),
-            (&mut self.store_station, AccessType::Store),
+            (&self.load_station, AccessType::Load),
+            (&self.store_station, AccessType::Store),
         ];
 
         // Filter out stations that has no ready slot
-        let mut stations: Vec<(&mut ReservationStation, AccessType)> = stations
+        let mut stations: Vec<(&ReservationStation, AccessType)> = stations
             .into_iter()
-            .filter(|(s, _)| s.ready().is_some())
+            .filter(|(s, _)| s.ready().is_some() && !Unit::fence_ready(s))
             .collect();
 
         /*
@@ -698,14 +1188,15 @@ impl AccessPath for Unit {
         let (station, access_type) = stations.pop()?;
 
         let slot_id = station.ready()?;
-        let logical_id = Unit::physical_slot_id_to_logical(slot_id, access_type);
+        let logical_id = self.physical_slot_id_to_logical(slot_id, access_type);
         let slot = station.get_slot(slot_id)?;
         if let SlotState::Pending(inst) = slot {
-            let (access_type, len) = AccessType::parse(inst.command());
+            let (op, len) = Operation::of(inst.command());
+            let thread = inst.thread();
             /*
              * Argument format of instructions are:
-             * - lw: [address]
-             * - sw: [value, address]
+             * - lw, lr.w: [address]
+             * - sw, sc.w, amo*.w: [value, address]
              */
             let args: Vec<u32> = inst
                 .arguments()
@@ -718,22 +1209,39 @@ impl AccessPath for Unit {
                 })
                 .collect();
 
-            let request = match access_type {
-                AccessType::Load => {
-                    let address = args.get(0).expect("Address not found");
-                    BusAccessRequst::new_load(path, logical_id, *address, len)
+            let request = match op {
+                Operation::Load | Operation::LoadReserved => {
+                    let address = *args.first().expect("Address not found");
+                    if op == Operation::Load {
+                        BusAccessRequst::new_load(path, logical_id, address, len)
+                    } else {
+                        BusAccessRequst::new_load_reserved(path, logical_id, address, len)
+                    }
                 }
-                AccessType::Store => {
-                    let value = args.get(0).expect("Value not found");
-                    let value = u32_to_raw_big_endian(*value);
-                    let address = args.get(1).expect("Address not found");
-                    BusAccessRequst::new_store(path, logical_id, *address, value)
+                Operation::Store | Operation::StoreConditional | Operation::Amo(_) => {
+                    let value = args.first().expect("Value not found");
+                    let value = u32_to_raw(*value, endian);
+                    let address = *args.get(1).expect("Address not found");
+                    match op {
+                        Operation::Amo(amo) => {
+                            BusAccessRequst::new_amo(path, logical_id, amo, address, value)
+                        }
+                        Operation::StoreConditional => {
+                            BusAccessRequst::new_store_conditional(path, logical_id, address, value)
+                        }
+                        _ => BusAccessRequst::new_store(path, logical_id, address, value),
+                    }
                 }
+                Operation::Fence => panic!("Fence never accesses the bus"),
+            };
+            let station = match access_type {
+                AccessType::Load => &mut self.load_station,
+                AccessType::Store => &mut self.store_station,
             };
             station
                 .start_execute(slot_id)
                 .unwrap_or_else(|msg| panic!("{}", msg));
-            Some(request)
+            Some(request.with_thread(thread))
         } else {
             None
         }
@@ -741,11 +1249,49 @@ impl AccessPath for Unit {
     fn response(&mut self, slot: usize, response: Result<BusAccessResult, String>) {
         let result = response
             .map(|resp| match resp {
-                BusAccessResult::Load(value) => ExecResult::MemLoad(value),
+                BusAccessResult::Load(value) => {
+                    ExecResult::MemLoad(raw_to_u32(&value, self.endian))
+                }
                 BusAccessResult::Store => ExecResult::MemStore,
+                BusAccessResult::Conditional(success) => ExecResult::MemConditional(success),
             })
             .or_else(|msg| -> Result<ExecResult, ()> { Ok(ExecResult::Err(msg)) })
             .expect("There is not path to Error");
-        self.result = Some((slot, result));
+        self.results.push_back((slot, result));
+    }
+    fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+    fn in_flight(&self) -> Vec<InFlightAccess> {
+        let evaluating = self.evaluation_queue.into_iter().map(|(phy_id, inst)| {
+            let logical_id = self.physical_slot_id_to_logical(*phy_id, inst.access_type());
+            (logical_id, inst as &dyn RenamedInst)
+        });
+        let stations = [
+            (&self.load_station, AccessType::Load),
+            (&self.store_station, AccessType::Store),
+        ];
+        let issued = stations.iter().flat_map(|(station, access_type)| {
+            station
+                .instructions()
+                .into_iter()
+                .map(move |(phy_id, inst)| {
+                    (self.physical_slot_id_to_logical(phy_id, *access_type), inst)
+                })
+        });
+        let evaluating = evaluating.map(|(logical_id, inst)| (logical_id, inst, None));
+        let issued = issued.map(|(logical_id, inst)| (logical_id, inst, evaluated_range(inst)));
+        evaluating
+            .chain(issued)
+            .map(|(logical_id, inst, range)| InFlightAccess {
+                tag: RStag::new(&self.name, logical_id).with_thread(inst.thread()),
+                seq: inst.seq(),
+                command: inst.command().to_string(),
+                range,
+            })
+            .collect()
+    }
+    fn set_other_accesses(&mut self, accesses: Vec<InFlightAccess>) {
+        self.others = accesses;
     }
 }
@@ -2,3 +2,4 @@ mod arithmetic_unit;
 pub mod factory;
 mod memory_access_unit;
 mod reservation_station;
+pub mod select_policy;
@@ -1,21 +1,20 @@
+use super::select_policy::{Candidate, FirstSlot, SelectPolicy};
 use crate::core::execution_path::{ArgState, RStag};
+use crate::core::pipeline::Phase;
+use crate::util::clone_box;
+use crate::util::json::Json;
 use std::fmt::{Debug, Display};
-use std::{default, mem};
+use std::mem;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub enum SlotState {
+    #[default]
     Empty,
     Pending(Box<dyn RenamedInst>),
     Executing(Box<dyn RenamedInst>),
     Reserved,
 }
 
-impl default::Default for SlotState {
-    fn default() -> Self {
-        SlotState::Empty
-    }
-}
-
 impl SlotState {
     fn is_pending(&self) -> bool {
         matches!(self, SlotState::Pending(_))
@@ -28,18 +27,41 @@ impl SlotState {
     }
 }
 
-pub trait RenamedInst: Display + Debug {
+clone_box! {
+    /// Clone an instruction behind a box, implemented for every cloneable RenamedInst
+    CloneRenamedInst for RenamedInst
+}
+
+pub trait RenamedInst: Display + Debug + CloneRenamedInst {
     /// Return command of the instruction
     fn command(&self) -> &str;
     fn arguments(&self) -> Vec<ArgState>;
     /// An instruction is ready if it's not waiting result of another instruction.
     fn is_ready(&self) -> bool;
     fn forward(&mut self, tag: &RStag, val: u32);
+    /// Sequence number given on issue, older instructions have smaller ones
+    fn seq(&self) -> u64;
+    /// Hardware thread which issued the instruction
+    fn thread(&self) -> usize;
+    /// Cycles the instruction takes to execute
+    fn latency(&self) -> usize {
+        1
+    }
+    /// Tags of older instructions which have to complete first, other than operands
+    fn ordering(&self) -> Vec<RStag> {
+        Vec::new()
+    }
+    /// Return the instruction to save in a snapshot, it's loaded by the path holding it
+    fn save(&self) -> Json;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReservationStation {
     slots: Vec<SlotState>,
+    /// Decide which ready instruction executes
+    policy: Box<dyn SelectPolicy>,
+    /// Length of the longest chain of instructions waiting for each slot, see [Candidate]
+    critical_paths: Vec<usize>,
 }
 
 impl Display for SlotState {
@@ -55,11 +77,15 @@ impl Display for SlotState {
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod resrvation_station {
     use super::*;
+    use crate::functional_units::select_policy::policy_of;
 
-    #[derive(Debug)]
-    struct InstStub {}
+    #[derive(Debug, Clone)]
+    struct InstStub {
+        seq: u64,
+    }
     impl Display for InstStub {
         fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             Ok(())
@@ -76,9 +102,18 @@ mod resrvation_station {
             true
         }
         fn forward(&mut self, _tag: &RStag, _val: u32) {}
+        fn seq(&self) -> u64 {
+            self.seq
+        }
+        fn thread(&self) -> usize {
+            0
+        }
+        fn save(&self) -> Json {
+            Json::Null
+        }
     }
     fn new_inst() -> Box<dyn RenamedInst> {
-        Box::new(InstStub {})
+        Box::new(InstStub { seq: 0 })
     }
     #[test]
     fn pending() {
@@ -90,14 +125,42 @@ mod resrvation_station {
         }
         assert_eq!(inst_cnt, station.occupied());
     }
+    #[test]
+    fn select() -> Result<(), String> {
+        let mut station = ReservationStation::new(4);
+        for seq in [5, 2, 9] {
+            station.insert(Box::new(InstStub { seq }));
+        }
+        assert_eq!(station.ready(), Some(0));
+        station.set_policy(policy_of("oldest")?);
+        assert_eq!(station.ready(), Some(1));
+        station.start_execute(1)?;
+        assert_eq!(station.ready(), Some(0));
+        station.set_policy(policy_of("critical-path")?);
+        station.set_critical_paths(vec![0, 0, 3, 0]);
+        assert_eq!(station.ready(), Some(2));
+        Ok(())
+    }
 }
 
 impl ReservationStation {
     pub fn new(size: usize) -> Self {
         Self {
             slots: (0..size).map(|_| SlotState::Empty).collect(),
+            policy: Box::new(FirstSlot),
+            critical_paths: vec![0; size],
         }
     }
+    pub fn set_policy(&mut self, policy: Box<dyn SelectPolicy>) {
+        self.policy = policy;
+    }
+    pub fn policy(&self) -> &dyn SelectPolicy {
+        self.policy.as_ref()
+    }
+    /// Set length of the longest chain of instructions waiting for each slot
+    pub fn set_critical_paths(&mut self, lengths: Vec<usize>) {
+        self.critical_paths = lengths;
+    }
     /// Return the capacity of the reservation station
     pub fn capacity(&self) -> usize {
         self.slots.len()
@@ -154,18 +217,29 @@ impl ReservationStation {
             Err(msg)
         }
     }
-    /// Find a ready instruction.
+    /// Find a ready instruction, chosen by the select policy if there are several.
     /// If found, its index returned.
     /// Otherwise, return None.
     pub fn ready(&self) -> Option<usize> {
-        for (idx, slot) in self.slots.iter().enumerate() {
-            if let SlotState::Pending(inst) = slot {
-                if inst.is_ready() {
-                    return Some(idx);
-                }
-            }
+        let candidates: Vec<Candidate> = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, slot)| match slot {
+                SlotState::Pending(inst) if inst.is_ready() => Some(Candidate {
+                    slot: idx,
+                    seq: inst.seq(),
+                    latency: inst.latency(),
+                    critical_path: self.critical_paths.get(idx).copied().unwrap_or_default(),
+                }),
+                _ => None,
+            })
+            .collect();
+        match candidates.len() {
+            0 => None,
+            1 => Some(candidates[0].slot),
+            _ => Some(candidates[self.policy.select(&candidates)].slot),
         }
-        None
     }
     /// The slot's instruction is solved, remove it.
     pub fn sloved(&mut self, idx: usize) {
@@ -189,12 +263,26 @@ impl ReservationStation {
         let empty: usize = self.slots.iter().filter(|s| s.is_empty()).count();
         self.slots.len() - empty
     }
+    /// Return count of slots which are executing
+    pub fn executing(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|s| matches!(s, SlotState::Executing(_)))
+            .count()
+    }
     pub fn is_full(&self) -> bool {
         self.occupied() == self.capacity()
     }
     pub fn get_slot(&self, idx: usize) -> Option<&SlotState> {
         self.slots.get(idx)
     }
+    /// Return hardware thread of the instruction in the slot, 0 if the slot holds none
+    pub fn thread(&self, idx: usize) -> usize {
+        match self.slots.get(idx) {
+            Some(SlotState::Pending(inst) | SlotState::Executing(inst)) => inst.thread(),
+            _ => 0,
+        }
+    }
     /// Change state of given slot into executing
     pub fn start_execute(&mut self, id: usize) -> Result<(), String> {
         let slot = self
@@ -210,6 +298,95 @@ impl ReservationStation {
             Err(format!("Slot {} isn't pending", id))
         }
     }
+    /// Return (slot index, instruction) of slots holding an instruction
+    pub fn instructions(&self) -> Vec<(usize, &dyn RenamedInst)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, s)| match s {
+                SlotState::Pending(inst) | SlotState::Executing(inst) => Some((idx, inst.as_ref())),
+                _ => None,
+            })
+            .collect()
+    }
+    /// Return (slot index, phase) of occupied slots
+    pub fn phases(&self) -> Vec<(usize, Phase)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, s)| match s {
+                SlotState::Pending(inst) if inst.is_ready() => Some((idx, Phase::Ready)),
+                SlotState::Pending(_) => Some((idx, Phase::Waiting)),
+                SlotState::Executing(_) => Some((idx, Phase::Executing)),
+                _ => None,
+            })
+            .collect()
+    }
+    /// Return state of each slot, null for empty slots
+    pub fn trace(&self) -> Json {
+        let slot = |state: &str, inst: &dyn RenamedInst| {
+            Json::object(vec![("state", Json::str(state)), ("inst", Json::str(inst))])
+        };
+        let slots = self
+            .slots
+            .iter()
+            .map(|s| match s {
+                SlotState::Empty => Json::Null,
+                SlotState::Pending(inst) => slot("pending", inst.as_ref()),
+                SlotState::Executing(inst) => slot("executing", inst.as_ref()),
+                SlotState::Reserved => Json::object(vec![("state", Json::str("reserved"))]),
+            })
+            .collect();
+        Json::Array(slots)
+    }
+    /// Return state of each slot, null for empty slots
+    pub fn save(&self) -> Json {
+        let slot = |state: &str, inst: &dyn RenamedInst| {
+            Json::object(vec![("state", Json::str(state)), ("inst", inst.save())])
+        };
+        let slots = self
+            .slots
+            .iter()
+            .map(|s| match s {
+                SlotState::Empty => Json::Null,
+                SlotState::Pending(inst) => slot("pending", inst.as_ref()),
+                SlotState::Executing(inst) => slot("executing", inst.as_ref()),
+                SlotState::Reserved => Json::object(vec![("state", Json::str("reserved"))]),
+            })
+            .collect();
+        Json::Array(slots)
+    }
+    /// Restore slots returned by [ReservationStation::save], instructions are constructed by `load`
+    pub fn restore<F>(&mut self, state: &Json, load: F) -> Result<(), String>
+    where
+        F: Fn(&Json) -> Result<Box<dyn RenamedInst>, String>,
+    {
+        let slots = state.as_array()?;
+        if slots.len() != self.capacity() {
+            let msg = format!(
+                "Expect {} reservation station slots, found {}",
+                self.capacity(),
+                slots.len()
+            );
+            return Err(msg);
+        }
+        self.slots = slots
+            .iter()
+            .map(|slot| {
+                let Some(slot) = slot.opt() else {
+                    return Ok(SlotState::Empty);
+                };
+                let state = match slot.get("state")?.as_str()? {
+                    "pending" => SlotState::Pending(load(slot.get("inst")?)?),
+                    "executing" => SlotState::Executing(load(slot.get("inst")?)?),
+                    "reserved" => SlotState::Reserved,
+                    state => return Err(format!("Unknown slot state {}", state)),
+                };
+                Ok(state)
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(())
+    }
     pub fn dump(&self) -> Vec<String> {
         self.slots
             .iter()
@@ -0,0 +1,159 @@
+use crate::util::clone_box;
+use crate::util::random::Random;
+use std::fmt::Debug;
+
+/// A ready instruction in a reservation station, which the select policy chooses from
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub slot: usize,
+    /// Sequence number given on issue, older instructions have smaller ones
+    pub seq: u64,
+    /// Cycles the instruction takes to execute
+    pub latency: usize,
+    /// Length of the longest chain of in-flight instructions waiting for its result
+    pub critical_path: usize,
+}
+
+clone_box! {
+    /// Clone a policy behind a box, implemented for every cloneable SelectPolicy
+    CloneSelectPolicy for SelectPolicy
+}
+
+/// Policy to decide which ready instruction of a reservation station executes
+pub trait SelectPolicy: Debug + CloneSelectPolicy {
+    fn name(&self) -> String;
+    /// Return index of the chosen one in `candidates`, which is never empty.
+    /// The choice only depends on the candidates, so a station selects the same instruction
+    /// until the candidates change.
+    fn select(&self, candidates: &[Candidate]) -> usize;
+    /// Whether candidates need [Candidate::critical_path], which is costly to find
+    fn uses_critical_path(&self) -> bool {
+        false
+    }
+}
+
+/// Index of the candidate with the maximum key, the oldest one among ties
+fn max_by_key<K: Ord>(candidates: &[Candidate], key: impl Fn(&Candidate) -> K) -> usize {
+    candidates
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| key(a).cmp(&key(b)).then(b.seq.cmp(&a.seq)))
+        .map_or(0, |(idx, _)| idx)
+}
+
+/// The instruction in the lowest slot executes first
+#[derive(Debug, Clone)]
+pub struct FirstSlot;
+
+impl SelectPolicy for FirstSlot {
+    fn name(&self) -> String {
+        String::from("First slot")
+    }
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        max_by_key(candidates, |c| std::cmp::Reverse(c.slot))
+    }
+}
+
+/// The oldest instruction executes first
+#[derive(Debug, Clone)]
+pub struct OldestFirst;
+
+impl SelectPolicy for OldestFirst {
+    fn name(&self) -> String {
+        String::from("Oldest first")
+    }
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        max_by_key(candidates, |_| ())
+    }
+}
+
+/// A pseudo random instruction executes, drawn from sequence numbers of the candidates.
+/// Runs are therefore reproducible.
+#[derive(Debug, Clone)]
+pub struct RandomSelect;
+
+impl SelectPolicy for RandomSelect {
+    fn name(&self) -> String {
+        String::from("Random")
+    }
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        let seed = candidates
+            .iter()
+            .fold(0, |seed: u64, c| seed.rotate_left(7) ^ c.seq);
+        Random::new(seed).below(candidates.len())
+    }
+}
+
+/// The instruction with the longest chain of instructions waiting for it executes first
+#[derive(Debug, Clone)]
+pub struct CriticalPath;
+
+impl SelectPolicy for CriticalPath {
+    fn name(&self) -> String {
+        String::from("Critical path")
+    }
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        max_by_key(candidates, |c| c.critical_path)
+    }
+    fn uses_critical_path(&self) -> bool {
+        true
+    }
+}
+
+/// The instruction which takes the most cycles executes first
+#[derive(Debug, Clone)]
+pub struct LongestLatency;
+
+impl SelectPolicy for LongestLatency {
+    fn name(&self) -> String {
+        String::from("Longest latency")
+    }
+    fn select(&self, candidates: &[Candidate]) -> usize {
+        max_by_key(candidates, |c| c.latency)
+    }
+}
+
+/// Construct a select policy by its name
+pub fn policy_of(name: &str) -> Result<Box<dyn SelectPolicy>, String> {
+    match name {
+        "first-slot" => Ok(Box::new(FirstSlot)),
+        "oldest" => Ok(Box::new(OldestFirst)),
+        "random" => Ok(Box::new(RandomSelect)),
+        "critical-path" => Ok(Box::new(CriticalPath)),
+        "longest-latency" => Ok(Box::new(LongestLatency)),
+        _ => Err(format!("Unknown select policy {}", name)),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod select_policy {
+    use super::*;
+
+    /// (slot, seq, latency, critical path) of each candidate
+    fn candidates(fields: &[(usize, u64, usize, usize)]) -> Vec<Candidate> {
+        fields
+            .iter()
+            .map(|(slot, seq, latency, critical_path)| Candidate {
+                slot: *slot,
+                seq: *seq,
+                latency: *latency,
+                critical_path: *critical_path,
+            })
+            .collect()
+    }
+    #[test]
+    fn select() -> Result<(), String> {
+        let c = candidates(&[(0, 7, 1, 0), (2, 3, 1, 2), (3, 5, 4, 2), (4, 1, 1, 1)]);
+        assert_eq!(policy_of("first-slot")?.select(&c), 0);
+        assert_eq!(policy_of("oldest")?.select(&c), 3);
+        // Ties are broken by age
+        assert_eq!(policy_of("critical-path")?.select(&c), 1);
+        assert_eq!(policy_of("longest-latency")?.select(&c), 2);
+        let random = policy_of("random")?;
+        assert_eq!(random.select(&c), random.select(&c));
+        assert!(random.select(&c) < c.len());
+        assert!(policy_of("youngest").is_err());
+        Ok(())
+    }
+}
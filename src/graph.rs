@@ -1,3 +1,132 @@
+use crate::core::execution_path::{ArgState, RStag};
+
+/// Component which is able to draw itself in Graphviz DOT
 pub trait Graph {
     fn get_graph(&self) -> String;
 }
+
+/// Quote a string as DOT id
+pub fn dot_id(id: &str) -> String {
+    format!("\"{}\"", id.replace('"', "\\\""))
+}
+
+/// Draw a component as a cluster.
+/// Nodes are (suffix, label), ids of nodes are `<name>.<suffix>`.
+/// Edges are (suffix, suffix).
+pub fn cluster(name: &str, nodes: &[(&str, String)], edges: &[(&str, &str)]) -> String {
+    let id = |suffix: &str| dot_id(&format!("{}.{}", name, suffix));
+    let mut dot = format!(
+        "    subgraph {} {{\n        label={};\n",
+        dot_id(&format!("cluster_{}", name)),
+        dot_id(name)
+    );
+    for (suffix, label) in nodes {
+        dot.push_str(&format!(
+            "        {} [shape=box, label={}];\n",
+            id(suffix),
+            dot_id(label)
+        ));
+    }
+    for (from, to) in edges {
+        dot.push_str(&format!("        {} -> {};\n", id(from), id(to)));
+    }
+    dot.push_str("    }\n");
+    dot
+}
+
+/// An in-flight instruction in the dependency graph
+#[derive(Debug)]
+pub struct DependencyNode {
+    /// Slot which holds the instruction
+    pub tag: RStag,
+    pub inst: String,
+    /// Tags of instructions whose results are waited for as operands
+    pub operands: Vec<RStag>,
+    /// Tags of older accesses which have to complete first, e.g. memory ordering
+    pub ordering: Vec<RStag>,
+}
+
+impl DependencyNode {
+    pub fn new(tag: RStag, inst: String, args: &[ArgState], ordering: Vec<RStag>) -> Self {
+        let operands = args
+            .iter()
+            .filter_map(|arg| match arg {
+                ArgState::Waiting(tag) => Some(tag.clone()),
+                ArgState::Ready(_) => None,
+            })
+            .collect();
+        Self {
+            tag,
+            inst,
+            operands,
+            ordering,
+        }
+    }
+}
+
+/// Draw in-flight instructions, and edges from producers to consumers.
+/// `renamed` are (register name, tag) of renamed registers.
+pub fn dependency_graph(nodes: &[DependencyNode], renamed: &[(String, RStag)]) -> String {
+    let mut dot = String::from("digraph dependency {\n    rankdir=LR;\n");
+    for node in nodes {
+        let label = format!("{}\\n{}", node.tag, node.inst);
+        dot.push_str(&format!(
+            "    {} [shape=box, label={}];\n",
+            dot_id(&node.tag.to_string()),
+            dot_id(&label)
+        ));
+    }
+    for node in nodes {
+        let to = dot_id(&node.tag.to_string());
+        for operand in node.operands.iter() {
+            dot.push_str(&format!(
+                "    {} -> {};\n",
+                dot_id(&operand.to_string()),
+                to
+            ));
+        }
+        for older in node.ordering.iter() {
+            dot.push_str(&format!(
+                "    {} -> {} [style=dashed];\n",
+                dot_id(&older.to_string()),
+                to
+            ));
+        }
+    }
+    for (register, tag) in renamed {
+        dot.push_str(&format!(
+            "    {} [shape=ellipse];\n    {} -> {} [style=dotted];\n",
+            dot_id(register),
+            dot_id(&tag.to_string()),
+            dot_id(register)
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod graph {
+    use super::*;
+
+    #[test]
+    fn dependency() {
+        let producer = RStag::new("arith0", 0);
+        let store = RStag::new("mem_access0", 4);
+        let nodes = [
+            DependencyNode::new(producer.clone(), String::from("addi"), &[], vec![]),
+            DependencyNode::new(
+                RStag::new("mem_access0", 0),
+                String::from("lw"),
+                &[ArgState::Waiting(producer.clone()), ArgState::Ready(4)],
+                vec![store],
+            ),
+        ];
+        let dot = dependency_graph(&nodes, &[(String::from("R1"), producer)]);
+        assert!(dot.contains(r#""arith0(0)" [shape=box, label="arith0(0)\naddi"];"#));
+        assert!(dot.contains(r#""arith0(0)" -> "mem_access0(0)";"#));
+        assert!(dot.contains(r#""mem_access0(4)" -> "mem_access0(0)" [style=dashed];"#));
+        assert!(dot.contains(r#""arith0(0)" -> "R1" [style=dotted];"#));
+    }
+}
@@ -1,17 +1,371 @@
-#![feature(trait_upcasting)]
 mod core;
 mod display;
 mod functional_units;
 mod graph;
 mod memory_bus;
+mod tui;
 mod util;
 mod virtual_machine;
+use crate::core::bus_controller;
 use crate::core::processor::Processor;
+use crate::core::smt;
+use crate::core::statistics::Statistics;
+use crate::display::into_table;
 use crate::functional_units::factory::{Factory, Function, MemFunction};
+use crate::functional_units::select_policy;
+use crate::memory_bus::cache::Protocol;
+use crate::tui::{MultiCoreTui, RawMode, Tui};
+use crate::util::json::Json;
+use crate::util::Endian;
+use crate::virtual_machine::checker::Checker;
+use crate::virtual_machine::debugger::{Debugger, StopCondition};
+use crate::virtual_machine::fuzz;
+use crate::virtual_machine::multi_core::MultiCoreMachine;
+use crate::virtual_machine::program::Program;
+use crate::virtual_machine::test_case;
+use crate::virtual_machine::timeline::{RunState, Timeline};
+use crate::virtual_machine::trace::TraceWriter;
+use crate::virtual_machine::Machine;
+use std::env;
+use std::fs;
 use std::io;
+use std::path::Path;
+
+/// Count of arithmetic units of the machine
+const ARITH_UNITS: usize = 2;
+/// Default bytes of DRAM of single-core and multi-core machines
+const DRAM_SIZE: usize = 20;
+const MULTI_CORE_DRAM_SIZE: usize = 64;
+
+/// Command line options
+struct Options {
+    /// Name of bus arbitration policy
+    arbitration: String,
+    /// Count of memory access units
+    mem_units: usize,
+    /// (depth, interval) of the pipeline of each arithmetic unit, or of all of them if given once
+    pipelines: Vec<(usize, usize)>,
+    /// Arithmetic units share one reservation station instead of having their own
+    shared: bool,
+    /// Count of physical registers to rename destinations to, renamed to reservation stations if None
+    physical_registers: Option<usize>,
+    /// Byte order of memory
+    endian: Endian,
+    /// Bytes of DRAM, the default of the kind of machine if None
+    dram: Option<usize>,
+    /// Paths of programs to execute on each core, execute the built-in program if empty
+    programs: Vec<String>,
+    /// Physical address of root page table, virtual memory is disabled if None
+    page_table: Option<u32>,
+    /// Count of TLB entries
+    tlb_entries: usize,
+    /// Count of processor cores
+    cores: usize,
+    /// Coherence protocol of caches in multi-core machine
+    protocol: Protocol,
+    /// Count of hardware threads in each core
+    threads: usize,
+    /// Name of fetch policy of SMT processor
+    fetch: String,
+    /// Name of policy selecting ready instructions of reservation stations
+    select: String,
+    /// Address of interrupt handler
+    vector: usize,
+    /// Path to export statistics to, in JSON or CSV by its extension
+    stats: Option<String>,
+    /// Path to write per-cycle trace to, in JSON Lines
+    trace: Option<String>,
+    /// Path to write pipeline diagram to, in Kanata log format
+    kanata: Option<String>,
+    /// Path to write pipeline diagram to, as a plain-text Gantt table
+    gantt: Option<String>,
+    /// Path to write the datapath to, in Graphviz DOT
+    dot: Option<String>,
+    /// Directory to write the dependency graph of every cycle to, in Graphviz DOT
+    dependencies: Option<String>,
+    /// Run in the full-screen terminal UI
+    tui: bool,
+    /// Stop conditions, see [StopCondition::parse]
+    breaks: Vec<String>,
+    /// Path to write a snapshot to whenever the run pauses
+    save: Option<String>,
+    /// Path of a snapshot to start from
+    load: Option<String>,
+    /// Check the processor against the golden model in lockstep
+    check: bool,
+    /// Count of random programs to fuzz instead of running a program
+    fuzz: Option<u64>,
+    /// Seed of the first fuzzed program
+    seed: u64,
+    /// Directory of test programs to run instead of running a program
+    test: Option<String>,
+    /// Record timing of the test programs instead of comparing with it
+    record_timing: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self {
+            arbitration: String::from("fifo"),
+            mem_units: 1,
+            pipelines: Vec::new(),
+            shared: false,
+            physical_registers: None,
+            endian: Endian::Big,
+            dram: None,
+            programs: Vec::new(),
+            page_table: None,
+            tlb_entries: 8,
+            cores: 1,
+            protocol: Protocol::Mesi,
+            threads: 1,
+            fetch: String::from("round-robin"),
+            select: String::from("first-slot"),
+            vector: 0,
+            stats: None,
+            trace: None,
+            kanata: None,
+            gantt: None,
+            dot: None,
+            dependencies: None,
+            tui: false,
+            breaks: Vec::new(),
+            save: None,
+            load: None,
+            check: false,
+            fuzz: None,
+            seed: 0,
+            test: None,
+            record_timing: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value of option {}", arg))
+            };
+            match arg.as_str() {
+                "--arbitration" => options.arbitration = value()?.clone(),
+                "--mem-units" => {
+                    options.mem_units = value()?
+                        .parse()
+                        .map_err(|_| String::from("Expect an integer for --mem-units"))?
+                }
+                "--pipeline" => options.pipelines.push(parse_pipeline(value()?)?),
+                "--stations" => {
+                    options.shared = match value()?.as_str() {
+                        "distributed" => false,
+                        "shared" => true,
+                        name => return Err(format!("Unknown organisation of stations {}", name)),
+                    }
+                }
+                "--physical-registers" => {
+                    options.physical_registers =
+                        Some(value()?.parse().map_err(|_| {
+                            String::from("Expect an integer for --physical-registers")
+                        })?)
+                }
+                "--endian" => options.endian = Endian::from_name(value()?)?,
+                "--dram" => {
+                    options.dram = Some(
+                        value()?
+                            .parse()
+                            .map_err(|_| String::from("Expect an integer for --dram"))?,
+                    )
+                }
+                "--program" => options.programs.push(value()?.clone()),
+                "--mmu" => {
+                    options.page_table = Some(
+                        value()?
+                            .parse()
+                            .map_err(|_| String::from("Expect an integer for --mmu"))?,
+                    )
+                }
+                "--tlb-entries" => {
+                    options.tlb_entries = value()?
+                        .parse()
+                        .map_err(|_| String::from("Expect an integer for --tlb-entries"))?
+                }
+                "--cores" => {
+                    options.cores = value()?
+                        .parse()
+                        .map_err(|_| String::from("Expect an integer for --cores"))?
+                }
+                "--protocol" => options.protocol = Protocol::from_name(value()?)?,
+                "--threads" => {
+                    options.threads = value()?
+                        .parse()
+                        .map_err(|_| String::from("Expect an integer for --threads"))?
+                }
+                "--fetch" => options.fetch = value()?.clone(),
+                "--select" => options.select = value()?.clone(),
+                "--vector" => {
+                    options.vector = value()?
+                        .parse()
+                        .map_err(|_| String::from("Expect an integer for --vector"))?
+                }
+                "--stats" => options.stats = Some(value()?.clone()),
+                "--trace" => options.trace = Some(value()?.clone()),
+                "--kanata" => options.kanata = Some(value()?.clone()),
+                "--gantt" => options.gantt = Some(value()?.clone()),
+                "--dot" => options.dot = Some(value()?.clone()),
+                "--dependencies" => options.dependencies = Some(value()?.clone()),
+                "--tui" => options.tui = true,
+                "--break" => options.breaks.push(value()?.clone()),
+                "--save" => options.save = Some(value()?.clone()),
+                "--load" => options.load = Some(value()?.clone()),
+                "--check" => options.check = true,
+                "--fuzz" => {
+                    let count = value()?
+                        .parse()
+                        .map_err(|_| String::from("Expect an integer for --fuzz"))?;
+                    options.fuzz = Some(count);
+                }
+                "--test" => options.test = Some(value()?.clone()),
+                "--record-timing" => options.record_timing = true,
+                "--seed" => {
+                    options.seed = value()?
+                        .parse()
+                        .map_err(|_| String::from("Expect an integer for --seed"))?
+                }
+                _ => return Err(format!("Unknown option {}", arg)),
+            }
+        }
+        if options.pipelines.len() > 1 && options.pipelines.len() != ARITH_UNITS {
+            return Err(format!(
+                "Expect either one pipeline or one pipeline for each of {} arithmetic units",
+                ARITH_UNITS
+            ));
+        }
+        if options.cores == 0 || options.threads == 0 {
+            return Err(String::from("Expect at least one core and one thread"));
+        }
+        if options.record_timing && options.test.is_none() {
+            return Err(String::from(
+                "--record-timing is supported with --test only",
+            ));
+        }
+        if options.check && (options.tui || options.cores > 1) {
+            return Err(String::from(
+                "--check is supported by single-core machine out of the terminal UI",
+            ));
+        }
+        // The terminal UI takes over the screen, and doesn't record the run
+        let outside_tui = [
+            ("--trace", options.trace.is_some()),
+            ("--dependencies", options.dependencies.is_some()),
+            ("--dot", options.dot.is_some()),
+        ];
+        if options.tui {
+            if let Some((option, _)) = outside_tui.iter().find(|(_, given)| *given) {
+                return Err(format!("{} is not supported by the terminal UI", option));
+            }
+        }
+        let registers = Processor::new().peek_registers().len() * options.threads;
+        if options
+            .physical_registers
+            .is_some_and(|count| count <= registers)
+        {
+            return Err(format!(
+                "Expect more than {} physical registers for {} threads",
+                registers, options.threads
+            ));
+        }
+        if options.cores > 1 && options.threads > 1 {
+            return Err(String::from("SMT is not supported by multi-core machine"));
+        }
+        // The multi-core machine only shows its state every cycle
+        let single_core_only = [
+            ("--mmu", options.page_table.is_some()),
+            ("--stats", options.stats.is_some()),
+            ("--trace", options.trace.is_some()),
+            ("--kanata", options.kanata.is_some()),
+            ("--gantt", options.gantt.is_some()),
+            ("--dot", options.dot.is_some()),
+            ("--dependencies", options.dependencies.is_some()),
+            ("--break", !options.breaks.is_empty()),
+        ];
+        if options.cores > 1 {
+            if let Some((option, _)) = single_core_only.iter().find(|(_, given)| *given) {
+                return Err(format!("{} is not supported by multi-core machine", option));
+            }
+        }
+        let contexts = options.cores.max(options.threads);
+        if options.programs.len() > 1 && options.programs.len() != contexts {
+            return Err(String::from(
+                "Expect either one program or one program for each core or thread",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// Parse value of --pipeline, "DEPTH" or "DEPTH:INTERVAL"
+fn parse_pipeline(value: &str) -> Result<(usize, usize), String> {
+    let (depth, interval) = value.split_once(':').unwrap_or((value, "1"));
+    match (depth.parse(), interval.parse()) {
+        (Ok(depth), Ok(interval)) if depth > 0 && interval > 0 => Ok((depth, interval)),
+        _ => Err(format!(
+            "Expect DEPTH or DEPTH:INTERVAL for --pipeline, found {}",
+            value
+        )),
+    }
+}
+
+/// Construct a processor with execution paths given by options
+fn new_processor(options: &Options) -> Result<Processor, String> {
+    let mut p = Processor::new();
+    let mut ff = Factory::new();
+    let pipelines: Vec<(usize, usize)> = (0..ARITH_UNITS)
+        .map(|idx| {
+            let pipeline = options.pipelines.get(idx).or(options.pipelines.first());
+            pipeline.copied().unwrap_or((1, 1))
+        })
+        .collect();
+    if options.shared {
+        p.add_path(ff.new_shared_unit(Function::Arithmetic, &pipelines))?;
+    } else {
+        for (depth, interval) in pipelines {
+            ff.set_pipeline(depth, interval);
+            p.add_path(ff.new_unit(Function::Arithmetic))?;
+        }
+    }
+    for _ in 0..options.mem_units {
+        let unit = ff.new_mem_unit(MemFunction::MemoryAccess);
+        p.add_mem_path(unit)?;
+    }
+    if let Some(count) = options.physical_registers {
+        p.use_physical_registers(count)?;
+    }
+    p.set_arbitration_policy(bus_controller::policy_of(&options.arbitration)?);
+    p.set_fetch_policy(smt::policy_of(&options.fetch)?);
+    p.set_select_policy(select_policy::policy_of(&options.select)?);
+    p.set_interrupt_vector(options.vector);
+    Ok(p)
+}
 
 fn main() -> Result<(), String> {
-    let program = vec![
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = Options::parse(&args)?;
+    if let Some(count) = options.fuzz {
+        match fuzz::fuzz(options.seed, count, &fuzz::CONFIGS) {
+            Ok(()) => println!("No divergence in {} programs", count),
+            Err(msg) => println!("Diverged from the golden model. {}", msg),
+        }
+        return Ok(());
+    }
+    if let Some(dir) = options.test.as_ref() {
+        let (report, failed) = test_case::run_all(Path::new(dir), options.record_timing)?;
+        for line in report.iter() {
+            println!("{}", line);
+        }
+        if failed > 0 {
+            return Err(format!("{} test programs failed", failed));
+        }
+        return Ok(());
+    }
+
+    let builtin = vec![
         "addi R1, R0, #0",
         "addi R2, R0, #10",
         "sw R1, R2, #0", // j = 0, &j == 10
@@ -41,38 +395,256 @@ fn main() -> Result<(), String> {
         "sw R1, R2, #4",
     ];
 
-    let program = program.iter().map(|i| i.to_string()).collect();
+    let mut programs = Vec::new();
+    for path in options.programs.iter() {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        programs.push(Program::parse(&source)?);
+    }
+    if programs.is_empty() {
+        programs.push(Program::parse(&builtin.join("\n"))?);
+    }
+    if options.cores > 1 {
+        if options.save.is_some() || options.load.is_some() {
+            return Err(String::from(
+                "Snapshots are not supported by multi-core machine",
+            ));
+        }
+        return run_multi_core(&options, &programs);
+    }
 
-    let mut p = Processor::new();
-    let mut ff = Factory::new();
-    for _ in 0..2 {
-        let unit = ff.new_unit(Function::Arithmetic);
-        p.add_path(unit)?;
+    let mut vm = new_machine(&options, &programs)?;
+    if let Some(path) = options.load.as_ref() {
+        let snapshot = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let snapshot = Json::parse(&snapshot).map_err(|e| format!("{}: {}", path, e))?;
+        vm.restore(&snapshot)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    let mut debugger = Debugger::new(Timeline::new(vm));
+    let labels = programs[0].labels();
+    for spec in options.breaks.iter() {
+        debugger.add(StopCondition::parse(spec, labels)?)?;
     }
-    let unit = ff.new_mem_unit(MemFunction::MemoryAccess);
-    p.add_mem_path(unit)?;
-    let mut vm = virtual_machine::Machine::new(p, program, 20);
+    let vm = if options.tui {
+        let mut tui = Tui::new(debugger, labels.clone());
+        let raw_mode = RawMode::enter()?;
+        let result = tui.run(io::stdin().lock(), io::stdout());
+        drop(raw_mode);
+        result?;
+        tui.into_machine()
+    } else {
+        run(&options, debugger)?
+    };
+    report(&options, vm)
+}
 
-    let mut result = Ok(());
-    while result.is_ok() {
-        println!("{}", vm);
-        pause();
-        result = vm.next_cycle();
+/// Construct a machine which runs programs given by options
+fn new_machine(options: &Options, programs: &[Program]) -> Result<Machine, String> {
+    let program = &programs[0];
+    let mut p = new_processor(options)?;
+    if options.kanata.is_some() || options.gantt.is_some() {
+        p.record_pipeline();
+    }
+    let dram = options.dram.unwrap_or(DRAM_SIZE);
+    let mut vm = Machine::with_endian(p, program.text().to_vec(), dram, options.endian);
+    // Other threads run their own programs, or the same one if only one given
+    for idx in 1..options.threads {
+        let program = programs.get(idx).unwrap_or(program);
+        vm.add_thread(program.text().to_vec());
+    }
+    for program in programs.iter() {
+        for (base, words) in program.data() {
+            vm.preload(*base, words)?;
+        }
     }
+    if let Some(root) = options.page_table {
+        vm.enable_mmu(root, options.tlb_entries);
+    }
+    Ok(vm)
+}
 
-    result = Ok(());
-    while result.is_ok() {
-        println!("{}", vm);
-        pause();
-        result = vm.next_flush_cycle();
+/// Run the machine until in-flight instructions complete.
+/// The machine is printed every cycle, or only when a stop condition hits if any given.
+fn run(options: &Options, mut debugger: Debugger) -> Result<Machine, String> {
+    let vm = debugger.timeline().machine();
+    if let Some(path) = options.dot.as_ref() {
+        fs::write(path, vm.topology()).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(dir) = options.dependencies.as_ref() {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir, e))?;
+    }
+    let mut trace = match options.trace.as_ref() {
+        Some(path) => {
+            let file = fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+            Some(TraceWriter::new(io::BufWriter::new(file)))
+        }
+        None => None,
+    };
+    let mut checker = if options.check {
+        Some(Checker::new(vm)?)
+    } else {
+        None
+    };
+    // With stop conditions, the machine runs freely until one of them hits
+    let stepping = debugger.conditions().is_empty();
+    let mut hits = Vec::new();
+    loop {
+        let vm = debugger.timeline().machine();
+        if stepping || !hits.is_empty() {
+            println!("{}", vm);
+            let conditions: Vec<String> = hits
+                .iter()
+                .map(|id| debugger.conditions()[id].to_string())
+                .collect();
+            if !hits.is_empty() {
+                println!("Stopped by {}", conditions.join(", "));
+            }
+        }
+        if let Some(trace) = trace.as_mut() {
+            trace.record(vm.trace())?;
+        }
+        if let Some(dir) = options.dependencies.as_ref() {
+            write_dependencies(dir, debugger.timeline().cycle(), vm)?;
+        }
+        if stepping || !hits.is_empty() {
+            if let Some(path) = options.save.as_ref() {
+                fs::write(path, vm.save().to_string()).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            pause();
+        }
+        match debugger.step() {
+            Some(hit) => hits = hit,
+            None => break,
+        }
+        if let Some(c) = checker.as_mut() {
+            if let Err(msg) = c.check(debugger.timeline().machine()) {
+                println!("Diverged from the golden model. {}", msg);
+                checker = None;
+                break;
+            }
+        }
     }
+    if checker.is_some() {
+        println!("No divergence from the golden model");
+    }
+    let timeline = debugger.into_timeline();
+    if let RunState::Finished(msg) = timeline.state() {
+        println!("Err({:?})", msg);
+    }
+    Ok(timeline.into_machine())
+}
 
-    println!("{:?}", result);
+/// Write the dependency graph of the cycle into the directory
+fn write_dependencies(dir: &str, cycle: usize, vm: &Machine) -> Result<(), String> {
+    let path = format!("{}/cycle_{}.dot", dir, cycle);
+    fs::write(&path, vm.dependency_graph()).map_err(|e| format!("{}: {}", path, e))
+}
 
-    let (p, dram) = vm.splite();
+/// Print the result and statistics of a run, and export them as requested
+fn report(options: &Options, vm: Machine) -> Result<(), String> {
+    if let Some(status) = vm.exit_status() {
+        println!("Program exited with status {}", status);
+    }
+    if let Some(fault) = vm.page_fault() {
+        println!("Program stopped by {}", fault);
+    }
+    if let Some(fault) = vm.bus_fault() {
+        println!("Program stopped by {}", fault);
+    }
+
+    if let Some((hit, miss)) = vm.tlb_statistics() {
+        println!("TLB hit: {}, miss: {}", hit, miss);
+    }
+    let memory = vm.dump_memory();
+    let (p, _) = vm.splite();
     println!("Emulation finished");
     println!("{:#?}", p);
-    println!("{:?}", dram);
+    if options.threads > 1 {
+        println!("{}", into_table("Issue", vec![p.issue_statistics()]));
+    }
+    let (taken, drain_cycles) = p.interrupt_statistics();
+    if taken > 0 {
+        println!(
+            "Interrupts taken: {}, cycles spent draining: {}",
+            taken, drain_cycles
+        );
+    }
+    println!("{}", into_table("Memory", memory));
+    let statistics = p
+        .bus_statistics()
+        .iter()
+        .map(|(path, stat)| format!("{}: {}", path, stat))
+        .collect();
+    println!("{}", into_table("Bus Statistics", statistics));
+    println!("{}", p.statistics());
+    if let Some(path) = options.stats.as_ref() {
+        export_statistics(p.statistics(), path)?;
+    }
+    if let Some(pipeline) = p.pipeline() {
+        if let Some(path) = options.kanata.as_ref() {
+            fs::write(path, pipeline.to_kanata()).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        if let Some(path) = options.gantt.as_ref() {
+            fs::write(path, pipeline.to_gantt()).map_err(|e| format!("{}: {}", path, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Write statistics to `path` as JSON if the path ends with `.json`, otherwise as CSV
+fn export_statistics(statistics: &Statistics, path: &str) -> Result<(), String> {
+    let content = if path.ends_with(".json") {
+        statistics.to_json()
+    } else {
+        statistics.to_csv()
+    };
+    fs::write(path, content).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Run each program on a core of a multi-core machine.
+/// If only one program given, all cores run the same program.
+fn run_multi_core(options: &Options, programs: &[Program]) -> Result<(), String> {
+    let mut cores = Vec::new();
+    for idx in 0..options.cores {
+        let program = programs.get(idx).unwrap_or(&programs[0]);
+        cores.push((new_processor(options)?, program.text().to_vec()));
+    }
+    let dram = options.dram.unwrap_or(MULTI_CORE_DRAM_SIZE);
+    let mut vm = MultiCoreMachine::new(cores, dram, options.protocol, options.endian);
+    for program in programs {
+        for (base, words) in program.data() {
+            vm.preload(*base, words)?;
+        }
+    }
+
+    let vm = if options.tui {
+        let mut tui = MultiCoreTui::new(vm);
+        let raw_mode = RawMode::enter()?;
+        let result = tui.run(io::stdin().lock(), io::stdout());
+        drop(raw_mode);
+        result?;
+        tui.into_machine()
+    } else {
+        let mut result = Ok(());
+        while result.is_ok() {
+            println!("{}", vm);
+            pause();
+            result = vm.next_cycle();
+        }
+        println!("{:?}", result);
+        vm
+    };
+    if let Some(status) = vm.exit_status() {
+        println!("Program exited with status {}", status);
+    }
+    println!("Emulation finished");
+    println!("{}", into_table("Memory", vm.dump_memory()));
+    let statistics = vm
+        .cache_statistics()
+        .iter()
+        .enumerate()
+        .map(|(idx, stat)| format!("core{}: {}", idx, stat))
+        .collect();
+    println!("{}", into_table("Cache Statistics", statistics));
     Ok(())
 }
 
@@ -0,0 +1,531 @@
+use std::fmt::{self, Debug, Display};
+use std::ops::Range;
+
+use crate::core::execution_path::{BusAccess, BusAccessRequst, BusAccessResponse, BusAccessResult};
+use crate::display::into_table;
+use crate::util::json::Json;
+use crate::util::{clone_box, raw_to_u32, u32_to_raw, Endian};
+
+clone_box! {
+    /// Clone a device behind a box, implemented for every cloneable Device
+    CloneDevice for Device
+}
+
+/// A device which can be attached to the memory bus
+pub trait Device: Debug + CloneDevice {
+    fn name(&self) -> String;
+    /// Return size of the address space occupied by the device in bytes.
+    fn size(&self) -> usize;
+    /// Return cycles required to serve an access.
+    fn latency(&self) -> usize;
+    /// Read `len` bytes from `offset`, relative to the base address of the device.
+    fn read(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, String>;
+    /// Write `data` to `offset`, relative to the base address of the device.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), String>;
+    /// Execute next machine cycle of the device.
+    fn next_cycle(&mut self) {}
+    /// If the device requests the machine to stop, return Some(exit status).
+    /// Otherwise, return None.
+    fn exit_status(&self) -> Option<u32> {
+        None
+    }
+    /// Return raw data held by the device.
+    fn dump(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Return interrupt lines requested by the device, as a bit mask.
+    fn interrupt(&self) -> u32 {
+        0
+    }
+    /// Print output of the device or not, for devices which print.
+    /// Echo is turned off while cycles already seen are re-executed, so output isn't printed twice.
+    fn set_echo(&mut self, _echo: bool) {}
+    /// Return state of the device to save in a snapshot, null if it's stateless
+    fn save(&self) -> Json {
+        Json::Null
+    }
+    /// Restore state returned by [Device::save]
+    fn restore(&mut self, _state: &Json) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A device and the address range it is mapped to
+#[derive(Debug, Clone)]
+struct Mapping {
+    range: Range<u32>,
+    device: Box<dyn Device>,
+}
+
+/// Access which no device could serve, e.g. to an unmapped address or a read only register.
+/// The machine stops on a bus fault, as there is no handler to trap to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusFault {
+    /// Physical address accessed
+    pub address: u32,
+    pub is_load: bool,
+    /// Tag of the instruction which accessed
+    pub tag: String,
+    /// Error reported by the bus or the device
+    pub message: String,
+}
+
+impl Display for BusFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.is_load { "load" } else { "store" };
+        write!(
+            f,
+            "Bus fault: {} at {:#x} from {} ({})",
+            access, self.address, self.tag, self.message
+        )
+    }
+}
+
+impl BusFault {
+    fn new(request: &BusAccessRequst, message: String) -> Self {
+        Self {
+            address: request.address(),
+            is_load: request.is_load(),
+            tag: request.tag().to_string(),
+            message,
+        }
+    }
+    fn save(&self) -> Json {
+        Json::object(vec![
+            ("address", Json::from(self.address)),
+            ("load", Json::Bool(self.is_load)),
+            ("tag", Json::str(&self.tag)),
+            ("message", Json::str(&self.message)),
+        ])
+    }
+    fn load(state: &Json) -> Result<Self, String> {
+        Ok(Self {
+            address: state.get("address")?.as_u32()?,
+            is_load: state.get("load")?.as_bool()?,
+            tag: state.get("tag")?.as_str()?.to_string(),
+            message: state.get("message")?.as_str()?.to_string(),
+        })
+    }
+}
+
+/// Memory bus which routes access requests to devices by address
+#[derive(Debug, Clone)]
+pub struct MemoryBus {
+    mappings: Vec<Mapping>,
+    /// (remaining cycles, request handler)
+    request: Option<(usize, BusAccessRequst)>,
+    /// Address ranges reserved by load reserved, indexed by hardware thread of the requester
+    reservations: Vec<(usize, Range<u32>)>,
+    /// Byte order used by atomic accesses to modify data
+    endian: Endian,
+    /// Address range written in the last cycle
+    written: Option<Range<u32>>,
+    /// Fault of the access which couldn't be served, no response is returned for it
+    fault: Option<BusFault>,
+}
+
+impl Display for MemoryBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let request = self
+            .request
+            .as_ref()
+            .map_or(String::new(), |(remain, request)| {
+                format!("{}: Remain {} cycles", request, remain)
+            });
+        let mut rows = vec![request];
+        if let Some(fault) = self.fault.as_ref() {
+            rows.push(fault.to_string());
+        }
+        write!(f, "{}", into_table("Memory Bus", rows))
+    }
+}
+
+impl MemoryBus {
+    pub fn new(endian: Endian) -> Self {
+        Self {
+            mappings: Vec::new(),
+            request: None,
+            reservations: Vec::new(),
+            endian,
+            written: None,
+            fault: None,
+        }
+    }
+    /// Map a device to address space which starts from `base`.
+    /// The address space of devices must be disjoint, otherwise, error message returned.
+    pub fn map(&mut self, base: u32, device: Box<dyn Device>) -> Result<(), String> {
+        let end = base as u64 + device.size() as u64;
+        if end > u32::MAX as u64 + 1 {
+            let msg = format!("Bus: {} exceeds address space", device.name());
+            return Err(msg);
+        }
+        let range = base..end as u32;
+        let overlaped = self
+            .mappings
+            .iter()
+            .find(|m| !(m.range.end <= range.start || m.range.start >= range.end));
+        if let Some(m) = overlaped {
+            let msg = format!(
+                "Bus: {} overlaps with {} at {:#x}",
+                device.name(),
+                m.device.name(),
+                m.range.start
+            );
+            return Err(msg);
+        }
+        self.mappings.push(Mapping { range, device });
+        Ok(())
+    }
+    /// Find the device which is mapped to address range [base, base + len).
+    /// On found, the device and offset of the address to the device returned.
+    fn decode(&mut self, base: u32, len: usize) -> Result<(&mut dyn Device, usize), String> {
+        let fin = base as u64 + len as u64;
+        let mapping = self
+            .mappings
+            .iter_mut()
+            .find(|m| m.range.start <= base && fin <= m.range.end as u64)
+            .ok_or(format!("Bus: address {:#x} is not mapped", base))?;
+        let offset = (base - mapping.range.start) as usize;
+        Ok((&mut *mapping.device, offset))
+    }
+    /// Write data to given address immediately, which is used to initialize devices
+    pub fn load(&mut self, base: u32, data: &[u8]) -> Result<(), String> {
+        let (device, offset) = self.decode(base, data.len())?;
+        device.write(offset, data)
+    }
+    /// Return the device with given name
+    pub fn device(&self, name: &str) -> Option<&dyn Device> {
+        self.mappings
+            .iter()
+            .find(|m| m.device.name() == name)
+            .map(|m| &*m.device)
+    }
+    pub fn is_idle(&self) -> bool {
+        self.request.is_none()
+    }
+    pub fn access(&mut self, request: BusAccessRequst) -> Result<(), String> {
+        if self.request.is_some() {
+            let msg = String::from("Memory is busy");
+            return Err(msg);
+        }
+        let base = request.address();
+        let len = request.access_len();
+        // Unmapped accesses are reported as soon as possible
+        let latency = self.decode(base, len).map_or(0, |(dev, _)| dev.latency());
+        self.request = Some((latency, request));
+        Ok(())
+    }
+    /// Execute next machine cycle of the bus and every attached devices.
+    /// If an access is done, its response returned.
+    /// If the access can't be served, a bus fault is raised instead of the response.
+    pub fn next_cycle(&mut self) -> Option<BusAccessResponse> {
+        self.written = None;
+        for m in self.mappings.iter_mut() {
+            m.device.next_cycle();
+        }
+
+        let (remain_cycle, request) = self.request.take()?;
+
+        if remain_cycle > 0 {
+            self.request = Some((remain_cycle - 1, request));
+            return None;
+        }
+        match self.serve(&request) {
+            Ok(result) => Some(request.into_respose(Ok(result))),
+            Err(msg) => {
+                self.fault = Some(BusFault::new(&request, msg));
+                None
+            }
+        }
+    }
+    /// Perform the access on the device which the address is mapped to
+    fn serve(&mut self, request: &BusAccessRequst) -> Result<BusAccessResult, String> {
+        let requester = request.thread();
+        let endian = self.endian;
+        let start = request.address();
+        let end = start
+            .checked_add(request.access_len() as u32)
+            .ok_or_else(|| format!("Bus: access at {:#x} runs past the address space", start))?;
+        let range = start..end;
+        let reserved = self
+            .reservations
+            .iter()
+            .any(|(thread, reserved)| *thread == requester && *reserved == range);
+        let reserving = matches!(request.request(), BusAccess::LoadReserved(..));
+        if reserving || matches!(request.request(), BusAccess::StoreConditional(..)) {
+            // Reservation is replaced by load reserved, and consumed by store conditional
+            // whether it succeeds or not
+            self.reservations.retain(|(thread, _)| *thread != requester);
+        }
+        let (device, offset) = self.decode(range.start, request.access_len())?;
+        let result = match request.request() {
+            BusAccess::Load(_, len) | BusAccess::LoadReserved(_, len) => {
+                device.read(offset, *len).map(BusAccessResult::Load)?
+            }
+            BusAccess::Store(_, data) => {
+                device.write(offset, data)?;
+                BusAccessResult::Store
+            }
+            BusAccess::StoreConditional(_, data) => {
+                if !reserved {
+                    return Ok(BusAccessResult::Conditional(false));
+                }
+                device.write(offset, data)?;
+                BusAccessResult::Conditional(true)
+            }
+            BusAccess::Amo(op, _, operand) => {
+                let old = device.read(offset, operand.len())?;
+                let new = op.apply(raw_to_u32(&old, endian), raw_to_u32(operand, endian));
+                device.write(offset, &u32_to_raw(new, endian))?;
+                BusAccessResult::Load(old)
+            }
+        };
+        if reserving {
+            self.reservations.push((requester, range.clone()));
+        }
+        // Memory has been written, invalidate reservations on the address
+        if request.writes() {
+            self.written = Some(range.clone());
+            self.reservations
+                .retain(|(_, reserved)| reserved.end <= range.start || reserved.start >= range.end);
+        }
+        Ok(result)
+    }
+    /// Turn printing of devices on or off
+    pub fn set_echo(&mut self, echo: bool) {
+        for m in self.mappings.iter_mut() {
+            m.device.set_echo(echo);
+        }
+    }
+    /// Return the access in flight, reservations, bus fault and state of each device by name
+    pub fn save(&self) -> Json {
+        let range =
+            |range: &Range<u32>| Json::Array(vec![Json::from(range.start), Json::from(range.end)]);
+        let request = self
+            .request
+            .as_ref()
+            .map_or(Json::Null, |(remain, request)| {
+                Json::object(vec![
+                    ("remain", Json::from(*remain)),
+                    ("request", request.save()),
+                ])
+            });
+        let reservations = self
+            .reservations
+            .iter()
+            .map(|(thread, reserved)| {
+                Json::object(vec![
+                    ("thread", Json::from(*thread)),
+                    ("range", range(reserved)),
+                ])
+            })
+            .collect();
+        let devices = self
+            .mappings
+            .iter()
+            .map(|m| (m.device.name(), m.device.save()))
+            .collect();
+        Json::object(vec![
+            ("request", request),
+            ("reservations", Json::Array(reservations)),
+            ("written", self.written.as_ref().map_or(Json::Null, range)),
+            (
+                "fault",
+                self.fault.as_ref().map_or(Json::Null, BusFault::save),
+            ),
+            ("devices", Json::object(devices)),
+        ])
+    }
+    /// Restore state returned by [MemoryBus::save], the same devices have to be mapped
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        let range = |range: &Json| match range.as_array()? {
+            [start, end] => Ok(start.as_u32()?..end.as_u32()?),
+            _ => Err(format!("Expect an address range, found {}", range)),
+        };
+        self.request = match state.get("request")?.opt() {
+            Some(request) => Some((
+                request.get("remain")?.as_usize()?,
+                BusAccessRequst::load(request.get("request")?)?,
+            )),
+            None => None,
+        };
+        self.reservations = state
+            .get("reservations")?
+            .as_array()?
+            .iter()
+            .map(|r| Ok((r.get("thread")?.as_usize()?, range(r.get("range")?)?)))
+            .collect::<Result<_, String>>()?;
+        self.written = state.get("written")?.opt().map(range).transpose()?;
+        self.fault = state.get("fault")?.opt().map(BusFault::load).transpose()?;
+        let devices = state.get("devices")?;
+        if devices.as_object()?.len() != self.mappings.len() {
+            return Err(String::from(
+                "Bus: devices of the snapshot differ from the machine",
+            ));
+        }
+        for m in self.mappings.iter_mut() {
+            let name = m.device.name();
+            m.device
+                .restore(devices.get(&name)?)
+                .map_err(|msg| format!("{}: {}", name, msg))?;
+        }
+        Ok(())
+    }
+    /// Return address range written in the last cycle
+    pub fn written(&self) -> Option<Range<u32>> {
+        self.written.clone()
+    }
+    /// Return the bus fault raised, if any
+    pub fn fault(&self) -> Option<&BusFault> {
+        self.fault.as_ref()
+    }
+    /// If any device requests the machine to stop, return Some(exit status).
+    /// Otherwise, return None.
+    pub fn exit_status(&self) -> Option<u32> {
+        self.mappings.iter().find_map(|m| m.device.exit_status())
+    }
+    /// Return interrupt lines requested by all devices
+    pub fn interrupt(&self) -> u32 {
+        self.mappings
+            .iter()
+            .fold(0, |lines, m| lines | m.device.interrupt())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod bus {
+    use super::*;
+    use crate::memory_bus::dram::Dram;
+
+    fn run(bus: &mut MemoryBus, request: BusAccessRequst) -> Result<BusAccessResult, String> {
+        bus.access(request)?;
+        loop {
+            if let Some(response) = bus.next_cycle() {
+                return response.into_result();
+            }
+            if let Some(fault) = bus.fault() {
+                return Err(fault.to_string());
+            }
+        }
+    }
+    #[test]
+    fn overlap_mapping() {
+        let mut bus = MemoryBus::new(Endian::Big);
+        bus.map(0, Box::new(Dram::new(16))).unwrap();
+        assert!(bus.map(8, Box::new(Dram::new(16))).is_err());
+        assert!(bus.map(16, Box::new(Dram::new(16))).is_ok());
+    }
+    #[test]
+    fn route_by_address() -> Result<(), String> {
+        let mut bus = MemoryBus::new(Endian::Big);
+        bus.map(0, Box::new(Dram::new(16)))?;
+        bus.map(0x100, Box::new(Dram::new(16)))?;
+
+        let data = vec![1, 2, 3, 4];
+        run(
+            &mut bus,
+            BusAccessRequst::new_store(String::new(), 0, 0x104, data.clone()),
+        )?;
+        let got = run(
+            &mut bus,
+            BusAccessRequst::new_load(String::new(), 0, 0x104, 4),
+        )?;
+        assert!(matches!(got, BusAccessResult::Load(v) if v == data));
+        let got = run(
+            &mut bus,
+            BusAccessRequst::new_load(String::new(), 0, 0x4, 4),
+        )?;
+        assert!(matches!(got, BusAccessResult::Load(v) if v == vec![0; 4]));
+        Ok(())
+    }
+    #[test]
+    fn atomic_access() -> Result<(), String> {
+        use crate::core::execution_path::AmoOp;
+        let path = || String::from("path");
+        let other = || String::from("other");
+        let mut bus = MemoryBus::new(Endian::Big);
+        bus.map(0, Box::new(Dram::new(16)))?;
+
+        let old = run(
+            &mut bus,
+            BusAccessRequst::new_amo(path(), 0, AmoOp::Add, 0, vec![0, 0, 0, 5]),
+        )?;
+        assert!(matches!(old, BusAccessResult::Load(v) if v == vec![0; 4]));
+        let old = run(
+            &mut bus,
+            BusAccessRequst::new_amo(path(), 0, AmoOp::Max, 0, vec![0xff; 4]),
+        )?;
+        assert!(matches!(old, BusAccessResult::Load(v) if v == vec![0, 0, 0, 5]));
+
+        // Store conditional without reservation fails
+        let sc = BusAccessRequst::new_store_conditional(path(), 0, 0, vec![0, 0, 0, 1]);
+        assert!(matches!(
+            run(&mut bus, sc)?,
+            BusAccessResult::Conditional(false)
+        ));
+        // Reservation is invalidated by store of another path
+        run(
+            &mut bus,
+            BusAccessRequst::new_load_reserved(path(), 0, 0, 4),
+        )?;
+        run(
+            &mut bus,
+            BusAccessRequst::new_store(other(), 0, 0, vec![0, 0, 0, 2]),
+        )?;
+        let sc = BusAccessRequst::new_store_conditional(path(), 0, 0, vec![0, 0, 0, 1]);
+        assert!(matches!(
+            run(&mut bus, sc)?,
+            BusAccessResult::Conditional(false)
+        ));
+
+        run(
+            &mut bus,
+            BusAccessRequst::new_load_reserved(path(), 0, 0, 4),
+        )?;
+        let sc = BusAccessRequst::new_store_conditional(path(), 0, 0, vec![0, 0, 0, 1]);
+        assert!(matches!(
+            run(&mut bus, sc)?,
+            BusAccessResult::Conditional(true)
+        ));
+        let got = run(&mut bus, BusAccessRequst::new_load(path(), 0, 0, 4))?;
+        assert!(matches!(got, BusAccessResult::Load(v) if v == vec![0, 0, 0, 1]));
+
+        // Reservation belongs to the thread, whichever path sends the access
+        run(
+            &mut bus,
+            BusAccessRequst::new_load_reserved(path(), 0, 0, 4).with_thread(1),
+        )?;
+        let sc = BusAccessRequst::new_store_conditional(other(), 0, 0, vec![0, 0, 0, 3]);
+        assert!(matches!(
+            run(&mut bus, sc)?,
+            BusAccessResult::Conditional(false)
+        ));
+        let sc = BusAccessRequst::new_store_conditional(other(), 0, 0, vec![0, 0, 0, 3]);
+        assert!(matches!(
+            run(&mut bus, sc.with_thread(1))?,
+            BusAccessResult::Conditional(true)
+        ));
+        Ok(())
+    }
+    #[test]
+    fn unmapped_access() {
+        let mut bus = MemoryBus::new(Endian::Big);
+        bus.map(0, Box::new(Dram::new(16))).unwrap();
+        let request = BusAccessRequst::new_load(String::new(), 0, 14, 4);
+        assert!(run(&mut bus, request).is_err());
+        let request = BusAccessRequst::new_load(String::new(), 0, 0x100, 4);
+        assert!(run(&mut bus, request).is_err());
+        let fault = bus.fault().expect("Unmapped access faults");
+        assert_eq!((fault.address, fault.is_load), (0x100, true));
+    }
+    #[test]
+    fn access_past_address_space() {
+        let mut bus = MemoryBus::new(Endian::Big);
+        bus.map(0, Box::new(Dram::new(16))).unwrap();
+        let request = BusAccessRequst::new_load(String::new(), 0, 0xffff_fffd, 4);
+        assert!(run(&mut bus, request).is_err());
+        let fault = bus.fault().expect("Access past the address space faults");
+        assert_eq!((fault.address, fault.is_load), (0xffff_fffd, true));
+    }
+}
@@ -0,0 +1,411 @@
+use std::fmt::{self, Display};
+
+use crate::core::execution_path::{BusAccess, BusAccessRequst, BusAccessResult};
+use crate::display::into_table;
+use crate::util::{raw_to_u32, u32_to_raw, Endian};
+
+/// Size of a cache line in bytes
+pub const LINE_SIZE: usize = 16;
+
+/// Base address of the line which contains the address
+pub fn line_base(address: u32) -> u32 {
+    address & !(LINE_SIZE as u32 - 1)
+}
+
+/// Snooping coherence protocol used by caches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Msi,
+    /// MSI with an exclusive state, which saves upgrades of private data
+    Mesi,
+}
+
+impl Protocol {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "msi" => Ok(Protocol::Msi),
+            "mesi" => Ok(Protocol::Mesi),
+            _ => Err(format!("Unknown coherence protocol {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineState {
+    Modified,
+    Exclusive,
+    Shared,
+    Invalid,
+}
+
+impl Display for LineState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            LineState::Modified => "M",
+            LineState::Exclusive => "E",
+            LineState::Shared => "S",
+            LineState::Invalid => "I",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// Transaction broadcasted on the shared bus
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transaction {
+    /// Read a line to share
+    BusRd,
+    /// Read a line to modify, other copies are invalidated
+    BusRdX,
+    /// Invalidate other copies of a shared line without reading it
+    BusUpgr,
+}
+
+impl Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Reaction of a cache to a transaction of another cache
+#[derive(Debug, Default)]
+pub struct Snoop {
+    /// The cache held a valid copy of the line
+    pub had_copy: bool,
+    /// Dirty data which have to be written back to memory
+    pub flushed: Option<Vec<u8>>,
+    /// The copy has been invalidated
+    pub invalidated: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CacheStatistics {
+    pub hits: usize,
+    pub misses: usize,
+    /// Writes to shared lines, which need no data but invalidate other copies
+    pub upgrades: usize,
+    /// Lines invalidated by transactions of other caches
+    pub invalidations: usize,
+    /// Dirty lines written back to memory
+    pub writebacks: usize,
+}
+
+impl Display for CacheStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hit {}, miss {}, upgrade {}, invalidated {}, writeback {}",
+            self.hits, self.misses, self.upgrades, self.invalidations, self.writebacks
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    base: u32,
+    state: LineState,
+    data: Vec<u8>,
+}
+
+/// Direct mapped, write back private cache which keeps coherent with other caches by snooping
+#[derive(Debug)]
+pub struct Cache {
+    name: String,
+    lines: Vec<Line>,
+    protocol: Protocol,
+    endian: Endian,
+    /// Base of the line reserved by load reserved
+    reservation: Option<u32>,
+    statistics: CacheStatistics,
+}
+
+impl Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rows = vec![format!("{}", self.statistics)];
+        if let Some(base) = self.reservation {
+            rows.push(format!("Reserved: {:#x}", base));
+        }
+        for (idx, line) in self.lines.iter().enumerate() {
+            if line.state != LineState::Invalid {
+                rows.push(format!("{}: {:#06x} {}", idx, line.base, line.state));
+            }
+        }
+        write!(f, "{}", into_table(&self.name, rows))
+    }
+}
+
+impl Cache {
+    pub fn new(name: String, lines: usize, protocol: Protocol, endian: Endian) -> Self {
+        let line = Line {
+            base: 0,
+            state: LineState::Invalid,
+            data: vec![0; LINE_SIZE],
+        };
+        Self {
+            name,
+            lines: vec![line; lines.max(1)],
+            protocol,
+            endian,
+            reservation: None,
+            statistics: CacheStatistics::default(),
+        }
+    }
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+    pub fn statistics(&self) -> &CacheStatistics {
+        &self.statistics
+    }
+    fn index(&self, base: u32) -> usize {
+        base as usize / LINE_SIZE % self.lines.len()
+    }
+    /// State of the line which contains the address
+    pub fn state(&self, address: u32) -> LineState {
+        let base = line_base(address);
+        let line = &self.lines[self.index(base)];
+        if line.base == base {
+            line.state
+        } else {
+            LineState::Invalid
+        }
+    }
+    fn line_mut(&mut self, base: u32) -> Option<&mut Line> {
+        let idx = self.index(base);
+        let line = &mut self.lines[idx];
+        if line.base == base && line.state != LineState::Invalid {
+            Some(line)
+        } else {
+            None
+        }
+    }
+    /// Return the transaction required before the access can be served.
+    /// None returned if the cache is able to serve it right now.
+    pub fn required_transaction(&self, request: &BusAccessRequst) -> Option<Transaction> {
+        let base = line_base(request.address());
+        // Store conditional without reservation fails without touching the line
+        if let BusAccess::StoreConditional(_, _) = request.request() {
+            if self.reservation != Some(base) {
+                return None;
+            }
+        }
+        match (self.state(base), request.writes()) {
+            (LineState::Invalid, false) => Some(Transaction::BusRd),
+            (LineState::Invalid, true) => Some(Transaction::BusRdX),
+            (LineState::Shared, true) => Some(Transaction::BusUpgr),
+            _ => None,
+        }
+    }
+    /// React to a transaction on the line, which is sent by another cache
+    pub fn snoop(&mut self, transaction: Transaction, base: u32) -> Snoop {
+        let mut snoop = Snoop::default();
+        let line = match self.line_mut(base) {
+            Some(line) => line,
+            None => return snoop,
+        };
+        snoop.had_copy = true;
+        if line.state == LineState::Modified {
+            snoop.flushed = Some(line.data.clone());
+        }
+        if transaction == Transaction::BusRd {
+            line.state = LineState::Shared;
+        } else {
+            line.state = LineState::Invalid;
+            snoop.invalidated = true;
+        }
+        if snoop.flushed.is_some() {
+            self.statistics.writebacks += 1;
+        }
+        if snoop.invalidated {
+            self.statistics.invalidations += 1;
+            if self.reservation == Some(base) {
+                self.reservation = None;
+            }
+        }
+        snoop
+    }
+    /// Evict the line which the given line is going to replace.
+    /// If the victim is dirty, return Some((base address, data)) to write back.
+    pub fn evict(&mut self, base: u32) -> Option<(u32, Vec<u8>)> {
+        let idx = self.index(base);
+        let victim = &mut self.lines[idx];
+        if victim.base == base || victim.state == LineState::Invalid {
+            return None;
+        }
+        let dirty = victim.state == LineState::Modified;
+        victim.state = LineState::Invalid;
+        if self.reservation == Some(victim.base) {
+            self.reservation = None;
+        }
+        if dirty {
+            self.statistics.writebacks += 1;
+            Some((victim.base, victim.data.clone()))
+        } else {
+            None
+        }
+    }
+    /// Install a line read by the transaction.
+    /// `shared` tells wheither other caches hold copies of the line or not.
+    pub fn fill(&mut self, base: u32, data: Vec<u8>, transaction: Transaction, shared: bool) {
+        let state = match transaction {
+            Transaction::BusRd if shared || self.protocol == Protocol::Msi => LineState::Shared,
+            Transaction::BusRd => LineState::Exclusive,
+            _ => LineState::Modified,
+        };
+        let idx = self.index(base);
+        self.lines[idx] = Line { base, state, data };
+        self.statistics.misses += 1;
+    }
+    /// Gain the ownership of a shared line after other copies invalidated
+    pub fn upgrade(&mut self, base: u32) {
+        if let Some(line) = self.line_mut(base) {
+            line.state = LineState::Modified;
+        }
+        self.statistics.upgrades += 1;
+    }
+    /// Count an access served without any transaction
+    pub fn hit(&mut self) {
+        self.statistics.hits += 1;
+    }
+    /// Serve the access with the line in the cache.
+    /// The line must have been brought into a proper state by [Cache::required_transaction].
+    pub fn perform(&mut self, request: &BusAccessRequst) -> Result<BusAccessResult, String> {
+        let address = request.address();
+        let base = line_base(address);
+        let offset = (address - base) as usize;
+        let len = request.access_len();
+        if offset + len > LINE_SIZE {
+            return Err(format!(
+                "{}: access at {:#x} crosses lines",
+                self.name, address
+            ));
+        }
+        let endian = self.endian;
+        let reserved = self.reservation == Some(base);
+        if let BusAccess::StoreConditional(_, _) | BusAccess::LoadReserved(_, _) = request.request()
+        {
+            self.reservation = None;
+        }
+        if let BusAccess::StoreConditional(_, _) = request.request() {
+            if !reserved {
+                return Ok(BusAccessResult::Conditional(false));
+            }
+        }
+        let name = self.name.clone();
+        let line = self
+            .line_mut(base)
+            .ok_or_else(|| format!("{}: line {:#x} not present", name, base))?;
+        let bytes = offset..offset + len;
+        let result = match request.request() {
+            BusAccess::Load(_, _) => BusAccessResult::Load(line.data[bytes].to_vec()),
+            BusAccess::LoadReserved(_, _) => {
+                let data = line.data[bytes].to_vec();
+                self.reservation = Some(base);
+                return Ok(BusAccessResult::Load(data));
+            }
+            BusAccess::Store(_, data) => {
+                line.data.splice(bytes, data.clone());
+                BusAccessResult::Store
+            }
+            BusAccess::StoreConditional(_, data) => {
+                line.data.splice(bytes, data.clone());
+                BusAccessResult::Conditional(true)
+            }
+            BusAccess::Amo(op, _, operand) => {
+                let old = line.data[bytes.clone()].to_vec();
+                let new = op.apply(raw_to_u32(&old, endian), raw_to_u32(operand, endian));
+                line.data.splice(bytes, u32_to_raw(new, endian));
+                BusAccessResult::Load(old)
+            }
+        };
+        if request.writes() {
+            line.state = LineState::Modified;
+        }
+        Ok(result)
+    }
+    /// Return dirty lines as (base address, data), which are newer than memory
+    pub fn dirty_lines(&self) -> Vec<(u32, Vec<u8>)> {
+        self.lines
+            .iter()
+            .filter(|line| line.state == LineState::Modified)
+            .map(|line| (line.base, line.data.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod cache {
+    use super::*;
+
+    fn load(address: u32) -> BusAccessRequst {
+        BusAccessRequst::new_load(String::from("unit"), 0, address, 4)
+    }
+    fn store(address: u32) -> BusAccessRequst {
+        BusAccessRequst::new_store(String::from("unit"), 0, address, vec![0, 0, 0, 1])
+    }
+    #[test]
+    fn mesi_states() -> Result<(), String> {
+        let mut cache = Cache::new(String::from("l1"), 4, Protocol::Mesi, Endian::Big);
+        assert_eq!(
+            cache.required_transaction(&load(0x14)),
+            Some(Transaction::BusRd)
+        );
+        cache.fill(0x10, vec![0; LINE_SIZE], Transaction::BusRd, false);
+        assert_eq!(cache.state(0x14), LineState::Exclusive);
+        // Exclusive lines are modified silently
+        assert_eq!(cache.required_transaction(&store(0x14)), None);
+        cache.perform(&store(0x14))?;
+        assert_eq!(cache.state(0x10), LineState::Modified);
+
+        let snoop = cache.snoop(Transaction::BusRd, 0x10);
+        assert!(snoop.had_copy);
+        assert_eq!(snoop.flushed.unwrap()[4..8], [0, 0, 0, 1]);
+        assert_eq!(cache.state(0x10), LineState::Shared);
+        assert_eq!(
+            cache.required_transaction(&store(0x10)),
+            Some(Transaction::BusUpgr)
+        );
+
+        let snoop = cache.snoop(Transaction::BusUpgr, 0x10);
+        assert!(snoop.invalidated && snoop.flushed.is_none());
+        assert_eq!(cache.state(0x10), LineState::Invalid);
+        Ok(())
+    }
+    #[test]
+    fn msi_shares_on_read() {
+        let mut cache = Cache::new(String::from("l1"), 4, Protocol::Msi, Endian::Big);
+        cache.fill(0x10, vec![0; LINE_SIZE], Transaction::BusRd, false);
+        assert_eq!(cache.state(0x10), LineState::Shared);
+    }
+    #[test]
+    fn eviction() -> Result<(), String> {
+        let mut cache = Cache::new(String::from("l1"), 1, Protocol::Mesi, Endian::Big);
+        cache.fill(0x10, vec![0; LINE_SIZE], Transaction::BusRdX, false);
+        cache.perform(&store(0x10))?;
+        let (base, data) = cache.evict(0x20).unwrap();
+        assert_eq!(base, 0x10);
+        assert_eq!(data[..4], [0, 0, 0, 1]);
+        assert_eq!(cache.state(0x10), LineState::Invalid);
+        Ok(())
+    }
+    #[test]
+    fn reservation_lost_on_invalidation() -> Result<(), String> {
+        let mut cache = Cache::new(String::from("l1"), 4, Protocol::Mesi, Endian::Big);
+        let sc = || BusAccessRequst::new_store_conditional(String::from("unit"), 0, 0, vec![0; 4]);
+        cache.fill(0, vec![0; LINE_SIZE], Transaction::BusRdX, false);
+        cache.perform(&BusAccessRequst::new_load_reserved(
+            String::from("unit"),
+            0,
+            0,
+            4,
+        ))?;
+        cache.snoop(Transaction::BusRdX, 0);
+        // Store conditional fails without any transaction
+        assert_eq!(cache.required_transaction(&sc()), None);
+        assert!(matches!(
+            cache.perform(&sc())?,
+            BusAccessResult::Conditional(false)
+        ));
+        Ok(())
+    }
+}
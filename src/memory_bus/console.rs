@@ -0,0 +1,88 @@
+use std::io::{self, Write};
+
+use super::bus::Device;
+use crate::util::json::Json;
+use crate::util::{raw_to_u32, Endian};
+
+const REGISTER_SIZE: usize = 4;
+const ACCESS_LATENCY: usize = 1;
+
+/// Console output register.
+/// Writing a word to the register prints its lowest byte as a character.
+#[derive(Debug, Clone)]
+pub struct Console {
+    /// Bytes which have been printed
+    output: Vec<u8>,
+    /// Print to stdout or not
+    echo: bool,
+    endian: Endian,
+}
+
+impl Console {
+    pub fn new(echo: bool, endian: Endian) -> Self {
+        Self {
+            output: Vec::new(),
+            echo,
+            endian,
+        }
+    }
+}
+
+impl Device for Console {
+    fn name(&self) -> String {
+        String::from("console")
+    }
+    fn size(&self) -> usize {
+        REGISTER_SIZE
+    }
+    fn latency(&self) -> usize {
+        ACCESS_LATENCY
+    }
+    fn read(&mut self, _offset: usize, len: usize) -> Result<Vec<u8>, String> {
+        Ok(vec![0; len])
+    }
+    fn write(&mut self, _offset: usize, data: &[u8]) -> Result<(), String> {
+        let byte = raw_to_u32(data, self.endian) as u8;
+        self.output.push(byte);
+        if self.echo {
+            let mut stdout = io::stdout();
+            stdout
+                .write_all(&[byte])
+                .and_then(|_| stdout.flush())
+                .map_err(|e| format!("Console: {}", e))?;
+        }
+        Ok(())
+    }
+    fn dump(&self) -> Vec<u8> {
+        self.output.clone()
+    }
+    fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+    /// Bytes printed so far, they are not printed again on restore
+    fn save(&self) -> Json {
+        Json::bytes(&self.output)
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.output = state.as_bytes()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod console {
+    use super::*;
+    #[test]
+    fn print_lowest_byte() {
+        let mut console = Console::new(false, Endian::Big);
+        console.write(0, &[0, 0, 0, b'O']).unwrap();
+        console.write(0, &[0, 0, 0x1, b'K']).unwrap();
+        assert_eq!(console.dump(), b"OK");
+
+        let mut console = Console::new(false, Endian::Little);
+        console.write(0, &[b'O', 0, 0, 0]).unwrap();
+        console.write(0, &[b'K', 0x1, 0, 0]).unwrap();
+        assert_eq!(console.dump(), b"OK");
+    }
+}
@@ -1,33 +1,41 @@
-use std::fmt::Display;
-
-use crate::core::execution_path::BusAccess;
-use crate::core::execution_path::BusAccessRequst;
-use crate::core::execution_path::BusAccessResponse;
-use crate::core::execution_path::BusAccessResult;
+use super::bus::Device;
+use crate::util::json::Json;
 
 const ACCESS_LATENCY: usize = 5;
 
+#[derive(Debug, Clone)]
 pub struct Dram {
     memory: Vec<u8>,
-    /// (remaining cycles, request handler)
-    request: Option<(usize, BusAccessRequst)>,
-}
-
-impl Display for Dram {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.request)
-    }
 }
 
 impl Dram {
     pub fn new(size: usize) -> Self {
         Self {
             memory: vec![0; size],
-            request: None,
         }
     }
+    /// Check wheither the address is in the bound of memory
+    pub fn bound_check(&self, address: usize) -> Result<(), String> {
+        if self.memory.len() <= address {
+            let msg = format!("DRAM: address {} out of bound", address);
+            return Err(msg);
+        }
+        Ok(())
+    }
+}
+
+impl Device for Dram {
+    fn name(&self) -> String {
+        String::from("dram")
+    }
+    fn size(&self) -> usize {
+        self.memory.len()
+    }
+    fn latency(&self) -> usize {
+        ACCESS_LATENCY
+    }
     /// Read len bytes from base adddress
-    fn read(&self, base: usize, len: usize) -> Result<Vec<u8>, String> {
+    fn read(&mut self, base: usize, len: usize) -> Result<Vec<u8>, String> {
         let fin = base + len - 1;
         let memory = &self.memory;
         self.bound_check(fin)?;
@@ -40,52 +48,29 @@ impl Dram {
         self.memory.splice(base..=fin, data.to_vec());
         Ok(())
     }
-    pub fn is_idle(&self) -> bool {
-        self.request.is_none()
+    fn dump(&self) -> Vec<u8> {
+        self.memory.clone()
     }
-    pub fn access(&mut self, request: BusAccessRequst) -> Result<(), String> {
-        if self.request.is_some() {
-            let msg = String::from("Memory is busy");
-            return Err(msg);
-        }
-        self.request = Some((ACCESS_LATENCY, request));
-        Ok(())
-    }
-    pub fn next_cycle(&mut self) -> Option<BusAccessResponse> {
-        let (remain_cycle, request) = self.request.take()?;
-
-        if remain_cycle > 0 {
-            self.request = Some((remain_cycle - 1, request));
-            return None;
-        }
-        let result = match request.request() {
-            BusAccess::Load(base, len) => {
-                let base = *base as usize;
-                let len = *len as usize;
-                self.read(base, len).map(BusAccessResult::Load)
-            }
-            BusAccess::Store(base, data) => self
-                .write(*base as usize, data)
-                .map(|_| BusAccessResult::Store),
-        };
-
-        Some(request.into_respose(result))
+    fn save(&self) -> Json {
+        Json::bytes(&self.memory)
     }
-    /// Check wheither the address is in the bound of memory
-    pub fn bound_check(&self, address: usize) -> Result<(), String> {
-        if self.memory.len() <= address {
-            let msg = format!("DRAM: address {} out of bound", address);
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        let memory = state.as_bytes()?;
+        if memory.len() != self.memory.len() {
+            let msg = format!(
+                "Snapshot has {} bytes of DRAM, but the machine has {}",
+                memory.len(),
+                self.memory.len()
+            );
             return Err(msg);
         }
+        self.memory = memory;
         Ok(())
     }
-    /// Consume the DRAM and return raw data inside
-    pub fn into_raw_data(self) -> Vec<u8> {
-        self.memory
-    }
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod dram {
     use super::*;
     #[test]
@@ -0,0 +1,52 @@
+use super::bus::Device;
+use crate::util::json::Json;
+use crate::util::{raw_to_u32, Endian};
+
+const REGISTER_SIZE: usize = 4;
+const ACCESS_LATENCY: usize = 1;
+
+/// Exit register.
+/// Writing a word to the register stops the machine with the word as exit status.
+#[derive(Debug, Clone)]
+pub struct Exit {
+    status: Option<u32>,
+    endian: Endian,
+}
+
+impl Exit {
+    pub fn new(endian: Endian) -> Self {
+        Self {
+            status: None,
+            endian,
+        }
+    }
+}
+
+impl Device for Exit {
+    fn name(&self) -> String {
+        String::from("exit")
+    }
+    fn size(&self) -> usize {
+        REGISTER_SIZE
+    }
+    fn latency(&self) -> usize {
+        ACCESS_LATENCY
+    }
+    fn read(&mut self, _offset: usize, len: usize) -> Result<Vec<u8>, String> {
+        Ok(vec![0; len])
+    }
+    fn write(&mut self, _offset: usize, data: &[u8]) -> Result<(), String> {
+        self.status = Some(raw_to_u32(data, self.endian));
+        Ok(())
+    }
+    fn exit_status(&self) -> Option<u32> {
+        self.status
+    }
+    fn save(&self) -> Json {
+        self.status.map_or(Json::Null, Json::from)
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.status = state.opt().map(Json::as_u32).transpose()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use super::bus::Device;
+use crate::util::json::Json;
+use crate::util::{raw_to_u32, u32_to_raw, Endian};
+
+const REGISTER_SIZE: usize = 4;
+const ACCESS_LATENCY: usize = 1;
+
+/// Mask of interrupt sources which are allowed to interrupt the processor
+const ENABLE_OFFSET: usize = 0;
+/// Interrupt sources which are requesting, writing 1 to a bit clears it
+const PENDING_OFFSET: usize = 4;
+/// Writing N arms the timer to raise an interrupt N cycles later, writing 0 disarms it
+const TIMER_OFFSET: usize = 8;
+
+/// Interrupt line of the timer source
+pub const TIMER_INTERRUPT: u32 = 1 << 0;
+
+/// Interrupt controller with a one-shot timer as interrupt source
+#[derive(Debug, Clone)]
+pub struct InterruptController {
+    enable: u32,
+    pending: u32,
+    /// Cycles remaining until the timer fires, None if the timer is disarmed
+    countdown: Option<u32>,
+    endian: Endian,
+}
+
+impl InterruptController {
+    pub fn new(endian: Endian) -> Self {
+        Self {
+            enable: 0,
+            pending: 0,
+            countdown: None,
+            endian,
+        }
+    }
+    fn register(offset: usize, len: usize) -> Result<usize, String> {
+        if !offset.is_multiple_of(REGISTER_SIZE) || len != REGISTER_SIZE {
+            let msg = format!("Interrupt controller: unaligned access at {}", offset);
+            return Err(msg);
+        }
+        Ok(offset)
+    }
+}
+
+impl Device for InterruptController {
+    fn name(&self) -> String {
+        String::from("intc")
+    }
+    fn size(&self) -> usize {
+        TIMER_OFFSET + REGISTER_SIZE
+    }
+    fn latency(&self) -> usize {
+        ACCESS_LATENCY
+    }
+    fn read(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, String> {
+        let val = match InterruptController::register(offset, len)? {
+            ENABLE_OFFSET => self.enable,
+            PENDING_OFFSET => self.pending,
+            _ => self.countdown.unwrap_or_default(),
+        };
+        Ok(u32_to_raw(val, self.endian))
+    }
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), String> {
+        let val = raw_to_u32(data, self.endian);
+        match InterruptController::register(offset, data.len())? {
+            ENABLE_OFFSET => self.enable = val,
+            PENDING_OFFSET => self.pending &= !val,
+            _ => self.countdown = if val == 0 { None } else { Some(val) },
+        }
+        Ok(())
+    }
+    fn next_cycle(&mut self) {
+        if let Some(remain) = self.countdown {
+            if remain <= 1 {
+                self.countdown = None;
+                self.pending |= TIMER_INTERRUPT;
+            } else {
+                self.countdown = Some(remain - 1);
+            }
+        }
+    }
+    fn interrupt(&self) -> u32 {
+        self.pending & self.enable
+    }
+    fn save(&self) -> Json {
+        Json::object(vec![
+            ("enable", Json::from(self.enable)),
+            ("pending", Json::from(self.pending)),
+            ("countdown", self.countdown.map_or(Json::Null, Json::from)),
+        ])
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.enable = state.get("enable")?.as_u32()?;
+        self.pending = state.get("pending")?.as_u32()?;
+        self.countdown = state
+            .get("countdown")?
+            .opt()
+            .map(Json::as_u32)
+            .transpose()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod interrupt_controller {
+    use super::*;
+
+    #[test]
+    fn timer() -> Result<(), String> {
+        let mut intc = InterruptController::new(Endian::Big);
+        intc.write(TIMER_OFFSET, &u32_to_raw(2, Endian::Big))?;
+        intc.next_cycle();
+        intc.next_cycle();
+        // Pending but masked
+        assert_eq!(intc.read(PENDING_OFFSET, 4)?, [0, 0, 0, 1]);
+        assert_eq!(intc.interrupt(), 0);
+
+        intc.write(ENABLE_OFFSET, &u32_to_raw(TIMER_INTERRUPT, Endian::Big))?;
+        assert_eq!(intc.interrupt(), TIMER_INTERRUPT);
+        intc.write(PENDING_OFFSET, &u32_to_raw(TIMER_INTERRUPT, Endian::Big))?;
+        assert_eq!(intc.interrupt(), 0);
+        assert!(intc.read(2, 4).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,597 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+
+use crate::core::execution_path::{BusAccessRequst, BusAccessResponse, BusAccessResult, RStag};
+use crate::display::into_table;
+use crate::util::json::Json;
+use crate::util::{raw_to_u32, Endian};
+
+/// Name of path which page table walker sends bus accesses by
+pub const PATH_NAME: &str = "mmu";
+
+const PAGE_BITS: u32 = 12;
+/// Bits of virtual page number in each level
+const VPN_BITS: u32 = 10;
+const LEVELS: usize = 2;
+const PTE_SIZE: usize = 4;
+
+const PTE_VALID: u32 = 1 << 0;
+const PTE_READ: u32 = 1 << 1;
+const PTE_WRITE: u32 = 1 << 2;
+const PTE_PPN_SHIFT: u32 = 10;
+
+/// Bits of page offset of a leaf in given level
+fn offset_bits(level: usize) -> u32 {
+    PAGE_BITS + VPN_BITS * level as u32
+}
+
+/// Virtual page number of given level
+fn vpn(address: u32, level: usize) -> u32 {
+    (address >> offset_bits(level)) & ((1 << VPN_BITS) - 1)
+}
+
+/// Address of the entry of given level for `address` in the page table at `table`,
+/// None if it's beyond the physical address space
+fn pte_address(table: u64, address: u32, level: usize) -> Option<u32> {
+    let offset = vpn(address, level) as u64 * PTE_SIZE as u64;
+    u32::try_from(table + offset).ok()
+}
+
+/// Why a page fault was raised
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultCause {
+    NotMapped,
+    InvalidTable,
+    MisalignedSuperpage,
+    NotReadable,
+    NotWritable,
+}
+
+impl FaultCause {
+    fn name(&self) -> &'static str {
+        match self {
+            FaultCause::NotMapped => "page not mapped",
+            FaultCause::InvalidTable => "invalid page table",
+            FaultCause::MisalignedSuperpage => "misaligned superpage",
+            FaultCause::NotReadable => "page not readable",
+            FaultCause::NotWritable => "page not writable",
+        }
+    }
+    fn of_name(name: &str) -> Result<Self, String> {
+        use FaultCause::*;
+        [
+            NotMapped,
+            InvalidTable,
+            MisalignedSuperpage,
+            NotReadable,
+            NotWritable,
+        ]
+        .iter()
+        .find(|cause| cause.name() == name)
+        .copied()
+        .ok_or_else(|| format!("Unknown page fault cause {}", name))
+    }
+}
+
+/// Access which the MMU refused to translate.
+/// It traps to the handler of the processor, otherwise the machine stops on it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageFault {
+    /// Virtual address accessed
+    pub address: u32,
+    pub is_load: bool,
+    /// Tag of the instruction which accessed
+    pub tag: RStag,
+    pub cause: FaultCause,
+}
+
+impl Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.is_load { "load" } else { "store" };
+        write!(
+            f,
+            "Page fault: {} at {:#x} from {} ({})",
+            access,
+            self.address,
+            self.tag,
+            self.cause.name()
+        )
+    }
+}
+
+impl PageFault {
+    fn new(request: &BusAccessRequst, cause: FaultCause) -> Self {
+        Self {
+            address: request.address(),
+            is_load: request.is_load(),
+            tag: request.tag(),
+            cause,
+        }
+    }
+    fn save(&self) -> Json {
+        Json::object(vec![
+            ("address", Json::from(self.address)),
+            ("load", Json::Bool(self.is_load)),
+            ("tag", self.tag.save()),
+            ("cause", Json::str(self.cause.name())),
+        ])
+    }
+    fn load(state: &Json) -> Result<Self, String> {
+        Ok(Self {
+            address: state.get("address")?.as_u32()?,
+            is_load: state.get("load")?.as_bool()?,
+            tag: RStag::load(state.get("tag")?)?,
+            cause: FaultCause::of_name(state.get("cause")?.as_str()?)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TlbEntry {
+    /// Virtual address >> offset bits
+    tag: u32,
+    /// Physical address >> offset bits
+    frame: u32,
+    /// Offset bits of the page, which is larger than PAGE_BITS for superpages
+    offset_bits: u32,
+    /// Permission bits of the page
+    flags: u32,
+    /// Time stamp of last hit, used to find least recently used entry
+    last_used: u64,
+}
+
+impl TlbEntry {
+    fn matches(&self, address: u32) -> bool {
+        address >> self.offset_bits == self.tag
+    }
+    fn translate(&self, address: u32) -> u32 {
+        let mask = (1 << self.offset_bits) - 1;
+        (self.frame << self.offset_bits) | (address & mask)
+    }
+}
+
+/// Fully associative translation lookaside buffer with LRU replacement
+#[derive(Debug, Clone)]
+struct Tlb {
+    entries: Vec<TlbEntry>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl Tlb {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: Vec::with_capacity(capacity),
+            capacity,
+            clock: 0,
+        }
+    }
+    /// Find the entry which maps given address
+    fn lookup(&mut self, address: u32) -> Option<&TlbEntry> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.iter_mut().find(|e| e.matches(address))?;
+        entry.last_used = clock;
+        Some(entry)
+    }
+    /// Insert an entry, evict the least recently used one if the TLB is full
+    fn fill(&mut self, mut entry: TlbEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        entry.last_used = self.clock;
+        if self.entries.len() < self.capacity {
+            self.entries.push(entry);
+        } else if let Some(victim) = self.entries.iter_mut().min_by_key(|e| e.last_used) {
+            *victim = entry;
+        }
+    }
+}
+
+/// Progress of a page table walk
+#[derive(Debug, Clone)]
+enum Walk {
+    /// Page table entry of given level at given address is going to be read
+    Pending(usize, u32),
+    /// Page table entry of given level is being read
+    Reading(usize),
+}
+
+/// Memory management unit which translates virtual addresses of bus accesses into physical ones
+#[derive(Debug, Clone)]
+pub struct Mmu {
+    tlb: Tlb,
+    /// Physical address of root page table
+    root: u32,
+    /// Byte order of page table entries
+    endian: Endian,
+    /// Access being translated and progress of its page table walk
+    translating: Option<(BusAccessRequst, Walk)>,
+    /// Translated access waiting for the bus
+    translated: Option<BusAccessRequst>,
+    /// Fault of the access refused, no access is translated after it
+    fault: Option<PageFault>,
+    hits: usize,
+    misses: usize,
+}
+
+impl Display for Mmu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rows = vec![format!(
+            "Root: {:#x}, TLB hit: {}, miss: {}",
+            self.root, self.hits, self.misses
+        )];
+        if let Some((request, walk)) = self.translating.as_ref() {
+            rows.push(format!("Walking {:?} for {}", walk, request));
+        }
+        if let Some(fault) = self.fault.as_ref() {
+            rows.push(fault.to_string());
+        }
+        for e in self.tlb.entries.iter() {
+            let mask = (1u64 << e.offset_bits) - 1;
+            let va = e.tag << e.offset_bits;
+            let pa = e.frame << e.offset_bits;
+            rows.push(format!(
+                "{:#x}-{:#x} => {:#x} ({}{})",
+                va,
+                va as u64 + mask,
+                pa,
+                if e.flags & PTE_READ != 0 { "r" } else { "-" },
+                if e.flags & PTE_WRITE != 0 { "w" } else { "-" },
+            ));
+        }
+        write!(f, "{}", into_table("MMU", rows))
+    }
+}
+
+impl Mmu {
+    pub fn new(root: u32, tlb_entries: usize, endian: Endian) -> Self {
+        Self {
+            tlb: Tlb::new(tlb_entries),
+            root,
+            endian,
+            translating: None,
+            translated: None,
+            fault: None,
+            hits: 0,
+            misses: 0,
+        }
+    }
+    /// Return root, TLB, and the access being translated
+    pub fn save(&self) -> Json {
+        let entries = self
+            .tlb
+            .entries
+            .iter()
+            .map(|e| {
+                Json::object(vec![
+                    ("tag", Json::from(e.tag)),
+                    ("frame", Json::from(e.frame)),
+                    ("offset_bits", Json::from(e.offset_bits)),
+                    ("flags", Json::from(e.flags)),
+                    ("last_used", Json::from(e.last_used)),
+                ])
+            })
+            .collect();
+        let translating = self
+            .translating
+            .as_ref()
+            .map_or(Json::Null, |(request, walk)| {
+                let (level, address) = match walk {
+                    Walk::Pending(level, address) => (*level, Json::from(*address)),
+                    Walk::Reading(level) => (*level, Json::Null),
+                };
+                Json::object(vec![
+                    ("request", request.save()),
+                    ("level", Json::from(level)),
+                    ("pending", address),
+                ])
+            });
+        let translated = self
+            .translated
+            .as_ref()
+            .map_or(Json::Null, BusAccessRequst::save);
+        Json::object(vec![
+            ("root", Json::from(self.root)),
+            ("tlb_capacity", Json::from(self.tlb.capacity)),
+            ("tlb_clock", Json::from(self.tlb.clock)),
+            ("tlb", Json::Array(entries)),
+            ("translating", translating),
+            ("translated", translated),
+            (
+                "fault",
+                self.fault.as_ref().map_or(Json::Null, PageFault::save),
+            ),
+            ("hits", Json::from(self.hits)),
+            ("misses", Json::from(self.misses)),
+        ])
+    }
+    /// Construct a MMU from state returned by [Mmu::save]
+    pub fn load(state: &Json, endian: Endian) -> Result<Self, String> {
+        let mut tlb = Tlb::new(state.get("tlb_capacity")?.as_usize()?);
+        tlb.clock = state.get("tlb_clock")?.as_u64()?;
+        for e in state.get("tlb")?.as_array()? {
+            tlb.entries.push(TlbEntry {
+                tag: e.get("tag")?.as_u32()?,
+                frame: e.get("frame")?.as_u32()?,
+                offset_bits: e.get("offset_bits")?.as_u32()?,
+                flags: e.get("flags")?.as_u32()?,
+                last_used: e.get("last_used")?.as_u64()?,
+            });
+        }
+        let translating = match state.get("translating")?.opt() {
+            Some(walk) => {
+                let level = walk.get("level")?.as_usize()?;
+                let progress = match walk.get("pending")?.opt() {
+                    Some(address) => Walk::Pending(level, address.as_u32()?),
+                    None => Walk::Reading(level),
+                };
+                Some((BusAccessRequst::load(walk.get("request")?)?, progress))
+            }
+            None => None,
+        };
+        let translated = state
+            .get("translated")?
+            .opt()
+            .map(BusAccessRequst::load)
+            .transpose()?;
+        Ok(Self {
+            tlb,
+            root: state.get("root")?.as_u32()?,
+            endian,
+            translating,
+            translated,
+            fault: state.get("fault")?.opt().map(PageFault::load).transpose()?,
+            hits: state.get("hits")?.as_usize()?,
+            misses: state.get("misses")?.as_usize()?,
+        })
+    }
+    /// Return wheither the MMU is able to accept another access or not
+    pub fn is_idle(&self) -> bool {
+        self.translating.is_none() && self.translated.is_none() && self.fault.is_none()
+    }
+    /// Return the page fault raised, if any
+    pub fn fault(&self) -> Option<&PageFault> {
+        self.fault.as_ref()
+    }
+    /// Take the page fault raised to trap it, then accesses are translated again
+    pub fn take_fault(&mut self) -> Option<PageFault> {
+        self.fault.take()
+    }
+    /// Return wheither the response belongs to the page table walker or not
+    pub fn owns(&self, response: &BusAccessResponse) -> bool {
+        response.path_name() == PATH_NAME
+    }
+    /// Return (hit, miss) count of the TLB
+    pub fn tlb_statistics(&self) -> (usize, usize) {
+        (self.hits, self.misses)
+    }
+    /// Start translating an access.
+    /// On TLB miss, a page table walk starts.
+    /// If the access violates protection of the page, a page fault is raised.
+    pub fn translate(&mut self, request: BusAccessRequst) {
+        let address = request.address();
+        let hit = self
+            .tlb
+            .lookup(address)
+            .map(|entry| (entry.translate(address), entry.flags));
+        if let Some((physical, flags)) = hit {
+            self.hits += 1;
+            self.complete(request, physical, flags);
+        } else {
+            self.misses += 1;
+            let level = LEVELS - 1;
+            match pte_address(self.root as u64, address, level) {
+                Some(pte) => self.translating = Some((request, Walk::Pending(level, pte))),
+                None => self.raise(&request, FaultCause::InvalidTable),
+            }
+        }
+    }
+    /// Return next access to send to the bus, which is either a translated access or a page table read.
+    pub fn bus_access(&mut self) -> Option<BusAccessRequst> {
+        if let Some(request) = self.translated.take() {
+            return Some(request);
+        }
+        let (_, walk) = self.translating.as_mut()?;
+        if let Walk::Pending(level, address) = *walk {
+            *walk = Walk::Reading(level);
+            Some(BusAccessRequst::new_load(
+                PATH_NAME.to_string(),
+                level,
+                address,
+                PTE_SIZE,
+            ))
+        } else {
+            None
+        }
+    }
+    /// Resolve a page table entry read by the walker.
+    /// If the walk finds the address unmapped or protected, a page fault is raised.
+    pub fn resolve(&mut self, response: BusAccessResponse) -> Result<(), String> {
+        let (request, walk) = self
+            .translating
+            .take()
+            .ok_or_else(|| String::from("MMU: Unexpected page table entry"))?;
+        let level = match walk {
+            Walk::Reading(level) => level,
+            Walk::Pending(_, _) => return Err(String::from("MMU: Page table entry not requested")),
+        };
+        let pte = match response.into_result()? {
+            BusAccessResult::Load(raw) => raw_to_u32(&raw, self.endian),
+            _ => return Err(String::from("MMU: Expect page table entry")),
+        };
+        let address = request.address();
+        if pte & PTE_VALID == 0 {
+            self.raise(&request, FaultCause::NotMapped);
+            return Ok(());
+        }
+        let table = ((pte >> PTE_PPN_SHIFT) as u64) << PAGE_BITS;
+        if pte & (PTE_READ | PTE_WRITE) == 0 {
+            // Pointer to next level page table
+            let next = level.checked_sub(1);
+            match next.and_then(|next| Some((next, pte_address(table, address, next)?))) {
+                Some((next, pte)) => self.translating = Some((request, Walk::Pending(next, pte))),
+                None => self.raise(&request, FaultCause::InvalidTable),
+            }
+            return Ok(());
+        }
+        let offset_bits = offset_bits(level);
+        // Frame of a superpage is aligned to its size
+        if table & ((1 << offset_bits) - 1) != 0 {
+            self.raise(&request, FaultCause::MisalignedSuperpage);
+            return Ok(());
+        }
+        let frame = match u32::try_from(table) {
+            Ok(base) => base >> offset_bits,
+            Err(_) => {
+                self.raise(&request, FaultCause::InvalidTable);
+                return Ok(());
+            }
+        };
+        let entry = TlbEntry {
+            tag: address >> offset_bits,
+            frame,
+            offset_bits,
+            flags: pte,
+            last_used: 0,
+        };
+        let physical = entry.translate(address);
+        self.tlb.fill(entry);
+        self.complete(request, physical, pte);
+        Ok(())
+    }
+    /// Send the access to `physical` if the page permits it, otherwise raise a page fault
+    fn complete(&mut self, request: BusAccessRequst, physical: u32, flags: u32) {
+        if request.reads() && flags & PTE_READ == 0 {
+            self.raise(&request, FaultCause::NotReadable);
+        } else if request.writes() && flags & PTE_WRITE == 0 {
+            self.raise(&request, FaultCause::NotWritable);
+        } else {
+            self.translated = Some(request.relocate(physical));
+        }
+    }
+    /// Raise a page fault. The page is dropped from the TLB,
+    /// so the access sees the page table again once the handler fixed it.
+    fn raise(&mut self, request: &BusAccessRequst, cause: FaultCause) {
+        let address = request.address();
+        self.tlb.entries.retain(|entry| !entry.matches(address));
+        self.fault = Some(PageFault::new(request, cause));
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod mmu {
+    use super::*;
+
+    fn entry(tag: u32, frame: u32) -> TlbEntry {
+        TlbEntry {
+            tag,
+            frame,
+            offset_bits: PAGE_BITS,
+            flags: PTE_VALID | PTE_READ,
+            last_used: 0,
+        }
+    }
+    #[test]
+    fn tlb_lru() {
+        let mut tlb = Tlb::new(2);
+        tlb.fill(entry(1, 10));
+        tlb.fill(entry(2, 20));
+        assert!(tlb.lookup(1 << PAGE_BITS).is_some());
+        tlb.fill(entry(3, 30));
+
+        assert!(tlb.lookup(1 << PAGE_BITS).is_some());
+        assert!(tlb.lookup(2 << PAGE_BITS).is_none());
+        let hit = tlb.lookup((3 << PAGE_BITS) + 4).unwrap();
+        assert_eq!(hit.translate((3 << PAGE_BITS) + 4), (30 << PAGE_BITS) + 4);
+    }
+    #[test]
+    fn superpage() {
+        let offset_bits = offset_bits(1);
+        let e = TlbEntry {
+            tag: 0x40000000 >> offset_bits,
+            frame: 0x400000 >> offset_bits,
+            offset_bits,
+            flags: PTE_VALID | PTE_READ,
+            last_used: 0,
+        };
+        assert!(e.matches(0x40123456));
+        assert_eq!(e.translate(0x40123456), 0x523456);
+    }
+    #[test]
+    fn walk() -> Result<(), String> {
+        let mut mmu = Mmu::new(0x1000, 4, Endian::Big);
+        let request = BusAccessRequst::new_load(String::from("unit"), 0, 0x00401004, 4);
+        mmu.translate(request);
+
+        let read = mmu.bus_access().unwrap();
+        assert_eq!(read.address(), 0x1000 + 4);
+        let pte = (0x2000 >> PAGE_BITS << PTE_PPN_SHIFT) | PTE_VALID;
+        let response = read.into_respose(Ok(BusAccessResult::Load(pte.to_be_bytes().to_vec())));
+        mmu.resolve(response)?;
+
+        let read = mmu.bus_access().unwrap();
+        assert_eq!(read.address(), 0x2000 + 4);
+        let pte = (0x5000 >> PAGE_BITS << PTE_PPN_SHIFT) | PTE_VALID | PTE_READ;
+        let response = read.into_respose(Ok(BusAccessResult::Load(pte.to_be_bytes().to_vec())));
+        mmu.resolve(response)?;
+
+        let translated = mmu.bus_access().unwrap();
+        assert_eq!(translated.address(), 0x5004);
+        assert!(mmu.is_idle());
+
+        let request = BusAccessRequst::new_store(String::from("unit"), 0, 0x00401008, vec![0; 4]);
+        mmu.translate(request);
+        assert_eq!(mmu.tlb_statistics(), (1, 1));
+        let fault = mmu.fault().unwrap();
+        assert_eq!((fault.address, fault.is_load), (0x00401008, false));
+        assert_eq!(fault.cause, FaultCause::NotWritable);
+        assert!(!mmu.is_idle());
+        assert!(mmu.bus_access().is_none());
+
+        // Once the fault is trapped, the page is walked again
+        assert!(mmu.take_fault().is_some());
+        assert!(mmu.is_idle());
+        let request = BusAccessRequst::new_load(String::from("unit"), 0, 0x00401008, 4);
+        mmu.translate(request);
+        assert_eq!(mmu.tlb_statistics(), (1, 2));
+        Ok(())
+    }
+    /// Walk a single level from the root whose entry is `pte`, return the MMU afterward
+    fn walk_root(root: u32, pte: u32) -> Result<Mmu, String> {
+        let mut mmu = Mmu::new(root, 4, Endian::Big);
+        let request = BusAccessRequst::new_load(String::from("unit"), 0, 0x00401004, 4);
+        mmu.translate(request);
+        if let Some(read) = mmu.bus_access() {
+            let response = read.into_respose(Ok(BusAccessResult::Load(pte.to_be_bytes().to_vec())));
+            mmu.resolve(response)?;
+        }
+        Ok(mmu)
+    }
+    #[test]
+    fn faults() -> Result<(), String> {
+        let cause = |mmu: Mmu| mmu.fault().map(|fault| fault.cause);
+        assert_eq!(cause(walk_root(0x1000, 0)?), Some(FaultCause::NotMapped));
+        // Superpage frames are aligned to 4 MiB
+        let pte = (0x1000 >> PAGE_BITS << PTE_PPN_SHIFT) | PTE_VALID | PTE_READ;
+        assert_eq!(
+            cause(walk_root(0x1000, pte)?),
+            Some(FaultCause::MisalignedSuperpage)
+        );
+        let pte = (0x400000 >> PAGE_BITS << PTE_PPN_SHIFT) | PTE_VALID | PTE_READ;
+        assert_eq!(cause(walk_root(0x1000, pte)?), None);
+        // Entries beyond the address space can't be read
+        assert_eq!(
+            cause(walk_root(u32::MAX - 2, 0)?),
+            Some(FaultCause::InvalidTable)
+        );
+        let pte = (u32::MAX >> PTE_PPN_SHIFT << PTE_PPN_SHIFT) | PTE_VALID;
+        assert_eq!(
+            cause(walk_root(0x1000, pte)?),
+            Some(FaultCause::InvalidTable)
+        );
+        Ok(())
+    }
+}
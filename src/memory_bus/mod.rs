@@ -1 +1,8 @@
+pub mod bus;
+pub mod cache;
+pub mod console;
 pub mod dram;
+pub mod exit;
+pub mod interrupt;
+pub mod mmu;
+pub mod timer;
@@ -0,0 +1,49 @@
+use super::bus::Device;
+use crate::util::json::Json;
+use crate::util::{u32_to_raw, Endian};
+
+const REGISTER_SIZE: usize = 4;
+const ACCESS_LATENCY: usize = 1;
+
+/// Cycle counter register.
+/// Reading the register returns cycles elapsed since the machine started.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    cycle: u32,
+    endian: Endian,
+}
+
+impl Timer {
+    pub fn new(endian: Endian) -> Self {
+        Self { cycle: 0, endian }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> String {
+        String::from("timer")
+    }
+    fn size(&self) -> usize {
+        REGISTER_SIZE
+    }
+    fn latency(&self) -> usize {
+        ACCESS_LATENCY
+    }
+    fn read(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, String> {
+        let raw = u32_to_raw(self.cycle, self.endian);
+        Ok(raw[offset..offset + len].to_vec())
+    }
+    fn write(&mut self, _offset: usize, _data: &[u8]) -> Result<(), String> {
+        Err(String::from("Timer: register is read only"))
+    }
+    fn next_cycle(&mut self) {
+        self.cycle = self.cycle.wrapping_add(1);
+    }
+    fn save(&self) -> Json {
+        Json::from(self.cycle)
+    }
+    fn restore(&mut self, state: &Json) -> Result<(), String> {
+        self.cycle = state.as_u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,806 @@
+use crate::virtual_machine::debugger::{Debugger, Stop, StopCondition};
+use crate::virtual_machine::multi_core::MultiCoreMachine;
+use crate::virtual_machine::timeline::{RunState, Timeline};
+use crate::virtual_machine::Machine;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{BufRead, Write};
+use std::process::{self, Stdio};
+
+/// Switch to the alternate screen, and back
+const ENTER_SCREEN: &str = "\x1b[?1049h";
+const LEAVE_SCREEN: &str = "\x1b[?1049l";
+/// Move the cursor home and clear the screen
+const CLEAR: &str = "\x1b[H\x1b[2J";
+const HIGHLIGHT: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+/// Cycles a single command runs at most, so that endless loops give control back
+const RUN_LIMIT: usize = 100_000;
+/// Lines of source shown around the PC
+const SOURCE_LINES: usize = 16;
+/// Screen size if the output is not a terminal
+const DEFAULT_SIZE: (usize, usize) = (160, 48);
+const HELP: &str =
+    "Enter: step | s: step N | r: run | c: continue | b: toggle stop condition | u: back | \
+                    g: go to cycle | w: go to last write | : command, e.g. save PATH | q: quit";
+const MULTI_CORE_HELP: &str = "Enter: step | s: step N | r: run | q: quit";
+/// Keys which open the prompt to type their argument
+const PROMPT_KEYS: &str = "sbgw";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Step(usize),
+    Run,
+    /// Run until a stop condition hits
+    Continue,
+    /// Add the stop condition, or remove it if exists
+    Toggle(String),
+    Back,
+    Goto(usize),
+    /// Go to the cycle a register (number, thread) was last written
+    LastWrite(usize, usize),
+    /// Write a snapshot of the machine to the path
+    Save(String),
+    Quit,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |word: Option<&&str>| {
+            word.map_or(Ok(1), |w| w.parse())
+                .map_err(|_| format!("Expect a number: {}", line.trim()))
+        };
+        let register = |word: Option<&&str>| {
+            word.and_then(|w| w.strip_prefix('R'))
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| format!("Expect a register: {}", line.trim()))
+        };
+        match words.first() {
+            None => Ok(Command::Step(1)),
+            Some(&"s") => Ok(Command::Step(number(words.get(1))?)),
+            Some(&"r") => Ok(Command::Run),
+            Some(&"c") => Ok(Command::Continue),
+            Some(&"b") if words.len() == 2 => Ok(Command::Toggle(words[1].to_string())),
+            Some(&"u") => Ok(Command::Back),
+            Some(&"g") if words.len() == 2 => Ok(Command::Goto(number(words.get(1))?)),
+            Some(&"w") if words.len() >= 2 => {
+                let thread = words.get(2).map_or(Ok(0), |_| number(words.get(2)))?;
+                Ok(Command::LastWrite(register(words.get(1))?, thread))
+            }
+            Some(&"save") if words.len() == 2 => Ok(Command::Save(words[1].to_string())),
+            Some(&"q") => Ok(Command::Quit),
+            _ => Err(format!("Unknown command: {}", line.trim())),
+        }
+    }
+}
+
+/// Terminal switched to raw mode, where each key is read as it's pressed without echo.
+/// The previous mode is restored on drop.
+pub struct RawMode {
+    /// Settings of the terminal before, as printed by `stty -g`
+    saved: String,
+}
+
+impl RawMode {
+    pub fn enter() -> Result<Self, String> {
+        let saved = stty(&["-g"]).ok_or("--tui requires a terminal")?;
+        // Signals are off too, so Ctrl-C reaches the UI and the terminal is restored on quit
+        stty(&[
+            "-icanon", "-echo", "-isig", "-ixon", "min", "1", "time", "0",
+        ])
+        .ok_or("Failed to switch the terminal to raw mode")?;
+        Ok(Self {
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[self.saved.as_str()]);
+    }
+}
+
+/// Run stty on the terminal of stdin, return its output
+fn stty(args: &[&str]) -> Option<String> {
+    let output = process::Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Return (columns, lines) of the terminal of stdin
+fn terminal_size() -> Option<(usize, usize)> {
+    let size = stty(&["size"])?;
+    let (lines, columns) = size.trim().split_once(' ')?;
+    match (columns.parse(), lines.parse()) {
+        (Ok(columns), Ok(lines)) if columns > 0 && lines > 0 => Some((columns, lines)),
+        _ => None,
+    }
+}
+
+/// A terminal UI, which executes commands entered by keys and draws its screen
+trait Ui {
+    fn screen(&mut self) -> &mut Screen;
+    fn execute(&mut self, command: Command);
+    fn draw(&mut self) -> String;
+}
+
+/// Read keys from `input` and draw the screen of `ui` to `out` until quit or input ends.
+/// The terminal should be in [RawMode], so keys arrive as they're pressed.
+fn run_ui<U: Ui, R: BufRead, W: Write>(ui: &mut U, input: R, mut out: W) -> Result<(), String> {
+    let err = |e: std::io::Error| format!("Terminal: {}", e);
+    write!(out, "{}", ENTER_SCREEN).map_err(err)?;
+    let mut keys = input.bytes();
+    loop {
+        // The terminal may be resized at any time
+        if let Some((width, height)) = terminal_size() {
+            ui.screen().width = width;
+            ui.screen().height = height;
+        }
+        let prompt = ui
+            .screen()
+            .prompt
+            .as_ref()
+            .map_or(String::new(), |p| format!("> {}", p));
+        write!(out, "{}{}{}", CLEAR, ui.draw(), prompt).map_err(err)?;
+        out.flush().map_err(err)?;
+        let Some(key) = keys.next() else {
+            break;
+        };
+        match ui.screen().key(key.map_err(err)?) {
+            Some(Command::Quit) => break,
+            Some(command) => ui.execute(command),
+            None => (),
+        }
+    }
+    write!(out, "{}", LEAVE_SCREEN).map_err(err)?;
+    out.flush().map_err(err)
+}
+
+/// Prompt, message and layout of panes on the screen, shared by terminal UIs
+struct Screen {
+    /// Panes of the previous cycle, to highlight changes
+    previous: Vec<String>,
+    layout: Layout,
+    /// Command being typed, if the prompt is open
+    prompt: Option<String>,
+    message: String,
+    width: usize,
+    height: usize,
+}
+
+impl Screen {
+    fn new() -> Self {
+        let (width, height) = DEFAULT_SIZE;
+        Self {
+            previous: Vec::new(),
+            layout: Layout::default(),
+            prompt: None,
+            message: String::new(),
+            width,
+            height,
+        }
+    }
+    /// Handle a key, return the command entered if any
+    fn key(&mut self, key: u8) -> Option<Command> {
+        const CTRL_C: u8 = 0x03;
+        const BACKSPACE: u8 = 0x08;
+        const ESCAPE: u8 = 0x1b;
+        const DELETE: u8 = 0x7f;
+        let Some(line) = self.prompt.as_mut() else {
+            self.message.clear();
+            return match key {
+                b'\r' | b'\n' | b' ' => Some(Command::Step(1)),
+                b'r' => Some(Command::Run),
+                b'c' => Some(Command::Continue),
+                b'u' => Some(Command::Back),
+                b'q' | CTRL_C => Some(Command::Quit),
+                b':' => {
+                    self.prompt = Some(String::new());
+                    None
+                }
+                key if PROMPT_KEYS.contains(char::from(key)) => {
+                    self.prompt = Some(format!("{} ", char::from(key)));
+                    None
+                }
+                ESCAPE => None,
+                key => {
+                    self.message = format!("Unknown key: {}", char::from(key).escape_default());
+                    None
+                }
+            };
+        };
+        match key {
+            b'\r' | b'\n' => {
+                let line = self.prompt.take().unwrap_or_default();
+                self.message.clear();
+                match Command::parse(&line) {
+                    Ok(command) => return Some(command),
+                    Err(msg) => self.message = msg,
+                }
+            }
+            BACKSPACE | DELETE if line.is_empty() => self.prompt = None,
+            BACKSPACE | DELETE => {
+                line.pop();
+            }
+            ESCAPE | CTRL_C => self.prompt = None,
+            key if key.is_ascii_graphic() || key == b' ' => line.push(char::from(key)),
+            _ => (),
+        }
+        None
+    }
+    /// Draw panes, highlighting changes from the previous cycle, above the status line and help
+    fn render(&mut self, panes: &[String], status: &str, help: &str) -> String {
+        let panes = titled(panes);
+        self.layout.place(&panes, self.height.saturating_sub(3));
+        let mut screen = self
+            .layout
+            .render(&panes, &titled(&self.previous), self.width);
+        screen.push_str(&format!("{} {}\n{}\n", status, self.message, help));
+        screen
+    }
+}
+
+/// Full-screen terminal UI
+pub struct Tui {
+    debugger: Debugger,
+    /// Labels of the program, which stop conditions may refer to
+    labels: BTreeMap<String, usize>,
+    screen: Screen,
+}
+
+impl Ui for Tui {
+    fn screen(&mut self) -> &mut Screen {
+        &mut self.screen
+    }
+    fn execute(&mut self, command: Command) {
+        let (cycle, panes) = (self.timeline().cycle(), self.panes());
+        match command {
+            Command::Step(n) => {
+                for _ in 0..n {
+                    if !self.timeline_mut().step() {
+                        break;
+                    }
+                }
+            }
+            Command::Run => self.run_to_end(),
+            Command::Continue => match self.debugger.run(RUN_LIMIT) {
+                Stop::Hit(ids) => {
+                    let hits: Vec<String> = ids
+                        .iter()
+                        .map(|id| self.debugger.conditions()[id].to_string())
+                        .collect();
+                    self.screen.message = format!("Stopped by {}", hits.join(", "));
+                }
+                Stop::Finished => (),
+                Stop::Limit => self.screen.message = format!("Stopped after {} cycles", RUN_LIMIT),
+            },
+            Command::Toggle(spec) => self.toggle(&spec),
+            Command::Back => {
+                if !self.timeline_mut().step_back() {
+                    self.screen.message = String::from("Already at the first cycle");
+                }
+            }
+            Command::Goto(cycle) => {
+                if let Err(msg) = self.timeline_mut().goto(cycle) {
+                    self.screen.message = msg;
+                }
+            }
+            Command::LastWrite(register, thread) => {
+                match self.timeline().last_write(thread, register) {
+                    Some(cycle) => {
+                        let _ = self.timeline_mut().goto(cycle);
+                    }
+                    None => {
+                        self.screen.message =
+                            format!("R{} of thread {} not written yet", register, thread)
+                    }
+                }
+            }
+            Command::Save(path) => {
+                let snapshot = self.timeline().machine().save().to_string();
+                self.screen.message = match fs::write(&path, snapshot) {
+                    Ok(()) => format!("Saved to {}", path),
+                    Err(e) => format!("{}: {}", path, e),
+                };
+            }
+            Command::Quit => (),
+        }
+        if self.timeline().cycle() != cycle {
+            self.screen.previous = self.previous_panes(cycle, panes);
+        }
+    }
+    fn draw(&mut self) -> String {
+        let state = match self.timeline().state() {
+            RunState::Running => String::from("running"),
+            RunState::Flushing => String::from("flushing"),
+            RunState::Finished(msg) => format!("finished: {}", msg),
+        };
+        let conditions: Vec<String> = self
+            .debugger
+            .conditions()
+            .values()
+            .map(|c| c.to_string())
+            .collect();
+        let status = format!(
+            "Cycle {} | {} | Stop conditions: [{}]",
+            self.timeline().cycle(),
+            state,
+            conditions.join(", ")
+        );
+        let panes = self.panes();
+        self.screen.render(&panes, &status, HELP)
+    }
+}
+
+impl Tui {
+    pub fn new(debugger: Debugger, labels: BTreeMap<String, usize>) -> Self {
+        Self {
+            debugger,
+            labels,
+            screen: Screen::new(),
+        }
+    }
+    /// Read keys from `input` and draw the screen to `out` until quit or input ends.
+    /// The terminal should be in [RawMode], so keys arrive as they're pressed.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: W) -> Result<(), String> {
+        run_ui(self, input, out)
+    }
+    /// Give back the machine, e.g. to report statistics
+    pub fn into_machine(self) -> Machine {
+        self.debugger.into_timeline().into_machine()
+    }
+    /// Run until the machine finishes, regardless of stop conditions
+    fn run_to_end(&mut self) {
+        for _ in 0..RUN_LIMIT {
+            if !self.timeline_mut().step() {
+                return;
+            }
+        }
+        self.screen.message = format!("Stopped after {} cycles", RUN_LIMIT);
+    }
+    fn toggle(&mut self, spec: &str) {
+        let condition = match StopCondition::parse(spec, &self.labels) {
+            Ok(condition) => condition,
+            Err(msg) => {
+                self.screen.message = msg;
+                return;
+            }
+        };
+        let existing = self
+            .debugger
+            .conditions()
+            .iter()
+            .find(|(_, c)| **c == condition)
+            .map(|(id, _)| *id);
+        match existing {
+            Some(id) => {
+                self.debugger.remove(id);
+            }
+            None => {
+                if let Err(msg) = self.debugger.add(condition) {
+                    self.screen.message = msg;
+                }
+            }
+        }
+    }
+    fn timeline(&self) -> &Timeline {
+        self.debugger.timeline()
+    }
+    fn timeline_mut(&mut self) -> &mut Timeline {
+        self.debugger.timeline_mut()
+    }
+    /// Return panes of the cycle before the current one, given panes shown before the command.
+    /// They are reused after a single step, otherwise the machine of the cycle is re-executed
+    /// from the latest snapshot, aside from the current one.
+    fn previous_panes(&self, shown_cycle: usize, shown: Vec<String>) -> Vec<String> {
+        match self.timeline().cycle().checked_sub(1) {
+            Some(cycle) if cycle == shown_cycle => shown,
+            Some(cycle) => self
+                .timeline()
+                .machine_at(cycle)
+                .map_or(Vec::new(), |vm| self.panes_of(&vm)),
+            None => Vec::new(),
+        }
+    }
+    /// Panes of the current cycle
+    fn panes(&self) -> Vec<String> {
+        self.panes_of(self.timeline().machine())
+    }
+    /// Source listings of each thread, followed by tables of the machine
+    fn panes_of(&self, vm: &Machine) -> Vec<String> {
+        let conditions = self.debugger.conditions();
+        let mut panes = Vec::new();
+        for thread in 0..vm.threads() {
+            let title = format!("Thread {} Source", thread);
+            panes.push(listing(&title, vm.program(thread), vm.pc(thread), |addr| {
+                let breakpoint = StopCondition::Breakpoint(addr);
+                conditions.values().any(|c| *c == breakpoint)
+            }));
+        }
+        panes.extend(vm.panes());
+        panes
+    }
+}
+
+/// Terminal UI of a multi-core machine, which shows each core and coherence transactions.
+/// It runs forward only, without stop conditions.
+pub struct MultiCoreTui {
+    vm: MultiCoreMachine,
+    /// Message the machine finished with, if finished
+    finished: Option<String>,
+    screen: Screen,
+}
+
+impl Ui for MultiCoreTui {
+    fn screen(&mut self) -> &mut Screen {
+        &mut self.screen
+    }
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Step(n) => {
+                for _ in 0..n {
+                    if !self.step() {
+                        break;
+                    }
+                }
+            }
+            Command::Run => {
+                for _ in 0..RUN_LIMIT {
+                    if !self.step() {
+                        return;
+                    }
+                }
+                self.screen.message = format!("Stopped after {} cycles", RUN_LIMIT);
+            }
+            Command::Quit => (),
+            _ => self.screen.message = String::from(
+                "Stop conditions, going back and snapshots are not supported by multi-core machine",
+            ),
+        }
+    }
+    fn draw(&mut self) -> String {
+        let state = match self.finished.as_ref() {
+            None => String::from("running"),
+            Some(msg) => format!("finished: {}", msg),
+        };
+        let status = format!("Cycle {} | {}", self.vm.cycle(), state);
+        let panes = self.panes();
+        self.screen.render(&panes, &status, MULTI_CORE_HELP)
+    }
+}
+
+impl MultiCoreTui {
+    pub fn new(vm: MultiCoreMachine) -> Self {
+        Self {
+            vm,
+            finished: None,
+            screen: Screen::new(),
+        }
+    }
+    /// Read keys from `input` and draw the screen to `out` until quit or input ends.
+    /// The terminal should be in [RawMode], so keys arrive as they're pressed.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: W) -> Result<(), String> {
+        run_ui(self, input, out)
+    }
+    /// Give back the machine, e.g. to report statistics
+    pub fn into_machine(self) -> MultiCoreMachine {
+        self.vm
+    }
+    /// Execute a cycle, keeping panes before it to highlight changes.
+    /// Return false if the machine has finished.
+    fn step(&mut self) -> bool {
+        if self.finished.is_some() {
+            return false;
+        }
+        let panes = self.panes();
+        match self.vm.next_cycle() {
+            Ok(()) => {
+                self.screen.previous = panes;
+                true
+            }
+            Err(msg) => {
+                self.finished = Some(msg);
+                false
+            }
+        }
+    }
+    /// Source listing and tables of each core, titled by the core,
+    /// followed by coherence transactions and the shared bus
+    fn panes(&self) -> Vec<String> {
+        let mut panes = Vec::new();
+        for core in 0..self.vm.cores() {
+            let source = format!("Core {} Source", core);
+            panes.push(listing(
+                &source,
+                self.vm.program(core),
+                self.vm.pc(core),
+                |_| false,
+            ));
+            // Cores have tables of the same titles, which tell panes apart
+            for pane in self.vm.core_panes(core) {
+                if let Some(title) = title(&pane) {
+                    panes.push(format!("Core {} {}\n{}", core, title, pane));
+                }
+            }
+        }
+        panes.extend(self.vm.panes());
+        panes
+    }
+}
+
+/// Source listing around the PC titled `title`, where addresses `marked` are marked by '*'
+fn listing<F: Fn(usize) -> bool>(title: &str, program: &[String], pc: usize, marked: F) -> String {
+    let start = pc.saturating_sub(SOURCE_LINES / 2);
+    let mut table = format!("{}\n", title);
+    for (addr, inst) in program.iter().enumerate().skip(start).take(SOURCE_LINES) {
+        let marker = if addr == pc { '>' } else { ' ' };
+        let mark = if marked(addr) { '*' } else { ' ' };
+        table.push_str(&format!("{}{}{:4} {}\n", marker, mark, addr, inst.trim()));
+    }
+    table
+}
+
+/// Title of a pane, its first line other than table borders
+fn title(pane: &str) -> Option<&str> {
+    pane.lines()
+        .map(|line| line.trim_matches(|c: char| c == '|' || c.is_whitespace()))
+        .find(|line| !line.is_empty() && !line.chars().all(|c| c == '+' || c == '-'))
+}
+
+/// Pair lines of panes with their titles. Empty panes have no title and are left out.
+fn titled(panes: &[String]) -> Vec<(&str, Vec<&str>)> {
+    panes
+        .iter()
+        .filter_map(|pane| Some((title(pane)?, pane.lines().collect())))
+        .collect()
+}
+
+/// Positions of panes on the screen, in columns.
+/// A pane keeps its position while other panes change, and reserves the most lines and width it had.
+/// Panes are placed again only when a new one shows up, one outgrows its lines, or the screen is resized.
+#[derive(Debug, Default)]
+struct Layout {
+    /// (column, first row, lines) of each pane by title
+    places: HashMap<String, (usize, usize, usize)>,
+    /// Width of each column
+    widths: Vec<usize>,
+    /// Lines of each column
+    height: usize,
+}
+
+impl Layout {
+    fn place(&mut self, panes: &[(&str, Vec<&str>)], height: usize) {
+        let misplaced = height != self.height
+            || panes.iter().any(|(title, lines)| {
+                self.places
+                    .get(*title)
+                    .is_none_or(|(_, _, reserved)| lines.len() > *reserved)
+            });
+        if misplaced {
+            let (mut column, mut row) = (0, 0);
+            let mut places = HashMap::new();
+            for (title, lines) in panes {
+                let reserved = self.places.get(*title).map_or(0, |(_, _, lines)| *lines);
+                let lines = lines.len().max(reserved);
+                if row > 0 && row + lines > height {
+                    column += 1;
+                    row = 0;
+                }
+                places.insert(title.to_string(), (column, row, lines));
+                row += lines;
+            }
+            self.places = places;
+            self.widths.clear();
+            self.height = height;
+        }
+        for (title, lines) in panes {
+            let column = self.places[*title].0;
+            if self.widths.len() <= column {
+                self.widths.resize(column + 1, 0);
+            }
+            let width = lines.iter().map(|l| l.len()).max().unwrap_or(0) + 1;
+            self.widths[column] = self.widths[column].max(width);
+        }
+    }
+    /// Draw placed panes in columns which fit in `width`.
+    /// Lines which differ from the pane of the same title in the previous panes are highlighted.
+    fn render(
+        &self,
+        panes: &[(&str, Vec<&str>)],
+        previous: &[(&str, Vec<&str>)],
+        width: usize,
+    ) -> String {
+        // Cells of each column, of (text, changed)
+        let mut columns: Vec<Vec<Option<(&str, bool)>>> =
+            vec![vec![None; self.height]; self.widths.len()];
+        for (title, after) in panes {
+            let changed = match previous.iter().find(|(t, _)| t == title) {
+                Some((_, before)) => changed_lines(before, after),
+                None => vec![!previous.is_empty(); after.len()],
+            };
+            let (column, row, _) = self.places[*title];
+            let cells = columns[column].iter_mut().skip(row);
+            for (cell, (line, changed)) in cells.zip(after.iter().zip(changed)) {
+                *cell = Some((line, changed && !line.is_empty()));
+            }
+        }
+        let mut screen = String::new();
+        for row in 0..self.height {
+            let mut used = 0;
+            for (column, column_width) in columns.iter().zip(self.widths.iter().copied()) {
+                if used + column_width > width {
+                    break;
+                }
+                used += column_width;
+                match column[row] {
+                    Some((line, true)) => screen.push_str(&format!(
+                        "{}{}{}{:pad$}",
+                        HIGHLIGHT,
+                        line,
+                        RESET,
+                        "",
+                        pad = column_width - line.len()
+                    )),
+                    Some((line, false)) => screen.push_str(&format!("{:column_width$}", line)),
+                    None => screen.push_str(&format!("{:column_width$}", "")),
+                }
+            }
+            screen.push('\n');
+        }
+        screen
+    }
+}
+
+/// Mark lines of `after` which are not in the longest common subsequence with `before`
+fn changed_lines(before: &[&str], after: &[&str]) -> Vec<bool> {
+    // common[i][j] is length of the LCS of before[i..] and after[j..]
+    let mut common = vec![vec![0; after.len() + 1]; before.len() + 1];
+    for i in (0..before.len()).rev() {
+        for j in (0..after.len()).rev() {
+            common[i][j] = if before[i] == after[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut changed = vec![true; after.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < before.len() && j < after.len() {
+        if before[i] == after[j] {
+            changed[j] = false;
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod tui {
+    use super::*;
+    use crate::memory_bus::cache::Protocol;
+    use crate::virtual_machine::fixture::{new_machine, new_multi_core};
+
+    #[test]
+    fn commands() -> Result<(), String> {
+        let program = ["addi R1, R0, #1", "addi R2, R1, #1", "addi R3, R2, #1"];
+        let debugger = Debugger::new(Timeline::new(new_machine(&program, 1, 0)));
+        let mut tui = Tui::new(debugger, BTreeMap::new());
+        // Keys which take an argument open the prompt, Escape closes it
+        let input = "s2\rb2\rgx\x1bucx".as_bytes();
+        let mut out = Vec::new();
+        tui.run(input, &mut out)?;
+        assert_eq!(tui.timeline().cycle(), 2);
+        assert_eq!(tui.timeline().machine().pc(0), 2);
+        assert_eq!(tui.screen.message, "Unknown key: x");
+        assert_eq!(tui.screen.prompt, None);
+        tui.screen.key(b':');
+        "g 1\x7f0"
+            .bytes()
+            .for_each(|key| assert_eq!(tui.screen.key(key), None));
+        assert_eq!(tui.screen.key(b'\r'), Some(Command::Goto(0)));
+        tui.execute(Command::parse("b R3==3")?);
+        tui.execute(Command::Continue);
+        assert_eq!(tui.screen.message, "Stopped by R3==3");
+        tui.execute(Command::Run);
+        assert!(matches!(tui.timeline().state(), RunState::Finished(_)));
+        let written = tui.timeline().last_write(0, 3).unwrap();
+        tui.execute(Command::parse("w R3")?);
+        assert_eq!(tui.timeline().cycle(), written);
+        tui.execute(Command::parse("g 1")?);
+        assert_eq!(tui.timeline().cycle(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn multi_core() -> Result<(), String> {
+        let program: &[&str] = &["addi R1, R0, #1", "sw R1, R0, #0", "lw R2, R0, #4"];
+        let mut tui = MultiCoreTui::new(new_multi_core(&[program, program], 64, Protocol::Mesi));
+        let mut out = Vec::new();
+        tui.run("s3\ru".as_bytes(), &mut out)?;
+        assert_eq!(tui.vm.cycle(), 3);
+        assert!(tui.screen.message.contains("not supported"));
+        // Each core has its own panes, next to coherence transactions
+        let panes = tui.panes();
+        let titles: Vec<&str> = panes.iter().filter_map(|p| title(p)).collect();
+        for core in 0..2 {
+            assert!(titles.contains(&format!("Core {} Source", core).as_str()));
+            assert!(titles.contains(&format!("Core {} Registers", core).as_str()));
+            assert!(titles.contains(&format!("Core {} L1 cache{}", core, core).as_str()));
+        }
+        tui.execute(Command::Run);
+        assert_eq!(tui.finished.as_deref(), Some("All cores are idle"));
+        assert!(!tui.step());
+        let panes = tui.panes();
+        let log = panes
+            .iter()
+            .find(|p| title(p) == Some("Coherence Transactions"))
+            .expect("Coherence transactions are shown");
+        assert!(log.contains("core1"), "{}", log);
+        assert!(tui.draw().contains("finished: All cores are idle"));
+        Ok(())
+    }
+
+    fn draw(layout: &mut Layout, panes: &[&str], previous: &[&str], height: usize) -> String {
+        let panes: Vec<String> = panes.iter().map(|p| p.to_string()).collect();
+        let previous: Vec<String> = previous.iter().map(|p| p.to_string()).collect();
+        let panes = titled(&panes);
+        layout.place(&panes, height);
+        layout.render(&panes, &titled(&previous), 80)
+    }
+
+    #[test]
+    fn highlight() {
+        let mut layout = Layout::default();
+        let screen = draw(&mut layout, &["a\nB\n", "c\n"], &["a\nb\n", "c\n"], 2);
+        let rows: Vec<&str> = screen.lines().collect();
+        assert_eq!(rows[0], "a c ");
+        assert_eq!(rows[1], format!("{}B{} ", HIGHLIGHT, RESET) + "  ");
+        // Panes are matched by title, a new pane is highlighted
+        let screen = draw(&mut layout, &["c\n", "d\n"], &["a\nB\n", "c\n"], 2);
+        let rows: Vec<&str> = screen.lines().collect();
+        assert_eq!(rows[0], "c ");
+        assert_eq!(rows[1], format!("{}d{} ", HIGHLIGHT, RESET));
+        // Inserted lines don't mark the following lines as changed
+        let changed = changed_lines(&["a", "b", "c"], &["a", "x", "b", "c"]);
+        assert_eq!(changed, [false, true, false, false]);
+    }
+
+    #[test]
+    fn fixed_positions() {
+        let mut layout = Layout::default();
+        let table = "+-----+\n|  b  |\n+-----+\n|  1  |\n+-----+\n";
+        assert_eq!(title(table), Some("b"));
+        draw(&mut layout, &["a\n1\n2\n", table], &[], 6);
+        assert_eq!(layout.places["a"], (0, 0, 3));
+        assert_eq!(layout.places["b"], (1, 0, 5));
+        // Panes keep their place when another shrinks, is emptied or comes back
+        let screen = draw(&mut layout, &["", table], &[], 6);
+        assert!(screen.starts_with("  +-----+"));
+        draw(&mut layout, &["a\n", table], &[], 6);
+        assert_eq!(layout.places["a"], (0, 0, 3));
+        assert_eq!(layout.places["b"], (1, 0, 5));
+        assert_eq!(layout.widths, [2, 8]);
+        // They are placed again when one outgrows its lines
+        draw(&mut layout, &["a\n1\n2\n3\n4\n5\n", table], &[], 6);
+        assert_eq!(layout.places["b"], (1, 0, 5));
+        draw(&mut layout, &["a\n1\n", "c\n", table], &[], 6);
+        assert_eq!(layout.places["c"], (1, 0, 1));
+        assert_eq!(layout.places["b"], (1, 1, 5));
+    }
+}
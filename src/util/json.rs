@@ -0,0 +1,434 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+
+/// JSON value, displayed in compact form
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// Wide enough for both i64 and u64
+    Int(i128),
+    /// Number with a fraction or exponent, displayed as null if it's not finite
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    /// Fields are kept in insertion order
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Construct an object from (key, value) pairs
+    pub fn object<K: ToString>(fields: Vec<(K, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+    pub fn str<S: ToString>(s: S) -> Self {
+        Json::Str(s.to_string())
+    }
+    /// Raw bytes as a string of hex digits
+    pub fn bytes(raw: &[u8]) -> Self {
+        Json::Str(raw.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+    /// Parse a JSON document
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let val = parser.value()?;
+        parser.skip_space();
+        if parser.pos != parser.text.len() {
+            return Err(parser.error("Unexpected trailing characters"));
+        }
+        Ok(val)
+    }
+    /// Return field of an object with given key
+    pub fn get(&self, key: &str) -> Result<&Json, String> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .ok_or_else(|| format!("Missing field {}", key)),
+            _ => Err(format!("Expect an object with field {}", key)),
+        }
+    }
+    /// Return None for null, otherwise Some(the value)
+    pub fn opt(&self) -> Option<&Json> {
+        match self {
+            Json::Null => None,
+            val => Some(val),
+        }
+    }
+    pub fn as_u64(&self) -> Result<u64, String> {
+        match self {
+            Json::Int(i) => u64::try_from(*i).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| format!("Expect a 64-bit non-negative integer, found {}", self))
+    }
+    pub fn as_usize(&self) -> Result<usize, String> {
+        self.as_u64().map(|val| val as usize)
+    }
+    pub fn as_u32(&self) -> Result<u32, String> {
+        let val = self.as_u64()?;
+        u32::try_from(val).map_err(|_| format!("{} exceeds 32 bits", val))
+    }
+    pub fn as_f64(&self) -> Result<f64, String> {
+        match self {
+            Json::Int(i) => Ok(*i as f64),
+            Json::Float(val) => Ok(*val),
+            _ => Err(format!("Expect a number, found {}", self)),
+        }
+    }
+    pub fn as_bool(&self) -> Result<bool, String> {
+        match self {
+            Json::Bool(b) => Ok(*b),
+            _ => Err(format!("Expect a boolean, found {}", self)),
+        }
+    }
+    pub fn as_str(&self) -> Result<&str, String> {
+        match self {
+            Json::Str(s) => Ok(s),
+            _ => Err(format!("Expect a string, found {}", self)),
+        }
+    }
+    pub fn as_array(&self) -> Result<&[Json], String> {
+        match self {
+            Json::Array(items) => Ok(items),
+            _ => Err(format!("Expect an array, found {}", self)),
+        }
+    }
+    /// Return fields of an object in order
+    pub fn as_object(&self) -> Result<&[(String, Json)], String> {
+        match self {
+            Json::Object(fields) => Ok(fields),
+            _ => Err(format!("Expect an object, found {}", self)),
+        }
+    }
+    /// Return raw bytes of a string written by [Json::bytes]
+    pub fn as_bytes(&self) -> Result<Vec<u8>, String> {
+        let hex = self.as_str()?;
+        if hex.len() % 2 != 0 {
+            return Err(String::from("Expect even count of hex digits"));
+        }
+        let digit = |c: u8| {
+            char::from(c)
+                .to_digit(16)
+                .ok_or_else(|| format!("Invalid hex digits in {}", hex))
+        };
+        hex.as_bytes()
+            .chunks(2)
+            .map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+            .collect()
+    }
+}
+
+/// Recursive descent parser of JSON, numbers without fraction and exponent are integers
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &str) -> String {
+        format!("JSON: {} at {}", msg, self.pos)
+    }
+    fn skip_space(&mut self) {
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_space();
+        self.text.get(self.pos).copied()
+    }
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("Expect '{}'", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+    fn keyword(&mut self, word: &str, val: Json) -> Result<Json, String> {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(val)
+        } else {
+            Err(self.error("Unknown keyword"))
+        }
+    }
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::Str),
+            Some(b'[') => self.array(),
+            Some(b'{') => self.object(),
+            Some(c) if c == b'-' || c.is_ascii_digit() => self.number(),
+            _ => Err(self.error("Expect a value")),
+        }
+    }
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        if self.text[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        let is_digit = |c: &u8| c.is_ascii_digit();
+        while self.text.get(self.pos).is_some_and(is_digit) {
+            self.pos += 1;
+        }
+        let integer = self.pos;
+        if self.text.get(self.pos).copied() == Some(b'.') {
+            self.pos += 1;
+            while self.text.get(self.pos).is_some_and(is_digit) {
+                self.pos += 1;
+            }
+        }
+        if matches!(self.text.get(self.pos).copied(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.text.get(self.pos).copied(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            while self.text.get(self.pos).is_some_and(is_digit) {
+                self.pos += 1;
+            }
+        }
+        let digits = std::str::from_utf8(&self.text[start..self.pos]).unwrap_or_default();
+        if self.pos == integer {
+            return digits
+                .parse()
+                .map(Json::Int)
+                .map_err(|_| self.error("Invalid integer"));
+        }
+        digits
+            .parse()
+            .map(Json::Float)
+            .map_err(|_| self.error("Invalid number"))
+    }
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut raw = Vec::new();
+        loop {
+            let c = *self
+                .text
+                .get(self.pos)
+                .ok_or_else(|| self.error("Unterminated string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = *self
+                        .text
+                        .get(self.pos)
+                        .ok_or_else(|| self.error("Unterminated string"))?;
+                    self.pos += 1;
+                    match escaped {
+                        b'"' | b'\\' | b'/' => raw.push(escaped),
+                        b'b' => raw.push(0x08),
+                        b'f' => raw.push(0x0c),
+                        b'n' => raw.push(b'\n'),
+                        b't' => raw.push(b'\t'),
+                        b'r' => raw.push(b'\r'),
+                        b'u' => {
+                            let hex = self
+                                .text
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("Invalid unicode escape"))?;
+                            self.pos += 4;
+                            let mut buf = [0; 4];
+                            raw.extend_from_slice(hex.encode_utf8(&mut buf).as_bytes());
+                        }
+                        _ => return Err(self.error("Invalid escape")),
+                    }
+                }
+                c => raw.push(c),
+            }
+        }
+        String::from_utf8(raw).map_err(|_| self.error("Invalid UTF-8"))
+    }
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("Expect ',' or ']'")),
+            }
+        }
+    }
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_space();
+            let key = self.string()?;
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("Expect ',' or '}'")),
+            }
+        }
+    }
+}
+
+impl From<u32> for Json {
+    fn from(val: u32) -> Self {
+        Json::Int(i128::from(val))
+    }
+}
+
+impl From<u64> for Json {
+    fn from(val: u64) -> Self {
+        Json::Int(i128::from(val))
+    }
+}
+
+impl From<f64> for Json {
+    fn from(val: f64) -> Self {
+        Json::Float(val)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(val: bool) -> Self {
+        Json::Bool(val)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(val: usize) -> Self {
+        Json::Int(val as i128)
+    }
+}
+
+fn escape(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            Json::Float(val) if val.is_finite() => write!(f, "{}", val),
+            Json::Float(_) => write!(f, "null"),
+            Json::Str(s) => escape(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (idx, (key, val)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    escape(f, key)?;
+                    write!(f, ":{}", val)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod json {
+    use super::*;
+    #[test]
+    fn display() {
+        let val = Json::object(vec![
+            ("a", Json::Array(vec![Json::from(1u32), Json::Null])),
+            ("b", Json::str("say \"hi\"\n")),
+            ("c", Json::Bool(false)),
+        ]);
+        assert_eq!(
+            val.to_string(),
+            r#"{"a":[1,null],"b":"say \"hi\"\n","c":false}"#
+        );
+    }
+    #[test]
+    fn parse() -> Result<(), String> {
+        let text = r#"{"a":[1,null],"b":"say \"hi\"\n","c":false,"d":-3,"e":"\u0001"}"#;
+        let val = Json::parse(&format!(" {} ", text.replace(',', " , ")))?;
+        assert_eq!(val.get("a")?.as_array()?[0].as_u32()?, 1);
+        assert_eq!(val.get("b")?.as_str()?, "say \"hi\"\n");
+        assert_eq!(val.get("d")?, &Json::Int(-3));
+        assert!(val.get("c")?.as_u64().is_err());
+        assert!(val.get("f").is_err());
+        // Printing the parsed value gives the same text
+        assert_eq!(Json::parse(text)?.to_string(), text);
+
+        assert_eq!(Json::bytes(&[0, 0xab]).as_bytes()?, [0, 0xab]);
+        assert_eq!(Json::parse("-1.5e2")?.as_f64()?, -150.0);
+        assert_eq!(Json::from(0.25).to_string(), "0.25");
+        assert_eq!(Json::from(f64::NAN).to_string(), "null");
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse(r#""\x""#).is_err());
+        Ok(())
+    }
+    #[test]
+    fn edge_cases() -> Result<(), String> {
+        let escapes = Json::parse(r#""\b\f\/""#)?;
+        assert_eq!(escapes.as_str()?, "\u{8}\u{c}/");
+        assert_eq!(Json::parse(&escapes.to_string())?, escapes);
+        // u64 values beyond i64 round-trip
+        let max = Json::from(u64::MAX);
+        assert_eq!(Json::parse(&max.to_string())?.as_u64()?, u64::MAX);
+        assert!(Json::parse("18446744073709551616")?.as_u64().is_err());
+        // Multi-byte characters are rejected rather than sliced
+        assert!(Json::str("aéb").as_bytes().is_err());
+        assert!(Json::str("0g").as_bytes().is_err());
+        assert!(Json::parse("{} 1").is_err());
+        Ok(())
+    }
+}
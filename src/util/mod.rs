@@ -1,42 +1,96 @@
+pub mod json;
 pub mod queue;
+pub mod random;
 
-pub fn raw_to_u32_big_endian(vec: &[u8]) -> u32 {
-    let mut ret = 0;
-    for byte in vec {
-        ret <<= 8;
-        ret |= *byte as u32;
+/// Declare a trait to clone trait objects behind a box, implemented for every cloneable
+/// type of the trait, and implement [Clone] for the boxes through it.
+/// ```ignore
+/// clone_box! {
+///     /// Clone a policy behind a box
+///     ClonePolicy for Policy
+/// }
+/// pub trait Policy: ClonePolicy {}
+/// ```
+macro_rules! clone_box {
+    ($(#[$meta:meta])* $clone:ident for $trait:ident) => {
+        $(#[$meta])*
+        pub trait $clone {
+            fn clone_box(&self) -> Box<dyn $trait>;
+        }
+
+        impl<T: $trait + Clone + 'static> $clone for T {
+            fn clone_box(&self) -> Box<dyn $trait> {
+                Box::new(self.clone())
+            }
+        }
+
+        impl Clone for Box<dyn $trait> {
+            fn clone(&self) -> Self {
+                $clone::clone_box(&**self)
+            }
+        }
+    };
+}
+pub(crate) use clone_box;
+
+/// Byte order of multi-byte data in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    /// The most significant byte resides in the lowest address
+    Big,
+    /// The least significant byte resides in the lowest address
+    Little,
+}
+
+impl Endian {
+    /// Parse byte order from its name, either "big" or "little"
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "big" => Ok(Endian::Big),
+            "little" => Ok(Endian::Little),
+            _ => Err(format!("Unknown endianness {}", name)),
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Endian::Big => "big",
+            Endian::Little => "little",
+        }
+    }
+}
+
+/// Turn raw bytes into an u32 by given byte order
+pub fn raw_to_u32(raw: &[u8], endian: Endian) -> u32 {
+    let fold = |acc: u32, byte: &u8| (acc << 8) | *byte as u32;
+    match endian {
+        Endian::Big => raw.iter().fold(0, fold),
+        Endian::Little => raw.iter().rev().fold(0, fold),
     }
-    ret
 }
 
-// TODO: generalize this function
-pub fn u32_to_raw_big_endian(mut val: u32) -> Vec<u8> {
-    let mut ret = Vec::with_capacity(4);
-    for _ in 0..4 {
-        let byte = (val & 0xff) as u8;
-        ret.push(byte);
-        val >>= 8;
+/// Turn an u32 into 4 raw bytes by given byte order
+pub fn u32_to_raw(val: u32, endian: Endian) -> Vec<u8> {
+    match endian {
+        Endian::Big => val.to_be_bytes().to_vec(),
+        Endian::Little => val.to_le_bytes().to_vec(),
     }
-    ret.reverse();
-    ret
 }
 
 #[cfg(test)]
 mod covert_functions {
     const U32: u32 = 0x0A0B0C0D;
-    const RAW: [u8; 4] = [0xA, 0xB, 0xC, 0xD];
+    const RAW_BIG: [u8; 4] = [0xA, 0xB, 0xC, 0xD];
+    const RAW_LITTLE: [u8; 4] = [0xD, 0xC, 0xB, 0xA];
     use super::*;
     #[test]
     fn test_raw_data_to_u32() {
-        let got = raw_to_u32_big_endian(&RAW);
-        let expect = U32;
-        assert_eq!(got, expect);
+        assert_eq!(raw_to_u32(&RAW_BIG, Endian::Big), U32);
+        assert_eq!(raw_to_u32(&RAW_LITTLE, Endian::Little), U32);
     }
 
     #[test]
     fn test_u32_to_raw() {
-        let got = u32_to_raw_big_endian(U32);
-        let expect = RAW;
-        assert_eq!(&got, &expect);
+        assert_eq!(&u32_to_raw(U32, Endian::Big), &RAW_BIG);
+        assert_eq!(&u32_to_raw(U32, Endian::Little), &RAW_LITTLE);
     }
 }
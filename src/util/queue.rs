@@ -1,7 +1,7 @@
 use std::collections::{linked_list, LinkedList};
 
 /// A FIFO data structure with fixed capacity
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Queue<T> {
     buffer: LinkedList<T>,
     capacity: usize,
@@ -78,6 +78,7 @@ impl<'b, T> IntoIterator for &'b mut Queue<T> {
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod queue {
     use super::Queue;
 
@@ -123,6 +124,7 @@ mod queue {
     }
 
     #[test]
+    #[allow(clippy::assign_op_pattern)]
     fn iteration_mut() -> Result<(), String> {
         let mut q = Queue::new(TEST_CAPACITY);
         for i in 0..TEST_CAPACITY {
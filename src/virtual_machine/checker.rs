@@ -0,0 +1,283 @@
+use super::golden::GoldenModel;
+use super::Machine;
+use crate::util::raw_to_u32;
+use std::collections::HashMap;
+
+/// Run the golden model in lockstep with a machine, and report the first divergence.
+/// Instructions are executed by the golden model as they are issued. Each register written
+/// at a commit is compared with the value the golden model computed for the committing instruction,
+/// memory whenever nothing is in flight.
+pub struct Checker {
+    golden: GoldenModel,
+    /// Count of instructions of each thread executed by the golden model
+    issued: Vec<u64>,
+    /// Register written by each instruction in flight, with its value in the golden model,
+    /// indexed by (thread, address)
+    pending: HashMap<(usize, usize), (usize, u32)>,
+    /// Count of cycles checked
+    cycle: usize,
+}
+
+impl Checker {
+    /// Start checking from the current state of the machine, which has nothing in flight
+    pub fn new(vm: &Machine) -> Result<Self, String> {
+        if vm.has_mmu() {
+            return Err(String::from(
+                "Golden model doesn't translate virtual addresses",
+            ));
+        }
+        if !vm.is_idle() {
+            return Err(String::from(
+                "Unable to check a machine with instructions in flight",
+            ));
+        }
+        let mut golden = GoldenModel::new(vm.memory(), vm.endian());
+        for thread in 0..vm.threads() {
+            let registers = vm
+                .registers(thread)
+                .iter()
+                .map(|r| r.val().unwrap_or_default())
+                .collect();
+            golden.add_thread(vm.pc(thread), registers);
+        }
+        golden.set_csr(vm.csr().clone());
+        Ok(Self {
+            golden,
+            issued: vm.thread_issued(),
+            pending: HashMap::new(),
+            cycle: 0,
+        })
+    }
+    /// Check the cycle the machine just executed.
+    /// Return the first divergence from the golden model as an error.
+    pub fn check(&mut self, vm: &Machine) -> Result<(), String> {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        // Cycle count and pending interrupts come from outside the instruction stream
+        let csr = vm.csr();
+        self.golden.set_inputs(csr.cycle, csr.pending);
+        if csr.taken > self.golden.csr().taken {
+            self.golden.interrupt();
+        }
+        for (thread, issued) in vm.thread_issued().into_iter().enumerate() {
+            while self.issued[thread] < issued {
+                self.issued[thread] += 1;
+                let address = self.golden.pc(thread);
+                // Nops are issued beyond the program while in-flight instructions drain
+                let Some(inst) = vm.program(thread).get(address) else {
+                    self.golden.skip(thread);
+                    continue;
+                };
+                let write = self
+                    .golden
+                    .step(thread, inst)
+                    .map_err(|msg| format!("Cycle {}: {}", cycle, msg))?;
+                if let Some(write) = write {
+                    self.pending.insert((thread, address), write);
+                }
+            }
+            if self.golden.pc(thread) != vm.pc(thread) {
+                let msg = format!(
+                    "Cycle {}: PC of thread {} is {}, expect {}",
+                    cycle,
+                    thread,
+                    vm.pc(thread),
+                    self.golden.pc(thread)
+                );
+                return Err(msg);
+            }
+        }
+        self.compare_registers(vm, cycle)?;
+        if vm.is_idle() {
+            self.compare_memory(vm, cycle)?;
+        }
+        Ok(())
+    }
+    /// Compare each register written in the cycle with the result of the committing instruction
+    fn compare_registers(&mut self, vm: &Machine, cycle: usize) -> Result<(), String> {
+        let committed: Vec<_> = vm
+            .committed()
+            .iter()
+            .map(|key| (*key, self.pending.remove(key)))
+            .collect();
+        for &(thread, register) in vm.written() {
+            let got = vm.registers(thread)[register].val().unwrap_or_default();
+            let writer = committed
+                .iter()
+                .find_map(|((t, address), write)| match write {
+                    Some((r, expect)) if *t == thread && *r == register => {
+                        Some((*address, *expect))
+                    }
+                    _ => None,
+                });
+            let msg = match writer {
+                Some((_, expect)) if got == expect => continue,
+                Some((address, expect)) => format!(
+                    "Cycle {}, thread {}: R{} is {}, expect {}, last written by `{}` at {}",
+                    cycle,
+                    thread,
+                    register,
+                    got,
+                    expect,
+                    vm.program(thread)[address],
+                    address
+                ),
+                None => format!(
+                    "Cycle {}, thread {}: R{} is written with {}, expect no write",
+                    cycle, thread, register, got
+                ),
+            };
+            return Err(msg);
+        }
+        Ok(())
+    }
+    /// Compare DRAM word by word
+    fn compare_memory(&self, vm: &Machine, cycle: usize) -> Result<(), String> {
+        let memory = vm.memory();
+        let expect = self.golden.memory();
+        let diverged = memory
+            .chunks(4)
+            .zip(expect.chunks(4))
+            .position(|(got, expect)| got != expect);
+        let Some(word) = diverged else {
+            return Ok(());
+        };
+        let address = word * 4;
+        let range = address..(address + 4).min(memory.len());
+        let endian = vm.endian();
+        let committed: Vec<String> = vm
+            .committed()
+            .iter()
+            .map(|(thread, address)| format!("`{}` at {}", vm.program(*thread)[*address], address))
+            .collect();
+        let committed = if committed.is_empty() {
+            String::new()
+        } else {
+            format!(", after committing {}", committed.join(", "))
+        };
+        let msg = format!(
+            "Cycle {}: memory at {:#x} is {}, expect {}{}",
+            cycle,
+            address,
+            raw_to_u32(&memory[range.clone()], endian),
+            raw_to_u32(&expect[range], endian),
+            committed
+        );
+        Err(msg)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod checker {
+    use super::*;
+    use crate::virtual_machine::{fixture, EXIT_ADDRESS, INTC_ADDRESS};
+
+    /// Run the machine to the end, checking every cycle
+    fn run(vm: &mut Machine, checker: &mut Checker) -> Result<(), String> {
+        while vm.next_cycle().is_ok() {
+            checker.check(vm)?;
+        }
+        while vm.next_flush_cycle().is_ok() {
+            checker.check(vm)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lockstep() -> Result<(), String> {
+        let program = [
+            "addi R1, R0, #8",
+            "sw R1, R1, #0",
+            "lw R2, R1, #0",
+            "add R3, R2, R1",
+            "amoadd.w R4, R3, R1",
+            "lr.w R5, R1",
+            "sc.w R6, R3, R1",
+            "sw R6, R0, #4",
+        ];
+        let mut vm = fixture::new_machine(&program, 2, 16);
+        let mut checker = Checker::new(&vm)?;
+        run(&mut vm, &mut checker)?;
+        assert_eq!(checker.golden.memory(), vm.memory());
+        assert_eq!(vm.dump_memory()[1], "0x0004: 0");
+        Ok(())
+    }
+
+    #[test]
+    fn divergence() -> Result<(), String> {
+        let program = ["lw R1, R0, #8", "sw R1, R1, #0", "add R2, R1, R1"];
+        let mut vm = fixture::new_machine(&program, 2, 16);
+        let mut checker = Checker::new(&vm)?;
+        // The golden model thinks memory is different
+        let mut memory = vm.memory();
+        memory[11] = 5;
+        checker.golden = GoldenModel::new(memory, vm.endian());
+        checker.golden.add_thread(0, vec![0; 16]);
+        let msg = run(&mut vm, &mut checker).unwrap_err();
+        assert!(msg.ends_with("R1 is 0, expect 5, last written by `lw R1, R0, #8` at 0"));
+
+        let mut vm = fixture::new_machine(&program, 2, 16);
+        let mut checker = Checker::new(&vm)?;
+        vm.next_cycle()?;
+        checker.check(&vm)?;
+        // Memory changed behind the processor
+        vm.preload(12, &[3])?;
+        let msg = run(&mut vm, &mut checker).unwrap_err();
+        assert!(msg.contains("memory at 0xc is 3, expect 0"));
+        Ok(())
+    }
+
+    #[test]
+    fn control_and_status_registers() -> Result<(), String> {
+        let program = [
+            format!("addi R1, R0, #{}", INTC_ADDRESS),
+            String::from("addi R2, R0, #1"),
+            String::from("addi R3, R0, #15"),
+            String::from("csrw R3, #773"),
+            String::from("csrr R4, #3074"),
+            String::from("csrr R5, #3072"),
+            String::from("sw R2, R1, #0"), // Enable timer interrupt
+            String::from("sw R2, R1, #8"), // Fire in next cycle
+            String::from("addi R6, R6, #1"),
+            String::from("addi R6, R6, #1"),
+            String::from("addi R6, R6, #1"),
+            String::from("addi R6, R6, #1"),
+            String::from("addi R6, R6, #1"),
+            format!("addi R7, R0, #{}", EXIT_ADDRESS),
+            String::from("sw R6, R7, #0"),
+            // Interrupt handler
+            String::from("sw R2, R1, #4"), // Acknowledge
+            String::from("csrr R8, #834"),
+            String::from("csrr R9, #833"),
+            String::from("sw R9, R0, #0"),
+            String::from("mret"),
+        ];
+        let mut vm = fixture::new_machine(&program, 2, 16);
+        let mut checker = Checker::new(&vm)?;
+        run(&mut vm, &mut checker)?;
+        assert_eq!(vm.exit_status(), Some(5));
+        assert_eq!(checker.golden.csr().taken, 1);
+        assert_eq!(checker.golden.registers(0)[8], 1);
+        assert_eq!(checker.golden.memory(), vm.memory());
+        Ok(())
+    }
+
+    #[test]
+    fn reservation() -> Result<(), String> {
+        // The store overlaps the reserved word without starting at it
+        let program = [
+            "addi R1, R0, #7",
+            "lr.w R2, R0",
+            "sw R1, R0, #2",
+            "sc.w R3, R1, R0",
+            "sw R3, R0, #8",
+        ];
+        let mut vm = fixture::new_machine(&program, 2, 16);
+        let mut checker = Checker::new(&vm)?;
+        run(&mut vm, &mut checker)?;
+        assert_eq!(checker.golden.registers(0)[3], 1);
+        assert_eq!(checker.golden.memory(), vm.memory());
+        Ok(())
+    }
+}
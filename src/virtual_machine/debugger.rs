@@ -0,0 +1,351 @@
+use super::program::parse_number;
+use super::timeline::Timeline;
+use super::Machine;
+use crate::core::execution_path::ArgState;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::ops::Range;
+
+/// Comparison of a conditional breakpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Compare {
+    /// Operators, ones which are prefixes of others come later
+    const ALL: [Compare; 6] = [
+        Compare::Eq,
+        Compare::Ne,
+        Compare::Le,
+        Compare::Ge,
+        Compare::Lt,
+        Compare::Gt,
+    ];
+    fn symbol(&self) -> &'static str {
+        match self {
+            Compare::Eq => "==",
+            Compare::Ne => "!=",
+            Compare::Lt => "<",
+            Compare::Le => "<=",
+            Compare::Gt => ">",
+            Compare::Ge => ">=",
+        }
+    }
+    fn test(&self, lhs: u32, rhs: u32) -> bool {
+        match self {
+            Compare::Eq => lhs == rhs,
+            Compare::Ne => lhs != rhs,
+            Compare::Lt => lhs < rhs,
+            Compare::Le => lhs <= rhs,
+            Compare::Gt => lhs > rhs,
+            Compare::Ge => lhs >= rhs,
+        }
+    }
+}
+
+/// Condition to stop the machine
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopCondition {
+    /// PC of any thread reaches the address
+    Breakpoint(usize),
+    /// Value of a register satisfies the comparison, a renamed register never does
+    Register {
+        thread: usize,
+        register: usize,
+        compare: Compare,
+        value: u32,
+    },
+    /// Memory in the physical address range is written
+    Watchpoint(Range<u32>),
+    /// Instruction at the address commits
+    Commit(usize),
+}
+
+impl Display for StopCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopCondition::Breakpoint(address) => write!(f, "{}", address),
+            StopCondition::Register {
+                thread,
+                register,
+                compare,
+                value,
+            } => {
+                if *thread > 0 {
+                    write!(f, "T{}.", thread)?;
+                }
+                write!(f, "R{}{}{}", register, compare.symbol(), value)
+            }
+            StopCondition::Watchpoint(range) => {
+                write!(f, "mem:{:#x}..{:#x}", range.start, range.end)
+            }
+            StopCondition::Commit(address) => write!(f, "commit:{}", address),
+        }
+    }
+}
+
+/// Parse an address or a label
+fn parse_address(token: &str, labels: &BTreeMap<String, usize>) -> Result<usize, String> {
+    match labels.get(token) {
+        Some(address) => Ok(*address),
+        None => parse_number(token)
+            .map(|address| address as usize)
+            .map_err(|_| format!("Expect an address or a label, found {}", token)),
+    }
+}
+
+impl StopCondition {
+    /// Parse a condition, which is one of
+    /// - `<address|label>`: breakpoint
+    /// - `[T<thread>.]R<n><op><value>`: conditional breakpoint, op is one of `== != < <= > >=`
+    /// - `mem:<start>[..<end>]`: watchpoint, a word if end omitted
+    /// - `commit:<address|label>`: stop when the instruction commits
+    pub fn parse(spec: &str, labels: &BTreeMap<String, usize>) -> Result<Self, String> {
+        let spec = spec.trim();
+        if let Some(range) = spec.strip_prefix("mem:") {
+            let (start, end) = match range.split_once("..") {
+                Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+                None => {
+                    let start = parse_number(range)?;
+                    (start, start.saturating_add(4))
+                }
+            };
+            if start >= end {
+                return Err(format!("Empty address range {}", range));
+            }
+            return Ok(StopCondition::Watchpoint(start..end));
+        }
+        if let Some(address) = spec.strip_prefix("commit:") {
+            return Ok(StopCondition::Commit(parse_address(address, labels)?));
+        }
+        let compare = Compare::ALL
+            .iter()
+            .find_map(|c| spec.split_once(c.symbol()).map(|split| (*c, split)));
+        let Some((compare, (register, value))) = compare else {
+            return Ok(StopCondition::Breakpoint(parse_address(spec, labels)?));
+        };
+        let (thread, register) = match register.trim().split_once('.') {
+            Some((thread, register)) => (Some(thread), register),
+            None => (None, register.trim()),
+        };
+        let thread = match thread {
+            Some(thread) => thread
+                .strip_prefix('T')
+                .and_then(|t| t.parse().ok())
+                .ok_or_else(|| format!("Expect a thread, found {}", thread))?,
+            None => 0,
+        };
+        let register = register
+            .strip_prefix('R')
+            .and_then(|r| r.parse().ok())
+            .ok_or_else(|| format!("Expect a register, found {}", register))?;
+        Ok(StopCondition::Register {
+            thread,
+            register,
+            compare,
+            value: parse_number(value.trim())?,
+        })
+    }
+    /// Return wheither the condition holds in the current cycle of the machine
+    fn holds(&self, vm: &Machine) -> bool {
+        match self {
+            StopCondition::Breakpoint(address) => {
+                (0..vm.threads()).any(|thread| vm.pc(thread) == *address)
+            }
+            StopCondition::Register {
+                thread,
+                register,
+                compare,
+                value,
+            } => match vm.registers(*thread).get(*register) {
+                Some(ArgState::Ready(val)) => compare.test(*val, *value),
+                _ => false,
+            },
+            StopCondition::Watchpoint(range) => vm
+                .memory_written()
+                .is_some_and(|written| written.start < range.end && range.start < written.end),
+            StopCondition::Commit(address) => vm
+                .committed()
+                .iter()
+                .any(|(_, committed)| committed == address),
+        }
+    }
+    /// Events hit whenever they happen, other conditions hit when they become true
+    fn is_event(&self) -> bool {
+        matches!(
+            self,
+            StopCondition::Watchpoint(_) | StopCondition::Commit(_)
+        )
+    }
+}
+
+/// Why the debugger stopped running
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// Ids of conditions hit
+    Hit(Vec<usize>),
+    /// The machine finished
+    Finished,
+    /// Ran the given count of cycles
+    Limit,
+}
+
+/// Run a machine until stop conditions hit
+pub struct Debugger {
+    timeline: Timeline,
+    /// Conditions by id, ids are never reused
+    conditions: BTreeMap<usize, StopCondition>,
+    next_id: usize,
+}
+
+impl Debugger {
+    pub fn new(timeline: Timeline) -> Self {
+        Self {
+            timeline,
+            conditions: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+    pub fn timeline_mut(&mut self) -> &mut Timeline {
+        &mut self.timeline
+    }
+    pub fn into_timeline(self) -> Timeline {
+        self.timeline
+    }
+    /// Add a stop condition, return its id
+    pub fn add(&mut self, condition: StopCondition) -> Result<usize, String> {
+        if let StopCondition::Register { thread, .. } = condition {
+            if thread >= self.timeline.machine().threads() {
+                return Err(format!("Thread {} not found", thread));
+            }
+        }
+        let id = self.next_id;
+        self.conditions.insert(id, condition);
+        self.next_id += 1;
+        Ok(id)
+    }
+    pub fn remove(&mut self, id: usize) -> Option<StopCondition> {
+        self.conditions.remove(&id)
+    }
+    pub fn conditions(&self) -> &BTreeMap<usize, StopCondition> {
+        &self.conditions
+    }
+    /// Execute a cycle, return ids of conditions hit.
+    /// Return None if the machine has finished.
+    pub fn step(&mut self) -> Option<Vec<usize>> {
+        let before: Vec<bool> = self
+            .conditions
+            .values()
+            .map(|c| !c.is_event() && c.holds(self.timeline.machine()))
+            .collect();
+        if !self.timeline.step() {
+            return None;
+        }
+        let vm = self.timeline.machine();
+        let hits = self
+            .conditions
+            .iter()
+            .zip(before)
+            .filter(|((_, condition), held)| !held && condition.holds(vm))
+            .map(|((id, _), _)| *id)
+            .collect();
+        Some(hits)
+    }
+    /// Run until a condition hits, the machine finishes, or `limit` cycles executed
+    pub fn run(&mut self, limit: usize) -> Stop {
+        for _ in 0..limit {
+            match self.step() {
+                None => return Stop::Finished,
+                Some(hits) if !hits.is_empty() => return Stop::Hit(hits),
+                Some(_) => (),
+            }
+        }
+        Stop::Limit
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod debugger {
+    use super::*;
+    use crate::virtual_machine::fixture::new_machine;
+    use crate::virtual_machine::program::Program;
+
+    fn new_debugger() -> (Debugger, BTreeMap<String, usize>) {
+        let source = "
+            addi R1, R0, #4
+            sw R1, R0, #8
+            loop:
+            addi R2, R2, #1
+            addi R3, R0, #3
+            add R4, R2, R3
+        ";
+        let program = Program::parse(source).unwrap();
+        let vm = new_machine(program.text(), 1, 16);
+        (Debugger::new(Timeline::new(vm)), program.labels().clone())
+    }
+
+    #[test]
+    fn parse() -> Result<(), String> {
+        let labels = BTreeMap::from([(String::from("loop"), 2)]);
+        let specs = ["loop", "T1.R3>=4", "R2!=0x10", "mem:8", "commit:4"];
+        let conditions: Vec<StopCondition> = specs
+            .iter()
+            .map(|spec| StopCondition::parse(spec, &labels))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(conditions[0], StopCondition::Breakpoint(2));
+        assert_eq!(
+            conditions[1],
+            StopCondition::Register {
+                thread: 1,
+                register: 3,
+                compare: Compare::Ge,
+                value: 4
+            }
+        );
+        assert_eq!(conditions[3], StopCondition::Watchpoint(8..12));
+        let displayed: Vec<String> = conditions.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            displayed,
+            ["2", "T1.R3>=4", "R2!=16", "mem:0x8..0xc", "commit:4"]
+        );
+        assert!(StopCondition::parse("nowhere", &labels).is_err());
+        assert!(StopCondition::parse("mem:8..8", &labels).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stop() -> Result<(), String> {
+        let (mut debugger, labels) = new_debugger();
+        let parse = |spec| StopCondition::parse(spec, &labels);
+        let at_loop = debugger.add(parse("loop")?)?;
+        let store = debugger.add(parse("mem:8")?)?;
+        let sum = debugger.add(parse("R4==4")?)?;
+        let commit = debugger.add(parse("commit:1")?)?;
+        assert!(debugger.add(parse("T1.R0==0")?).is_err());
+
+        assert_eq!(debugger.run(100), Stop::Hit(vec![at_loop]));
+        assert_eq!(debugger.timeline().machine().pc(0), 2);
+        // Each condition hits once, in the cycle it becomes true
+        let mut hits = Vec::new();
+        while let Stop::Hit(hit) = debugger.run(100) {
+            hits.push((hit, debugger.timeline().cycle()));
+        }
+        let order: Vec<Vec<usize>> = hits.iter().map(|(hit, _)| hit.clone()).collect();
+        assert!(order.contains(&vec![store]));
+        assert!(order.contains(&vec![commit]));
+        assert!(order.contains(&vec![sum]));
+        // Breakpoints hit only when the PC arrives
+        assert!(!order.contains(&vec![at_loop]));
+        Ok(())
+    }
+}
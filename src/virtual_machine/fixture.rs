@@ -0,0 +1,57 @@
+use super::multi_core::MultiCoreMachine;
+use super::Machine;
+use crate::core::processor::Processor;
+use crate::functional_units::factory::{Factory, Function, MemFunction};
+use crate::memory_bus::cache::Protocol;
+use crate::util::Endian;
+
+/// Return a processor with `arith_units` arithmetic paths and `mem_units` memory access paths,
+/// whose reservation stations hold `station_size` slots, or the default of each unit if None
+pub fn new_processor(
+    arith_units: usize,
+    mem_units: usize,
+    station_size: Option<usize>,
+) -> Processor {
+    let mut p = Processor::new();
+    let mut ff = Factory::new();
+    if let Some(size) = station_size {
+        ff.set_station_size(size);
+    }
+    for _ in 0..arith_units {
+        p.add_path(ff.new_unit(Function::Arithmetic)).unwrap();
+    }
+    for _ in 0..mem_units {
+        p.add_mem_path(ff.new_mem_unit(MemFunction::MemoryAccess))
+            .unwrap();
+    }
+    p
+}
+
+/// Return a machine which runs the program on the processor with `ram_size` bytes of memory
+pub fn with_processor<S: ToString>(program: &[S], core: Processor, ram_size: usize) -> Machine {
+    let program = program.iter().map(|i| i.to_string()).collect();
+    Machine::new(core, program, ram_size)
+}
+
+/// Return a machine which runs the program with `arith_units` arithmetic paths,
+/// a memory access path and `ram_size` bytes of memory, shared by tests
+pub fn new_machine<S: ToString>(program: &[S], arith_units: usize, ram_size: usize) -> Machine {
+    with_processor(program, new_processor(arith_units, 1, None), ram_size)
+}
+
+/// Return a machine with a core for each program, which has an arithmetic path and
+/// a memory access path, sharing `ram_size` bytes of memory kept coherent by `protocol`
+pub fn new_multi_core<S: ToString>(
+    programs: &[&[S]],
+    ram_size: usize,
+    protocol: Protocol,
+) -> MultiCoreMachine {
+    let cores = programs
+        .iter()
+        .map(|program| {
+            let program = program.iter().map(|i| i.to_string()).collect();
+            (new_processor(1, 1, None), program)
+        })
+        .collect();
+    MultiCoreMachine::new(cores, ram_size, protocol, Endian::Big)
+}
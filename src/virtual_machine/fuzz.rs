@@ -0,0 +1,515 @@
+use super::golden::GoldenModel;
+use super::Machine;
+use crate::core::decoder::{InstFormat, TokenType};
+use crate::core::processor::Processor;
+use crate::functional_units::factory::{Factory, Function, MemFunction};
+use crate::util::random::Random;
+use crate::util::{raw_to_u32, Endian};
+use std::fmt::{self, Display};
+use std::ops::{Range, RangeInclusive};
+use std::panic::{self, AssertUnwindSafe};
+
+/// Registers which hold data, R0 is kept zero
+const DATA_REGISTERS: RangeInclusive<usize> = 1..=11;
+/// Registers which hold addresses, written only to point to another word
+const ADDRESS_REGISTERS: Range<usize> = 12..16;
+/// Count of the latest results and accesses which are recent
+const RECENT: usize = 3;
+/// Instructions of each fuzzed program, other than the prologue
+const LENGTH: usize = 40;
+/// Words of memory fuzzed programs access
+const WORDS: u32 = 8;
+
+/// Generate random valid programs from instruction formats of the paths.
+/// Memory accesses only go to the first `words` words of memory.
+pub struct Generator {
+    /// (format, whether it accesses memory)
+    formats: Vec<(InstFormat, bool)>,
+    /// Percentage of source operands taken from recent results
+    dependency: u32,
+    /// Percentage of accesses to a recently accessed word
+    aliasing: u32,
+    words: u32,
+    random: Random,
+    /// Registers written recently, the latest last
+    recent_writes: Vec<usize>,
+    /// Words accessed recently, the latest last
+    recent_accesses: Vec<u32>,
+    /// Address held by each address register
+    addresses: Vec<u32>,
+}
+
+impl Generator {
+    /// Generate instructions of paths constructed by [Factory]
+    pub fn new(seed: u64, words: u32) -> Self {
+        let mut ff = Factory::new();
+        let arith = ff.new_unit(Function::Arithmetic).list_insts();
+        let mem = ff.new_mem_unit(MemFunction::MemoryAccess).list_insts();
+        let formats = arith
+            .into_iter()
+            .map(|f| (f, false))
+            .chain(mem.into_iter().map(|f| (f, true)))
+            .collect();
+        Self {
+            formats,
+            dependency: 50,
+            aliasing: 50,
+            words: words.max(2),
+            random: Random::new(seed),
+            recent_writes: Vec::new(),
+            recent_accesses: Vec::new(),
+            addresses: Vec::new(),
+        }
+    }
+    /// Set percentage of source operands taken from recent results,
+    /// and percentage of accesses to a recently accessed word
+    pub fn set_density(&mut self, dependency: u32, aliasing: u32) {
+        self.dependency = dependency;
+        self.aliasing = aliasing;
+    }
+    /// Generate a program of `length` random instructions, after a prologue setting address registers
+    pub fn program(&mut self, length: usize) -> Vec<String> {
+        self.recent_writes.clear();
+        self.recent_accesses.clear();
+        self.addresses.clear();
+        let mut program = Vec::new();
+        for register in ADDRESS_REGISTERS {
+            // Words are accessed with an offset of at most a word
+            let address = self.random.below(self.words as usize - 1) as u32 * 4;
+            self.addresses.push(address);
+            program.push(format!("addi R{}, R0, #{}", register, address));
+        }
+        let end = program.len() + length;
+        while program.len() < end {
+            let (format, memory) = self.random.pick(&self.formats).clone();
+            program.extend(self.instruction(&format, memory));
+        }
+        program
+    }
+    /// Generate an instruction of the format.
+    /// An access may be preceded by an instruction pointing an address register to the word.
+    fn instruction(&mut self, format: &InstFormat, memory: bool) -> Vec<String> {
+        let mut insts = Vec::new();
+        // The address of an access is the last register, followed by the offset if any
+        let address_at = memory
+            .then(|| {
+                format
+                    .syntax()
+                    .iter()
+                    .rposition(|t| *t == TokenType::Register)
+            })
+            .flatten();
+        let mut offset = 0;
+        let mut args = Vec::new();
+        let mut dest = None;
+        for (idx, token) in format.syntax().iter().enumerate() {
+            let arg = match token {
+                TokenType::Writeback => {
+                    let register = self.random.below(*DATA_REGISTERS.end()) + 1;
+                    dest = Some(register);
+                    format!("R{}", register)
+                }
+                TokenType::Register if Some(idx) == address_at => {
+                    let has_offset = format.syntax().get(idx + 1) == Some(&TokenType::Immediate);
+                    let (register, word_offset, repoint) = self.address(has_offset);
+                    insts.extend(repoint);
+                    offset = word_offset;
+                    format!("R{}", register)
+                }
+                TokenType::Register => format!("R{}", self.source()),
+                TokenType::Immediate if memory => format!("#{}", offset),
+                TokenType::Immediate => format!("#{}", self.random.below(64)),
+            };
+            args.push(arg);
+        }
+        if let Some(register) = dest {
+            self.recent_writes.push(register);
+        }
+        if args.is_empty() {
+            insts.push(format.name().to_string());
+        } else {
+            insts.push(format!("{} {}", format.name(), args.join(", ")));
+        }
+        insts
+    }
+    /// Return a source register, recently written one by the dependency density
+    fn source(&mut self) -> usize {
+        let recent = &self.recent_writes[self.recent_writes.len().saturating_sub(RECENT)..];
+        if !recent.is_empty() && self.random.chance(self.dependency) {
+            *self.random.pick(recent)
+        } else {
+            self.random.below(*DATA_REGISTERS.end() + 1)
+        }
+    }
+    /// Choose a word to access, a recently accessed one by the aliasing density.
+    /// Return (address register, offset, instruction pointing the register to the word if needed).
+    fn address(&mut self, has_offset: bool) -> (usize, u32, Option<String>) {
+        let recent = &self.recent_accesses[self.recent_accesses.len().saturating_sub(RECENT)..];
+        let word = if !recent.is_empty() && self.random.chance(self.aliasing) {
+            *self.random.pick(recent)
+        } else {
+            self.random.below(self.words as usize) as u32 * 4
+        };
+        self.recent_accesses.push(word);
+        let offsets: &[u32] = if has_offset { &[0, 4] } else { &[0] };
+        for offset in offsets {
+            let found = self
+                .addresses
+                .iter()
+                .position(|address| address + offset == word);
+            if let Some(idx) = found {
+                return (ADDRESS_REGISTERS.start + idx, *offset, None);
+            }
+        }
+        let idx = self.random.below(self.addresses.len());
+        let register = ADDRESS_REGISTERS.start + idx;
+        // The last word is only reachable by an offset
+        let offset = if word / 4 == self.words - 1 { 4 } else { 0 };
+        self.addresses[idx] = word - offset;
+        let repoint = format!("addi R{}, R0, #{}", register, word - offset);
+        (register, offset, Some(repoint))
+    }
+}
+
+/// Shape of a machine programs run on
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub arith_units: usize,
+    pub mem_units: usize,
+    /// Slots of each reservation station
+    pub station_size: usize,
+    /// (depth, interval) of pipelines of arithmetic units, see [Factory::set_pipeline]
+    pub pipeline: (usize, usize),
+    /// Arithmetic units share a reservation station, see [Factory::new_shared_unit]
+    pub shared: bool,
+    /// Count of physical registers destinations are renamed to, see [Processor::use_physical_registers]
+    pub physical_registers: Option<usize>,
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} arith, {} mem, {} slots",
+            self.arith_units, self.mem_units, self.station_size
+        )?;
+        if self.pipeline != (1, 1) {
+            let (depth, interval) = self.pipeline;
+            write!(f, ", depth {}, interval {}", depth, interval)?;
+        }
+        if self.shared {
+            write!(f, ", shared")?;
+        }
+        if let Some(count) = self.physical_registers {
+            write!(f, ", {} registers", count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Configurations programs are compared on
+pub const CONFIGS: [Config; 7] = [
+    Config {
+        arith_units: 1,
+        mem_units: 1,
+        station_size: 1,
+        pipeline: (1, 1),
+        shared: false,
+        physical_registers: None,
+    },
+    Config {
+        arith_units: 2,
+        mem_units: 1,
+        station_size: 4,
+        pipeline: (1, 1),
+        shared: false,
+        physical_registers: None,
+    },
+    Config {
+        arith_units: 3,
+        mem_units: 1,
+        station_size: 2,
+        pipeline: (1, 1),
+        shared: false,
+        physical_registers: None,
+    },
+    Config {
+        arith_units: 1,
+        mem_units: 1,
+        station_size: 4,
+        pipeline: (4, 1),
+        shared: false,
+        physical_registers: None,
+    },
+    Config {
+        arith_units: 3,
+        mem_units: 1,
+        station_size: 2,
+        pipeline: (1, 1),
+        shared: true,
+        physical_registers: None,
+    },
+    Config {
+        arith_units: 2,
+        mem_units: 1,
+        station_size: 4,
+        pipeline: (1, 1),
+        shared: false,
+        physical_registers: Some(20),
+    },
+    Config {
+        arith_units: 2,
+        mem_units: 2,
+        station_size: 4,
+        pipeline: (1, 1),
+        shared: false,
+        physical_registers: None,
+    },
+];
+
+impl Config {
+    /// Parse a configuration in the format it's displayed, e.g. "2 arith, 1 mem, 4 slots",
+    /// followed by options of arithmetic units if any: the pipeline, e.g. ", depth 4, interval 1",
+    /// ", shared" and the count of physical registers, e.g. ", 32 registers"
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let error = || {
+            format!(
+                "Expect e.g. 2 arith, 1 mem, 4 slots[, depth 4, interval 1][, shared][, 32 registers], found {}",
+                text
+            )
+        };
+        let fields: Vec<&str> = text.split(',').map(str::trim).collect();
+        let count = |idx: usize, name: &str| {
+            let count = fields.get(idx)?.strip_suffix(name)?.trim().parse().ok()?;
+            (count > 0).then_some(count)
+        };
+        let value = |idx: usize, name: &str| {
+            let value = fields.get(idx)?.strip_prefix(name)?.trim().parse().ok()?;
+            (value > 0).then_some(value)
+        };
+        let mut config = match (count(0, "arith"), count(1, "mem"), count(2, "slots")) {
+            (Some(arith_units), Some(mem_units), Some(station_size)) => Self {
+                arith_units,
+                mem_units,
+                station_size,
+                pipeline: (1, 1),
+                shared: false,
+                physical_registers: None,
+            },
+            _ => return Err(error()),
+        };
+        let mut rest = 3;
+        if let (Some(depth), Some(interval)) = (value(3, "depth"), value(4, "interval")) {
+            config.pipeline = (depth, interval);
+            rest = 5;
+        }
+        if fields.get(rest) == Some(&"shared") {
+            config.shared = true;
+            rest += 1;
+        }
+        if let Some(registers) = count(rest, "registers") {
+            config.physical_registers = Some(registers);
+            rest += 1;
+        }
+        if rest != fields.len() {
+            return Err(error());
+        }
+        Ok(config)
+    }
+    pub fn processor(&self) -> Result<Processor, String> {
+        let mut p = Processor::new();
+        let mut ff = Factory::new();
+        ff.set_station_size(self.station_size);
+        ff.set_pipeline(self.pipeline.0, self.pipeline.1);
+        if self.shared {
+            let pipelines = vec![self.pipeline; self.arith_units];
+            p.add_path(ff.new_shared_unit(Function::Arithmetic, &pipelines))?;
+        } else {
+            for _ in 0..self.arith_units {
+                p.add_path(ff.new_unit(Function::Arithmetic))?;
+            }
+        }
+        for _ in 0..self.mem_units {
+            p.add_mem_path(ff.new_mem_unit(MemFunction::MemoryAccess))?;
+        }
+        if let Some(count) = self.physical_registers {
+            p.use_physical_registers(count)?;
+        }
+        Ok(p)
+    }
+    fn machine(&self, program: &[String], words: u32) -> Result<Machine, String> {
+        Ok(Machine::new(
+            self.processor()?,
+            program.to_vec(),
+            words as usize * 4,
+        ))
+    }
+    /// Run the program to the end, return final registers and memory.
+    /// Errors, panics and running too long are reported as errors.
+    fn run(&self, program: &[String], words: u32) -> Result<(Vec<u32>, Vec<u8>), String> {
+        let mut vm = self.machine(program, words)?;
+        let limit = program.len() * 50 + 100;
+        let run = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), String> {
+            let mut cycles = 0;
+            while vm.pc(0) < program.len() || !vm.is_idle() {
+                if cycles == limit {
+                    return Err(format!("Not finished in {} cycles", limit));
+                }
+                if vm.pc(0) < program.len() {
+                    vm.next_cycle()?;
+                } else {
+                    vm.next_flush_cycle()?;
+                }
+                cycles += 1;
+            }
+            Ok(())
+        }));
+        match run {
+            Ok(result) => result?,
+            Err(_) => return Err(String::from("Panicked")),
+        }
+        let registers = vm
+            .registers(0)
+            .iter()
+            .map(|r| r.val().unwrap_or_default())
+            .collect();
+        Ok((registers, vm.memory()))
+    }
+}
+
+/// Run the program on each configuration, and compare final registers and memory with the golden model.
+/// Return the first mismatch as an error.
+pub fn differential(program: &[String], words: u32, configs: &[Config]) -> Result<(), String> {
+    let mut golden = GoldenModel::new(vec![0; words as usize * 4], Endian::Big);
+    golden.add_thread(0, vec![0; Processor::new().peek_registers().len()]);
+    for inst in program {
+        golden.step(0, inst)?;
+    }
+    for config in configs {
+        let (registers, memory) = config
+            .run(program, words)
+            .map_err(|msg| format!("{}: {}", config, msg))?;
+        let expect = golden.registers(0);
+        if let Some(idx) = (0..expect.len()).find(|idx| registers[*idx] != expect[*idx]) {
+            let msg = format!(
+                "{}: R{} is {}, expect {}",
+                config, idx, registers[idx], expect[idx]
+            );
+            return Err(msg);
+        }
+        let expect = golden.memory();
+        if let Some(word) =
+            (0..memory.len() / 4).find(|w| memory[w * 4..][..4] != expect[w * 4..][..4])
+        {
+            let msg = format!(
+                "{}: memory at {:#x} is {}, expect {}",
+                config,
+                word * 4,
+                raw_to_u32(&memory[word * 4..][..4], Endian::Big),
+                raw_to_u32(&expect[word * 4..][..4], Endian::Big)
+            );
+            return Err(msg);
+        }
+    }
+    Ok(())
+}
+
+/// Generate `count` programs from consecutive seeds with random densities, and compare each of
+/// them across the configurations. Return the first mismatch with its seed and program.
+pub fn fuzz(seed: u64, count: u64, configs: &[Config]) -> Result<(), String> {
+    for seed in seed..seed + count {
+        let mut generator = Generator::new(seed, WORDS);
+        let mut random = Random::new(seed);
+        generator.set_density(random.below(101) as u32, random.below(101) as u32);
+        let program = generator.program(LENGTH);
+        differential(&program, WORDS, configs)
+            .map_err(|msg| format!("Seed {}: {}\n{}", seed, msg, program.join("\n")))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod fuzz {
+    use super::*;
+
+    #[test]
+    fn generate() {
+        let program = |seed| {
+            let mut generator = Generator::new(seed, 4);
+            generator.set_density(100, 100);
+            generator.program(20)
+        };
+        assert_eq!(program(1), program(1));
+        assert_ne!(program(1), program(2));
+        for inst in program(3) {
+            let (name, _) = crate::core::decoder::scan(&inst).unwrap();
+            assert!(
+                !["csrr", "csrw", "nop"].contains(&name.as_str()),
+                "{}",
+                inst
+            );
+        }
+    }
+
+    #[test]
+    fn config() -> Result<(), String> {
+        for config in CONFIGS.iter() {
+            let parsed = Config::parse(&config.to_string())?;
+            assert_eq!(parsed.to_string(), config.to_string());
+        }
+        assert!(Config::parse("2 arith, 1 mem").is_err());
+        assert!(Config::parse("2 arith, 0 mem, 4 slots").is_err());
+        assert!(Config::parse("2 mem, 1 arith, 4 slots").is_err());
+        let pipelined = Config::parse("1 arith, 1 mem, 4 slots, depth 4, interval 2")?;
+        assert_eq!(pipelined.pipeline, (4, 2));
+        assert_eq!(Config::parse("1 arith, 1 mem, 4 slots")?.pipeline, (1, 1));
+        assert!(Config::parse("1 arith, 1 mem, 4 slots, depth 4").is_err());
+        let shared = Config::parse("3 arith, 1 mem, 2 slots, depth 2, interval 1, shared")?;
+        assert!(shared.shared);
+        assert_eq!(shared.pipeline, (2, 1));
+        assert!(Config::parse("3 arith, 1 mem, 2 slots, shared, depth 2, interval 1").is_err());
+        let renamed = Config::parse("2 arith, 1 mem, 4 slots, shared, 32 registers")?;
+        assert_eq!(renamed.physical_registers, Some(32));
+        assert!(Config::parse("2 arith, 1 mem, 4 slots, 32 registers, shared").is_err());
+        assert!(Config::parse("2 arith, 1 mem, 4 slots, 16 registers")?
+            .processor()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn all_configs() {
+        if let Err(msg) = fuzz(0, 50, &CONFIGS) {
+            panic!("{}", msg);
+        }
+    }
+
+    #[test]
+    fn memory_units_diverged() {
+        // Seed 3 loaded a word before an older store of the other unit wrote it,
+        // and seed 5 lost the reservation of lr.w executed by the other unit
+        let config = Config::parse("2 arith, 2 mem, 4 slots").unwrap();
+        for seed in [3, 5] {
+            if let Err(msg) = fuzz(seed, 1, &[config]) {
+                panic!("{}", msg);
+            }
+        }
+    }
+
+    #[test]
+    fn reservation_order() -> Result<(), String> {
+        // The second lr.w waits for the amoadd.w, and the sc.w must not overtake it
+        let program = [
+            "addi R1, R0, #8",
+            "addi R2, R0, #4",
+            "lr.w R3, R1",
+            "lw R4, R0, #0",
+            "amoadd.w R5, R4, R2",
+            "lr.w R6, R2",
+            "sc.w R7, R0, R1",
+        ];
+        let program: Vec<String> = program.iter().map(|i| i.to_string()).collect();
+        differential(&program, 4, &CONFIGS)
+    }
+}
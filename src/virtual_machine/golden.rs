@@ -0,0 +1,237 @@
+use crate::core::csr::CsrFile;
+use crate::core::decoder::{scan, ArgType};
+use crate::core::execution_path::AmoOp;
+use crate::util::{raw_to_u32, u32_to_raw, Endian};
+use std::ops::Range;
+
+/// Architectural state of a hardware thread
+#[derive(Debug, Clone)]
+struct Thread {
+    pc: usize,
+    registers: Vec<u32>,
+    /// Address range reserved by load reserved
+    reservation: Option<Range<u32>>,
+}
+
+/// In-order functional interpreter of the instruction set.
+/// Every instruction completes before the next one starts, so it's the reference
+/// the processor is checked against.
+#[derive(Debug, Clone)]
+pub struct GoldenModel {
+    threads: Vec<Thread>,
+    /// DRAM, accesses beyond it go to devices which are not modelled
+    memory: Vec<u8>,
+    endian: Endian,
+    /// Control and status registers shared by threads, as in the processor
+    csr: CsrFile,
+}
+
+impl GoldenModel {
+    pub fn new(memory: Vec<u8>, endian: Endian) -> Self {
+        Self {
+            threads: Vec::new(),
+            memory,
+            endian,
+            csr: CsrFile::new(),
+        }
+    }
+    /// Add a thread which starts from `pc` with given register values
+    pub fn add_thread(&mut self, pc: usize, registers: Vec<u32>) {
+        self.threads.push(Thread {
+            pc,
+            registers,
+            reservation: None,
+        });
+    }
+    pub fn pc(&self, thread: usize) -> usize {
+        self.threads[thread].pc
+    }
+    pub fn registers(&self, thread: usize) -> &[u32] {
+        &self.threads[thread].registers
+    }
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+    pub fn csr(&self) -> &CsrFile {
+        &self.csr
+    }
+    /// Start from the given CSRs, e.g. those of the machine being checked
+    pub fn set_csr(&mut self, csr: CsrFile) {
+        self.csr = csr;
+    }
+    /// Set the CSRs driven from outside the instruction stream, the cycle count and pending interrupts
+    pub fn set_inputs(&mut self, cycle: u32, pending: u32) {
+        self.csr.cycle = cycle;
+        self.csr.pending = pending;
+    }
+    /// Take the pending interrupt. As in the processor, only thread 0 is redirected to the vector.
+    pub fn interrupt(&mut self) {
+        let thread = &mut self.threads[0];
+        thread.pc = self.csr.take(thread.pc);
+    }
+    /// Execute the instruction at PC of the thread.
+    /// Return the register written and its value, if any.
+    pub fn step(&mut self, thread: usize, inst: &str) -> Result<Option<(usize, u32)>, String> {
+        let (name, args) = scan(inst)?;
+        let address = self.threads[thread].pc;
+        let t = &self.threads[thread];
+        let reg = |idx: usize| match args.get(idx) {
+            Some(ArgType::Reg(r)) if *r < t.registers.len() => Ok(*r),
+            _ => Err(format!("Argument {} of {} should be a register", idx, inst)),
+        };
+        let val = |idx: usize| match args.get(idx) {
+            Some(ArgType::Imm(imm)) => Ok(*imm),
+            _ => reg(idx).map(|r| t.registers[r]),
+        };
+        let amo = |op: AmoOp| -> Result<_, String> { Ok((op, reg(0)?, val(1)?, val(2)?)) };
+        let mut write = None;
+        let mut next = address + 1;
+        match name.as_str() {
+            "add" | "addi" => write = Some((reg(0)?, val(1)?.wrapping_add(val(2)?))),
+            "mul" => write = Some((reg(0)?, val(1)?.wrapping_mul(val(2)?))),
+            "lw" => {
+                let loaded = self.read(val(1)?.wrapping_add(val(2)?))?;
+                write = Some((reg(0)?, loaded));
+            }
+            "sw" => self.write(val(1)?.wrapping_add(val(2)?), val(0)?),
+            "lr.w" => {
+                let address = val(1)?;
+                write = Some((reg(0)?, self.read(address)?));
+                self.threads[thread].reservation = Some(word(address));
+            }
+            "sc.w" => {
+                let (dest, value, address) = (reg(0)?, val(1)?, val(2)?);
+                let reserved = self.threads[thread].reservation.take() == Some(word(address));
+                if reserved {
+                    self.write(address, value);
+                }
+                write = Some((dest, !reserved as u32));
+            }
+            "amoadd.w" | "amoswap.w" | "amomax.w" => {
+                let (op, dest, operand, address) = match name.as_str() {
+                    "amoadd.w" => amo(AmoOp::Add)?,
+                    "amoswap.w" => amo(AmoOp::Swap)?,
+                    _ => amo(AmoOp::Max)?,
+                };
+                let old = self.read(address)?;
+                self.write(address, op.apply(old, operand));
+                write = Some((dest, old));
+            }
+            "csrr" => write = Some((reg(0)?, self.csr.read(val(1)?)?)),
+            "csrw" => self.csr.write(val(1)?, val(0)?)?,
+            "mret" => next = self.csr.ret(),
+            "nop" | "fence" => (),
+            _ => return Err(format!("Golden model doesn't support {}", inst)),
+        }
+        let t = &mut self.threads[thread];
+        if let Some((dest, value)) = write {
+            t.registers[dest] = value;
+        }
+        t.pc = next;
+        self.csr.instret = self.csr.instret.wrapping_add(1);
+        Ok(write)
+    }
+    /// Skip the instruction at PC of the thread, e.g. a nop issued beyond the program
+    pub fn skip(&mut self, thread: usize) {
+        self.threads[thread].pc += 1;
+        self.csr.instret = self.csr.instret.wrapping_add(1);
+    }
+    fn read(&self, address: u32) -> Result<u32, String> {
+        let start = address as usize;
+        self.memory
+            .get(start..start + 4)
+            .map(|word| raw_to_u32(word, self.endian))
+            .ok_or_else(|| format!("Golden model doesn't model device at {:#x}", address))
+    }
+    /// Write a word, writes to devices are dropped
+    fn write(&mut self, address: u32, value: u32) {
+        let start = address as usize;
+        if let Some(word) = self.memory.get_mut(start..start + 4) {
+            word.copy_from_slice(&u32_to_raw(value, self.endian));
+        }
+        // Like the memory bus, any write cancels reservations overlapping it
+        let range = word(address);
+        for t in self.threads.iter_mut() {
+            if let Some(reserved) = t.reservation.as_ref() {
+                if reserved.start < range.end && range.start < reserved.end {
+                    t.reservation = None;
+                }
+            }
+        }
+    }
+}
+
+/// Return the address range of the word at `address`
+fn word(address: u32) -> Range<u32> {
+    address..address.wrapping_add(4)
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod golden {
+    use super::*;
+
+    #[test]
+    fn execute() -> Result<(), String> {
+        let program = [
+            "addi R1, R0, #8",
+            "sw R1, R1, #0",
+            "lw R2, R0, #8",
+            "add R3, R2, R1",
+            "amoadd.w R4, R3, R1",
+            "lr.w R5, R1",
+            "sc.w R6, R3, R1",
+            "sc.w R7, R3, R1",
+        ];
+        let mut golden = GoldenModel::new(vec![0; 16], Endian::Little);
+        golden.add_thread(0, vec![0; 8]);
+        let writes = program
+            .iter()
+            .map(|inst| golden.step(0, inst))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(golden.registers(0), [0, 8, 8, 16, 8, 24, 0, 1]);
+        assert_eq!(writes[1], None);
+        assert_eq!(writes[3], Some((3, 16)));
+        assert_eq!(golden.memory()[8..12], [16, 0, 0, 0]);
+        assert_eq!(golden.pc(0), program.len());
+        assert!(golden.step(0, "csrr R1, #1").is_err());
+        assert!(golden.step(0, "lw R1, R0, #4294901764").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn control_and_status_registers() -> Result<(), String> {
+        let mut golden = GoldenModel::new(vec![0; 16], Endian::Little);
+        golden.add_thread(0, vec![0; 4]);
+        golden.set_inputs(10, 0);
+        golden.step(0, "addi R1, R0, #20")?;
+        golden.step(0, "csrw R1, #773")?;
+        assert_eq!(golden.step(0, "csrr R2, #3074")?, Some((2, 2)));
+        assert_eq!(golden.step(0, "csrr R3, #3072")?, Some((3, 10)));
+        golden.set_inputs(11, 1);
+        golden.interrupt();
+        assert_eq!(golden.pc(0), 20);
+        assert_eq!(golden.step(0, "csrr R3, #834")?, Some((3, 1)));
+        golden.step(0, "mret")?;
+        assert_eq!(golden.pc(0), 4);
+        assert_eq!(golden.csr().taken, 1);
+        assert!(golden.csr().enabled);
+        assert!(golden.step(0, "csrw R1, #3072").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reservation() -> Result<(), String> {
+        let mut golden = GoldenModel::new(vec![0; 16], Endian::Little);
+        golden.add_thread(0, vec![0; 4]);
+        golden.add_thread(0, vec![0; 4]);
+        golden.step(0, "lr.w R1, R0")?;
+        // A store overlapping the reserved word cancels the reservation
+        golden.step(1, "sw R0, R0, #2")?;
+        assert_eq!(golden.step(0, "sc.w R2, R0, R0")?, Some((2, 1)));
+        golden.step(0, "lr.w R1, R0")?;
+        golden.step(1, "sw R0, R0, #4")?;
+        assert_eq!(golden.step(0, "sc.w R2, R0, R0")?, Some((2, 0)));
+        Ok(())
+    }
+}
@@ -1,70 +1,403 @@
+use crate::core::csr::{self, CsrFile};
+use crate::core::execution_path::ArgState;
 use crate::core::processor::Processor;
+use crate::graph::Graph;
+use crate::memory_bus::bus::{BusFault, MemoryBus};
+use crate::memory_bus::console::Console;
 use crate::memory_bus::dram::Dram;
+use crate::memory_bus::exit::Exit;
+use crate::memory_bus::interrupt::InterruptController;
+use crate::memory_bus::mmu::{Mmu, PageFault};
+use crate::memory_bus::timer::Timer;
+use crate::util::json::Json;
+use crate::util::{raw_to_u32, u32_to_raw, Endian};
 use std::fmt;
+use std::ops::Range;
 
+pub mod checker;
+pub mod debugger;
+#[cfg(test)]
+pub mod fixture;
+pub mod fuzz;
+pub mod golden;
+pub mod multi_core;
+pub mod program;
+pub mod test_case;
+pub mod timeline;
+pub mod timing;
+pub mod trace;
+
+/// Address of console output register
+pub const CONSOLE_ADDRESS: u32 = 0xffff_0000;
+/// Address of cycle counter register
+pub const TIMER_ADDRESS: u32 = 0xffff_0004;
+/// Address of exit register
+pub const EXIT_ADDRESS: u32 = 0xffff_0008;
+/// Base address of interrupt controller registers
+pub const INTC_ADDRESS: u32 = 0xffff_0010;
+/// Format version of snapshots, bumped whenever saved state changes
+pub const SNAPSHOT_VERSION: u32 = 11;
+
+#[derive(Clone)]
 pub struct Machine {
     core: Processor,
-    iram: Vec<String>,
-    dram: Dram,
+    /// Instructions of each hardware thread
+    iram: Vec<Vec<String>>,
+    bus: MemoryBus,
+    /// Translate addresses of memory accesses if enabled
+    mmu: Option<Mmu>,
+    endian: Endian,
 }
 
 impl fmt::Display for Machine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "{}", self.core)
+        writeln!(f, "{}", self.core)?;
+        if let Some(mmu) = self.mmu.as_ref() {
+            writeln!(f, "{}", mmu)?;
+        }
+        writeln!(f, "{}", self.bus)
     }
 }
 
 impl Machine {
     pub fn new(core: Processor, insts: Vec<String>, ram_size: usize) -> Self {
+        Machine::with_endian(core, insts, ram_size, Endian::Big)
+    }
+    /// Construct a machine which accesses memory in given byte order
+    pub fn with_endian(
+        mut core: Processor,
+        insts: Vec<String>,
+        ram_size: usize,
+        endian: Endian,
+    ) -> Self {
+        core.set_endian(endian);
+        let mut bus = MemoryBus::new(endian);
+        bus.map(0, Box::new(Dram::new(ram_size)))
+            .expect("Unable to map DRAM");
+        bus.map(CONSOLE_ADDRESS, Box::new(Console::new(true, endian)))
+            .expect("Unable to map console");
+        bus.map(TIMER_ADDRESS, Box::new(Timer::new(endian)))
+            .expect("Unable to map timer");
+        bus.map(EXIT_ADDRESS, Box::new(Exit::new(endian)))
+            .expect("Unable to map exit register");
+        bus.map(INTC_ADDRESS, Box::new(InterruptController::new(endian)))
+            .expect("Unable to map interrupt controller");
         Self {
             core,
-            iram: insts,
-            dram: Dram::new(ram_size),
+            iram: vec![insts],
+            bus,
+            mmu: None,
+            endian,
         }
     }
+    /// Add a hardware thread to the processor, which executes given instructions
+    pub fn add_thread(&mut self, insts: Vec<String>) {
+        self.core.add_thread();
+        self.iram.push(insts);
+    }
+    /// Translate memory accesses through a MMU with given root page table and TLB size
+    pub fn enable_mmu(&mut self, root: u32, tlb_entries: usize) {
+        self.mmu = Some(Mmu::new(root, tlb_entries, self.endian));
+        self.core.translate_accesses();
+    }
+    /// Write words to memory from `base` before execution
+    pub fn preload(&mut self, base: u32, words: &[u32]) -> Result<(), String> {
+        let raw: Vec<u8> = words
+            .iter()
+            .flat_map(|word| u32_to_raw(*word, self.endian))
+            .collect();
+        self.bus.load(base, &raw)
+    }
+    /// Print console output or not
+    pub fn set_echo(&mut self, echo: bool) {
+        self.bus.set_echo(echo);
+    }
+    /// Return contents of DRAM
+    pub fn memory(&self) -> Vec<u8> {
+        self.bus
+            .device("dram")
+            .map(|dram| dram.dump())
+            .unwrap_or_default()
+    }
+    /// Return words in DRAM as rows of "address: value"
+    pub fn dump_memory(&self) -> Vec<String> {
+        self.memory()
+            .chunks(4)
+            .enumerate()
+            .map(|(idx, word)| format!("{:#06x}: {}", idx * 4, raw_to_u32(word, self.endian)))
+            .collect()
+    }
     /// Execute next machine cycle of virtual machine
     pub fn next_cycle(&mut self) -> Result<(), String> {
-        let line = self.core.fetch_address();
-        let inst = self
+        let insts: Vec<Option<String>> = self
             .iram
-            .get(line)
-            .ok_or(format!("Inst addr: {} out of bound", line))?;
-        let inst = inst.clone();
-        self.do_next_cycle(&inst)
+            .iter()
+            .enumerate()
+            .map(|(thread, iram)| iram.get(self.core.fetch_address_of(thread)).cloned())
+            .collect();
+        if insts.iter().all(|inst| inst.is_none()) {
+            let line = self.core.fetch_address();
+            return Err(format!("Inst addr: {} out of bound", line));
+        }
+        self.do_next_cycle(|thread, _| insts[thread].clone())
     }
-    fn do_next_cycle(&mut self, inst: &str) -> Result<(), String> {
+    fn do_next_cycle<F>(&mut self, fetch: F) -> Result<(), String>
+    where
+        F: FnMut(usize, usize) -> Option<String>,
+    {
+        if let Some(status) = self.exit_status() {
+            let msg = format!("Machine exited with status {}", status);
+            return Err(msg);
+        }
+        if let Some(fault) = self.page_fault() {
+            return Err(format!("Machine stopped by {}", fault));
+        }
+        if let Some(fault) = self.bus_fault() {
+            return Err(format!("Machine stopped by {}", fault));
+        }
         let p = &mut self.core;
-        p.next_cycle(inst)?;
-        if self.dram.is_idle() {
+        p.set_interrupt_pending(self.bus.interrupt());
+        p.next_smt_cycle(fetch)?;
+        if let Some(mmu) = self.mmu.as_mut() {
+            if mmu.is_idle() {
+                if let Some(request) = p.bus_access() {
+                    mmu.translate(request);
+                }
+            }
+            if self.bus.is_idle() {
+                if let Some(request) = mmu.bus_access() {
+                    p.translated(&request.tag());
+                    self.bus.access(request)?;
+                }
+            }
+        } else if self.bus.is_idle() {
             if let Some(request) = p.bus_access() {
-                self.dram.access(request)?;
+                self.bus.access(request)?;
             }
         }
-        if let Some(response) = self.dram.next_cycle() {
-            p.resolve_access(response)?;
+        if let Some(response) = self.bus.next_cycle() {
+            match self.mmu.as_mut() {
+                Some(mmu) if mmu.owns(&response) => mmu.resolve(response)?,
+                _ => p.resolve_access(response)?,
+            }
+        }
+        self.trap_page_fault()
+    }
+    /// Trap the page fault raised, if the processor has a handler for it.
+    /// Otherwise, it's left to stop the machine.
+    fn trap_page_fault(&mut self) -> Result<(), String> {
+        let Some(mmu) = self.mmu.as_mut() else {
+            return Ok(());
+        };
+        if !self.core.traps_exceptions() {
+            return Ok(());
+        }
+        match mmu.take_fault() {
+            Some(fault) => {
+                let cause = if fault.is_load {
+                    csr::LOAD_PAGE_FAULT
+                } else {
+                    csr::STORE_PAGE_FAULT
+                };
+                self.core.page_fault(&fault.tag, cause, fault.address)
+            }
+            None => Ok(()),
         }
-        Ok(())
     }
     pub fn next_flush_cycle(&mut self) -> Result<(), String> {
         if self.core.is_idle() {
             let msg = String::from("This machine is idle");
             return Err(msg);
         }
-        self.do_next_cycle("nop")
+        self.do_next_cycle(|_, _| Some(String::from("nop")))
+    }
+    /// Return tables of the processor, the MMU and the memory bus
+    pub fn panes(&self) -> Vec<String> {
+        let mut panes = self.core.panes();
+        if let Some(mmu) = self.mmu.as_ref() {
+            panes.push(mmu.to_string());
+        }
+        panes.push(self.bus.to_string());
+        panes
+    }
+    /// Return wheither no instruction is in flight
+    pub fn is_idle(&self) -> bool {
+        self.core.is_idle()
+    }
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+    /// Return wheither memory accesses are translated by a MMU
+    pub fn has_mmu(&self) -> bool {
+        self.mmu.is_some()
+    }
+    /// Return count of hardware threads
+    pub fn threads(&self) -> usize {
+        self.iram.len()
+    }
+    /// Return instructions executed by given thread
+    pub fn program(&self, thread: usize) -> &[String] {
+        &self.iram[thread]
+    }
+    /// Return registers of given thread, a renamed register is waiting for its tag
+    pub fn registers(&self, thread: usize) -> Vec<ArgState> {
+        self.core.peek_thread_registers(thread)
+    }
+    /// Return instructions (thread, address) committed in the last cycle
+    pub fn committed(&self) -> &[(usize, usize)] {
+        self.core.committed()
+    }
+    /// Return physical address range written through the memory bus in the last cycle
+    pub fn memory_written(&self) -> Option<Range<u32>> {
+        self.bus.written()
+    }
+    /// Return registers (thread, register number) written in the last cycle
+    pub fn written(&self) -> &[(usize, usize)] {
+        self.core.written()
+    }
+    /// Return control and status registers of the processor
+    pub fn csr(&self) -> &CsrFile {
+        self.core.csr()
+    }
+    /// Return count of issued instructions of each thread
+    pub fn thread_issued(&self) -> Vec<u64> {
+        self.core.thread_issued()
+    }
+    /// Return fetch address of given thread
+    pub fn pc(&self, thread: usize) -> usize {
+        self.core.fetch_address_of(thread)
+    }
+    /// Return state of the processor, memory bus and MMU in current cycle for tracing
+    pub fn trace(&self) -> Json {
+        // State of the processor is an object, extended with the memory system
+        let mut fields = match self.core.trace() {
+            Json::Object(fields) => fields,
+            core => vec![(String::from("core"), core)],
+        };
+        fields.push((String::from("memory_bus"), self.bus.save()));
+        let mmu = self.mmu.as_ref().map_or(Json::Null, Mmu::save);
+        fields.push((String::from("mmu"), mmu));
+        Json::Object(fields)
+    }
+    /// Return DOT of the datapath of the processor
+    pub fn topology(&self) -> String {
+        self.core.get_graph()
+    }
+    /// Return DOT of dependencies between in-flight instructions
+    pub fn dependency_graph(&self) -> String {
+        self.core.dependency_graph()
+    }
+    /// Return (hit, miss) count of TLB if MMU is enabled
+    pub fn tlb_statistics(&self) -> Option<(usize, usize)> {
+        self.mmu.as_ref().map(|mmu| mmu.tlb_statistics())
+    }
+    /// If the program has written the exit register, return Some(exit status).
+    /// Otherwise, return None.
+    pub fn exit_status(&self) -> Option<u32> {
+        self.bus.exit_status()
+    }
+    /// Return the page fault which stopped the machine, if any.
+    /// A page fault trapped to the handler is not.
+    pub fn page_fault(&self) -> Option<&PageFault> {
+        self.mmu.as_ref().and_then(Mmu::fault)
+    }
+    /// Return the bus fault which stopped the machine, if any
+    pub fn bus_fault(&self) -> Option<&BusFault> {
+        self.bus.fault()
+    }
+    /// Return bytes printed by the program through console
+    #[cfg(test)]
+    pub fn console_output(&self) -> Vec<u8> {
+        self.bus
+            .device("console")
+            .map(|console| console.dump())
+            .unwrap_or_default()
+    }
+    /// Return complete state of the machine, including the program
+    pub fn save(&self) -> Json {
+        let iram = self
+            .iram
+            .iter()
+            .map(|insts| Json::Array(insts.iter().map(Json::str).collect()))
+            .collect();
+        Json::object(vec![
+            ("version", Json::from(SNAPSHOT_VERSION)),
+            ("endian", Json::str(self.endian.name())),
+            ("iram", Json::Array(iram)),
+            ("core", self.core.save()),
+            ("bus", self.bus.save()),
+            ("mmu", self.mmu.as_ref().map_or(Json::Null, Mmu::save)),
+        ])
+    }
+    /// Restore state returned by [Machine::save].
+    /// The machine has to be constructed with the same paths, policies, threads and memory.
+    /// On error, the machine is left as it was.
+    pub fn restore(&mut self, state: &Json) -> Result<(), String> {
+        let mut restored = self.clone();
+        restored.restore_state(state)?;
+        *self = restored;
+        Ok(())
+    }
+    /// Restore state field by field, which may be left partially restored on error
+    fn restore_state(&mut self, state: &Json) -> Result<(), String> {
+        let version = state.get("version")?.as_u32()?;
+        if version != SNAPSHOT_VERSION {
+            let msg = format!(
+                "Snapshot version {} is not supported, expect {}",
+                version, SNAPSHOT_VERSION
+            );
+            return Err(msg);
+        }
+        let endian = Endian::from_name(state.get("endian")?.as_str()?)?;
+        if endian != self.endian {
+            return Err(format!("Snapshot is taken in {} endian", endian.name()));
+        }
+        let iram = state
+            .get("iram")?
+            .as_array()?
+            .iter()
+            .map(|insts| {
+                let insts = insts.as_array()?.iter();
+                insts.map(|inst| Ok(inst.as_str()?.to_string())).collect()
+            })
+            .collect::<Result<Vec<Vec<String>>, String>>()?;
+        if iram.len() != self.iram.len() {
+            let msg = format!(
+                "Snapshot has {} threads, but the machine has {}",
+                iram.len(),
+                self.iram.len()
+            );
+            return Err(msg);
+        }
+        self.core.restore(state.get("core")?)?;
+        self.bus.restore(state.get("bus")?)?;
+        self.mmu = match state.get("mmu")?.opt() {
+            Some(mmu) => Some(Mmu::load(mmu, self.endian)?),
+            None => None,
+        };
+        self.iram = iram;
+        Ok(())
     }
     /// Splite virtual machine into components
     pub fn splite(self) -> (Processor, Vec<u8>) {
-        (self.core, self.dram.into_raw_data())
+        let dram = self.memory();
+        (self.core, dram)
     }
 }
 
 #[cfg(test)]
 mod vm {
-    use crate::core::execution_path::ArgState;
+    use super::fixture;
+    use super::program::Program;
+    use super::trace::TraceWriter;
+    use crate::core::bus_controller::policy_of;
+    use crate::core::csr;
+    use crate::core::execution_path::{ArgState, RStag};
+    use crate::core::statistics::StallCause;
     use crate::functional_units::factory::Factory;
     use crate::functional_units::factory::Function;
     use crate::functional_units::factory::MemFunction;
-    use crate::util::raw_to_u32_big_endian;
+    use crate::memory_bus::mmu::FaultCause;
 
     use super::*;
 
@@ -72,6 +405,7 @@ mod vm {
         while vm.next_flush_cycle().is_ok() {}
     }
     #[test]
+    #[allow(clippy::useless_vec)]
     fn sequential_execution() -> Result<(), String> {
         let program = vec![
             "addi R1, R0, #100", // R1 = 100
@@ -176,7 +510,7 @@ mod vm {
         let assert = |expect: (u32, u32)| {
             let expect_value = expect.0;
             let address = expect.1 as usize;
-            let got = raw_to_u32_big_endian(&dram[address..address + 4]);
+            let got = raw_to_u32(&dram[address..address + 4], Endian::Big);
             assert_eq!(got, expect_value);
         };
         println!("{:?}", dram);
@@ -184,4 +518,715 @@ mod vm {
         assert(expect_k);
         Ok(())
     }
+
+    #[test]
+    fn memory_access_statistics() -> Result<(), String> {
+        let program = [
+            "addi R2, R0, #10",
+            "addi R3, R0, #4",
+            "lw R1, R2, #0",
+            "add R1, R3, R1",
+            "sw R1, R2, #0",
+            "lw R1, R2, #4",
+            "add R1, R3, R1",
+            "sw R1, R2, #4",
+        ];
+        let mut vm = fixture::new_machine(&program, 2, 200);
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+
+        let (processor, _) = vm.splite();
+        let stat = processor.statistics();
+        assert_eq!(stat.issued, stat.committed);
+        assert!(stat.ipc() > 0.0);
+        // Loads wait for the memory, and adds wait for the loads
+        assert!(stat.stall_cycles(StallCause::MemoryBusy) > 0);
+        assert!(stat.stall_cycles(StallCause::WaitingOperands) > 0);
+        assert!(stat.to_csv().contains("mem_access"));
+        Ok(())
+    }
+
+    #[test]
+    fn trace() -> Result<(), String> {
+        let program = ["addi R1, R0, #8", "sw R1, R1, #0", "add R2, R1, R1"];
+        let mut vm = fixture::new_machine(&program, 1, 16);
+
+        let mut out = Vec::new();
+        let mut writer = TraceWriter::new(&mut out);
+        for _ in 0..3 {
+            vm.next_cycle()?;
+            writer.record(vm.trace())?;
+        }
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0]
+            .starts_with(r#"{"cycle":0,"state":{"threads":[{"pc":1,"registers":[0,"arith0(0)""#));
+        // Store waits for its base in the memory unit, the add is renamed
+        assert!(lines[2].contains(r#""evaluation":[{"slot":0,"inst":"sw"#));
+        assert!(lines[2].contains(r#"0,"arith0(0)","arith0(1)""#));
+        assert!(lines[2].contains(r#""bus_queue":[],"result_bus":"#));
+        // The store is still in the memory unit, so the bus is free and nothing is written
+        assert!(
+            lines[2].contains(r#""memory_bus":{"request":null,"reservations":[],"written":null"#)
+        );
+        assert!(lines[2].ends_with(r#""mmu":null}}"#));
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_diagram() -> Result<(), String> {
+        let program = ["addi R1, R0, #8", "add R2, R1, R1", "nop"];
+        let mut p = fixture::new_processor(1, 0, None);
+        p.record_pipeline();
+        let mut vm = fixture::with_processor(&program, p, 0);
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+
+        let (p, _) = vm.splite();
+        let gantt = p.pipeline().unwrap().to_gantt();
+        let rows: Vec<&str> = gantt.lines().skip(1).collect();
+        // The add waits for R1 in the station, then executes once it's forwarded. Nops are skipped.
+        // Execution ends a cycle before the result is on the result bus.
+        assert_eq!(
+            rows,
+            [
+                "   0 addi R1, R0, #8 |XEWC...",
+                "   1 add R2, R1, R1  |.IIXEWC"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn graphs() -> Result<(), String> {
+        let program = ["addi R1, R0, #8", "lw R2, R1, #0", "add R3, R2, R1"];
+        let mut vm = fixture::new_machine(&program, 1, 0);
+
+        let topology = vm.topology();
+        assert!(topology.starts_with("digraph processor {"));
+        assert!(topology.contains(r#"subgraph "cluster_arith0""#));
+        assert!(topology.contains(r#"subgraph "cluster_mem_access0""#));
+        assert!(topology.contains(r#""issue" -> "arith0.in";"#));
+        assert!(topology.contains(r#""arith0.out" -> "result_bus";"#));
+        assert!(topology.contains(r#""mem_access0.out" -> "bus_controller";"#));
+        assert!(topology.contains(r#"subgraph "cluster_csr1""#));
+
+        // The load waits for R1, and the add waits for both
+        for _ in 0..3 {
+            vm.next_cycle()?;
+        }
+        let dependency = vm.dependency_graph();
+        assert!(dependency.contains(r#""arith0(0)" -> "mem_access0(0)";"#));
+        assert!(dependency.contains(r#""mem_access0(0)" -> "arith0(1)";"#));
+        assert!(dependency.contains(r#""arith0(1)" -> "R3" [style=dotted];"#));
+        Ok(())
+    }
+
+    #[test]
+    fn arbitration_policies() -> Result<(), String> {
+        let program = [
+            "addi R1, R0, #1",
+            "addi R2, R0, #2",
+            "sw R1, R0, #0",
+            "sw R2, R0, #4",
+            "lw R3, R0, #0",
+            "lw R4, R0, #4",
+            "add R5, R3, R4",
+            "sw R5, R0, #8",
+            "sw R0, R0, #0",
+        ];
+        for policy in ["fifo", "oldest", "load-first", "round-robin"] {
+            let mut p = fixture::new_processor(1, 1, None);
+            p.set_arbitration_policy(policy_of(policy)?);
+
+            let mut vm = fixture::with_processor(&program, p, 12);
+            while vm.next_cycle().is_ok() {}
+            flush(&mut vm);
+
+            let (p, dram) = vm.splite();
+            assert_eq!(raw_to_u32(&dram[0..4], Endian::Big), 0, "{}", policy);
+            assert_eq!(raw_to_u32(&dram[8..12], Endian::Big), 3, "{}", policy);
+            assert_eq!(p.bus_statistics()["mem_access0"].granted, 6);
+        }
+        Ok(())
+    }
+    #[test]
+    fn arbitration_across_units() -> Result<(), String> {
+        let mut program = vec![String::from("addi R1, R0, #1")];
+        program.extend((0..6).map(|i| format!("sw R1, R0, #{}", i * 4)));
+        for policy in ["fifo", "oldest", "load-first", "round-robin"] {
+            let mut p = fixture::new_processor(1, 2, Some(1));
+            p.set_arbitration_policy(policy_of(policy)?);
+
+            let mut vm = fixture::with_processor(&program, p, 24);
+            while vm.next_cycle().is_ok() {}
+            flush(&mut vm);
+
+            let (p, dram) = vm.splite();
+            for word in dram.chunks(4) {
+                assert_eq!(raw_to_u32(word, Endian::Big), 1, "{}", policy);
+            }
+            let stats = p.bus_statistics();
+            let granted = [&stats["mem_access0"], &stats["mem_access1"]].map(|s| s.granted);
+            assert_eq!(granted.iter().sum::<usize>(), 6, "{}", policy);
+            assert!(granted.iter().all(|g| *g > 0), "{}", policy);
+            // Ages of committed instructions are forgotten
+            assert!(p.save().get("issue_order")?.as_array()?.is_empty());
+        }
+        Ok(())
+    }
+    #[test]
+    fn aliasing_arbitration_across_units() -> Result<(), String> {
+        let program = [
+            "addi R1, R0, #2",
+            "mul R2, R1, R1",
+            "lw R6, R2, #0",
+            "sw R2, R0, #0",
+            "lw R3, R0, #0",
+            "add R4, R3, R3",
+            "sw R4, R0, #4",
+            "lw R5, R0, #4",
+            "sw R1, R0, #0",
+        ];
+        for policy in ["fifo", "oldest", "load-first", "round-robin"] {
+            let mut p = fixture::new_processor(1, 2, Some(1));
+            p.set_arbitration_policy(policy_of(policy)?);
+
+            let mut vm = fixture::with_processor(&program, p, 8);
+            while vm.next_cycle().is_ok() {}
+            flush(&mut vm);
+
+            // Stations of the first unit are full, so the first load goes to the other unit, and
+            // still reads the word stored before it
+            let (p, dram) = vm.splite();
+            let registers = p.peek_registers();
+            assert_eq!(registers[3], ArgState::Ready(4), "{}", policy);
+            assert_eq!(registers[5], ArgState::Ready(8), "{}", policy);
+            assert_eq!(raw_to_u32(&dram[0..4], Endian::Big), 2, "{}", policy);
+            let stats = p.bus_statistics();
+            let granted = [&stats["mem_access0"], &stats["mem_access1"]].map(|s| s.granted);
+            assert!(granted.iter().all(|g| *g > 0), "{}", policy);
+        }
+        Ok(())
+    }
+    #[test]
+    fn little_endian() -> Result<(), String> {
+        let program = Program::parse(
+            "
+            .data 0
+            .word 0x01020304
+            .text
+            lw R1, R0, #0
+            addi R1, R1, #1
+            sw R1, R0, #4
+            ",
+        )?;
+
+        let p = fixture::new_processor(1, 1, None);
+        let mut vm = Machine::with_endian(p, program.text().to_vec(), 8, Endian::Little);
+        for (base, words) in program.data() {
+            vm.preload(*base, words)?;
+        }
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+
+        assert_eq!(vm.dump_memory(), ["0x0000: 16909060", "0x0004: 16909061"]);
+        let (_, dram) = vm.splite();
+        assert_eq!(dram, [4, 3, 2, 1, 5, 3, 2, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn atomic_access() -> Result<(), String> {
+        let program = Program::parse(
+            "
+            .data 0
+            .word 5
+            .text
+            addi R3, R0, #3
+            amoadd.w R1, R3, R0 // R1 = 5, [0] = 8
+            lr.w R4, R0
+            addi R4, R4, #1
+            sc.w R5, R4, R0     // R5 = 0, [0] = 9
+            fence
+            sw R1, R0, #4
+            sw R5, R0, #8
+            sc.w R6, R3, R0     // No reservation, R6 = 1
+            amomax.w R7, R6, R0 // R7 = 9
+            sw R6, R0, #12
+            sw R7, R0, #16
+            ",
+        )?;
+
+        let mut vm = fixture::new_machine(program.text(), 1, 20);
+        for (base, words) in program.data() {
+            vm.preload(*base, words)?;
+        }
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+
+        let (_, dram) = vm.splite();
+        let words: Vec<u32> = dram
+            .chunks(4)
+            .map(|word| raw_to_u32(word, Endian::Big))
+            .collect();
+        assert_eq!(words, [9, 5, 0, 1, 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn fence_across_units() -> Result<(), String> {
+        let program = [
+            "addi R1, R0, #2",
+            "mul R2, R1, R1",
+            "lw R4, R2, #0",
+            "sw R2, R0, #0",
+            "fence",
+            "lw R3, R0, #0",
+            "sw R3, R0, #4",
+        ];
+        let p = fixture::new_processor(1, 2, Some(1));
+        let mut vm = fixture::with_processor(&program, p, 8);
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+
+        let (p, dram) = vm.splite();
+        // The fence and the load after it are in the other unit, as stations of the first are full
+        let stats = p.bus_statistics();
+        assert_eq!(stats["mem_access1"].granted, 1);
+        assert_eq!(raw_to_u32(&dram[0..4], Endian::Big), 4);
+        assert_eq!(raw_to_u32(&dram[4..8], Endian::Big), 4);
+        Ok(())
+    }
+    #[test]
+    fn aliasing_across_units() -> Result<(), String> {
+        let program = [
+            "addi R1, R0, #2",
+            "mul R2, R1, R1",
+            "lw R5, R2, #0",
+            "sw R2, R0, #0",
+            "lw R3, R0, #0",
+            "add R4, R3, R0",
+        ];
+        let p = fixture::new_processor(1, 2, Some(1));
+        let mut vm = fixture::with_processor(&program, p, 8);
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+
+        // The store waits in the first unit, the load after it in the other unit waits for it
+        let (p, _) = vm.splite();
+        assert_eq!(p.peek_registers()[3], ArgState::Ready(4));
+        assert_eq!(p.peek_registers()[4], ArgState::Ready(4));
+        Ok(())
+    }
+    #[test]
+    fn simultaneous_multithreading() -> Result<(), String> {
+        let thread0 = ["addi R1, R0, #1", "add R2, R1, R1", "sw R2, R0, #0"];
+        let thread1 = ["addi R1, R0, #5", "add R2, R1, R1", "sw R2, R0, #4"];
+
+        for policy in ["round-robin", "icount"] {
+            let mut p = fixture::new_processor(1, 1, None);
+            p.set_fetch_policy(crate::core::smt::policy_of(policy)?);
+
+            let mut vm = fixture::with_processor(&thread0, p, 8);
+            vm.add_thread(thread1.iter().map(|i| i.to_string()).collect());
+            while vm.next_cycle().is_ok() {}
+            // Each cycle an instruction of either thread issued
+            assert_eq!(vm.core.issue_counts(), (6, 6), "{}", policy);
+            assert_eq!(vm.core.thread_issued(), [3, 3], "{}", policy);
+            flush(&mut vm);
+
+            assert_eq!(vm.dump_memory(), ["0x0000: 2", "0x0004: 10"], "{}", policy);
+            let (p, _) = vm.splite();
+            // Renamed registers of a thread are never written by the other
+            assert_eq!(p.peek_thread_registers(0)[1], ArgState::Ready(1));
+            assert_eq!(p.peek_thread_registers(1)[1], ArgState::Ready(5));
+        }
+        Ok(())
+    }
+    #[test]
+    fn threads_reuse_tags() -> Result<(), String> {
+        let thread0 = [
+            "addi R1, R0, #1",
+            "addi R1, R1, #1",
+            "addi R1, R1, #1",
+            "add R2, R1, R1",
+        ];
+        let thread1 = [
+            "addi R1, R0, #10",
+            "addi R1, R1, #10",
+            "addi R1, R1, #10",
+            "add R3, R1, R1",
+        ];
+        // A single slot, so every instruction of both threads is in "arith0(0)"
+        let p = fixture::new_processor(1, 1, Some(1));
+        let mut vm = fixture::with_processor(&thread0, p, 8);
+        vm.add_thread(thread1.iter().map(|i| i.to_string()).collect());
+        let mut committed = Vec::new();
+        // Tags are told apart by the thread they carry
+        while vm.registers(1)[1] == ArgState::Ready(0) {
+            vm.next_cycle()?;
+            committed.extend_from_slice(vm.committed());
+        }
+        assert_eq!(
+            vm.registers(1)[1],
+            ArgState::Waiting(RStag::new("arith0", 0).with_thread(1))
+        );
+        while vm.next_cycle().is_ok() {
+            committed.extend_from_slice(vm.committed());
+        }
+        while vm.next_flush_cycle().is_ok() {
+            committed.extend_from_slice(vm.committed());
+        }
+        // Each instruction commits once to the thread which issued it, in program order
+        for thread in 0..2 {
+            let addresses: Vec<usize> = committed
+                .iter()
+                .filter(|(id, _)| *id == thread)
+                .map(|(_, address)| *address)
+                .collect();
+            assert_eq!(addresses, [0, 1, 2, 3], "thread {}", thread);
+        }
+        let registers = |thread: usize| -> Vec<Option<u32>> {
+            vm.registers(thread)[1..4]
+                .iter()
+                .map(ArgState::val)
+                .collect()
+        };
+        assert_eq!(registers(0), [Some(3), Some(6), Some(0)]);
+        assert_eq!(registers(1), [Some(30), Some(0), Some(60)]);
+        Ok(())
+    }
+
+    #[test]
+    fn timer_interrupt() -> Result<(), String> {
+        let program = [
+            format!("addi R1, R0, #{}", INTC_ADDRESS),
+            String::from("addi R2, R0, #1"),
+            String::from("sw R2, R1, #0"), // Enable timer interrupt
+            String::from("sw R2, R1, #8"), // Fire in next cycle
+            String::from("addi R4, R0, #1"),
+            String::from("addi R4, R4, #1"),
+            String::from("addi R4, R4, #1"),
+            String::from("addi R4, R4, #1"),
+            String::from("addi R4, R4, #1"),
+            String::from("addi R4, R4, #1"),
+            format!("addi R6, R0, #{}", EXIT_ADDRESS),
+            String::from("sw R4, R6, #0"),
+            // Interrupt handler
+            String::from("sw R2, R1, #4"), // Acknowledge
+            String::from("addi R5, R5, #1"),
+            String::from("sw R5, R0, #0"),
+            String::from("mret"),
+        ];
+        let mut p = fixture::new_processor(1, 1, None);
+        p.set_interrupt_vector(12);
+
+        let mut vm = fixture::with_processor(&program, p, 4);
+        let msg = loop {
+            if let Err(msg) = vm.next_cycle() {
+                break msg;
+            }
+        };
+        assert_eq!(msg, "Machine exited with status 6");
+        // Handler runs once, and the interrupted work resumes
+        assert_eq!(vm.dump_memory(), ["0x0000: 1"]);
+        let (taken, drain_cycles) = vm.core.interrupt_statistics();
+        assert_eq!(taken, 1);
+        assert!(drain_cycles > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn control_and_status_registers() -> Result<(), String> {
+        let program = [
+            format!("addi R1, R0, #{}", INTC_ADDRESS),
+            String::from("addi R2, R0, #1"),
+            String::from("addi R3, R0, #19"),
+            String::from("csrw R3, #773"),  // Vector to the handler
+            String::from("csrr R7, #3074"), // Retired instructions
+            String::from("csrr R9, #3072"), // Cycles
+            String::from("addi R4, R0, #1"),
+            String::from("csrr R8, #3074"),
+            String::from("csrr R10, #3072"),
+            String::from("sw R2, R1, #0"), // Enable timer interrupt
+            String::from("sw R2, R1, #8"), // Fire in next cycle
+            String::from("addi R4, R4, #1"),
+            String::from("addi R4, R4, #1"),
+            String::from("addi R4, R4, #1"),
+            String::from("addi R4, R4, #1"),
+            String::from("addi R4, R4, #1"),
+            format!("addi R6, R0, #{}", EXIT_ADDRESS),
+            String::from("sw R4, R6, #0"),
+            String::from("nop"),
+            // Interrupt handler
+            String::from("sw R2, R1, #4"), // Acknowledge
+            String::from("csrr R5, #834"),
+            String::from("sw R5, R0, #8"),
+            String::from("csrr R5, #833"),
+            String::from("sw R5, R0, #12"),
+            String::from("sw R7, R0, #0"),
+            String::from("sw R8, R0, #4"),
+            String::from("sw R9, R0, #16"),
+            String::from("sw R10, R0, #20"),
+            String::from("mret"),
+        ];
+        let mut vm = fixture::new_machine(&program, 1, 24);
+        let msg = loop {
+            if let Err(msg) = vm.next_cycle() {
+                break msg;
+            }
+        };
+        assert_eq!(msg, "Machine exited with status 6");
+        let endian = vm.endian();
+        let (p, dram) = vm.splite();
+        let memory: Vec<u32> = dram
+            .chunks_exact(4)
+            .map(|word| raw_to_u32(word, endian))
+            .collect();
+        // CSR instructions are serialized, so all older instructions have retired
+        assert_eq!(memory[0], 4);
+        assert_eq!(memory[1], 7);
+        // Cause is the timer line, interrupted after enabling it
+        assert_eq!(memory[2], 1);
+        assert!((11..18).contains(&memory[3]));
+        assert!(memory[5] > memory[4]);
+        // mret re-enabled interrupts, the handler left EPC and cause untouched
+        assert_eq!(p.csr().read(csr::MSTATUS)?, 1 << 3);
+        assert_eq!(p.csr().read(csr::MEPC)?, memory[3]);
+        assert_eq!(p.csr().read(csr::MCAUSE)?, memory[2]);
+        assert_eq!(p.csr().read(csr::MTVEC)?, 19);
+        assert_eq!(p.csr().read(csr::MIP)?, 0);
+        Ok(())
+    }
+
+    fn virtual_memory_machine(program: &[&str]) -> Result<Machine, String> {
+        map_pages(fixture::new_machine(program, 1, 0x3000))
+    }
+
+    fn map_pages(mut vm: Machine) -> Result<Machine, String> {
+        /*
+         * Root page table at 0x0, second level page table at 0x1000
+         * 0x40000000 => 0x2000 (read, write)
+         * 0x40001000 => 0x2000 (read only)
+         */
+        vm.preload(0x400, &[(0x1 << 10) | 0x1])?;
+        vm.preload(0x1000, &[(0x2 << 10) | 0x7, (0x2 << 10) | 0x3])?;
+        vm.enable_mmu(0, 4);
+        Ok(vm)
+    }
+
+    #[test]
+    fn virtual_memory() -> Result<(), String> {
+        let mut vm = virtual_memory_machine(&[
+            "addi R1, R0, #1073741824", // R1 = 0x40000000
+            "addi R2, R0, #1073745920", // R2 = 0x40001000
+            "addi R3, R0, #7",
+            "sw R3, R1, #8",
+            "lw R4, R2, #8",
+            "add R5, R4, R3",
+            "sw R5, R1, #12",
+        ])?;
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+
+        assert_eq!(vm.tlb_statistics(), Some((1, 2)));
+        let (_, dram) = vm.splite();
+        assert_eq!(raw_to_u32(&dram[0x2008..0x200c], Endian::Big), 7);
+        assert_eq!(raw_to_u32(&dram[0x200c..0x2010], Endian::Big), 14);
+        Ok(())
+    }
+
+    #[test]
+    fn page_fault() -> Result<(), String> {
+        let mut vm = virtual_memory_machine(&[
+            "addi R1, R0, #1073745920", // R1 = 0x40001000
+            "sw R1, R1, #0",
+        ])?;
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+        let fault = vm.page_fault().expect("Store to a read only page faults");
+        assert_eq!((fault.address, fault.is_load), (0x40001000, false));
+        assert_eq!(fault.cause, FaultCause::NotWritable);
+        // The machine stops at the fault
+        let msg = vm.next_flush_cycle().unwrap_err();
+        assert!(msg.contains("Page fault"), "{}", msg);
+        let mut restored = virtual_memory_machine(&[])?;
+        restored.restore(&vm.save())?;
+        assert_eq!(restored.page_fault(), vm.page_fault());
+        Ok(())
+    }
+
+    #[test]
+    fn page_fault_trap() -> Result<(), String> {
+        let program = [
+            String::from("addi R1, R0, #10"),
+            String::from("csrw R1, #773"), // Vector to the handler
+            String::from("addi R10, R0, #1073754112"), // R10 = 0x40003000, not mapped yet
+            format!("addi R12, R0, #{}", EXIT_ADDRESS),
+            String::from("addi R2, R0, #7"),
+            String::from("lw R2, R10, #0"), // Faults, and runs again after the handler
+            String::from("addi R9, R9, #1"),
+            String::from("add R9, R9, R2"),
+            String::from("sw R9, R12, #0"),
+            String::from("nop"),
+            // Page fault handler maps 0x40003000 => 0x2000
+            String::from("csrr R5, #834"),
+            String::from("csrr R6, #835"),
+            String::from("add R11, R2, R0"),
+            String::from("addi R7, R0, #1073750016"), // R7 = 0x40002000, the page table
+            String::from("addi R8, R0, #2055"),
+            String::from("sw R8, R7, #12"),
+            String::from("mret"),
+        ];
+        for physical in [None, Some(48)] {
+            let mut p = fixture::new_processor(1, 1, None);
+            if let Some(count) = physical {
+                p.use_physical_registers(count)?;
+            }
+            let mut vm = map_pages(fixture::with_processor(&program, p, 0x3000))?;
+            // 0x40002000 => 0x1000, and devices are mapped to themselves by a superpage
+            vm.preload(0x1008, &[(0x1 << 10) | 0x7])?;
+            vm.preload(0xffc, &[(0xffc00 << 10) | 0x7])?;
+            vm.preload(0x2000, &[35])?;
+            let msg = loop {
+                if let Err(msg) = vm.next_cycle() {
+                    break msg;
+                }
+            };
+            assert_eq!(msg, "Machine exited with status 36");
+            assert_eq!(vm.page_fault(), None);
+            let registers: Vec<_> = vm.registers(0).iter().map(|r| r.val()).collect();
+            // The handler sees the cause, the address and the destination before the load
+            assert_eq!(registers[5], Some(csr::LOAD_PAGE_FAULT));
+            assert_eq!(registers[6], Some(0x40003000));
+            assert_eq!(registers[11], Some(7));
+            // Younger instructions ran once, after the load
+            assert_eq!(registers[9], Some(36));
+            assert_eq!(vm.csr().read(csr::MEPC)?, 5);
+            assert!(vm.csr().enabled);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn memory_mapped_io() -> Result<(), String> {
+        let program = [
+            format!("addi R1, R0, #{}", CONSOLE_ADDRESS),
+            format!("addi R2, R0, #{}", EXIT_ADDRESS),
+            String::from("addi R3, R0, #72"),  // 'H'
+            String::from("addi R4, R0, #105"), // 'i'
+            String::from("addi R5, R0, #3"),
+            String::from("sw R3, R1, #0"),
+            String::from("sw R4, R1, #0"),
+            String::from("sw R5, R2, #0"),
+            String::from("addi R6, R0, #1"),
+        ];
+        let mut vm = fixture::new_machine(&program, 1, 0);
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+
+        assert_eq!(vm.exit_status(), Some(3));
+        assert_eq!(vm.console_output(), b"Hi");
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_register() -> Result<(), String> {
+        let program = [
+            format!("addi R1, R0, #{}", TIMER_ADDRESS),
+            String::from("sw R1, R1, #0"),
+        ];
+        let mut vm = fixture::new_machine(&program, 1, 16);
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+        let fault = vm.bus_fault().expect("Store to the timer faults");
+        assert_eq!((fault.address, fault.is_load), (TIMER_ADDRESS, false));
+        assert!(fault.message.contains("read only"), "{}", fault.message);
+        // The machine stops at the fault
+        let msg = vm.next_flush_cycle().unwrap_err();
+        assert!(msg.contains("Bus fault"), "{}", msg);
+        let mut restored = fixture::new_machine(&program, 1, 16);
+        restored.restore(&vm.save())?;
+        assert_eq!(restored.bus_fault(), vm.bus_fault());
+        Ok(())
+    }
+
+    #[test]
+    fn unmapped_access() {
+        let program = ["lw R1, R0, #100", "addi R2, R0, #1"];
+        let mut vm = fixture::new_machine(&program, 1, 16);
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+        let fault = vm.bus_fault().expect("Load beyond memory faults");
+        assert_eq!((fault.address, fault.is_load), (100, true));
+        assert_eq!(vm.registers(0)[1].val(), None);
+        assert!(vm.next_flush_cycle().is_err());
+    }
+
+    #[test]
+    fn access_past_address_space() {
+        let program = ["addi R1, R0, #4294967295", "lw R2, R1, #0"];
+        let mut vm = fixture::new_machine(&program, 1, 16);
+        while vm.next_cycle().is_ok() {}
+        flush(&mut vm);
+        let fault = vm.bus_fault().expect("Load past the address space faults");
+        assert_eq!((fault.address, fault.is_load), (u32::MAX, true));
+        assert!(vm.next_flush_cycle().is_err());
+    }
+
+    #[test]
+    fn snapshot() -> Result<(), String> {
+        let program = [
+            "addi R1, R0, #8",
+            "sw R1, R1, #0",
+            "lw R2, R1, #0",
+            "add R3, R2, R1",
+            "amoadd.w R4, R3, R1",
+            "sw R3, R0, #4",
+        ];
+        let new_machine = || fixture::new_machine(&program, 2, 16);
+        let mut vm = new_machine();
+        for _ in 0..6 {
+            vm.next_cycle()?;
+        }
+        let snapshot = Json::parse(&vm.save().to_string())?;
+        let mut restored = new_machine();
+        restored.restore(&snapshot)?;
+        assert_eq!(restored.trace().to_string(), vm.trace().to_string());
+        // Both continue in the same way
+        for machine in [&mut vm, &mut restored] {
+            while machine.next_cycle().is_ok() {}
+            flush(machine);
+        }
+        assert_eq!(restored.trace().to_string(), vm.trace().to_string());
+        assert_eq!(restored.dump_memory(), vm.dump_memory());
+        assert_eq!(vm.dump_memory()[2], "0x0008: 24");
+
+        let mut other = new_machine();
+        other.add_thread(Vec::new());
+        assert!(other.restore(&snapshot).is_err());
+        // A snapshot broken in the middle leaves the machine untouched
+        let broken = match snapshot.clone() {
+            Json::Object(mut fields) => {
+                fields.retain(|(key, _)| key != "bus");
+                Json::Object(fields)
+            }
+            _ => unreachable!(),
+        };
+        let mut untouched = new_machine();
+        let fresh = untouched.trace().to_string();
+        assert!(untouched.restore(&broken).is_err());
+        assert_eq!(untouched.trace().to_string(), fresh);
+        let outdated = match vm.save() {
+            Json::Object(mut fields) => {
+                fields[0].1 = Json::from(0u32);
+                Json::Object(fields)
+            }
+            _ => unreachable!(),
+        };
+        assert!(new_machine().restore(&outdated).is_err());
+        Ok(())
+    }
 }
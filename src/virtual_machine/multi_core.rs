@@ -0,0 +1,464 @@
+use super::{CONSOLE_ADDRESS, EXIT_ADDRESS, TIMER_ADDRESS};
+use crate::core::execution_path::{BusAccessRequst, BusAccessResult};
+use crate::core::processor::Processor;
+use crate::display::into_table;
+use crate::memory_bus::bus::MemoryBus;
+use crate::memory_bus::cache::{
+    line_base, Cache, CacheStatistics, Protocol, Transaction, LINE_SIZE,
+};
+use crate::memory_bus::console::Console;
+use crate::memory_bus::dram::Dram;
+use crate::memory_bus::exit::Exit;
+use crate::memory_bus::timer::Timer;
+use crate::util::{raw_to_u32, u32_to_raw, Endian};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Count of lines in each private cache
+pub const CACHE_LINES: usize = 4;
+/// Count of coherence transactions kept for display
+const LOG_SIZE: usize = 8;
+
+/// A bus access waiting for the shared bus
+#[derive(Debug)]
+struct Pending {
+    request: BusAccessRequst,
+    /// Transaction required to serve the access, None for uncached accesses
+    transaction: Option<Transaction>,
+}
+
+/// A processor with its own instruction stream and private cache
+#[derive(Debug)]
+struct Core {
+    processor: Processor,
+    iram: Vec<String>,
+    cache: Cache,
+    /// Access which is waiting for or holding the shared bus
+    pending: Option<Pending>,
+}
+
+impl Core {
+    /// Return wheither all instructions are fetched and completed or not
+    fn is_finished(&self) -> bool {
+        self.processor.fetch_address() >= self.iram.len()
+            && self.processor.is_idle()
+            && self.pending.is_none()
+    }
+    fn next_cycle(&mut self) -> Result<(), String> {
+        match self.iram.get(self.processor.fetch_address()) {
+            Some(inst) => self.processor.next_cycle(inst),
+            None => self.processor.next_cycle("nop"),
+        }
+    }
+}
+
+/// Transaction holding the shared bus
+#[derive(Debug)]
+struct Owner {
+    core: usize,
+    /// Line read for the core, None if the core accesses an uncached device
+    transaction: Option<Transaction>,
+    /// Other caches hold copies of the line
+    shared: bool,
+}
+
+/// A machine whose cores share memory through a snooping bus.
+/// Each core has a private cache kept coherent by MSI or MESI protocol.
+/// Accesses to memory mapped devices and accesses crossing lines bypass the caches.
+pub struct MultiCoreMachine {
+    cores: Vec<Core>,
+    bus: MemoryBus,
+    /// Size of DRAM, accesses below it are cached
+    ram_size: usize,
+    owner: Option<Owner>,
+    /// Core granted the shared bus last time, used for round robin arbitration
+    last_granted: usize,
+    /// Recent coherence transactions
+    log: VecDeque<String>,
+    cycle: usize,
+    endian: Endian,
+}
+
+impl fmt::Display for MultiCoreMachine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for idx in 0..self.cores.len() {
+            writeln!(f, "Core {}", idx)?;
+            for pane in self.core_panes(idx) {
+                writeln!(f, "{}", pane)?;
+            }
+        }
+        for pane in self.panes() {
+            writeln!(f, "{}", pane)?;
+        }
+        Ok(())
+    }
+}
+
+impl MultiCoreMachine {
+    /// Construct a machine which runs each program on a core
+    pub fn new(
+        cores: Vec<(Processor, Vec<String>)>,
+        ram_size: usize,
+        protocol: Protocol,
+        endian: Endian,
+    ) -> Self {
+        let cores = cores
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (mut processor, iram))| {
+                processor.set_endian(endian);
+                let cache = Cache::new(format!("L1 cache{}", idx), CACHE_LINES, protocol, endian);
+                Core {
+                    processor,
+                    iram,
+                    cache,
+                    pending: None,
+                }
+            })
+            .collect();
+        let mut bus = MemoryBus::new(endian);
+        bus.map(0, Box::new(Dram::new(ram_size)))
+            .expect("Unable to map DRAM");
+        bus.map(CONSOLE_ADDRESS, Box::new(Console::new(true, endian)))
+            .expect("Unable to map console");
+        bus.map(TIMER_ADDRESS, Box::new(Timer::new(endian)))
+            .expect("Unable to map timer");
+        bus.map(EXIT_ADDRESS, Box::new(Exit::new(endian)))
+            .expect("Unable to map exit register");
+        Self {
+            cores,
+            bus,
+            ram_size,
+            owner: None,
+            last_granted: 0,
+            log: VecDeque::new(),
+            cycle: 0,
+            endian,
+        }
+    }
+    /// Write words to memory from `base` before execution
+    pub fn preload(&mut self, base: u32, words: &[u32]) -> Result<(), String> {
+        let raw: Vec<u8> = words
+            .iter()
+            .flat_map(|word| u32_to_raw(*word, self.endian))
+            .collect();
+        self.bus.load(base, &raw)
+    }
+    /// Return words in memory as rows of "address: value".
+    /// Dirty lines in caches are newer than DRAM, so they are taken into account.
+    pub fn dump_memory(&self) -> Vec<String> {
+        self.memory()
+            .chunks(4)
+            .enumerate()
+            .map(|(idx, word)| format!("{:#06x}: {}", idx * 4, raw_to_u32(word, self.endian)))
+            .collect()
+    }
+    fn memory(&self) -> Vec<u8> {
+        let mut memory = self
+            .bus
+            .device("dram")
+            .map(|dram| dram.dump())
+            .unwrap_or_default();
+        for core in self.cores.iter() {
+            for (base, data) in core.cache.dirty_lines() {
+                let base = base as usize;
+                memory[base..base + LINE_SIZE].copy_from_slice(&data);
+            }
+        }
+        memory
+    }
+    /// Return cache statistics of each core
+    pub fn cache_statistics(&self) -> Vec<CacheStatistics> {
+        self.cores
+            .iter()
+            .map(|core| core.cache.statistics().clone())
+            .collect()
+    }
+    /// If the program has written the exit register, return Some(exit status).
+    /// Otherwise, return None.
+    pub fn exit_status(&self) -> Option<u32> {
+        self.bus.exit_status()
+    }
+    /// Return count of cores
+    pub fn cores(&self) -> usize {
+        self.cores.len()
+    }
+    /// Return instructions executed by given core
+    pub fn program(&self, core: usize) -> &[String] {
+        &self.cores[core].iram
+    }
+    /// Return fetching address of given core
+    pub fn pc(&self, core: usize) -> usize {
+        self.cores[core].processor.fetch_address()
+    }
+    /// Return count of cycles executed
+    pub fn cycle(&self) -> usize {
+        self.cycle
+    }
+    /// Return tables of the processor and the private cache of given core
+    pub fn core_panes(&self, core: usize) -> Vec<String> {
+        let core = &self.cores[core];
+        let mut panes = core.processor.panes();
+        panes.push(core.cache.to_string());
+        panes
+    }
+    /// Return tables of recent coherence transactions and the shared bus
+    pub fn panes(&self) -> Vec<String> {
+        let log = self.log.iter().cloned().collect();
+        vec![
+            into_table("Coherence Transactions", log),
+            self.bus.to_string(),
+        ]
+    }
+    /// Execute next machine cycle of all cores.
+    /// Once all cores finished their programs, error returned.
+    pub fn next_cycle(&mut self) -> Result<(), String> {
+        if let Some(status) = self.exit_status() {
+            let msg = format!("Machine exited with status {}", status);
+            return Err(msg);
+        }
+        if let Some(fault) = self.bus.fault() {
+            return Err(format!("Machine stopped by {}", fault));
+        }
+        if self.owner.is_none() && self.cores.iter().all(|core| core.is_finished()) {
+            return Err(String::from("All cores are idle"));
+        }
+        for idx in 0..self.cores.len() {
+            self.cores[idx].next_cycle()?;
+            self.take_access(idx)?;
+        }
+        if self.owner.is_none() && self.bus.is_idle() {
+            self.arbitrate()?;
+        }
+        if let Some(response) = self.bus.next_cycle() {
+            let owner = self
+                .owner
+                .take()
+                .ok_or_else(|| String::from("Bus responsed without owner"))?;
+            let core = &mut self.cores[owner.core];
+            match owner.transaction {
+                Some(transaction) => {
+                    let pending = core
+                        .pending
+                        .take()
+                        .ok_or_else(|| String::from("Bus owner has no pending access"))?;
+                    let data = match response.into_result()? {
+                        BusAccessResult::Load(data) => data,
+                        _ => return Err(String::from("Expect data of cache line")),
+                    };
+                    let base = line_base(pending.request.address());
+                    core.cache.fill(base, data, transaction, owner.shared);
+                    let result = core.cache.perform(&pending.request);
+                    core.processor
+                        .resolve_access(pending.request.into_respose(result))?;
+                }
+                None => core.processor.resolve_access(response)?,
+            }
+        }
+        self.cycle += 1;
+        Ok(())
+    }
+    /// Take a bus access from the core.
+    /// Cache hits are served immediately, other accesses wait for the shared bus.
+    fn take_access(&mut self, idx: usize) -> Result<(), String> {
+        let core = &mut self.cores[idx];
+        if core.pending.is_some() {
+            return Ok(());
+        }
+        let request = match core.processor.bus_access() {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        let end = request.address() as usize + request.access_len();
+        let base = line_base(request.address());
+        // Accesses crossing lines are rare, serve them by memory rather than two lines
+        let crossing = end > base as usize + LINE_SIZE;
+        if end > self.ram_size || crossing {
+            core.pending = Some(Pending {
+                request,
+                transaction: None,
+            });
+            return Ok(());
+        }
+        match core.cache.required_transaction(&request) {
+            Some(transaction) => {
+                core.pending = Some(Pending {
+                    request,
+                    transaction: Some(transaction),
+                });
+            }
+            None => {
+                core.cache.hit();
+                let result = core.cache.perform(&request);
+                core.processor
+                    .resolve_access(request.into_respose(result))?;
+            }
+        }
+        Ok(())
+    }
+    /// Prepare for an access which bypasses caches.
+    /// If it touches DRAM, all cached copies of the lines are written back and invalidated.
+    fn bypass(&mut self, request: &BusAccessRequst) -> Result<(), String> {
+        let start = request.address() as usize;
+        let end = (start + request.access_len()).min(self.ram_size);
+        for base in (line_base(start as u32) as usize..end).step_by(LINE_SIZE) {
+            for core in self.cores.iter_mut() {
+                let snoop = core.cache.snoop(Transaction::BusRdX, base as u32);
+                if let Some(data) = snoop.flushed {
+                    self.bus.load(base as u32, &data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Grant the shared bus to the next waiting core in round robin order
+    fn arbitrate(&mut self) -> Result<(), String> {
+        let cnt = self.cores.len();
+        let granted = (1..=cnt)
+            .map(|offset| (self.last_granted + offset) % cnt)
+            .find(|idx| self.cores[*idx].pending.is_some());
+        let idx = match granted {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+        self.last_granted = idx;
+
+        let pending = self.cores[idx]
+            .pending
+            .take()
+            .expect("Granted core has no access");
+        if pending.transaction.is_none() {
+            self.bypass(&pending.request)?;
+            self.bus.access(pending.request)?;
+            self.owner = Some(Owner {
+                core: idx,
+                transaction: None,
+                shared: false,
+            });
+            return Ok(());
+        }
+        let request = pending.request;
+        // Transactions of other cores may have changed the line since the access was taken
+        let transaction = match self.cores[idx].cache.required_transaction(&request) {
+            Some(transaction) => transaction,
+            None => {
+                let core = &mut self.cores[idx];
+                core.cache.hit();
+                let result = core.cache.perform(&request);
+                return core.processor.resolve_access(request.into_respose(result));
+            }
+        };
+
+        let base = line_base(request.address());
+        let mut shared = false;
+        let mut reactions = Vec::new();
+        for (other, core) in self.cores.iter_mut().enumerate() {
+            if other == idx {
+                continue;
+            }
+            let snoop = core.cache.snoop(transaction, base);
+            shared |= snoop.had_copy;
+            if let Some(data) = snoop.flushed {
+                self.bus.load(base, &data)?;
+                reactions.push(format!("core{} flushed", other));
+            }
+            if snoop.invalidated {
+                reactions.push(format!("core{} invalidated", other));
+            }
+        }
+        let mut entry = format!("{}: core{} {} {:#x}", self.cycle, idx, transaction, base);
+        if !reactions.is_empty() {
+            entry = format!("{} ({})", entry, reactions.join(", "));
+        }
+        self.log.push_back(entry);
+        if self.log.len() > LOG_SIZE {
+            self.log.pop_front();
+        }
+
+        let core = &mut self.cores[idx];
+        if transaction == Transaction::BusUpgr {
+            core.cache.upgrade(base);
+            let result = core.cache.perform(&request);
+            return core.processor.resolve_access(request.into_respose(result));
+        }
+        if let Some((victim, data)) = core.cache.evict(base) {
+            self.bus.load(victim, &data)?;
+        }
+        let fill = BusAccessRequst::new_load(core.cache.name(), idx, base, LINE_SIZE);
+        self.bus.access(fill)?;
+        core.pending = Some(Pending {
+            request,
+            transaction: Some(transaction),
+        });
+        self.owner = Some(Owner {
+            core: idx,
+            transaction: Some(transaction),
+            shared,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod multi_core {
+    use super::*;
+    use crate::virtual_machine::fixture::new_multi_core;
+
+    fn run(vm: &mut MultiCoreMachine) -> String {
+        loop {
+            if let Err(msg) = vm.next_cycle() {
+                break msg;
+            }
+        }
+    }
+    #[test]
+    fn shared_counter() -> Result<(), String> {
+        let program: &[&str] = &[
+            "addi R1, R0, #1",
+            "amoadd.w R2, R1, R0",
+            "amoadd.w R2, R1, R0",
+            "amoadd.w R2, R1, R0",
+        ];
+        let mut vm = new_multi_core(&[program, program], 64, Protocol::Mesi);
+        vm.preload(0, &[10])?;
+        assert_eq!(run(&mut vm), "All cores are idle");
+        assert_eq!(vm.dump_memory()[0], "0x0000: 16");
+        Ok(())
+    }
+    #[test]
+    fn false_sharing() -> Result<(), String> {
+        let writer = |offset: &str| -> Vec<String> {
+            (0..4)
+                .map(|i| format!("addi R1, R0, #{}", i))
+                .flat_map(|addi| [addi, format!("sw R1, R0, #{}", offset)])
+                .collect()
+        };
+        let invalidations = |a: &[String], b: &[String]| -> Result<usize, String> {
+            let mut vm = new_multi_core(&[a, b], 64, Protocol::Mesi);
+            run(&mut vm);
+            let stat = vm.cache_statistics();
+            Ok(stat.iter().map(|s| s.invalidations).sum())
+        };
+        // Words in the same line bounce between caches
+        let same_line = invalidations(&writer("0"), &writer("4"))?;
+        let separate_lines = invalidations(&writer("0"), &writer("16"))?;
+        assert!(same_line > 0);
+        assert_eq!(separate_lines, 0);
+        Ok(())
+    }
+    #[test]
+    fn exclusive_state() -> Result<(), String> {
+        let program: &[&str] = &["lw R1, R0, #0", "addi R1, R1, #1", "sw R1, R0, #0"];
+        let mut vm = new_multi_core(&[program, &[]], 64, Protocol::Msi);
+        run(&mut vm);
+        assert_eq!(vm.cache_statistics()[0].upgrades, 1);
+
+        // Private data needs no upgrade in MESI
+        let mut vm = new_multi_core(&[program, &[]], 64, Protocol::Mesi);
+        run(&mut vm);
+        assert_eq!(vm.cache_statistics()[0].upgrades, 0);
+        assert_eq!(vm.dump_memory()[0], "0x0000: 1");
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+
+/// An assembly program which consists of instructions and data to preload
+///
+/// # Syntax
+/// ```text
+/// .data 16          // Following words are placed from address 16
+/// .word 1, -2, 0x10
+/// .text             // Following lines are instructions
+/// start:            // Label of the address of next instruction
+/// lw R1, R0, #16
+/// ```
+/// Comments start with `//` or `;`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Program {
+    text: Vec<String>,
+    /// (base address, words)
+    data: Vec<(u32, Vec<u32>)>,
+    /// Address of instruction of each label
+    labels: BTreeMap<String, usize>,
+}
+
+/// Section which is being parsed
+enum Section {
+    Text,
+    Data,
+}
+
+fn is_label(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn parse_number(token: &str) -> Result<u32, String> {
+    let parsed = if let Some(hex) = token.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else {
+        token.parse()
+    };
+    parsed.map_err(|_| format!("Expect an integer, found {}", token))
+}
+
+/// Parse a word, negative ones into their two's complement
+fn parse_word(token: &str) -> Result<u32, String> {
+    match token.strip_prefix('-') {
+        Some(magnitude) => match parse_number(magnitude)? {
+            magnitude if magnitude <= 1 << 31 => Ok(magnitude.wrapping_neg()),
+            _ => Err(format!("Word {} out of range", token)),
+        },
+        None => parse_number(token),
+    }
+}
+
+impl Program {
+    /// Parse a program from its source code
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut program = Program::default();
+        let mut section = Section::Text;
+        for (idx, line) in source.lines().enumerate() {
+            let line = line
+                .split("//")
+                .next()
+                .and_then(|l| l.split(';').next())
+                .unwrap_or_default()
+                .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: String| format!("Line {}: {}", idx + 1, msg);
+            let line = match line.split_once(':') {
+                Some((label, rest)) if is_label(label) => {
+                    if !matches!(section, Section::Text) {
+                        return Err(error(format!("Label {} outside of .text section", label)));
+                    }
+                    let address = program.text.len();
+                    if program.labels.insert(label.to_string(), address).is_some() {
+                        return Err(error(format!("Duplicated label {}", label)));
+                    }
+                    rest.trim()
+                }
+                _ => line,
+            };
+            if line.is_empty() {
+                continue;
+            }
+            let (directive, operand) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match directive {
+                ".text" => section = Section::Text,
+                ".data" => {
+                    let base = parse_number(operand.trim()).map_err(error)?;
+                    program.data.push((base, Vec::new()));
+                    section = Section::Data;
+                }
+                ".word" => {
+                    let words = match (&section, program.data.last_mut()) {
+                        (Section::Data, Some((_, words))) => words,
+                        _ => return Err(error(String::from(".word outside of .data section"))),
+                    };
+                    for word in operand.split(',') {
+                        let word = parse_word(word.trim()).map_err(error)?;
+                        words.push(word);
+                    }
+                }
+                _ if directive.starts_with('.') => {
+                    return Err(error(format!("Unknown directive {}", directive)))
+                }
+                _ => match section {
+                    Section::Text => program.text.push(line.to_string()),
+                    Section::Data => {
+                        return Err(error(format!("Instruction in .data section: {}", line)))
+                    }
+                },
+            }
+        }
+        Ok(program)
+    }
+    /// Return instructions of the program
+    pub fn text(&self) -> &[String] {
+        &self.text
+    }
+    /// Return addresses of labels
+    pub fn labels(&self) -> &BTreeMap<String, usize> {
+        &self.labels
+    }
+    /// Return data to preload as (base address, words) pairs
+    pub fn data(&self) -> &[(u32, Vec<u32>)] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod program {
+    use super::*;
+    #[test]
+    fn sections() -> Result<(), String> {
+        let source = "
+            .data 16 // j and k
+            .word 1, 0x10
+            .word 3
+            .text
+            lw R1, R0, #16 ; load j
+            addi R1, R1, #1
+            .data 0x40
+            .word 7
+        ";
+        let program = Program::parse(source)?;
+        assert_eq!(program.text(), ["lw R1, R0, #16", "addi R1, R1, #1"]);
+        assert_eq!(program.data(), [(16, vec![1, 16, 3]), (64, vec![7])]);
+        Ok(())
+    }
+    #[test]
+    fn labels() -> Result<(), String> {
+        let source = "
+            lw R1, R0, #16
+            add_one:
+            addi R1, R1, #1
+            done: nop // label and instruction on a line
+        ";
+        let program = Program::parse(source)?;
+        assert_eq!(program.text(), ["lw R1, R0, #16", "addi R1, R1, #1", "nop"]);
+        assert_eq!(program.labels()["add_one"], 1);
+        assert_eq!(program.labels()["done"], 2);
+        assert!(Program::parse(".data 0\nword: .word 1").is_err());
+        Ok(())
+    }
+    #[test]
+    fn whitespace_and_signed_words() -> Result<(), String> {
+        let source = ".data\t8\n.word\t-1,  -0x10, 2147483647, -2147483648\n.text\nnop";
+        let program = Program::parse(source)?;
+        let words = vec![u32::MAX, 0xffff_fff0, 0x7fff_ffff, 0x8000_0000];
+        assert_eq!(program.data(), [(8, words)]);
+        assert_eq!(program.text(), ["nop"]);
+        assert!(Program::parse(".data 0\n.word -2147483649").is_err());
+        Ok(())
+    }
+    #[test]
+    fn invalid() {
+        assert!(Program::parse(".word 1").is_err());
+        assert!(Program::parse(".data 0\naddi R1, R0, #1").is_err());
+        assert!(Program::parse(".data zero").is_err());
+        assert!(Program::parse(".bss 0").is_err());
+        assert!(Program::parse("a:\na:").is_err());
+    }
+}
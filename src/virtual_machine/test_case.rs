@@ -0,0 +1,326 @@
+use super::fuzz::Config;
+use super::program::{parse_number, Program};
+use super::timing::Timing;
+use super::Machine;
+use crate::util::raw_to_u32;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Bytes of DRAM test programs run with
+const MEMORY: usize = 256;
+/// Cycles a test program may run if no limit is expected
+const CYCLE_LIMIT: usize = 100_000;
+/// Machine test programs run on if none is given
+const MACHINE: Config = Config {
+    arith_units: 2,
+    mem_units: 1,
+    station_size: 4,
+    pipeline: (1, 1),
+    shared: false,
+    physical_registers: None,
+};
+
+/// Final state a test program is expected to reach
+#[derive(Debug, Clone, PartialEq)]
+enum Expectation {
+    Register(usize, u32),
+    /// (address, word)
+    Memory(u32, u32),
+    /// Run at most the count of cycles
+    MaxCycles(usize),
+}
+
+impl Expectation {
+    /// Parse operand of `.expect`, e.g. "R1 = 15", "[0x10] = 15" or "cycles <= 60"
+    fn parse(operand: &str) -> Result<Self, String> {
+        if let Some(limit) = operand.strip_prefix("cycles") {
+            let limit = limit
+                .trim()
+                .strip_prefix("<=")
+                .ok_or_else(|| format!("Expect cycles <= N, found {}", operand))?;
+            return Ok(Expectation::MaxCycles(parse_number(limit.trim())? as usize));
+        }
+        let (target, value) = operand
+            .split_once('=')
+            .ok_or_else(|| format!("Expect TARGET = VALUE, found {}", operand))?;
+        let (target, value) = (target.trim(), parse_number(value.trim())?);
+        if let Some(address) = target.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            let address = parse_number(address.trim())?;
+            if address % 4 != 0 {
+                return Err(format!("Unaligned address {:#x}", address));
+            }
+            Ok(Expectation::Memory(address, value))
+        } else if let Some(register) = target.strip_prefix('R') {
+            let register = register
+                .parse()
+                .map_err(|_| format!("Expect a register, found {}", target))?;
+            Ok(Expectation::Register(register, value))
+        } else {
+            Err(format!("Expect a register or [address], found {}", target))
+        }
+    }
+}
+
+impl Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expectation::Register(register, value) => write!(f, "R{} = {}", register, value),
+            Expectation::Memory(address, value) => write!(f, "[{:#x}] = {}", address, value),
+            Expectation::MaxCycles(limit) => write!(f, "cycles <= {}", limit),
+        }
+    }
+}
+
+/// An assembly program annotated with the final state it's expected to reach
+///
+/// # Syntax
+/// ```text
+/// .expect R1 = 15         // Final value of a register
+/// .expect [0x10] = 15     // Final word at an address of DRAM
+/// .expect cycles <= 60    // Run to the end in at most 60 cycles
+/// .machine 3 arith, 1 mem, 2 slots  // Run on the machine, see [Config::parse]
+/// ```
+/// Other lines are parsed by [Program::parse].
+/// Without `.machine`, the program runs on 2 arithmetic units and a memory access unit with 4 slots.
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    program: Program,
+    expectations: Vec<Expectation>,
+    machines: Vec<Config>,
+}
+
+impl TestCase {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut expectations = Vec::new();
+        let mut machines = Vec::new();
+        // Directives of tests are blanked out, so the program keeps its line numbers
+        let mut text = Vec::new();
+        for (idx, line) in source.lines().enumerate() {
+            let (directive, operand) = line.trim().split_once(' ').unwrap_or_default();
+            if directive != ".expect" && directive != ".machine" {
+                text.push(line);
+                continue;
+            }
+            let operand = operand
+                .split("//")
+                .next()
+                .and_then(|o| o.split(';').next())
+                .unwrap_or_default()
+                .trim();
+            let error = |msg| format!("Line {}: {}", idx + 1, msg);
+            if directive == ".expect" {
+                expectations.push(Expectation::parse(operand).map_err(error)?);
+            } else {
+                machines.push(Config::parse(operand).map_err(error)?);
+            }
+            text.push("");
+        }
+        let program = Program::parse(&text.join("\n"))?;
+        if machines.is_empty() {
+            machines.push(MACHINE);
+        }
+        Ok(Self {
+            program,
+            expectations,
+            machines,
+        })
+    }
+    /// Run the program on each machine.
+    /// Return a line for each expectation not met, which is empty if the test passes,
+    /// and timing on each machine.
+    pub fn run(&self) -> Result<(Vec<String>, Vec<Timing>), String> {
+        let mut diffs = Vec::new();
+        let mut timings = Vec::new();
+        for config in self.machines.iter() {
+            let (mut missed, timing) = self.run_on(config)?;
+            if self.machines.len() > 1 {
+                for diff in missed.iter_mut() {
+                    *diff = format!("{}: {}", config, diff);
+                }
+            }
+            diffs.append(&mut missed);
+            timings.push(timing);
+        }
+        Ok((diffs, timings))
+    }
+    fn run_on(&self, config: &Config) -> Result<(Vec<String>, Timing), String> {
+        let mut p = config.processor()?;
+        p.record_pipeline();
+        let mut vm = Machine::new(p, self.program.text().to_vec(), MEMORY);
+        for (base, words) in self.program.data() {
+            vm.preload(*base, words)?;
+        }
+
+        let limit = self
+            .expectations
+            .iter()
+            .find_map(|e| match e {
+                Expectation::MaxCycles(limit) => Some(*limit),
+                _ => None,
+            })
+            .unwrap_or(CYCLE_LIMIT);
+        let mut cycles = 0;
+        // Run one cycle over the limit, so exceeding it is reported
+        while cycles <= limit && vm.next_cycle().is_ok() {
+            cycles += 1;
+        }
+        while cycles <= limit && vm.next_flush_cycle().is_ok() {
+            cycles += 1;
+        }
+
+        let registers = vm.registers(0);
+        let memory = vm.memory();
+        let mut diffs = Vec::new();
+        for expectation in self.expectations.iter() {
+            let got = match expectation {
+                Expectation::Register(register, expect) => match registers.get(*register) {
+                    Some(got) => match got.val() {
+                        Some(got) => (got != *expect).then(|| got.to_string()),
+                        None => Some(format!("{:?}", got)),
+                    },
+                    None => Some(String::from("no such register")),
+                },
+                Expectation::Memory(address, expect) => {
+                    let start = *address as usize;
+                    match memory.get(start..start + 4) {
+                        Some(word) => {
+                            let word = raw_to_u32(word, vm.endian());
+                            (word != *expect).then(|| word.to_string())
+                        }
+                        None => Some(String::from("out of DRAM")),
+                    }
+                }
+                Expectation::MaxCycles(limit) => (cycles > *limit).then(|| String::from("more")),
+            };
+            if let Some(got) = got {
+                diffs.push(format!("expect {}, got {}", expectation, got));
+            }
+        }
+        let (p, _) = vm.splite();
+        let pipeline = p.pipeline().expect("Pipeline is recorded");
+        let timing = Timing::new(config, cycles, pipeline, self.program.text().len());
+        Ok((diffs, timing))
+    }
+}
+
+/// Return test programs in the directory, the files ending with ".s", sorted by name
+pub fn discover(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "s"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Run a test program, and compare its timing with the one recorded next to it, if any.
+/// If `record` is set, the timing is recorded instead.
+fn run_file(path: &Path, record: bool) -> Result<Vec<String>, String> {
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let (mut diffs, timings) = TestCase::parse(&source)?.run()?;
+    let timing_path = path.with_extension("timing");
+    let error = |e: String| format!("{}: {}", timing_path.display(), e);
+    if record {
+        let mut text = String::from("// Issue and complete cycles of each instruction\n");
+        let sections: Vec<String> = timings.iter().map(|t| t.to_string()).collect();
+        text.push_str(&sections.join("\n"));
+        fs::write(&timing_path, text).map_err(|e| error(e.to_string()))?;
+    } else if timing_path.exists() {
+        let recorded = fs::read_to_string(&timing_path).map_err(|e| error(e.to_string()))?;
+        let recorded = Timing::parse_all(&recorded).map_err(error)?;
+        for timing in timings.iter() {
+            match recorded.iter().find(|r| r.config() == timing.config()) {
+                Some(expect) => diffs.extend(timing.diff(expect)),
+                None => diffs.push(format!("{}: timing not recorded", timing.config())),
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+/// Run test programs in the directory, and return a report of each of them.
+/// Failed tests are reported with their diffs, the count of failed tests is returned as well.
+/// Timing deviations fail tests unless `record` is set, which records timing of every test.
+pub fn run_all(dir: &Path, record: bool) -> Result<(Vec<String>, usize), String> {
+    let mut report = Vec::new();
+    let mut failed = 0;
+    for path in discover(dir)? {
+        match run_file(&path, record) {
+            Ok(diffs) if diffs.is_empty() => report.push(format!("PASS {}", path.display())),
+            Ok(diffs) => {
+                failed += 1;
+                report.push(format!("FAIL {}", path.display()));
+                report.extend(diffs.iter().map(|diff| format!("    {}", diff)));
+            }
+            Err(msg) => {
+                failed += 1;
+                report.push(format!("FAIL {}", path.display()));
+                report.push(format!("    {}", msg));
+            }
+        }
+    }
+    Ok((report, failed))
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod test_case {
+    use super::*;
+
+    #[test]
+    fn expectations() -> Result<(), String> {
+        let source = "
+            .data 16
+            .word 7
+            .text
+            lw R1, R0, #16
+            addi R2, R1, #1
+            sw R2, R0, #20
+            .expect R1 = 7
+            .expect R2 = 9 // wrong
+            .expect [0x14] = 8
+            .expect [24] = 1
+            .expect cycles <= 100
+        ";
+        let test = TestCase::parse(source)?;
+        assert_eq!(test.program.text().len(), 3);
+        assert_eq!(test.expectations[4], Expectation::MaxCycles(100));
+        let (diffs, timings) = test.run()?;
+        assert_eq!(diffs, ["expect R2 = 9, got 8", "expect [0x18] = 1, got 0"]);
+        assert_eq!(timings[0].config(), "2 arith, 1 mem, 4 slots");
+
+        let test = TestCase::parse(".expect cycles <= 2\naddi R1, R0, #1\naddi R1, R0, #2")?;
+        assert_eq!(test.run()?.0, ["expect cycles <= 2, got more"]);
+
+        let source = [
+            ".machine 1 arith, 1 mem, 1 slots",
+            ".machine 3 arith, 1 mem, 2 slots",
+            ".expect R1 = 2",
+            "addi R1, R0, #1",
+        ];
+        let (diffs, timings) = TestCase::parse(&source.join("\n"))?.run()?;
+        assert_eq!(diffs[1], "3 arith, 1 mem, 2 slots: expect R1 = 2, got 1");
+        assert_eq!(timings.len(), 2);
+        assert!(TestCase::parse(".machine 1 arith").is_err());
+
+        assert!(TestCase::parse(".expect R1 15").is_err());
+        assert!(TestCase::parse(".expect [2] = 1").is_err());
+        assert!(TestCase::parse(".expect cycles < 2").is_err());
+        assert!(TestCase::parse("\n.expect PC = 1")
+            .unwrap_err()
+            .starts_with("Line 2"));
+        Ok(())
+    }
+
+    /// Regression programs of the repository
+    #[test]
+    fn programs() -> Result<(), String> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+        let (report, failed) = run_all(&dir, false)?;
+        assert!(!report.is_empty());
+        assert_eq!(failed, 0, "{}", report.join("\n"));
+        Ok(())
+    }
+}
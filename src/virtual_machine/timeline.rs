@@ -0,0 +1,248 @@
+use super::Machine;
+use std::collections::BTreeMap;
+
+/// Cycles between snapshots
+pub const SNAPSHOT_INTERVAL: usize = 64;
+
+/// Whether the machine is executing the program, draining or finished
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunState {
+    Running,
+    /// Issuing nops until in-flight instructions complete
+    Flushing,
+    /// Finished with the message of the last error
+    Finished(String),
+}
+
+/// A machine which is able to step backward.
+/// Snapshots are taken periodically, and an earlier cycle is restored by
+/// re-executing from the latest snapshot before it, since execution is deterministic.
+pub struct Timeline {
+    vm: Machine,
+    state: RunState,
+    cycle: usize,
+    interval: usize,
+    /// Snapshot `k` is taken at cycle `k * interval`
+    snapshots: Vec<(Machine, RunState)>,
+    /// Cycles in which each register (thread, number) was written, in order
+    writes: BTreeMap<(usize, usize), Vec<usize>>,
+    /// The furthest cycle ever executed, writes are recorded up to it
+    furthest: usize,
+    /// The cycle the machine finished at, and why
+    end: Option<(usize, String)>,
+}
+
+impl Timeline {
+    pub fn new(vm: Machine) -> Self {
+        Self::with_interval(vm, SNAPSHOT_INTERVAL)
+    }
+    pub fn with_interval(vm: Machine, interval: usize) -> Self {
+        let snapshots = vec![(vm.clone(), RunState::Running)];
+        Self {
+            vm,
+            state: RunState::Running,
+            cycle: 0,
+            interval: interval.max(1),
+            snapshots,
+            writes: BTreeMap::new(),
+            furthest: 0,
+            end: None,
+        }
+    }
+    pub fn machine(&self) -> &Machine {
+        &self.vm
+    }
+    pub fn into_machine(self) -> Machine {
+        self.vm
+    }
+    /// Return count of cycles executed
+    pub fn cycle(&self) -> usize {
+        self.cycle
+    }
+    pub fn state(&self) -> &RunState {
+        &self.state
+    }
+    /// Execute a cycle, fetching the program until it's out of instructions, then flushing.
+    /// Return false if the machine has finished.
+    pub fn step(&mut self) -> bool {
+        if let RunState::Finished(_) = self.state {
+            return false;
+        }
+        // Console output of cycles executed before has been printed already
+        self.vm.set_echo(self.cycle == self.furthest);
+        if let Err(msg) = advance(&mut self.vm, &mut self.state) {
+            self.end = Some((self.cycle, msg));
+            return false;
+        }
+        self.cycle += 1;
+        if self.cycle > self.furthest {
+            self.furthest = self.cycle;
+            for register in self.vm.written() {
+                self.writes.entry(*register).or_default().push(self.cycle);
+            }
+        }
+        if self.cycle.is_multiple_of(self.interval)
+            && self.snapshots.len() == self.cycle / self.interval
+        {
+            self.snapshots.push((self.vm.clone(), self.state.clone()));
+        }
+        true
+    }
+    /// Step back a cycle, return false if already at the first cycle
+    pub fn step_back(&mut self) -> bool {
+        match self.cycle.checked_sub(1) {
+            Some(target) => self.goto(target).is_ok(),
+            None => false,
+        }
+    }
+    /// Move to the given cycle, backward or forward.
+    /// Error if the machine finishes before the cycle.
+    pub fn goto(&mut self, target: usize) -> Result<(), String> {
+        if target < self.cycle {
+            let k = (target / self.interval).min(self.snapshots.len() - 1);
+            let (vm, state) = &self.snapshots[k];
+            self.vm = vm.clone();
+            self.state = state.clone();
+            self.cycle = k * self.interval;
+        }
+        while self.cycle < target {
+            if !self.step() {
+                return Err(format!("Machine finished at cycle {}", self.cycle));
+            }
+        }
+        // The machine is known to finish here, without trying another cycle
+        if let Some((end, msg)) = self.end.as_ref() {
+            if *end == self.cycle {
+                self.state = RunState::Finished(msg.clone());
+            }
+        }
+        Ok(())
+    }
+    /// Return the machine at an earlier cycle, re-executed from the latest snapshot before it.
+    /// The current machine is left as is.
+    pub fn machine_at(&self, target: usize) -> Option<Machine> {
+        if target > self.cycle {
+            return None;
+        }
+        let k = (target / self.interval).min(self.snapshots.len() - 1);
+        let (vm, state) = &self.snapshots[k];
+        let (mut vm, mut state) = (vm.clone(), state.clone());
+        vm.set_echo(false);
+        for _ in k * self.interval..target {
+            advance(&mut vm, &mut state).ok()?;
+        }
+        Some(vm)
+    }
+    /// Return the latest cycle, up to the current one, in which the register was written
+    pub fn last_write(&self, thread: usize, register: usize) -> Option<usize> {
+        self.writes
+            .get(&(thread, register))?
+            .iter()
+            .rev()
+            .find(|cycle| **cycle <= self.cycle)
+            .copied()
+    }
+}
+
+/// Execute a cycle, fetching the program until it's out of instructions, then flushing.
+/// On error, the machine is finished with the message.
+fn advance(vm: &mut Machine, state: &mut RunState) -> Result<(), String> {
+    let result = match state {
+        RunState::Running => match vm.next_cycle() {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                *state = RunState::Flushing;
+                vm.next_flush_cycle()
+            }
+        },
+        RunState::Flushing => vm.next_flush_cycle(),
+        RunState::Finished(msg) => Err(msg.clone()),
+    };
+    if let Err(msg) = result.as_ref() {
+        *state = RunState::Finished(msg.clone());
+    }
+    result
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod timeline {
+    use super::*;
+    use crate::virtual_machine::fixture;
+
+    fn new_machine() -> Machine {
+        let program = [
+            "addi R1, R0, #4",
+            "sw R1, R0, #0",
+            "lw R2, R0, #0",
+            "add R3, R2, R1",
+            "addi R1, R0, #1",
+        ];
+        fixture::new_machine(&program, 1, 8)
+    }
+
+    /// Return trace of registers of thread 0, and the rest of the trace
+    fn registers(timeline: &Timeline) -> String {
+        let trace = timeline.machine().trace().to_string();
+        let key = "\"registers\":";
+        let start = trace.find(key).unwrap() + key.len();
+        trace[start..].to_string()
+    }
+
+    #[test]
+    fn step_back() -> Result<(), String> {
+        let mut timeline = Timeline::with_interval(new_machine(), 4);
+        timeline.goto(7)?;
+        let expected = timeline.machine().trace().to_string();
+        while timeline.step() {}
+        let end = timeline.cycle();
+        let finished = timeline.machine().trace().to_string();
+
+        // Going back restores the same state as executing forward
+        timeline.goto(7)?;
+        assert_eq!(timeline.machine().trace().to_string(), expected);
+        assert!(timeline.step_back());
+        assert_eq!(timeline.cycle(), 6);
+
+        timeline.goto(end)?;
+        assert_eq!(timeline.machine().trace().to_string(), finished);
+        assert!(timeline.goto(end + 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn machine_at() -> Result<(), String> {
+        let mut timeline = Timeline::with_interval(new_machine(), 4);
+        timeline.goto(6)?;
+        let expected = timeline.machine().trace().to_string();
+        timeline.goto(9)?;
+        let current = timeline.machine().trace().to_string();
+        let earlier = timeline.machine_at(6).unwrap();
+        assert_eq!(earlier.trace().to_string(), expected);
+        // The timeline stays at its cycle
+        assert_eq!(timeline.cycle(), 9);
+        assert_eq!(timeline.machine().trace().to_string(), current);
+        assert!(timeline.machine_at(10).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn last_write() -> Result<(), String> {
+        let mut timeline = Timeline::new(new_machine());
+        while timeline.step() {}
+        let second = timeline.last_write(0, 1).unwrap();
+        // The add waits for the load, so it commits after the independent addi
+        assert!(second < timeline.last_write(0, 3).unwrap());
+        assert_eq!(timeline.last_write(0, 4), None);
+
+        // Going back to before the second write finds the first one
+        timeline.goto(second - 1)?;
+        let first = timeline.last_write(0, 1).unwrap();
+        assert!(first < second);
+        timeline.goto(first)?;
+        assert!(registers(&timeline).starts_with("[0,4,"));
+        timeline.step_back();
+        assert!(!registers(&timeline).starts_with("[0,4,"));
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use super::fuzz::Config;
+use crate::core::pipeline::PipelineLog;
+use std::fmt::{self, Display};
+
+/// Cycles an instruction issued and completed in
+#[derive(Debug, Clone, PartialEq)]
+struct InstTiming {
+    text: String,
+    issue: u64,
+    /// None if it never completed
+    complete: Option<u64>,
+}
+
+/// Timing of a program run on a machine configuration, to detect scheduling changes
+///
+/// # Format
+/// ```text
+/// [2 arith, 1 mem, 4 slots]   // Configuration
+/// cycles 12                   // Cycles to run to the end
+///    0    2  addi R1, R0, #8  // Issue and complete cycles of each instruction
+///    1    -  sw R1, R1, #0    // The instruction never completed
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timing {
+    config: String,
+    cycles: usize,
+    insts: Vec<InstTiming>,
+}
+
+impl Timing {
+    /// Take timing of the first `len` instructions issued, the program without nops issued beyond it
+    pub fn new(config: &Config, cycles: usize, pipeline: &PipelineLog, len: usize) -> Self {
+        let insts = pipeline
+            .timing()
+            .into_iter()
+            .take(len)
+            .map(|(text, issue, complete)| InstTiming {
+                text: text.to_string(),
+                issue,
+                complete,
+            })
+            .collect();
+        Self {
+            config: config.to_string(),
+            cycles,
+            insts,
+        }
+    }
+    pub fn config(&self) -> &str {
+        &self.config
+    }
+    /// Parse timing of all configurations recorded in a file
+    pub fn parse_all(source: &str) -> Result<Vec<Self>, String> {
+        let mut timings: Vec<Self> = Vec::new();
+        for (idx, line) in source.lines().enumerate() {
+            let line = line.split("//").next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: &str| format!("Line {}: {}", idx + 1, msg);
+            if let Some(config) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                timings.push(Self {
+                    config: Config::parse(config)
+                        .map_err(|msg| error(&msg))?
+                        .to_string(),
+                    cycles: 0,
+                    insts: Vec::new(),
+                });
+                continue;
+            }
+            let timing = timings
+                .last_mut()
+                .ok_or_else(|| error("Expect a [configuration] first"))?;
+            if let Some(cycles) = line.strip_prefix("cycles ") {
+                timing.cycles = cycles
+                    .trim()
+                    .parse()
+                    .map_err(|_| error("Expect count of cycles"))?;
+                continue;
+            }
+            let (issue, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let (complete, text) = rest.trim_start().split_once(' ').unwrap_or((rest, ""));
+            let complete = match complete {
+                "-" => Some(None),
+                c => c.parse().ok().map(Some),
+            };
+            match (issue.parse(), complete, text.trim()) {
+                (Ok(issue), Some(complete), text) if !text.is_empty() => {
+                    timing.insts.push(InstTiming {
+                        text: text.to_string(),
+                        issue,
+                        complete,
+                    })
+                }
+                _ => return Err(error("Expect issue cycle, complete cycle and instruction")),
+            }
+        }
+        Ok(timings)
+    }
+    /// Return a line for each deviation from the expected timing
+    pub fn diff(&self, expect: &Self) -> Vec<String> {
+        let mut diffs = Vec::new();
+        if self.cycles != expect.cycles {
+            diffs.push(format!(
+                "{}: cycles {} -> {}",
+                self.config, expect.cycles, self.cycles
+            ));
+        }
+        if self.insts.len() != expect.insts.len() {
+            diffs.push(format!(
+                "{}: {} instructions, recorded {}",
+                self.config,
+                self.insts.len(),
+                expect.insts.len()
+            ));
+            return diffs;
+        }
+        let cycle = |cycle: Option<u64>| cycle.map_or(String::from("-"), |c| c.to_string());
+        for (idx, (got, expect)) in self.insts.iter().zip(expect.insts.iter()).enumerate() {
+            if got.text != expect.text {
+                diffs.push(format!(
+                    "{}: instruction {} is `{}`, recorded `{}`",
+                    self.config, idx, got.text, expect.text
+                ));
+                return diffs;
+            }
+            let mut changes = Vec::new();
+            if got.issue != expect.issue {
+                changes.push(format!("issue {} -> {}", expect.issue, got.issue));
+            }
+            if got.complete != expect.complete {
+                changes.push(format!(
+                    "complete {} -> {}",
+                    cycle(expect.complete),
+                    cycle(got.complete)
+                ));
+            }
+            if !changes.is_empty() {
+                diffs.push(format!(
+                    "{}: {} `{}` {}",
+                    self.config,
+                    idx,
+                    got.text,
+                    changes.join(", ")
+                ));
+            }
+        }
+        diffs
+    }
+}
+
+impl Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[{}]", self.config)?;
+        writeln!(f, "cycles {}", self.cycles)?;
+        for inst in self.insts.iter() {
+            let complete = inst.complete.map_or(String::from("-"), |c| c.to_string());
+            writeln!(f, "{:>4} {:>4}  {}", inst.issue, complete, inst.text)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod timing {
+    use super::*;
+    use crate::core::execution_path::RStag;
+
+    #[test]
+    fn record_and_diff() -> Result<(), String> {
+        let config = Config::parse("2 arith, 1 mem, 4 slots")?;
+        let mut log = PipelineLog::new();
+        let tag = RStag::new("arith0", 0);
+        log.issue(0, 0, "addi R1, R0, #8", Some(tag.clone()));
+        log.issue(1, 0, "sw R1, R1, #0", Some(RStag::new("mem_access0", 4)));
+        log.issue(2, 0, "nop", None);
+        log.writeback(2, &tag);
+        let timing = Timing::new(&config, 12, &log, 2);
+        let text = timing.to_string();
+        assert_eq!(
+            text,
+            "[2 arith, 1 mem, 4 slots]\ncycles 12\n   0    2  addi R1, R0, #8\n   1    -  sw R1, R1, #0\n"
+        );
+        let parsed = Timing::parse_all(&format!("// Recorded\n{}", text))?;
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0], timing);
+        assert!(timing.diff(&parsed[0]).is_empty());
+
+        let mut slower = timing.clone();
+        slower.cycles = 13;
+        slower.insts[1].complete = Some(9);
+        assert_eq!(
+            slower.diff(&timing),
+            [
+                "2 arith, 1 mem, 4 slots: cycles 12 -> 13",
+                "2 arith, 1 mem, 4 slots: 1 `sw R1, R1, #0` complete - -> 9",
+            ]
+        );
+
+        assert!(Timing::parse_all("cycles 3").is_err());
+        assert!(Timing::parse_all("[2 arith]").is_err());
+        assert!(Timing::parse_all("[2 arith, 1 mem, 4 slots]\n0 addi R1, R0, #1").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+use crate::util::json::Json;
+use std::io::Write;
+
+/// Write state of every cycle as JSON Lines.
+/// Each line is an object with the cycle number and the state of the machine.
+pub struct TraceWriter<W: Write> {
+    out: W,
+    cycle: u64,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, cycle: 0 }
+    }
+    /// Append state of current cycle, then move to next cycle
+    pub fn record(&mut self, state: Json) -> Result<(), String> {
+        let line = Json::object(vec![("cycle", Json::from(self.cycle)), ("state", state)]);
+        writeln!(self.out, "{}", line).map_err(|e| format!("Trace: {}", e))?;
+        self.cycle += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+// Atomic memory operations, and a store conditional which lost its reservation
+.expect R2 = 3       // amoadd.w returns the old value
+.expect R3 = 8
+.expect R4 = 0       // sc.w succeeds
+.expect R5 = 1       // sc.w fails after the reservation is consumed
+.expect R6 = 9
+.expect [0x10] = 9
+.expect [0x14] = 7
+
+.data 16
+.word 3, 7
+.text
+addi R1, R0, #16
+addi R7, R0, #5
+amoadd.w R2, R7, R1
+lr.w R3, R1
+addi R8, R0, #9
+sc.w R4, R8, R1
+sc.w R5, R7, R1
+amomax.w R6, R0, R1
@@ -0,0 +1,33 @@
+// j += 4 and k += 5 for three iterations, through memory
+.expect [16] = 12
+.expect [20] = 15
+.expect R1 = 15
+.expect cycles <= 200
+.machine 2 arith, 1 mem, 4 slots
+.machine 1 arith, 1 mem, 1 slots
+.machine 3 arith, 1 mem, 2 slots
+
+addi R1, R0, #0
+addi R2, R0, #16
+sw R1, R2, #0 // j = 0
+sw R1, R2, #4 // k = 0
+addi R3, R0, #4
+addi R4, R0, #5
+lw R1, R2, #0
+add R1, R3, R1
+sw R1, R2, #0
+lw R1, R2, #4
+add R1, R4, R1
+sw R1, R2, #4
+lw R1, R2, #0
+add R1, R3, R1
+sw R1, R2, #0
+lw R1, R2, #4
+add R1, R4, R1
+sw R1, R2, #4
+lw R1, R2, #0
+add R1, R3, R1
+sw R1, R2, #0
+lw R1, R2, #4
+add R1, R4, R1
+sw R1, R2, #4
@@ -0,0 +1,21 @@
+// Independent multiplies overlap in a pipelined unit
+.expect R1 = 6
+.expect R2 = 12
+.expect R3 = 20
+.expect R4 = 30
+.expect R5 = 68
+.machine 1 arith, 1 mem, 4 slots
+.machine 1 arith, 1 mem, 4 slots, depth 4, interval 1
+.machine 1 arith, 1 mem, 4 slots, depth 4, interval 2
+addi R6, R0, #2
+addi R7, R0, #3
+addi R8, R0, #4
+addi R9, R0, #5
+addi R10, R0, #6
+mul R1, R6, R7
+mul R2, R7, R8
+mul R3, R8, R9
+mul R4, R9, R10
+add R5, R1, R2
+add R5, R5, R3
+add R5, R5, R4
@@ -0,0 +1,20 @@
+// R1 is written over and over while a multiplication is in flight.
+// Reservation stations rename it freely, physical registers stall once the free list runs out.
+.expect R1 = 9
+.expect R2 = 36
+.expect R3 = 18
+.machine 2 arith, 1 mem, 4 slots
+.machine 2 arith, 1 mem, 4 slots, 18 registers
+.machine 2 arith, 1 mem, 4 slots, 24 registers
+addi R2, R0, #6
+mul R2, R2, R2
+addi R1, R0, #1
+addi R1, R1, #1
+addi R1, R1, #1
+addi R1, R1, #1
+addi R1, R1, #1
+addi R1, R1, #1
+addi R1, R1, #1
+addi R1, R1, #1
+addi R1, R1, #1
+add R3, R1, R1
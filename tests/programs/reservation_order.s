@@ -0,0 +1,12 @@
+// The second lr.w waits for amoadd.w to the same word, and the sc.w to the
+// first reserved word must not overtake it, so it fails
+.expect R7 = 1
+.expect [8] = 0
+
+addi R1, R0, #8
+addi R2, R0, #4
+lr.w R3, R1
+lw R4, R0, #0
+amoadd.w R5, R4, R2
+lr.w R6, R2
+sc.w R7, R2, R1
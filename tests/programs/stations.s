@@ -0,0 +1,17 @@
+// A long chain and independent work, on private and shared reservation stations
+.expect R1 = 40
+.expect R5 = 12
+.machine 2 arith, 1 mem, 2 slots
+.machine 2 arith, 1 mem, 2 slots, shared
+addi R1, R0, #5
+mul R1, R1, R1
+addi R2, R0, #1
+addi R3, R0, #2
+add R1, R1, R1
+addi R4, R0, #3
+add R5, R2, R3
+addi R1, R1, #10
+add R5, R5, R4
+add R5, R5, R5
+mul R1, R1, R0
+addi R1, R1, #40
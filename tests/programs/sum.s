@@ -0,0 +1,15 @@
+// Sum an array preloaded in .data
+.expect R5 = 100
+.expect [0x30] = 100
+
+.data 0x20
+.word 10, 20, 0x1e, 40
+.text
+lw R1, R0, #32
+lw R2, R0, #36
+lw R3, R0, #40
+lw R4, R0, #44
+add R5, R1, R2
+add R6, R3, R4
+add R5, R5, R6
+sw R5, R0, #48